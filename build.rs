@@ -0,0 +1,27 @@
+//! Embeds the git commit and build time for `--version-path`.
+use std::{env, path::Path, process::Command, time::{SystemTime, UNIX_EPOCH}};
+
+fn main() {
+    // Docker builds don't copy `.git`, so the commit can be passed in instead
+    // with `--build-arg GIT_COMMIT=$(git rev-parse HEAD)`
+    let commit = env::var("GIT_COMMIT").ok().filter(|c| !c.is_empty()).or_else(|| {
+        let out = Command::new("git").args(["rev-parse", "HEAD"]).output().ok().filter(|out| out.status.success())?;
+        Some(String::from_utf8(out.stdout).ok()?.trim().to_owned())
+    }).unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=ZIPSTREAM_GIT_COMMIT={}", commit);
+
+    // SOURCE_DATE_EPOCH makes the build reproducible
+    let build_time = env::var("SOURCE_DATE_EPOCH").ok().and_then(|s| s.parse::<u64>().ok()).unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    });
+    println!("cargo:rustc-env=ZIPSTREAM_BUILD_TIME={}", build_time);
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    for path in [".git/HEAD", ".git/refs/heads", ".git/packed-refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
@@ -1,6 +1,6 @@
-/// This demo server is a stand-in for your application. A real application
-/// would store the length and CRC of your S3 files in a database, and use the
-/// proxied request URL and headers to generate a manifest like the one below.
+//! This demo server is a stand-in for your application. A real application
+//! would store the length and CRC of your S3 files in a database, and use the
+//! proxied request URL and headers to generate a manifest like the one below.
 
 use std::convert::Infallible;
 use bytes::Bytes;
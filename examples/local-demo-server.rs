@@ -0,0 +1,143 @@
+//! A self-contained version of `demo-server` that also serves the files the
+//! manifest refers to, so the whole pipeline runs without an AWS account.
+//!
+//! The manifest entries use http:// sources pointing back at this server,
+//! which zipstream reads with Range requests like an S3 presigned GET URL.
+
+use std::convert::Infallible;
+use bytes::Bytes;
+use hyper::service::service_fn;
+use hyper::{header, Request, Response, StatusCode, body::Body};
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use std::net::SocketAddr;
+
+/// The files in the archive, served under `/objects/`
+const FILES: &[(&str, &[u8])] = &[
+    ("test1.txt", b"hello\n"),
+    ("test2.txt", b"world\n"),
+];
+
+/// The manifest for `FILES`, with sources on the server at `base_url`
+fn manifest(base_url: &str) -> String {
+    let entries: Vec<_> = FILES.iter().map(|(name, data)| serde_json::json!({
+        "archive_name": name,
+        "length": data.len(),
+        "crc": crc32fast::hash(data),
+        "source": format!("{}/objects/{}", base_url, name),
+        "last_modified": "2022-09-29T22:06:27.884Z",
+    })).collect();
+
+    serde_json::json!({ "filename": "test.zip", "entries": entries }).to_string()
+}
+
+/// Parse a `Range: bytes=start-end` header, the only form zipstream sends
+fn parse_range(value: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = (start.parse().ok()?, end.parse::<usize>().ok()?);
+    (start <= end && end < len).then_some((start, end + 1))
+}
+
+async fn handler(base_url: String, req: Request<impl Body>) -> Result<Response<http_body_util::Full<Bytes>>, Infallible> {
+    eprintln!("Local demo server got a request for {}", req.uri());
+
+    let Some(name) = req.uri().path().strip_prefix("/objects/") else {
+        return Ok(Response::builder()
+          .header("Content-type", "application/json")
+          .header("X-Zip-Stream", "true")
+          .body(Bytes::from(manifest(&base_url)).into())
+          .unwrap()
+        );
+    };
+
+    let Some((_, data)) = FILES.iter().find(|(n, _)| *n == name) else {
+        return Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Bytes::new().into()).unwrap());
+    };
+
+    let range = req.headers().get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_range(v, data.len()));
+
+    Ok(match range {
+        Some((start, end)) => Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::ACCEPT_RANGES, "bytes")
+            .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, data.len()))
+            .body(Bytes::from_static(&data[start..end]).into())
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .body(Bytes::new().into())
+            .unwrap(),
+    })
+}
+
+/// Serve the manifest and files on `listener` forever
+async fn serve(listener: TcpListener) -> std::io::Result<()> {
+    let base_url = format!("http://{}", listener.local_addr()?);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let io = TokioIo::new(stream);
+        let base_url = base_url.clone();
+
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(io, service_fn(|req| handler(base_url.clone(), req)))
+                .await
+            {
+                println!("Error serving connection: {:?}", err);
+            }
+        });
+    }
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 3001));
+    let listener = TcpListener::bind(addr).await?;
+    serve(listener).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use aws_sdk_s3 as s3;
+    use http_body_util::{BodyExt, Empty};
+    use zipstream::{stream_range::HttpClient, upstream, Config};
+
+    /// The archive for the manifest is built from this server alone, with an
+    /// S3 client that has no credentials or network access
+    #[tokio::test]
+    async fn test_local_demo_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(serve(listener));
+
+        let http_client: HttpClient = hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
+            .build(hyper_tls::HttpsConnector::new());
+        let s3_client = s3::Client::from_conf(s3::Config::builder()
+            .behavior_version(s3::config::BehaviorVersion::latest())
+            .region(s3::config::Region::from_static("us-east-1"))
+            .build());
+
+        let manifest = http_client.get(format!("http://{}/foo/bar/test.zip", addr).parse().unwrap()).await.unwrap();
+        assert_eq!(manifest.headers()["X-Zip-Stream"], "true");
+        let manifest = manifest.into_body().collect().await.unwrap().to_bytes();
+
+        let req = Request::builder().uri("/foo/bar/test.zip").body(Empty::<Bytes>::new()).unwrap();
+        let Ok(res) = upstream::response(&Config::default(), s3_client, http_client, &req, manifest).await else { panic!("response failed") };
+        assert_eq!(res.status(), StatusCode::OK);
+        let zip = res.into_body().collect().await.unwrap().to_bytes();
+
+        std::fs::write("test_local_demo.zip", &zip).unwrap();
+        let output = std::process::Command::new("python3").arg("-c")
+            .arg("import sys, zipfile; z = zipfile.ZipFile('test_local_demo.zip'); assert z.testzip() is None; sys.stdout.write(''.join(z.read(n).decode() for n in z.namelist()))")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "hello\nworld\n");
+    }
+}
@@ -0,0 +1,114 @@
+//! Access logs in Apache Combined Log Format, for log pipelines that expect
+//! them rather than the structured tracing events.
+use chrono::{DateTime, Utc};
+use hyper::{header, Request, StatusCode, body::Body};
+use std::{net::IpAddr, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::{Instant, SystemTime}};
+use tracing::info;
+
+/// Format of the access log enabled with `--access-log-format`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum AccessLogFormat {
+    /// Apache Combined Log Format
+    Combined,
+}
+
+/// Details of a request needed for its access log line.
+///
+/// This is added to the request extensions when access logging is enabled.
+/// Responses that stream their body through `hyper_response` log the line
+/// when the body is finished, so the byte count is what was actually sent;
+/// the line for any other response is logged when it is returned.
+#[derive(Clone, Debug)]
+pub struct AccessLogRequest {
+    client_ip: IpAddr,
+    request_line: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    time: DateTime<Utc>,
+    start: Instant,
+    claimed: Arc<AtomicBool>,
+}
+
+impl AccessLogRequest {
+    pub fn new(req: &Request<impl Body>, client_ip: IpAddr) -> Self {
+        let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_owned);
+
+        AccessLogRequest {
+            client_ip,
+            request_line: format!(
+                "{} {} {:?}",
+                req.method(),
+                req.uri().path_and_query().map_or("/", |p| p.as_str()),
+                req.version()
+            ),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+            time: SystemTime::now().into(),
+            start: Instant::now(),
+            claimed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Mark the line as to be logged by the response body. Returns false if
+    /// it was already claimed.
+    pub(crate) fn claim(&self) -> bool {
+        !self.claimed.swap(true, Ordering::Relaxed)
+    }
+
+    /// Whether the response body will log the line
+    pub fn is_claimed(&self) -> bool {
+        self.claimed.load(Ordering::Relaxed)
+    }
+
+    /// Format the line for the response to this request
+    pub fn combined_line(&self, status: StatusCode, bytes: Option<u64>) -> String {
+        // Quoted fields escape `"` and `\` like Apache does
+        let quote = |s: Option<&str>| s.map_or("-".to_owned(), |s| s.replace('\\', "\\\\").replace('"', "\\\""));
+
+        format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"",
+            self.client_ip,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            quote(Some(&self.request_line)),
+            status.as_u16(),
+            bytes.map_or("-".to_owned(), |b| b.to_string()),
+            quote(self.referer.as_deref()),
+            quote(self.user_agent.as_deref()),
+        )
+    }
+
+    /// Log the line for the response to this request
+    pub fn log(&self, status: StatusCode, bytes: Option<u64>) {
+        info!(
+            target: "access_log",
+            { http.response.status_code = status.as_u16(), time = self.start.elapsed().as_secs_f64() * 1000.0 },
+            "{}", self.combined_line(status, bytes)
+        );
+    }
+}
+
+#[test]
+fn test_combined_line() {
+    let req = Request::builder()
+        .uri("/dl/test.zip?a=b")
+        .header(header::USER_AGENT, "curl/8.0 \"quoted\"")
+        .body(http_body_util::Empty::<bytes::Bytes>::new()).unwrap();
+
+    let entry = AccessLogRequest::new(&req, "192.0.2.1".parse().unwrap());
+    let line = entry.combined_line(StatusCode::PARTIAL_CONTENT, Some(1234));
+
+    let grammar = regex::Regex::new(concat!(
+        r#"^(\S+) (\S+) (\S+) \[(\d{2}/[A-Z][a-z]{2}/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4})\] "#,
+        r#""((?:[^"\\]|\\.)*)" (\d{3}) (\d+|-) "((?:[^"\\]|\\.)*)" "((?:[^"\\]|\\.)*)"$"#,
+    )).unwrap();
+
+    let captures = grammar.captures(&line).unwrap_or_else(|| panic!("{}", line));
+    assert_eq!(&captures[1], "192.0.2.1");
+    assert_eq!(&captures[5], "GET /dl/test.zip?a=b HTTP/1.1");
+    assert_eq!(&captures[6], "206");
+    assert_eq!(&captures[7], "1234");
+    assert_eq!(&captures[8], "-");
+    assert_eq!(&captures[9], r#"curl/8.0 \"quoted\""#);
+
+    assert!(entry.combined_line(StatusCode::NOT_FOUND, None).contains(r#"" 404 - "-""#));
+}
@@ -1,4 +1,9 @@
-use std::{error::Error, fmt::{self, Display}};
+use std::{borrow::Cow, error::Error, fmt::{self, Display}};
+use hyper::StatusCode;
+
+/// An error response: status code and message sent to the client
+pub type ErrorResponse = (StatusCode, Cow<'static, str>);
+
 
 /// Helper for displaying errors with their sources
 pub struct Report<T>(pub T);
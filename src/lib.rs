@@ -4,11 +4,332 @@ pub mod zip;
 pub mod upstream;
 pub mod s3url;
 pub mod error;
+pub mod access_log;
+pub mod telemetry;
+pub mod upload;
 
+#[cfg(test)]
+mod test_util;
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: test_util::CountingAllocator = test_util::CountingAllocator;
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use hyper::header::{self, HeaderName};
+
+use crate::s3url::S3Url;
+use crate::stream_range::ObjectCache;
+use crate::zip::Password;
+
+/// How the `Content-Disposition` header is sent with generated archives
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum ContentDisposition {
+    /// `attachment; filename="..."` with the manifest's filename
+    #[default]
+    Filename,
+
+    /// `attachment` without a filename, leaving the client to choose one
+    Attachment,
+
+    /// No `Content-Disposition` header
+    Omit,
+}
+
+/// What to do with backslashes in a manifest's `archive_name`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum Backslashes {
+    /// Treat them as Windows-style path separators and replace them with `/`
+    #[default]
+    Normalize,
+
+    /// Reject the manifest
+    Reject,
+}
+
+/// Order of the manifest's entries in generated archives
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum EntryOrder {
+    /// Sorted by `archive_name`
+    #[default]
+    Name,
+
+    /// Grouped by `source`, then sorted by `archive_name`, so entries read
+    /// from the same object are adjacent
+    Source,
+}
+
+/// Hash function used to compute archive ETags
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum ETagAlgorithm {
+    /// 64-bit XXH3, sent as 16 hex digits
+    #[default]
+    Xxhash,
+
+    /// SHA-256, sent as 64 hex digits
+    Sha256,
+}
+
+/// Normalization applied to each `archive_name`, for extraction on
+/// filesystems that treat differently written names as the same file
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum NameNormalization {
+    /// Use names as given
+    #[default]
+    Keep,
+
+    /// Unicode NFC, as used by macOS, so composed and decomposed accents match
+    Nfc,
+
+    /// NFC, then lowercase, for case-insensitive filesystems
+    Lowercase,
+}
 
 #[derive(Clone)]
 pub struct Config {
     pub upstream: String,
     pub strip_prefix: String,
     pub via_zip_stream_header_value: String,
+
+    /// Remove empty path segments before matching `strip_prefix` and proxying
+    pub merge_slashes: bool,
+
+    /// Reject manifests with an `archive_name` longer than this many bytes
+    pub max_path_length: usize,
+
+    /// Reject archives with more than this many entries with a 413
+    pub max_entries: Option<usize>,
+
+    /// Reject archives whose entries would extract to more than this many bytes in total with a 413
+    pub max_uncompressed_bytes: Option<u64>,
+
+    /// Log a warning for manifests with more than this many entries that repeat another entry's source
+    pub warn_duplicate_sources: Option<usize>,
+
+    /// Close idle pooled connections to the upstream server after this long
+    pub upstream_pool_idle_timeout: Duration,
+
+    /// Time to wait for the response headers of an upstream request before failing with 504
+    pub upstream_timeout: Option<Duration>,
+
+    /// Reject requests with a body over this many bytes
+    pub max_request_body: Option<u64>,
+
+    /// Time to wait for the body of a request before failing with 408
+    pub request_body_timeout: Option<Duration>,
+
+    /// Buffer proxied responses up to this many bytes so Range requests can be served for them
+    pub passthrough_range_limit: Option<u64>,
+
+    /// Use the LastModified time of each S3 object rather than the one in the manifest
+    pub s3_last_modified: bool,
+
+    /// Reject manifests with an entry whose `etag` doesn't match its S3 object's ETag
+    pub verify_s3_etag: bool,
+
+    /// Allow manifest entries with S3 sources to omit `length`, and find it with a HeadObject request
+    pub resolve_lengths: bool,
+
+    /// Reject manifests with an entry whose `crc` doesn't fit its `length`, instead of only logging it
+    pub strict_manifest: bool,
+
+    /// Reject manifests where an entry's `archive_name` is also used as a directory by another entry
+    pub reject_file_directory_conflicts: bool,
+
+    /// Add a `_contents.txt` file listing the path, length and modification time of each entry
+    pub contents_listing: bool,
+
+    /// Start each archive with an `index.json` file describing the archive and each entry
+    pub index_json: bool,
+
+    /// Reject requests with more than this many byte ranges with a 400
+    pub max_ranges_per_request: usize,
+
+    /// Reject requests for the full content, with no Range or one covering all of it, with a 400
+    /// unless the content has no ETag and so can't be requested in parts, or the request is a HEAD
+    pub require_range: bool,
+
+    /// Start each archive with a copy of its central directory (see `ZipOptions::central_directory_hint`)
+    pub central_directory_hint: bool,
+
+    /// Template for the archive comment. `{request_id}` and `{time}` are
+    /// replaced by the request's log id and the current time.
+    pub comment_template: Option<String>,
+
+    /// Template for the filename in the `Content-Disposition` header.
+    /// `{filename}`, `{date}`, `{count}`, `{size}` and `{etag}` are replaced
+    /// by the manifest filename, the date of the newest entry, the number of
+    /// entries in the manifest, the archive length, and its ETag.
+    pub filename_template: Option<String>,
+
+    /// Form of the `Content-Disposition` header on archive responses
+    pub content_disposition: ContentDisposition,
+
+    /// Maximum number of concurrent S3 HeadObject requests made while preparing an archive
+    pub head_concurrency: usize,
+
+    /// Total attempts to read each range of an S3 object, retrying throttling, 5xx
+    /// errors and interrupted bodies with exponential backoff (see `stream_range::RetryingSource`)
+    pub s3_max_attempts: u32,
+
+    /// Cache of small S3 objects shared by all requests (see `stream_range::CachingSource`)
+    pub s3_cache: Option<Arc<ObjectCache>>,
+
+    /// Serve requests with `m` query parameters as one archive merging the manifests at those upstream paths
+    pub merge_manifests: bool,
+
+    /// Handling of backslashes in `archive_name`, which the zip format doesn't allow as separators
+    pub backslashes: Backslashes,
+
+    /// Normalization of `archive_name`. Manifests with names that become equal are rejected.
+    pub name_normalization: NameNormalization,
+
+    /// Pace each response body to at most this many bytes per second
+    pub max_bytes_per_second_per_download: Option<u64>,
+
+    /// Read archives smaller than this many bytes completely before responding, and send them with one write
+    pub buffer_small_responses_under: Option<u64>,
+
+    /// Answer with 503 and a Retry-After of S3's, or else this, if S3 throttles
+    /// a request before the first 64 KiB of an archive is sent
+    pub throttled_retry_after: Option<Duration>,
+
+    /// Order of the entries in the archive
+    pub entry_order: EntryOrder,
+
+    /// Hash function used for archive ETags
+    pub etag_algorithm: ETagAlgorithm,
+
+    /// Start archives with the single-segment spanning marker (see `ZipOptions::spanning_marker`)
+    pub spanning_marker: bool,
+
+    /// Write each entry's modification time with 100 ns resolution (see `ZipOptions::ntfs_timestamps`)
+    pub ntfs_timestamps: bool,
+
+    /// Number of following entries whose data is read while streaming each entry (see `ZipOptions::prefetch_entries`)
+    pub prefetch_entries: usize,
+
+    /// Encrypt every archive with WinZip AES-256 and this password (see `ZipOptions::password`)
+    pub encryption_password: Option<Password>,
+
+    /// Encrypt archives requested with an `X-Zip-Stream-Password` header
+    /// with its value instead, rather than rejecting such requests with a 400
+    pub password_header: bool,
+
+    /// Also upload each fully downloaded archive to S3, under this bucket and key
+    /// prefix, named by its ETag (see `upload::TeeToS3`)
+    pub upload_archives_to: Option<S3Url>,
+
+    /// Allow `file://` sources for files under this directory. Without it, they are rejected.
+    pub file_sources_under: Option<PathBuf>,
+
+    /// Request headers passed on to the upstream server
+    pub forward_headers: Vec<HeaderName>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            upstream: String::new(),
+            strip_prefix: String::new(),
+            via_zip_stream_header_value: "true".into(),
+            merge_slashes: false,
+            max_path_length: u16::MAX as usize,
+            max_entries: None,
+            max_uncompressed_bytes: None,
+            warn_duplicate_sources: None,
+            upstream_pool_idle_timeout: Duration::from_secs(90),
+            upstream_timeout: Some(Duration::from_secs(30)),
+            max_request_body: None,
+            request_body_timeout: Some(Duration::from_secs(30)),
+            passthrough_range_limit: None,
+            s3_last_modified: false,
+            verify_s3_etag: false,
+            resolve_lengths: false,
+            strict_manifest: false,
+            reject_file_directory_conflicts: false,
+            contents_listing: false,
+            index_json: false,
+            max_ranges_per_request: 10,
+            require_range: false,
+            central_directory_hint: false,
+            comment_template: None,
+            filename_template: None,
+            content_disposition: ContentDisposition::Filename,
+            head_concurrency: 16,
+            s3_max_attempts: 3,
+            s3_cache: None,
+            merge_manifests: false,
+            backslashes: Backslashes::Normalize,
+            name_normalization: NameNormalization::Keep,
+            max_bytes_per_second_per_download: None,
+            buffer_small_responses_under: None,
+            throttled_retry_after: None,
+            entry_order: EntryOrder::Name,
+            etag_algorithm: ETagAlgorithm::Xxhash,
+            spanning_marker: false,
+            ntfs_timestamps: false,
+            prefetch_entries: 0,
+            encryption_password: None,
+            password_header: false,
+            upload_archives_to: None,
+            file_sources_under: None,
+            forward_headers: vec![header::AUTHORIZATION, header::COOKIE, header::USER_AGENT, header::REFERER],
+        }
+    }
+}
+
+/// A table of upstream configurations selected by request path prefix
+#[derive(Clone, Default)]
+pub struct Routes(Vec<(String, Config)>);
+
+impl Routes {
+    /// Route all requests to a single upstream
+    pub fn single(config: Config) -> Routes {
+        Routes(vec![(String::new(), config)])
+    }
+
+    /// Add a route for requests whose path is under `path_prefix`
+    pub fn add(&mut self, path_prefix: String, config: Config) {
+        self.0.push((path_prefix, config));
+    }
+
+    /// Select the config of the route with the longest prefix matching `path`
+    pub fn select(&self, path: &str) -> Option<&Config> {
+        self.0.iter()
+            .filter(|(prefix, _)| path_has_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, config)| config)
+    }
+}
+
+/// Whether `path` is under `prefix`, matching whole path segments so that
+/// `/a` matches `/a` and `/a/b` but not `/ab`. A trailing slash on `prefix`
+/// is ignored.
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix.trim_end_matches('/'))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[test]
+fn test_routes() {
+    let route = |upstream: &str| Config { upstream: upstream.into(), ..Default::default() };
+
+    let mut routes = Routes::default();
+    routes.add("/a".into(), route("http://a"));
+    routes.add("/a/b".into(), route("http://ab"));
+
+    assert_eq!(routes.select("/a/x").map(|c| &c.upstream[..]), Some("http://a"));
+    assert_eq!(routes.select("/a/b/x").map(|c| &c.upstream[..]), Some("http://ab"));
+    assert!(routes.select("/c").is_none());
+    assert_eq!(routes.select("/a").map(|c| &c.upstream[..]), Some("http://a"));
+    assert!(routes.select("/ab/x").is_none());
+    assert_eq!(routes.select("/a/bc").map(|c| &c.upstream[..]), Some("http://a"));
+
+    routes.add("/ab/".into(), route("http://ab-slash"));
+    assert_eq!(routes.select("/ab/x").map(|c| &c.upstream[..]), Some("http://ab-slash"));
+
+    routes.add("".into(), route("http://default"));
+    assert_eq!(routes.select("/c").map(|c| &c.upstream[..]), Some("http://default"));
 }
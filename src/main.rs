@@ -3,20 +3,27 @@ use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_s3 as s3;
 
 use bytes::Bytes;
-use http_body_util::{BodyExt, Either};
+use http_body_util::{BodyExt, Either, Empty};
 use hyper::server::conn::http1;
-use hyper_util::rt::{TokioIo, TokioExecutor};
-use tokio::net::TcpListener;
+use hyper_util::rt::{TokioIo, TokioExecutor, TokioTimer};
+use tokio::{net::{TcpListener, TcpStream}, sync::{OnceCell, Semaphore}, task::JoinSet};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use zipstream::{
-    upstream,
-    Config, stream_range::BoxError,
-    error::Report,
+    access_log::{AccessLogFormat, AccessLogRequest},
+    upstream::{self, RequestId},
+    Backslashes, Config, ContentDisposition, ETagAlgorithm, EntryOrder, NameNormalization, Routes, stream_range::{BoxError, ObjectCache},
+    s3url::S3Url,
+    error::{Report, ErrorResponse},
+    serve_range::{self, ConnectionBudget},
+    telemetry,
+    zip::Password,
 };
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt};
 
-use std::{net::SocketAddr, time::Duration};
+use std::{collections::BTreeMap, convert::TryFrom, future::Future, net::SocketAddr, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
 
 use clap::Parser;
-use hyper::{ Request, Response, StatusCode, body::{self, Body} };
+use hyper::{ Request, Response, StatusCode, Version, body::{self, Body}, header::{self, HeaderValue} };
 use hyper::service::service_fn;
 use hyper_tls::HttpsConnector;
 use tracing::{error, event, info, info_span, warn, Instrument, Level};
@@ -24,26 +31,258 @@ use tracing::{error, event, info, info_span, warn, Instrument, Level};
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-type HyperClient = hyper_util::client::legacy::Client<HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>, http_body_util::Empty<Bytes>>;
+type HyperClient = zipstream::stream_range::HttpClient;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     /// Upstream server that provides zip file manifests
-    #[arg(long, value_name="URL")]
-    upstream: String,
+    #[arg(long, value_name="URL", required_unless_present="routes")]
+    upstream: Option<String>,
+
+    /// JSON file listing upstreams selected by request path prefix
+    #[arg(long, value_name="FILE")]
+    pub routes: Option<PathBuf>,
 
     /// Remove a prefix from the URL path before proxying to upstream server
     #[arg(long, value_name="PREFIX", default_value="")]
     pub strip_prefix: String,
 
+    /// Remove empty path segments (repeated slashes) from the URL path before proxying
+    #[arg(long)]
+    pub merge_slashes: bool,
+
     /// Value passed in the X-Via-Zip-Stream header on the request to the upstream server
     #[arg(long, value_name="VAL", default_value="true")]
     pub header_value: String,
 
-    /// IP:port to listen for HTTP connections
+    /// IP:port to listen for HTTP connections. Repeat to listen on several addresses.
     #[arg(long, value_name="IP:PORT", default_value="[::1]:3000")]
-    pub listen: SocketAddr,
+    pub listen: Vec<SocketAddr>,
+
+    /// Reject manifests containing an archive_name longer than this many bytes
+    #[arg(long, value_name="BYTES", default_value_t=u16::MAX as usize)]
+    pub max_path_length: usize,
+
+    /// Reject archives with more than this many entries, after any prefix selection, with 413 Payload Too Large
+    #[arg(long, value_name="N")]
+    pub max_entries: Option<usize>,
+
+    /// Reject archives whose entries would extract to more than this many bytes in total with 413 Payload Too Large
+    #[arg(long, value_name="BYTES")]
+    pub max_uncompressed_bytes: Option<u64>,
+
+    /// Log a warning for manifests with more than this many entries that repeat another entry's source
+    #[arg(long, value_name="N")]
+    pub warn_duplicate_sources: Option<usize>,
+
+    /// Close idle pooled connections to the upstream server after this many seconds
+    #[arg(long, value_name="SECONDS", default_value="90")]
+    pub upstream_pool_idle_timeout: u64,
+
+    /// Fail with 504 if the upstream server doesn't send response headers within this many seconds. 0 waits indefinitely.
+    #[arg(long, value_name="SECONDS", default_value="30")]
+    pub upstream_timeout: u64,
+
+    /// Reject requests whose body is over this many bytes with 413
+    #[arg(long, value_name="BYTES")]
+    pub max_request_body: Option<u64>,
+
+    /// Fail with 408 if the request body isn't received within this many seconds. 0 waits indefinitely.
+    #[arg(long, value_name="SECONDS", default_value="30")]
+    pub request_body_timeout: u64,
+
+    /// Buffer proxied (non-zip) responses up to this many bytes so Range requests can be served for them
+    #[arg(long, value_name="BYTES")]
+    pub passthrough_range_limit: Option<u64>,
+
+    /// Use the LastModified time of each S3 object rather than the one in the manifest.
+    /// This makes a HeadObject request for each entry before streaming begins.
+    #[arg(long)]
+    pub s3_last_modified: bool,
+
+    /// Check the etag of manifest entries against their S3 object's ETag, rejecting the manifest on a mismatch.
+    /// This makes a HeadObject request for each entry with an etag before streaming begins.
+    #[arg(long)]
+    pub verify_s3_etag: bool,
+
+    /// Allow manifest entries with s3:// sources to omit length, and find it from the object's size.
+    /// This makes a HeadObject request for each such entry before streaming begins.
+    #[arg(long)]
+    pub resolve_lengths: bool,
+
+    /// Reject manifests with an entry that has a nonzero crc but length 0, or crc 0 and a nonzero length,
+    /// rather than only logging a warning
+    #[arg(long)]
+    pub strict_manifest: bool,
+
+    /// Reject manifests with a file entry whose archive_name is also the directory of another entry, such as foo and foo/bar.txt
+    #[arg(long)]
+    pub reject_file_directory_conflicts: bool,
+
+    /// Add a _contents.txt file to each archive listing the path, length and modification time of each entry
+    #[arg(long)]
+    pub contents_listing: bool,
+
+    /// Start each archive with an index.json file giving its filename and the path, length, crc and modification time of each entry
+    #[arg(long)]
+    pub index_json: bool,
+
+    /// Reject requests with more than this many byte ranges in the Range header
+    #[arg(long, value_name="N", default_value="10")]
+    pub max_ranges_per_request: usize,
+
+    /// Reject requests without a Range header, or with one covering the whole file, so clients download in parts
+    #[arg(long)]
+    pub require_range: bool,
+
+    /// Start each archive with a .zip-central-directory entry holding a copy of the central directory
+    #[arg(long)]
+    pub central_directory_hint: bool,
+
+    /// Start each archive with the PK00 marker of a single-segment spanned archive, for legacy tools that require it
+    #[arg(long)]
+    pub spanning_marker: bool,
+
+    /// Also write each entry's modification time in an NTFS extra field, with 100 ns resolution and no 2038 limit
+    #[arg(long)]
+    pub ntfs_timestamps: bool,
+
+    /// While streaming each archive entry, start reading the data of up to this many following entries, so downloads don't stall between small files
+    #[arg(long, value_name="N", default_value="0")]
+    pub prefetch_entries: usize,
+
+    /// Encrypt every archive with WinZip AES-256 and the password in this file. Encrypted archives are only served in full.
+    #[arg(long, value_name = "PATH")]
+    pub encryption_password_file: Option<PathBuf>,
+
+    /// Encrypt archives requested with an X-Zip-Stream-Password header with its value, instead of rejecting them
+    #[arg(long)]
+    pub allow_password_header: bool,
+
+    /// Also upload each fully downloaded archive to s3://bucket/prefix, named by its ETag
+    #[arg(long, value_name = "S3_URL")]
+    pub upload_archives_to: Option<S3Url>,
+
+    /// Allow manifest entries with a file:// source, reading local files under this directory
+    #[arg(long, value_name = "DIR")]
+    pub file_sources_under: Option<PathBuf>,
+
+    /// Request header passed on to the upstream server. Repeat for several; giving any replaces the defaults.
+    #[arg(long, value_name = "NAME", default_values = ["authorization", "cookie", "user-agent", "referer"])]
+    pub forward_header: Vec<header::HeaderName>,
+
+    /// Set the archive comment from this template, replacing {request_id} and {time}
+    #[arg(long)]
+    pub comment_template: Option<String>,
+
+    /// Set the filename in the Content-Disposition header from this template, replacing {filename}, {date}, {count}, {size} and {etag}
+    #[arg(long)]
+    pub filename_template: Option<String>,
+
+    /// Content-Disposition header sent with archives
+    #[arg(long, value_enum, default_value_t)]
+    pub content_disposition: ContentDisposition,
+
+    /// Maximum number of concurrent S3 HeadObject requests per archive, such as for --s3-last-modified
+    #[arg(long, default_value_t = 16)]
+    pub head_concurrency: usize,
+
+    /// Maximum number of attempts to read each S3 object range, retrying throttling, 5xx errors and
+    /// interrupted downloads with exponential backoff from where they stopped. 1 disables retries
+    #[arg(long, default_value_t = 3, value_name = "N")]
+    pub s3_max_attempts: u32,
+
+    /// Keep S3 objects of up to --s3-cache-max-object-bytes in memory, up to this many bytes in total,
+    /// dropping the least recently used, so objects in many archives aren't read from S3 for each
+    #[arg(long, value_name = "BYTES")]
+    pub s3_cache_bytes: Option<u64>,
+
+    /// Largest S3 object kept by --s3-cache-bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024)]
+    pub s3_cache_max_object_bytes: u64,
+
+    /// Serve requests with ?m=/path query parameters as a single archive merging the manifests at those upstream paths
+    #[arg(long)]
+    pub merge_manifests: bool,
+
+    /// Handling of backslashes in archive_name: replace them with / or reject the manifest
+    #[arg(long, value_enum, default_value_t)]
+    pub backslashes: Backslashes,
+
+    /// Normalize archive_name: keep, nfc, or lowercase (NFC and lowercase); names that collide are rejected
+    #[arg(long, value_enum, default_value_t)]
+    pub normalize_names: NameNormalization,
+
+    /// Limit each download to this many bytes per second
+    #[arg(long, value_name="BYTES")]
+    pub max_bytes_per_second_per_download: Option<u64>,
+
+    /// Read archives smaller than this completely before responding, and send them in one write
+    #[arg(long, value_name="BYTES")]
+    pub buffer_small_responses_under: Option<u64>,
+
+    /// Answer with 503 Service Unavailable if S3 throttles a request before archive data is sent,
+    /// with this Retry-After unless S3 gave one
+    #[arg(long, value_name="SECONDS")]
+    pub throttled_retry_after: Option<u64>,
+
+    /// Order of the entries in archives: by archive_name, or grouped by source
+    #[arg(long, value_enum, default_value_t)]
+    pub entry_order: EntryOrder,
+
+    /// Hash function for archive ETags: a short xxhash, or sha256 for collision resistance
+    #[arg(long, value_enum, default_value_t)]
+    pub etag_algorithm: ETagAlgorithm,
+
+    /// Answer requests for this path with 200 if S3 credentials can be resolved and haven't expired, or 503 if not, instead of proxying them
+    #[arg(long, value_name="PATH")]
+    pub readiness_path: Option<String>,
+
+    /// When to load the AWS configuration for the S3 client: before accepting connections, or in the background
+    /// while --readiness-path answers 503 and requests that need S3 wait for it
+    #[arg(long, value_enum, default_value_t)]
+    pub s3_init: S3Init,
+
+    /// Answer requests for PREFIX{request-id} with the progress of that in-flight download as JSON, instead of proxying them
+    #[arg(long, value_name="PREFIX")]
+    pub status_path_prefix: Option<String>,
+
+    /// Answer requests for this path with metrics in the Prometheus text format, instead of proxying them
+    #[arg(long, value_name="PATH")]
+    pub metrics_path: Option<String>,
+
+    /// Answer requests for this path with the version, git commit and build time as JSON, instead of proxying them
+    #[arg(long, value_name="PATH")]
+    pub version_path: Option<String>,
+
+    /// Limit the number of requests to the upstream server in flight at once
+    #[arg(long, value_name="N")]
+    pub max_concurrent_upstream_requests: Option<usize>,
+
+    /// How long a request waits for one of --max-concurrent-upstream-requests before failing with 503. 0 fails immediately.
+    #[arg(long, value_name="MILLISECONDS", default_value="1000")]
+    pub upstream_queue_timeout: u64,
+
+    /// Log an access log line for each request in this format
+    #[arg(long, value_enum)]
+    pub access_log_format: Option<AccessLogFormat>,
+
+    /// Abort downloads once a client connection has streamed more than this many bytes
+    #[arg(long, value_name="BYTES")]
+    pub max_bytes_per_connection: Option<u64>,
+
+    /// Limit the number of client connections open at once. Connections beyond it get a 503 and are closed.
+    #[arg(long, value_name="N")]
+    pub max_connections: Option<usize>,
+
+    /// On SIGTERM or SIGINT, stop accepting connections and wait this long for in-flight requests and downloads to finish before exiting
+    #[arg(long, value_name="SECONDS", default_value="30")]
+    pub shutdown_grace_period: u64,
+
+    /// Export tracing spans to the OpenTelemetry collector at this OTLP/HTTP endpoint, such as `http://otel-collector:4318`
+    #[arg(long, value_name="URL")]
+    pub otel_endpoint: Option<String>,
 }
 
 
@@ -52,104 +291,686 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     log_panics::init();
     let args = Args::parse();
 
-    let subscriber = tracing_subscriber::fmt()
-        .json()
-        .flatten_event(true)
-        .with_current_span(false)
-        .finish();
+    let tracer_provider = args.otel_endpoint.as_deref().map(telemetry::tracer_provider).transpose()?;
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false))
+        .with(tracer_provider.as_ref().map(telemetry::layer))
+        .with(LevelFilter::INFO);
     tracing::subscriber::set_global_default(subscriber)?;
     
     info!("Startup");
 
     tokio::task::spawn(log_metrics());
 
-    let app = App::new(Config {
-        upstream: args.upstream,
+    let s3_cache_max_object_bytes = args.s3_cache_max_object_bytes;
+    let upstream_timeout = args.upstream_timeout;
+    let request_body_timeout = args.request_body_timeout;
+    let encryption_password = args.encryption_password_file.as_deref().map(read_password).transpose()?;
+    let config = Config {
+        upstream: args.upstream.clone().unwrap_or_default(),
         strip_prefix: args.strip_prefix,
+        merge_slashes: args.merge_slashes,
         via_zip_stream_header_value: args.header_value,
-    }).await;
+        max_path_length: args.max_path_length,
+        max_entries: args.max_entries,
+        max_uncompressed_bytes: args.max_uncompressed_bytes,
+        warn_duplicate_sources: args.warn_duplicate_sources,
+        upstream_pool_idle_timeout: Duration::from_secs(args.upstream_pool_idle_timeout),
+        upstream_timeout: (upstream_timeout > 0).then(|| Duration::from_secs(upstream_timeout)),
+        max_request_body: args.max_request_body,
+        request_body_timeout: (request_body_timeout > 0).then(|| Duration::from_secs(request_body_timeout)),
+        passthrough_range_limit: args.passthrough_range_limit,
+        s3_last_modified: args.s3_last_modified,
+        verify_s3_etag: args.verify_s3_etag,
+        resolve_lengths: args.resolve_lengths,
+        strict_manifest: args.strict_manifest,
+        reject_file_directory_conflicts: args.reject_file_directory_conflicts,
+        contents_listing: args.contents_listing,
+        index_json: args.index_json,
+        max_ranges_per_request: args.max_ranges_per_request,
+        require_range: args.require_range,
+        central_directory_hint: args.central_directory_hint,
+        comment_template: args.comment_template,
+        filename_template: args.filename_template,
+        content_disposition: args.content_disposition,
+        head_concurrency: args.head_concurrency,
+        s3_max_attempts: args.s3_max_attempts,
+        s3_cache: args.s3_cache_bytes.map(|max_bytes| ObjectCache::new(max_bytes, s3_cache_max_object_bytes)),
+        merge_manifests: args.merge_manifests,
+        backslashes: args.backslashes,
+        name_normalization: args.normalize_names,
+        max_bytes_per_second_per_download: args.max_bytes_per_second_per_download,
+        buffer_small_responses_under: args.buffer_small_responses_under,
+        throttled_retry_after: args.throttled_retry_after.map(Duration::from_secs),
+        entry_order: args.entry_order,
+        etag_algorithm: args.etag_algorithm,
+        spanning_marker: args.spanning_marker,
+        ntfs_timestamps: args.ntfs_timestamps,
+        prefetch_entries: args.prefetch_entries,
+        encryption_password,
+        password_header: args.allow_password_header,
+        upload_archives_to: args.upload_archives_to.clone(),
+        file_sources_under: args.file_sources_under.clone(),
+        forward_headers: args.forward_header.clone(),
+    };
+
+    let mut routes = match &args.routes {
+        Some(path) => load_routes(path, &config)?,
+        None => Routes::default(),
+    };
+
+    if args.upstream.is_some() {
+        routes.add(String::new(), config.clone());
+    }
+
+    let queue_timeout = Duration::from_millis(args.upstream_queue_timeout);
+    let upstream_limit = args.max_concurrent_upstream_requests.map(|max| UpstreamLimit {
+        permits: Arc::new(Semaphore::new(max)),
+        queue_timeout,
+    });
+
+    let app = App::new(config, routes, args.readiness_path.clone(), args.status_path_prefix.clone(), args.metrics_path.clone(), args.version_path.clone(), upstream_limit);
+
+    match args.s3_init {
+        S3Init::BeforeListening => { app.s3().await; }
+        S3Init::Background => {
+            let app = app.clone();
+            tokio::task::spawn(async move { app.s3().await; });
+        }
+    }
+
+    let mut listeners = Vec::with_capacity(args.listen.len());
+    for addr in &args.listen {
+        listeners.push(TcpListener::bind(addr).await?);
+    }
+    let connection_limit = args.max_connections.map(|max| Arc::new(Semaphore::new(max)));
+
+    let grace_period = Duration::from_secs(args.shutdown_grace_period);
+    accept_connections(listeners, app, connection_limit, args.max_bytes_per_connection, args.access_log_format, shutdown_signal(), grace_period).await?;
+    info!("Shutdown");
+    if let Some(provider) = tracer_provider {
+        provider.shutdown()?;
+    }
+    Ok(())
+}
+
+/// Wait for SIGTERM, sent by Kubernetes and most process managers to stop the
+/// process, or SIGINT (Ctrl-C)
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+    }
+}
+
+/// Accept client connections on each of `listeners` and serve each on its own
+/// task. With `connection_limit`, each connection holds a permit until it
+/// closes, and connections arriving when none are left are answered with a 503
+/// and closed. The limit is shared by all listeners.
+///
+/// Once `shutdown` completes, stops listening and waits up to `grace_period`
+/// for open connections to finish the request they are serving, including
+/// its download, before returning. Idle connections are closed right away.
+async fn accept_connections(
+    listeners: Vec<TcpListener>,
+    app: App,
+    connection_limit: Option<Arc<Semaphore>>,
+    max_bytes_per_connection: Option<u64>,
+    access_log_format: Option<AccessLogFormat>,
+    shutdown: impl Future<Output = ()>,
+    grace_period: Duration,
+) -> std::io::Result<()> {
+    let closing = CancellationToken::new();
+    let connections = TaskTracker::new();
+
+    let mut accept_loops = JoinSet::new();
+    for listener in listeners {
+        accept_loops.spawn(accept_loop(
+            listener,
+            app.clone(),
+            connection_limit.clone(),
+            max_bytes_per_connection,
+            access_log_format,
+            closing.clone(),
+            connections.clone(),
+        ));
+    }
+
+    // The accept loops only return on error
+    let result = tokio::select! {
+        () = shutdown => Ok(()),
+        Some(joined) = accept_loops.join_next() => joined.expect("accept loop panicked"),
+    };
 
-    let listener = TcpListener::bind(args.listen).await?;
+    // Aborting the accept loops drops their listeners
+    accept_loops.shutdown().await;
+    result?;
 
+    info!(
+        zipstream.active_downloads = serve_range::active_downloads(),
+        "Stopped accepting connections, waiting up to {}s for {} open connections to finish", grace_period.as_secs(), connections.len()
+    );
+
+    closing.cancel();
+    connections.close();
+    if tokio::time::timeout(grace_period, connections.wait()).await.is_err() {
+        let active_downloads = serve_range::active_downloads();
+        warn!(
+            zipstream.active_downloads = active_downloads,
+            "Shutdown grace period ended with {} downloads still active", active_downloads
+        );
+    }
+
+    Ok(())
+}
+
+/// Accept client connections on `listener`, spawning a task onto `connections`
+/// to serve each, until accepting fails
+async fn accept_loop(
+    listener: TcpListener,
+    app: App,
+    connection_limit: Option<Arc<Semaphore>>,
+    max_bytes_per_connection: Option<u64>,
+    access_log_format: Option<AccessLogFormat>,
+    closing: CancellationToken,
+    connections: TaskTracker,
+) -> std::io::Result<()> {
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
+        let (stream, client_addr) = listener.accept().await?;
 
+        let permit = match &connection_limit {
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    warn!("Rejecting connection from {}: too many open connections", client_addr);
+                    tokio::task::spawn(reject_connection(stream));
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        let budget = max_bytes_per_connection.map(ConnectionBudget::new);
         let app = app.clone();
+        let closing = closing.clone();
+        connections.spawn(async move {
+            serve_connection(app, stream, client_addr, budget, access_log_format, closing).await;
+            drop(permit);
+        });
+    }
+}
 
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(io, service_fn(|req| { async {
-                    let span = info_span!(
-                        "request",
-                        id = %uuid::Uuid::now_v7().simple(),
-                        path = req.uri().path(),
-                    );
-
-                    span.in_scope(|| {
-                        info!(
-                            http.request.method = ?req.method(),
-                            url.path = req.uri().path(),
-                            http.request.raw_headers = ?req.headers(),
-                            "{:?} {}", req.method(), req.uri(),
-                        )
-                    });
-
-                    match app.handle_request(req).instrument(span).await {
-                        Ok(res) => Ok(res.map(Either::Right)),
-                        Err((status, msg)) => {
-                            Response::builder().status(status).body(Either::Left(http_body_util::Full::new(Bytes::from(msg))))
-                        }
-                    }
-                }}))
-                .await
-            {
-                warn!("Error serving connection: {}", Report(err));
+/// Answer a connection over `--max-connections` with a 503 without reading its
+/// request, and close it
+async fn reject_connection(mut stream: TcpStream) {
+    use tokio::io::AsyncWriteExt;
+
+    let response = b"HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\nContent-Length: 20\r\nConnection: close\r\n\r\nToo many connections";
+    let _ = tokio::time::timeout(Duration::from_secs(1), async {
+        stream.write_all(response).await?;
+        stream.shutdown().await
+    }).await;
+}
+
+/// Serve the requests on a client connection until it closes or `closing` is
+/// canceled, which lets the request in progress finish first
+async fn serve_connection(app: App, stream: TcpStream, client_addr: SocketAddr, budget: Option<ConnectionBudget>, access_log_format: Option<AccessLogFormat>, closing: CancellationToken) {
+    let io = TokioIo::new(stream);
+
+    let conn = http1::Builder::new()
+        .serve_connection(io, service_fn(|mut req| { async {
+            if let Some(res) = app.readiness_response(&req).await {
+                return Ok(res.map(Either::Left));
+            }
+
+            if let Some(res) = app.status_response(&req) {
+                return Ok(res.map(Either::Left));
+            }
+
+            if let Some(res) = app.metrics_response(&req) {
+                return Ok(res.map(Either::Left));
+            }
+
+            if let Some(res) = app.version_response(&req) {
+                return Ok(res.map(Either::Left));
+            }
+
+            if let Some(budget) = &budget {
+                req.extensions_mut().insert(budget.clone());
+            }
+
+            let http10 = req.version() == Version::HTTP_10;
+
+            let id = uuid::Uuid::now_v7().simple().to_string();
+            req.extensions_mut().insert(RequestId(id.clone()));
+
+            let access_log = access_log_format.map(|_| AccessLogRequest::new(&req, client_addr.ip()));
+            if let Some(entry) = &access_log {
+                req.extensions_mut().insert(entry.clone());
+            }
+
+            let span = info_span!(
+                "request",
+                id = %id,
+                path = req.uri().path(),
+            );
+
+            span.in_scope(|| telemetry::log_request(&req));
+
+            let mut res = match app.handle_request(req).instrument(span.clone()).await {
+                Ok(res) => Ok(res.map(Either::Right)),
+                Err((status, msg)) => {
+                    Response::builder().status(status).body(Either::Left(http_body_util::Full::new(Bytes::from(msg.into_owned()))))
+                }
+            };
+
+            // Tell the client the id to look up its download's status with
+            if let (Some(_), Ok(res)) = (&app.status_path_prefix, &mut res) {
+                res.headers_mut().insert("X-Request-Id", HeaderValue::from_str(&id).unwrap());
             }
+
+            // HTTP/1.0 has no chunked encoding, so close the connection after
+            // the response, which also delimits a body without a Content-Length
+            // such as an unbuffered proxied one. hyper replaces this with
+            // `keep-alive` if the client explicitly asked for it, which it
+            // only allows when the body has a Content-Length.
+            if let (true, Ok(res)) = (http10, &mut res) {
+                res.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("close"));
+            }
+
+            if let Ok(res) = &res {
+                count_response(res.status());
+            }
+
+            // Streamed archives log their line once the body is sent
+            if let (Some(entry), Ok(res)) = (access_log.filter(|entry| !entry.is_claimed()), &res) {
+                let bytes = res.body().size_hint().exact();
+                span.in_scope(|| entry.log(res.status(), bytes));
+            }
+
+            res
+        }}));
+    tokio::pin!(conn);
+
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        () = closing.cancelled() => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+
+    if let Err(err) = result {
+        warn!("Error serving connection: {}", Report(err));
+    }
+}
+
+/// Read the `--encryption-password-file`, which is used instead of an
+/// argument so that the password isn't visible in the process list
+fn read_password(path: &Path) -> Result<Password, Box<dyn std::error::Error + Send + Sync>> {
+    let contents = std::fs::read(path)?;
+    // Without the newline editors add
+    let password = contents.strip_suffix(b"\n").map_or(&contents[..], |p| p.strip_suffix(b"\r").unwrap_or(p));
+    if password.is_empty() {
+        return Err(format!("Password file {} is empty", path.display()).into());
+    }
+    Ok(Password::new(password))
+}
+
+/// An entry in the `--routes` file
+#[derive(serde_derive::Deserialize)]
+struct RouteDescription {
+    path_prefix: String,
+    upstream: String,
+    strip_prefix: Option<String>,
+    header_value: Option<String>,
+}
+
+/// Load routes from a JSON file, using `base` for settings not specified per route
+fn load_routes(path: &Path, base: &Config) -> Result<Routes, Box<dyn std::error::Error + Send + Sync>> {
+    let descriptions: Vec<RouteDescription> = serde_json::from_slice(&std::fs::read(path)?)
+        .map_err(|e| format!("Invalid routes file {}: {}", path.display(), e))?;
+
+    let mut routes = Routes::default();
+    for route in descriptions {
+        routes.add(route.path_prefix, Config {
+            upstream: route.upstream,
+            strip_prefix: route.strip_prefix.unwrap_or_default(),
+            via_zip_stream_header_value: route.header_value.unwrap_or_else(|| base.via_zip_stream_header_value.clone()),
+            ..base.clone()
         });
     }
+
+    Ok(routes)
+}
+
+/// Limit on concurrent requests to the upstream server, so a burst of
+/// client requests doesn't become a burst of manifest requests
+#[derive(Clone)]
+struct UpstreamLimit {
+    permits: Arc<Semaphore>,
+    queue_timeout: Duration,
+}
+
+/// When the AWS configuration for the S3 client is loaded, which can take a
+/// while if credentials come from a slow provider such as the instance metadata
+/// service
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+enum S3Init {
+    /// Before binding the listeners, so no connection is accepted until S3 is usable
+    #[default]
+    BeforeListening,
+
+    /// In the background, accepting connections right away. The readiness
+    /// path answers 503 until it has loaded, and requests that need S3 wait for it.
+    Background,
+}
+
+/// The S3 client, with the credentials provider it uses for readiness checks
+#[derive(Clone)]
+struct S3State {
+    client: s3::Client,
+    credentials: Option<s3::config::SharedCredentialsProvider>,
+}
+
+impl S3State {
+    async fn load() -> S3State {
+        let region_provider = RegionProviderChain::default_provider();
+        let s3_config = aws_config::defaults(aws_config::BehaviorVersion::latest()).region(region_provider).load().await;
+        info!("S3 client initialized");
+
+        S3State { client: s3::Client::new(&s3_config), credentials: s3_config.credentials_provider() }
+    }
 }
 
 #[derive(Clone)]
 struct App {
-    config: Config,
+    routes: Routes,
     upstream_client: HyperClient,
-    s3_client: s3::Client,
+    /// Set once the AWS configuration has loaded (see `S3Init`)
+    s3: Arc<OnceCell<S3State>>,
+    readiness_path: Option<String>,
+    status_path_prefix: Option<String>,
+    metrics_path: Option<String>,
+    version_path: Option<String>,
+    upstream_limit: Option<UpstreamLimit>,
+}
+
+/// Read a request body, failing with 413 if it is over
+/// `Config::max_request_body`, whether it has a `Content-Length` or is
+/// chunked, and with 408 if it takes longer than `Config::request_body_timeout`.
+async fn read_request_body<B>(config: &Config, headers: &hyper::HeaderMap, body: B) -> Result<Bytes, ErrorResponse>
+    where B: Body, B::Error: Into<BoxError>
+{
+    let max = config.max_request_body.unwrap_or(u64::MAX);
+    let too_large = || -> ErrorResponse {
+        warn!("Rejecting request with a body over the limit of {} bytes", max);
+        (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large".into())
+    };
+
+    // One announcing more than the limit is rejected without reading it
+    let len = headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    if len.is_some_and(|len| len > max) {
+        return Err(too_large());
+    }
+
+    let limited = http_body_util::Limited::new(body, usize::try_from(max).unwrap_or(usize::MAX));
+    let collected = match config.request_body_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, limited.collect()).await.map_err(|_| {
+            warn!("Request body not received within {:?}", timeout);
+            (StatusCode::REQUEST_TIMEOUT, "Request body timed out".into())
+        })?,
+        None => limited.collect().await,
+    };
+
+    match collected {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(e) if e.is::<http_body_util::LengthLimitError>() => Err(too_large()),
+        Err(e) => {
+            warn!("Failed to read request body: {}", Report(&*e));
+            Err((StatusCode::BAD_REQUEST, "Failed to read request body".into()))
+        }
+    }
 }
 
 impl App {
-    async fn new(config: Config) -> App {
-        let upstream_client = hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build(HttpsConnector::new());
+    fn new(config: Config, routes: Routes, readiness_path: Option<String>, status_path_prefix: Option<String>, metrics_path: Option<String>, version_path: Option<String>, upstream_limit: Option<UpstreamLimit>) -> App {
+        let upstream_client = upstream_client_builder(&config).build(HttpsConnector::new());
+        App { routes, upstream_client, s3: Arc::new(OnceCell::new()), readiness_path, status_path_prefix, metrics_path, version_path, upstream_limit }
+    }
 
-        let region_provider = RegionProviderChain::default_provider();
-        let s3_config = aws_config::defaults(aws_config::BehaviorVersion::v2023_11_09()).region(region_provider).load().await;
-        let s3_client = s3::Client::new(&s3_config);
+    /// The S3 client, loading the AWS configuration if it hasn't been yet, or
+    /// waiting for the load already in progress
+    async fn s3(&self) -> &S3State {
+        self.s3.get_or_init(S3State::load).await
+    }
 
-        App { config, upstream_client, s3_client }
+    /// Respond to a request for `--readiness-path`, reporting whether the S3
+    /// client has been initialized and its credentials are usable
+    async fn readiness_response(&self, req: &Request<impl Body>) -> Option<Response<http_body_util::Full<Bytes>>> {
+        if self.readiness_path.as_deref() != Some(req.uri().path()) {
+            return None;
+        }
+
+        let (status, msg) = match self.s3.get() {
+            None => (StatusCode::SERVICE_UNAVAILABLE, "S3 client initializing"),
+            Some(s3) if upstream::s3_credentials_valid(s3.credentials.as_ref()).await => (StatusCode::OK, "ready"),
+            Some(_) => (StatusCode::SERVICE_UNAVAILABLE, "S3 credentials unavailable"),
+        };
+
+        Some(Response::builder().status(status).body(http_body_util::Full::new(Bytes::from_static(msg.as_bytes()))).unwrap())
     }
 
-    async fn handle_request(&self, req: Request<body::Incoming>) -> Result<
-        Response<Either<body::Incoming, impl Body<Data=Bytes, Error=BoxError>>>,
-        (StatusCode, &'static str)
-    > {
-        let upstream_req = upstream::request(&self.config, &req)?;
-        let upstream_res = self.upstream_client.request(upstream_req).await.map_err(|e| {
-            error!("Failed to connect upstream: {}", Report(e));
-            (StatusCode::SERVICE_UNAVAILABLE, "Upstream connection failed")
-        })?;
+    /// Respond to a request under `--status-path-prefix` with the progress of
+    /// the download with the request id that follows the prefix
+    fn status_response(&self, req: &Request<impl Body>) -> Option<Response<http_body_util::Full<Bytes>>> {
+        let id = req.uri().path().strip_prefix(self.status_path_prefix.as_deref()?)?;
+
+        let Some(progress) = serve_range::download_progress(id) else {
+            return Some(Response::builder().status(StatusCode::NOT_FOUND).body(http_body_util::Full::new(Bytes::from_static(b"Not found"))).unwrap());
+        };
+
+        let percent = if progress.length == 0 { 100.0 } else { progress.bytes_sent as f64 * 100.0 / progress.length as f64 };
+        let status = serde_json::json!({
+            "id": id,
+            "bytes_sent": progress.bytes_sent,
+            "length": progress.length,
+            "percent": percent,
+        });
+
+        Some(Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(http_body_util::Full::new(Bytes::from(status.to_string())))
+            .unwrap())
+    }
+
+    /// Respond to a request for `--metrics-path` with the current metrics
+    fn metrics_response(&self, req: &Request<impl Body>) -> Option<Response<http_body_util::Full<Bytes>>> {
+        if self.metrics_path.as_deref() != Some(req.uri().path()) {
+            return None;
+        }
+
+        Some(Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(http_body_util::Full::new(Bytes::from(prometheus_metrics())))
+            .unwrap())
+    }
+
+    /// Respond to a request for `--version-path` with the build information
+    /// embedded by `build.rs`
+    fn version_response(&self, req: &Request<impl Body>) -> Option<Response<http_body_util::Full<Bytes>>> {
+        if self.version_path.as_deref() != Some(req.uri().path()) {
+            return None;
+        }
+
+        let build_time = env!("ZIPSTREAM_BUILD_TIME").parse().ok()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+        let version = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "git_commit": env!("ZIPSTREAM_GIT_COMMIT"),
+            "build_time": build_time,
+        });
+
+        Some(Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(http_body_util::Full::new(Bytes::from(version.to_string())))
+            .unwrap())
+    }
+
+    async fn handle_request<B>(&self, req: Request<B>) -> Result<
+        Response<Either<body::Incoming, Either<impl Body<Data=Bytes, Error=BoxError>, impl Body<Data=Bytes, Error=BoxError>>>>,
+        ErrorResponse
+    > where B: Body, B::Error: Into<BoxError> {
+        let config = self.routes.select(req.uri().path())
+            .ok_or((StatusCode::NOT_FOUND, "Not found".into()))?;
+
+        // Request bodies aren't forwarded, but are read within the limits
+        // before making the upstream request
+        let (parts, body) = req.into_parts();
+        read_request_body(config, &parts.headers, body).await?;
+        let req = Request::from_parts(parts, Empty::<Bytes>::new());
+
+        let manifest_paths = upstream::merge_manifest_paths(config, &req)?;
+        if !manifest_paths.is_empty() {
+            let mut manifests = Vec::with_capacity(manifest_paths.len());
 
-        if upstream_res.headers().get("X-Zip-Stream").is_some() {
-            let body = upstream_res.into_body().collect().await.map_err(|e| {
+            for path in &manifest_paths {
+                // Fetched from the route for the path, like a request for it
+                let route = self.routes.select(path).ok_or((StatusCode::NOT_FOUND, "Not found".into()))?;
+                let fetch_span = info_span!("upstream_fetch", path = %path);
+                let upstream_req = upstream::manifest_request(route, &req, path)?;
+                let upstream_res = self.upstream_request(route, upstream_req).instrument(fetch_span.clone()).await?;
+
+                if !upstream_res.status().is_success() {
+                    error!("Upstream response for {} has status {}", path, upstream_res.status());
+                    return Err((StatusCode::BAD_GATEWAY, "Upstream manifest request failed".into()));
+                }
+
+                if upstream_res.headers().get("X-Zip-Stream").is_none() {
+                    error!("Upstream response for {} is not a manifest", path);
+                    return Err((StatusCode::BAD_GATEWAY, "Upstream response is not a manifest".into()));
+                }
+
+                let content_encoding = upstream_res.headers().get(header::CONTENT_ENCODING).cloned();
+                let manifest = upstream::read_manifest(config, content_encoding.as_ref(), upstream_res.into_body()).instrument(fetch_span).await?;
+                manifests.push(manifest);
+            }
+
+            return upstream::merged_response(config, self.s3().await.client.clone(), self.upstream_client.clone(), &req, manifests).await.map(|res| res.map(|b| Either::Right(Either::Left(b))));
+        }
+
+        let fetch_span = info_span!("upstream_fetch");
+        let upstream_req = upstream::request(config, &req)?;
+        let upstream_res = self.upstream_request(config, upstream_req).instrument(fetch_span.clone()).await?;
+
+        // Checked first because a 304 has no body, even if it has the X-Zip-Stream header
+        if upstream_res.status() == StatusCode::NOT_MODIFIED {
+            info!("Upstream response is 304 Not Modified");
+            Ok(upstream::not_modified_response(&upstream_res).map(|b| Either::Right(Either::Left(b))))
+        } else if upstream_res.headers().get("X-Zip-Stream").is_some() {
+            let content_encoding = upstream_res.headers().get(header::CONTENT_ENCODING).cloned();
+            upstream::streamed_response(config, self.s3().await.client.clone(), self.upstream_client.clone(), &req, content_encoding.as_ref(), upstream_res.into_body()).await.map(|res| res.map(|b| Either::Right(Either::Left(b))))
+        } else if upstream::should_buffer_passthrough(config, &upstream_res) {
+            let (parts, body) = upstream_res.into_parts();
+            let body = body.collect().await.map_err(|e| {
                 error!("Failed to read upstream body: {}", Report(e));
-                (StatusCode::SERVICE_UNAVAILABLE, "Upstream request failed")
+                (StatusCode::SERVICE_UNAVAILABLE, "Upstream request failed".into())
             })?;
 
-            upstream::response(self.s3_client.clone(), &req, body.to_bytes()).map(|res| res.map(Either::Right))
+            info!("Response proxied from upstream with buffering");
+            Ok(upstream::buffered_passthrough(config, &req, parts, body.to_bytes()).map(|b| Either::Right(Either::Right(b))))
         } else {
             info!("Response proxied from upstream");
             Ok(upstream_res.map(Either::Left))
         }
     }
+
+    /// Send a request to the upstream server, waiting for a permit first if
+    /// `--max-concurrent-upstream-requests` is set. The permit is held until
+    /// the response headers arrive, or `Config::upstream_timeout` passes.
+    async fn upstream_request(&self, config: &Config, req: Request<Empty<Bytes>>) -> Result<Response<body::Incoming>, ErrorResponse> {
+        let _permit = match &self.upstream_limit {
+            Some(limit) => {
+                let permit = tokio::time::timeout(limit.queue_timeout, limit.permits.clone().acquire_owned()).await;
+                match permit {
+                    Ok(permit) => Some(permit.expect("upstream semaphore is never closed")),
+                    Err(_) => {
+                        warn!("Too many concurrent upstream requests");
+                        return Err((StatusCode::SERVICE_UNAVAILABLE, "Too many concurrent requests".into()));
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let res = match config.upstream_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.upstream_client.request(req)).await.map_err(|_| {
+                error!("Upstream request timed out after {:?}", timeout);
+                (StatusCode::GATEWAY_TIMEOUT, "Upstream request timed out".into())
+            })?,
+            None => self.upstream_client.request(req).await,
+        };
+
+        res.map_err(|e| {
+            error!("Failed to connect upstream: {}", Report(e));
+            (StatusCode::BAD_GATEWAY, "Upstream connection failed".into())
+        })
+    }
+}
+
+fn upstream_client_builder(config: &Config) -> hyper_util::client::legacy::Builder {
+    let mut builder = hyper_util::client::legacy::Client::builder(TokioExecutor::new());
+
+    // The idle timeout only takes effect with a timer to drive it
+    builder.pool_timer(TokioTimer::new())
+        .pool_idle_timeout(config.upstream_pool_idle_timeout);
+
+    builder
+}
+
+/// Number of responses sent to clients by status code, other than those for
+/// `--readiness-path`, `--status-path-prefix` and `--metrics-path`
+static RESPONSES: Mutex<BTreeMap<u16, u64>> = Mutex::new(BTreeMap::new());
+
+fn count_response(status: StatusCode) {
+    *RESPONSES.lock().unwrap().entry(status.as_u16()).or_default() += 1;
+}
+
+/// The metrics logged by `log_metrics` and the response counts, in the
+/// Prometheus text exposition format
+fn prometheus_metrics() -> String {
+    use std::fmt::Write;
+
+    jemalloc_ctl::epoch::advance().unwrap();
+    let allocated = jemalloc_ctl::stats::allocated::read().unwrap();
+    let resident = jemalloc_ctl::stats::resident::read().unwrap();
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}").unwrap();
+    };
+    metric("zipstream_active_downloads", "gauge", "Downloads in progress", serve_range::active_downloads().into());
+    metric("zipstream_bytes_served_total", "counter", "Bytes of downloads sent", serve_range::bytes_served());
+    metric("zipstream_duplicate_sources_total", "counter", "Manifest entries repeating the source of another entry", upstream::duplicate_sources());
+    metric("jemalloc_allocated_bytes", "gauge", "Bytes allocated by the application", allocated as u64);
+    metric("jemalloc_resident_bytes", "gauge", "Bytes in physically resident data pages mapped by the allocator", resident as u64);
+
+    writeln!(out, "# HELP zipstream_requests_total Responses sent, by HTTP status code\n# TYPE zipstream_requests_total counter").unwrap();
+    for (status, count) in RESPONSES.lock().unwrap().iter() {
+        writeln!(out, "zipstream_requests_total{{status=\"{status}\"}} {count}").unwrap();
+    }
+
+    out
 }
 
 async fn log_metrics() {
@@ -163,11 +984,610 @@ async fn log_metrics() {
         let resident = jemalloc_ctl::stats::resident::read().unwrap();
 
         let active_downloads = zipstream::serve_range::active_downloads();
+        let duplicate_sources = zipstream::upstream::duplicate_sources();
 
         event!(target: "zipstream::metrics", Level::INFO,
             zipstream.active_downloads = active_downloads,
+            zipstream.duplicate_sources = duplicate_sources,
             jemalloc.allocated = allocated,
             jemalloc.resident = resident,
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http_body_util::{Empty, StreamBody};
+    use hyper::body::Frame;
+    use std::convert::Infallible;
+
+    /// Start a server on a local port that responds to every request with `body`
+    async fn mock_upstream(body: impl Into<Bytes>) -> String {
+        mock_upstream_with_headers(body, &[]).await
+    }
+
+    /// Like `mock_upstream`, with `headers` added to every response
+    async fn mock_upstream_with_headers(body: impl Into<Bytes>, headers: &'static [(&'static str, &'static str)]) -> String {
+        mock_upstream_with_status(StatusCode::OK, body, headers).await
+    }
+
+    /// Like `mock_upstream_with_headers`, responding with `status`
+    async fn mock_upstream_with_status(status: StatusCode, body: impl Into<Bytes>, headers: &'static [(&'static str, &'static str)]) -> String {
+        let body = body.into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let body = body.clone();
+                tokio::task::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |_req| {
+                    let body = body.clone();
+                    let mut res = Response::new(http_body_util::Full::new(body));
+                    *res.status_mut() = status;
+                    for (name, value) in headers {
+                        res.headers_mut().insert(*name, HeaderValue::from_static(value));
+                    }
+                    async move { Ok::<_, std::convert::Infallible>(res) }
+                })));
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    fn test_s3(credentials: Option<s3::config::SharedCredentialsProvider>) -> S3State {
+        S3State {
+            client: s3::Client::from_conf(s3::Config::builder()
+                .behavior_version(s3::config::BehaviorVersion::latest())
+                .region(s3::config::Region::from_static("us-east-1"))
+                .build()),
+            credentials,
+        }
+    }
+
+    fn test_app(routes: Routes) -> App {
+        App {
+            routes,
+            upstream_client: upstream_client_builder(&Config::default()).build(HttpsConnector::new()),
+            s3: Arc::new(OnceCell::new_with(Some(test_s3(None)))),
+            readiness_path: None,
+            status_path_prefix: None,
+            metrics_path: None,
+            version_path: None,
+            upstream_limit: None,
+        }
+    }
+
+    async fn get(app: &App, path: &str) -> (StatusCode, Bytes) {
+        let req = Request::builder().uri(path).body(Empty::<Bytes>::new()).unwrap();
+        match app.handle_request(req).await {
+            Ok(res) => (res.status(), res.into_body().collect().await.unwrap().to_bytes()),
+            Err((status, msg)) => (status, Bytes::from(msg.into_owned())),
+        }
+    }
+
+    #[test]
+    fn test_forward_header_args() {
+        let args = Args::try_parse_from(["zipstream", "--upstream", "http://upstream"]).unwrap();
+        assert_eq!(args.forward_header, Config::default().forward_headers);
+
+        let args = Args::try_parse_from(["zipstream", "--upstream", "http://upstream", "--forward-header", "X-Tenant-Id", "--forward-header", "authorization"]).unwrap();
+        assert_eq!(args.forward_header, ["x-tenant-id", "authorization"]);
+
+        let err = Args::try_parse_from(["zipstream", "--upstream", "http://upstream", "--forward-header", "X Tenant"]).unwrap_err();
+        assert!(err.to_string().contains("invalid HTTP header name"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let mut routes = Routes::default();
+        routes.add("/a/".into(), Config { upstream: mock_upstream("upstream A").await, ..Default::default() });
+        routes.add("/b/".into(), Config { upstream: mock_upstream("upstream B").await, strip_prefix: "/b".into(), ..Default::default() });
+        let app = test_app(routes);
+
+        assert_eq!(get(&app, "/a/file").await, (StatusCode::OK, Bytes::from_static(b"upstream A")));
+        assert_eq!(get(&app, "/b/file").await, (StatusCode::OK, Bytes::from_static(b"upstream B")));
+        assert_eq!(get(&app, "/c/file").await.0, StatusCode::NOT_FOUND);
+    }
+
+    /// An upstream that returns an already assembled zip, such as another
+    /// zipstream, is proxied with Range support when it sends a Content-Length
+    #[tokio::test]
+    async fn test_chained_zip_range() {
+        use zipstream::{stream_range::{Range, StreamRange}, zip::{zip_stream, ZipEntry, ZipOptions}};
+
+        let zip = zip_stream(vec![ZipEntry {
+            archive_path: "foo.txt".into(),
+            data: Box::new(Bytes::from_static(b"xx")),
+            crc: Some(0xf8e1180f),
+            last_modified: "2006-11-10T15:40:56Z".parse().unwrap(),
+            is_directory: false,
+            comment: String::new(),
+            compression: Default::default(),
+        }], ZipOptions::default()).unwrap();
+        let chunks: Vec<Bytes> = futures::TryStreamExt::try_collect(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        let zip = Bytes::from(chunks.concat());
+
+        let config = Config { upstream: mock_upstream(zip.clone()).await, passthrough_range_limit: Some(1 << 20), ..Default::default() };
+        let app = test_app(Routes::single(config));
+
+        let req = Request::builder().uri("/archive.zip").header(hyper::header::RANGE, "bytes=10-29").body(Empty::<Bytes>::new()).unwrap();
+        let Ok(res) = app.handle_request(req).await else { panic!("request failed") };
+
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[hyper::header::CONTENT_RANGE], format!("bytes 10-29/{}", zip.len()));
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), zip[10..30]);
+    }
+
+    /// An upstream 304 is passed on without parsing its empty body as a manifest
+    #[tokio::test]
+    async fn test_upstream_not_modified() {
+        let headers = &[("X-Zip-Stream", "true"), ("ETag", "\"v1\""), ("Cache-Control", "max-age=60"), ("X-Other", "x")];
+        let config = Config { upstream: mock_upstream_with_status(StatusCode::NOT_MODIFIED, "", headers).await, ..Default::default() };
+        let app = test_app(Routes::single(config));
+
+        let req = Request::builder().uri("/test.zip").header(hyper::header::IF_NONE_MATCH, "\"v1\"").body(Empty::<Bytes>::new()).unwrap();
+        let Ok(res) = app.handle_request(req).await else { panic!("request failed") };
+
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[hyper::header::ETAG], "\"v1\"");
+        assert_eq!(res.headers()[hyper::header::CACHE_CONTROL], "max-age=60");
+        assert!(res.headers().get("X-Other").is_none());
+        assert!(res.headers().get("X-Zip-Stream").is_none());
+        assert!(res.into_body().collect().await.unwrap().to_bytes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        use std::time::SystemTime;
+
+        let credentials = |expiry: SystemTime| Some(s3::config::SharedCredentialsProvider::new(
+            s3::config::Credentials::new("AKID", "SECRET", None, Some(expiry), "test")
+        ));
+        let app = |expiry: SystemTime| App {
+            s3: Arc::new(OnceCell::new_with(Some(test_s3(credentials(expiry))))),
+            readiness_path: Some("/readyz".into()),
+            ..test_app(Routes::default())
+        };
+        let req = |path: &str| Request::builder().uri(path).body(Empty::<Bytes>::new()).unwrap();
+
+        let valid = app(SystemTime::now() + Duration::from_secs(3600));
+        assert_eq!(valid.readiness_response(&req("/readyz")).await.unwrap().status(), StatusCode::OK);
+        assert!(valid.readiness_response(&req("/other")).await.is_none());
+
+        let expired = app(SystemTime::now() - Duration::from_secs(60));
+        assert_eq!(expired.readiness_response(&req("/readyz")).await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // No credentials provider at all
+        let unresolvable = App { readiness_path: Some("/readyz".into()), ..test_app(Routes::default()) };
+        assert_eq!(unresolvable.readiness_response(&req("/readyz")).await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Not ready until the S3 client has been initialized
+        let initializing = App { s3: Arc::new(OnceCell::new()), readiness_path: Some("/readyz".into()), ..test_app(Routes::default()) };
+        let res = initializing.readiness_response(&req("/readyz")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "S3 client initializing");
+        initializing.s3.set(test_s3(credentials(SystemTime::now() + Duration::from_secs(3600)))).ok().unwrap();
+        assert_eq!(initializing.readiness_response(&req("/readyz")).await.unwrap().status(), StatusCode::OK);
+    }
+
+    /// An HTTP/1.0 client gets a Content-Length rather than chunked encoding,
+    /// the connection is closed unless it asked for keep-alive, and ranges work
+    #[tokio::test]
+    async fn test_http10_client() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Config { upstream: mock_upstream("0123456789").await, passthrough_range_limit: Some(1 << 20), ..Default::default() };
+        let app = test_app(Routes::single(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, client_addr) = listener.accept().await.unwrap();
+                tokio::task::spawn(serve_connection(app.clone(), stream, client_addr, None, None, CancellationToken::new()));
+            }
+        });
+
+        // Returns the response, and whether the server closed the connection after it
+        let request = |req: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(req.as_bytes()).await.unwrap();
+
+            let mut res = Vec::new();
+            let mut buf = [0; 1024];
+            let closed = loop {
+                match tokio::time::timeout(Duration::from_millis(500), stream.read(&mut buf)).await {
+                    Ok(Ok(0)) => break true,
+                    Ok(Ok(n)) => res.extend_from_slice(&buf[..n]),
+                    Ok(Err(e)) => panic!("{}", e),
+                    Err(_) => break false,
+                }
+            };
+            (String::from_utf8(res).unwrap().to_lowercase(), closed)
+        };
+
+        let (res, closed) = request("GET /file HTTP/1.0\r\n\r\n").await;
+        assert!(res.starts_with("http/1.0 200 ok\r\n"), "{}", res);
+        assert!(res.contains("\r\nconnection: close\r\n"), "{}", res);
+        assert!(res.contains("\r\ncontent-length: 10\r\n"), "{}", res);
+        assert!(!res.contains("transfer-encoding"), "{}", res);
+        assert!(res.ends_with("\r\n\r\n0123456789"), "{}", res);
+        assert!(closed);
+
+        let (res, _) = request("GET /file HTTP/1.0\r\nRange: bytes=2-4\r\n\r\n").await;
+        assert!(res.starts_with("http/1.0 206 partial content\r\n"), "{}", res);
+        assert!(res.contains("\r\ncontent-length: 3\r\n"), "{}", res);
+        assert!(res.ends_with("\r\n\r\n234"), "{}", res);
+
+        // hyper honors an explicit keep-alive, which is safe with the Content-Length
+        let (res, closed) = request("GET /file HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").await;
+        assert!(res.contains("\r\nconnection: keep-alive\r\n"), "{}", res);
+        assert!(res.ends_with("\r\n\r\n0123456789"), "{}", res);
+        assert!(!closed);
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Config { upstream: mock_upstream("0123456789").await, ..Default::default() };
+        let app = test_app(Routes::single(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limit = Arc::new(Semaphore::new(1));
+        tokio::task::spawn(accept_connections(vec![listener], app, Some(limit.clone()), None, None, futures::future::pending(), Duration::ZERO));
+
+        let read_all = |mut stream: TcpStream| async move {
+            let mut res = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut res)).await.unwrap().unwrap();
+            String::from_utf8(res).unwrap()
+        };
+
+        let open = TcpStream::connect(addr).await.unwrap();
+        while limit.available_permits() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let res = read_all(TcpStream::connect(addr).await.unwrap()).await;
+        assert!(res.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", res);
+        assert!(res.ends_with("\r\n\r\nToo many connections"), "{}", res);
+
+        // Closing the open connection releases its slot
+        drop(open);
+        while limit.available_permits() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /file HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let res = read_all(stream).await;
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+        assert!(res.ends_with("0123456789"), "{}", res);
+    }
+
+    /// Requests to each of several listeners are served
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Config { upstream: mock_upstream("0123456789").await, ..Default::default() };
+        let app = test_app(Routes::single(config));
+
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+        tokio::task::spawn(accept_connections(vec![first, second], app, None, None, None, futures::future::pending(), Duration::ZERO));
+
+        for addr in addrs {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET /file HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+            let mut res = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut res)).await.unwrap().unwrap();
+            let res = String::from_utf8(res).unwrap();
+            assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+            assert!(res.ends_with("0123456789"), "{}", res);
+        }
+    }
+
+    /// On shutdown, a request in progress finishes while idle connections and
+    /// new ones are turned away
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // An upstream that takes a while to respond
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, _) = upstream.accept().await.unwrap();
+                tokio::task::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|_req| async {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    Ok::<_, std::convert::Infallible>(Response::new(http_body_util::Full::new(Bytes::from_static(b"0123456789"))))
+                })));
+            }
+        });
+
+        let config = Config { upstream: format!("http://{}", upstream_addr), ..Default::default() };
+        let app = test_app(Routes::single(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::task::spawn(accept_connections(vec![listener], app, None, None, None, async { shutdown_rx.await.unwrap() }, Duration::from_secs(10)));
+
+        let mut busy = TcpStream::connect(addr).await.unwrap();
+        busy.write_all(b"GET /file HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let start = std::time::Instant::now();
+        shutdown.send(()).unwrap();
+
+        let mut res = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), busy.read_to_end(&mut res)).await.unwrap().unwrap();
+        let res = String::from_utf8(res).unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+        assert!(res.ends_with("0123456789"), "{}", res);
+
+        let mut buf = Vec::new();
+        assert_eq!(tokio::time::timeout(Duration::from_secs(5), idle.read_to_end(&mut buf)).await.unwrap().unwrap(), 0);
+
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    /// Each stage of serving an archive gets a span inside the request span
+    #[tokio::test]
+    async fn test_stage_spans() {
+        use tracing::{span, Subscriber};
+        use tracing_subscriber::{layer::{Context, SubscriberExt}, registry::LookupSpan, Layer};
+
+        /// Records each span as `parent/name`
+        struct SpanNames(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanNames {
+            fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+                let span = ctx.span(id).unwrap();
+                let parent = span.parent().map_or("", |parent| parent.name());
+                self.0.lock().unwrap().push(format!("{}/{}", parent, span.name()));
+            }
+        }
+
+        let spans = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(SpanNames(spans.clone())));
+
+        let manifest = r#"{"filename": "test.zip", "entries": [{"type": "directory", "archive_name": "a", "last_modified": "2021-03-04T05:06:08Z"}]}"#;
+        let config = Config { upstream: mock_upstream_with_headers(manifest, &[("X-Zip-Stream", "true")]).await, ..Default::default() };
+        let app = test_app(Routes::single(config));
+
+        let (status, zip) = get(&app, "/test.zip").instrument(info_span!("request")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(zip.starts_with(b"PK"));
+
+        let spans = spans.lock().unwrap();
+        for name in ["upstream_fetch", "manifest_parse", "stream"] {
+            assert!(spans.contains(&format!("request/{}", name)), "{} in {:?}", name, spans);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge_manifests() {
+        let manifest = r#"{"filename": "test.zip", "entries": [{"type": "directory", "archive_name": "a", "last_modified": "2021-03-04T05:06:08Z"}]}"#;
+        let other = r#"{"filename": "test.zip", "entries": [{"type": "directory", "archive_name": "b", "last_modified": "2021-03-04T05:06:08Z"}]}"#;
+        let mut routes = Routes::default();
+        routes.add(String::new(), Config { upstream: mock_upstream_with_headers(manifest, &[("X-Zip-Stream", "true")]).await, merge_manifests: true, ..Default::default() });
+        routes.add("/ok".into(), Config { upstream: mock_upstream_with_headers(other, &[("X-Zip-Stream", "true")]).await, strip_prefix: "/ok".into(), ..Default::default() });
+        routes.add("/missing".into(), Config { upstream: mock_upstream_with_status(StatusCode::NOT_FOUND, manifest, &[("X-Zip-Stream", "true")]).await, ..Default::default() });
+        let app = test_app(routes);
+
+        // Each path is fetched from the upstream of its own route
+        let (status, zip) = get(&app, "/merged.zip?m=/a.json&m=%2Fok%2Fb.json").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(zip.windows(2).any(|w| w == b"a/") && zip.windows(2).any(|w| w == b"b/"));
+
+        // A manifest that can't be fetched fails the archive rather than being skipped
+        assert_eq!(get(&app, "/merged.zip?m=/a.json&m=/missing/b.json").await.0, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_gzip_manifest() {
+        let manifest = r#"{"filename": "test.zip", "entries": [{"type": "directory", "archive_name": "a", "last_modified": "2021-03-04T05:06:08Z"}]}"#;
+        let gzipped = std::process::Command::new("python3").arg("-c")
+            .arg(format!("import gzip, sys; sys.stdout.buffer.write(gzip.compress({:?}.encode()))", manifest))
+            .output().unwrap().stdout;
+
+        let plain = Config { upstream: mock_upstream_with_headers(manifest, &[("X-Zip-Stream", "true")]).await, ..Default::default() };
+        let gzip = Config { upstream: mock_upstream_with_headers(gzipped, &[("X-Zip-Stream", "true"), ("Content-Encoding", "gzip")]).await, ..Default::default() };
+
+        let (status, zip) = get(&test_app(Routes::single(gzip)), "/test.zip").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(zip, get(&test_app(Routes::single(plain)), "/test.zip").await.1);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let app = App { metrics_path: Some("/metrics".into()), ..test_app(Routes::default()) };
+        let req = |path: &str| Request::builder().uri(path).body(Empty::<Bytes>::new()).unwrap();
+        assert!(app.metrics_response(&req("/other")).is_none());
+
+        count_response(StatusCode::IM_A_TEAPOT);
+        let res = app.metrics_response(&req("/metrics")).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/plain; version=0.0.4");
+
+        let text = String::from_utf8(res.into_body().collect().await.unwrap().to_bytes().to_vec()).unwrap();
+        for name in ["zipstream_active_downloads", "zipstream_bytes_served_total", "jemalloc_allocated_bytes", "jemalloc_resident_bytes"] {
+            assert!(text.lines().any(|line| line.starts_with(&format!("{} ", name))), "{} missing from {}", name, text);
+            assert!(text.contains(&format!("# TYPE {} ", name)));
+        }
+        assert!(text.contains("zipstream_requests_total{status=\"418\"} "), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let app = App { version_path: Some("/version".into()), ..test_app(Routes::default()) };
+        let req = |path: &str| Request::builder().uri(path).body(Empty::<Bytes>::new()).unwrap();
+        assert!(app.version_response(&req("/other")).is_none());
+
+        let res = app.version_response(&req("/version")).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+
+        let json: serde_json::Value = serde_json::from_slice(&res.into_body().collect().await.unwrap().to_bytes()).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["git_commit"].as_str().is_some_and(|c| !c.is_empty()), "{}", json);
+        let build_time = json["build_time"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(build_time).is_ok(), "{}", build_time);
+    }
+
+    #[tokio::test]
+    async fn test_download_status() {
+        use zipstream::stream_range::Concatenated;
+
+        let app = App { status_path_prefix: Some("/status/".into()), ..test_app(Routes::default()) };
+        let status = |path: &str| {
+            let res = app.status_response(&Request::builder().uri(path).body(Empty::<Bytes>::new()).unwrap());
+            res.map(|res| (res.status(), futures::executor::block_on(res.into_body().collect()).unwrap().to_bytes()))
+        };
+
+        let mut req = Request::builder().uri("/test.zip").body(Empty::<Bytes>::new()).unwrap();
+        req.extensions_mut().insert(RequestId("0123abcd".into()));
+        let data = Concatenated::new(vec![Box::new(Bytes::from_static(b"0123")), Box::new(Bytes::from_static(b"456789"))]);
+        let res = serve_range::hyper_response(&Config::default(), &req, "application/zip", "ETAG", None, "test.zip", &data);
+
+        let mut body = res.into_body();
+        assert_eq!(body.frame().await.unwrap().unwrap().into_data().unwrap(), "0123");
+
+        let (code, json) = status("/status/0123abcd").unwrap();
+        assert_eq!(code, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json, serde_json::json!({ "id": "0123abcd", "bytes_sent": 4, "length": 10, "percent": 40.0 }));
+
+        body.collect().await.unwrap();
+        assert_eq!(status("/status/0123abcd").unwrap().0, StatusCode::NOT_FOUND);
+        assert_eq!(status("/status/unknown").unwrap().0, StatusCode::NOT_FOUND);
+        assert!(status("/other/0123abcd").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_upstream_requests() {
+        let config = Config { upstream: mock_upstream("upstream").await, ..Default::default() };
+        let limit = |queue_timeout| UpstreamLimit { permits: Arc::new(Semaphore::new(1)), queue_timeout };
+
+        // A request holding the only permit makes the next one fail at once
+        let rejecting = App { upstream_limit: Some(limit(Duration::ZERO)), ..test_app(Routes::single(config.clone())) };
+        let permit = rejecting.upstream_limit.as_ref().unwrap().permits.clone().acquire_owned().await.unwrap();
+        assert_eq!(get(&rejecting, "/file").await, (StatusCode::SERVICE_UNAVAILABLE, Bytes::from_static(b"Too many concurrent requests")));
+        drop(permit);
+        assert_eq!(get(&rejecting, "/file").await, (StatusCode::OK, Bytes::from_static(b"upstream")));
+
+        // or wait for it to be released
+        let queueing = App { upstream_limit: Some(limit(Duration::from_secs(5))), ..test_app(Routes::single(config)) };
+        let permit = queueing.upstream_limit.as_ref().unwrap().permits.clone().acquire_owned().await.unwrap();
+        tokio::task::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(permit);
+        });
+        assert_eq!(get(&queueing, "/file").await, (StatusCode::OK, Bytes::from_static(b"upstream")));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timeout() {
+        // Accepts connections but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::task::spawn(async move {
+            let mut connections = Vec::new();
+            loop {
+                connections.push(listener.accept().await.unwrap().0);
+            }
+        });
+
+        let config = Config { upstream, upstream_timeout: Some(Duration::from_millis(100)), ..Default::default() };
+        let app = test_app(Routes::single(config));
+        assert_eq!(get(&app, "/file").await, (StatusCode::GATEWAY_TIMEOUT, Bytes::from_static(b"Upstream request timed out")));
+    }
+
+    #[tokio::test]
+    async fn test_max_request_body() {
+        let config = Config { upstream: mock_upstream("upstream").await, max_request_body: Some(10), ..Default::default() };
+        let app = test_app(Routes::single(config));
+        let with_body = |len: &str| Request::get("/file").header(header::CONTENT_LENGTH, len).body(Empty::<Bytes>::new()).unwrap();
+
+        let Err((status, _)) = app.handle_request(with_body("11")).await else { panic!("oversized body accepted") };
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(app.handle_request(with_body("10")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(get(&app, "/file").await, (StatusCode::OK, Bytes::from_static(b"upstream")));
+
+        // A chunked body is limited as it is read
+        let chunked = |chunks: Vec<&'static [u8]>| {
+            let frames = futures::stream::iter(chunks.into_iter().map(|chunk| Ok::<_, Infallible>(Frame::data(Bytes::from_static(chunk)))));
+            Request::get("/file").body(StreamBody::new(frames)).unwrap()
+        };
+        let Err((status, _)) = app.handle_request(chunked(vec![b"123456", b"78901"])).await else { panic!("oversized body accepted") };
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(app.handle_request(chunked(vec![b"12345", b"67890"])).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_request_body_timeout() {
+        let config = Config {
+            upstream: mock_upstream("upstream").await,
+            request_body_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let app = test_app(Routes::single(config));
+
+        // A body that never finishes
+        let frames = futures::stream::pending::<Result<Frame<Bytes>, Infallible>>();
+        let req = Request::get("/file").body(StreamBody::new(frames)).unwrap();
+        let Err((status, _)) = app.handle_request(req).await else { panic!("slow body accepted") };
+        assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(get(&app, "/file").await, (StatusCode::OK, Bytes::from_static(b"upstream")));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_pool_idle_timeout() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // An upstream that counts its open connections
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let open = Arc::new(AtomicUsize::new(0));
+        let counter = open.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let counter = counter.clone();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::task::spawn(async move {
+                    let service = service_fn(|_req| async { Ok::<_, Infallible>(Response::new(http_body_util::Full::new(Bytes::from_static(b"ok")))) });
+                    let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+                    counter.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        // The connection stays pooled until it has been idle for the timeout
+        for (timeout, closed) in [(Duration::from_secs(90), false), (Duration::from_millis(100), true)] {
+            let config = Config { upstream_pool_idle_timeout: timeout, ..Default::default() };
+            let client = upstream_client_builder(&config).build::<_, Empty<Bytes>>(HttpsConnector::new());
+            let res = client.get(url.parse().unwrap()).await.unwrap();
+            res.into_body().collect().await.unwrap();
+            assert_eq!(open.load(Ordering::SeqCst), 1);
+
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            assert_eq!(open.load(Ordering::SeqCst) == 0, closed, "idle timeout {:?}", timeout);
+            drop(client);
+            while open.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+}
@@ -27,6 +27,8 @@ impl fmt::Display for ParseS3UrlError {
     }
 }
 
+impl std::error::Error for ParseS3UrlError {}
+
 impl FromStr for S3Url {
     type Err = ParseS3UrlError;
 
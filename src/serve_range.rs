@@ -1,14 +1,16 @@
 // © 2019 3D Robotics. License: Apache-2.0
 
-use std::{error::Error, pin::Pin, sync::atomic::{AtomicU32, Ordering}, task::Poll, time::Instant};
+use std::{collections::BTreeMap, error::Error, future::Future, pin::Pin, sync::{Arc, Mutex, atomic::{AtomicU32, AtomicU64, Ordering}}, task::Poll, time::{Duration, Instant}};
 
 use bytes::Bytes;
-use futures::{Stream, StreamExt};
-use crate::{error::Report, stream_range::BoxBytesStream};
+use futures::{future, stream, Stream, StreamExt};
+use crate::{Config, ContentDisposition, access_log::AccessLogRequest, error::Report, stream_range::BoxBytesStream, upstream::RequestId, zip::EntryError};
 use http_body_util::StreamBody;
-use hyper::{Request, Response, body::{Body, Frame}, StatusCode, header};
+use chrono::{DateTime, Utc};
+use hyper::{Method, Request, Response, body::{Body, Frame}, StatusCode, header::{self, HeaderValue}};
 use crate::stream_range::{ BoxError, Range, StreamRange };
-use tracing::{error, info, Span};
+use tracing::{error, info, info_span, Span};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 
 /// Parse an HTTP range header to a `Range`
 ///
@@ -22,7 +24,7 @@ pub fn parse_range(range_val: &str, total_len: u64) -> Result<Option<Range>, &'s
     let range_val = &range_val["bytes=".len()..].trim();
 
     if range_val.contains(',') {
-        return Ok(None); // multiple ranges unsupported, but it's legal to just ignore the header
+        return Ok(None); // multiple ranges are parsed by `parse_multiple_ranges`
     }
 
     if let Some(range_end) = range_val.strip_prefix('-') {
@@ -55,6 +57,30 @@ pub fn parse_range(range_val: &str, total_len: u64) -> Result<Option<Range>, &'s
     }
 }
 
+/// Parse an HTTP range header with several comma-separated ranges
+///
+/// Returns Ok(Some(ranges)) if every range is valid and they are in ascending
+/// order without overlapping, Ok(None) if not, or Err(msg) if parsing fails.
+pub fn parse_multiple_ranges(range_val: &str, total_len: u64) -> Result<Option<Vec<Range>>, &'static str> {
+    let Some(range_list) = range_val.strip_prefix("bytes=") else {
+        return Err("invalid range unit");
+    };
+
+    let mut ranges: Vec<Range> = Vec::new();
+    for spec in range_list.split(',') {
+        let Some(range) = parse_range(&format!("bytes={}", spec.trim()), total_len)? else {
+            return Ok(None);
+        };
+
+        if ranges.last().is_some_and(|prev| range.start < prev.end) {
+            return Ok(None); // overlapping or descending
+        }
+        ranges.push(range);
+    }
+
+    Ok(Some(ranges))
+}
+
 #[test]
 fn test_range() {
     assert_eq!(parse_range("lines=0-10", 1000), Err("invalid range unit"));
@@ -77,65 +103,667 @@ fn test_range() {
     assert_eq!(parse_range("bytes=-b", 1000), Err("invalid range number"));
 }
 
+#[test]
+fn test_multiple_ranges() {
+    assert_eq!(parse_multiple_ranges("bytes=0-99, 500-599", 1000), Ok(Some(vec![Range { start: 0, end: 100 }, Range { start: 500, end: 600 }])));
+    assert_eq!(parse_multiple_ranges("bytes=0-99,100-", 1000), Ok(Some(vec![Range { start: 0, end: 100 }, Range { start: 100, end: 1000 }])));
+    assert_eq!(parse_multiple_ranges("bytes=0-99,-100", 1000), Ok(Some(vec![Range { start: 0, end: 100 }, Range { start: 900, end: 1000 }])));
+
+    assert_eq!(parse_multiple_ranges("bytes=500-599,0-99", 1000), Ok(None));
+    assert_eq!(parse_multiple_ranges("bytes=0-99,50-149", 1000), Ok(None));
+    assert_eq!(parse_multiple_ranges("bytes=0-99,900-1000", 1000), Ok(None));
+
+    assert_eq!(parse_multiple_ranges("bytes=0-99,a-b", 1000), Err("invalid range number"));
+    assert_eq!(parse_multiple_ranges("lines=0-1,2-3", 1000), Err("invalid range unit"));
+}
+
+/// How the Range header of a request was interpreted
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) enum RangeOutcome {
+    /// No Range header, or an If-Range that doesn't match: serve the full content
+    Full,
+
+    /// Serve the specified range with a 206
+    Satisfiable(Range),
+
+    /// Serve the specified ranges with a 206 and a multipart/byteranges body
+    Multiple(Vec<Range>),
+
+    /// A range unit other than `bytes`, which is ignored per RFC 9110
+    UnsupportedUnit,
+
+    /// A `bytes` range that can't be served, e.g. because it is out of bounds,
+    /// or contains multiple ranges that overlap or are out of order
+    Unsatisfiable,
+
+    /// A Range header that failed to parse
+    Malformed,
+
+    /// More ranges than `Config::max_ranges_per_request`, rejected with a 400
+    TooManyRanges,
+
+    /// `part` or `part-size` query parameters that failed to parse, rejected with a 400
+    InvalidPart,
+
+    /// A `part` after the last part, rejected with a 416
+    PartOutOfRange,
+
+    /// A Range header or `part` parameters for content without an ETag,
+    /// which differs between requests, so that ranges of it can't be combined
+    Unrepeatable,
+}
+
+impl RangeOutcome {
+    /// Value of the `zipstream.range_ignored` log field when the header is not used
+    fn reason(&self) -> &'static str {
+        match self {
+            RangeOutcome::Full | RangeOutcome::Satisfiable(_) | RangeOutcome::Multiple(_) => "none",
+            RangeOutcome::UnsupportedUnit => "unsupported_unit",
+            RangeOutcome::Unsatisfiable => "unsatisfiable",
+            RangeOutcome::Malformed => "malformed",
+            RangeOutcome::TooManyRanges => "too_many_ranges",
+            RangeOutcome::InvalidPart => "invalid_part",
+            RangeOutcome::PartOutOfRange => "part_out_of_range",
+            RangeOutcome::Unrepeatable => "unrepeatable",
+        }
+    }
+}
+
+/// Whether an If-Range header, holding either an entity tag or an HTTP date,
+/// indicates the client's copy is current so its Range can be honored.
+fn if_range_matches(val: &HeaderValue, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+    if val == etag {
+        return true;
+    }
+
+    let date = val.to_str().ok().and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+    match (date, last_modified) {
+        (Some(date), Some(last_modified)) => last_modified <= date,
+        _ => false,
+    }
+}
+
+/// Whether an If-Match header matches the current entity tag. It matches if
+/// it is `*` or lists the tag; weak tags never match, since If-Match uses the
+/// strong comparison.
+fn if_match_matches(val: &HeaderValue, etag: &str) -> bool {
+    let Ok(val) = val.to_str() else {
+        return false;
+    };
+
+    val.split(',').map(str::trim).any(|tag| {
+        tag == "*" || (!tag.starts_with("W/") && tag.trim_matches('"') == etag.trim_matches('"'))
+    })
+}
+
+/// Parse a `part-size` query parameter: a number of bytes, optionally with a
+/// `KiB`, `MiB` or `GiB` suffix
+fn parse_part_size(value: &str) -> Option<u64> {
+    let (digits, multiplier) = [("KiB", 1 << 10), ("MiB", 1 << 20), ("GiB", 1 << 30)].iter()
+        .find_map(|(suffix, multiplier)| Some((value.strip_suffix(suffix)?, *multiplier)))
+        .unwrap_or((value, 1));
+    digits.parse::<u64>().ok()?.checked_mul(multiplier).filter(|&size| size > 0)
+}
+
+/// Interpret the `part` and `part-size` query parameters of a request, which
+/// select bytes `(part - 1) * part-size` up to `part * part-size` of the
+/// content, numbering parts from 1 like S3 multipart uploads. The last part
+/// is shorter unless the length is a multiple of the part size.
+fn select_part(req: &Request<impl Body>, full_len: u64) -> Option<RangeOutcome> {
+    let query = req.uri().query()?;
+    let param = |name: &str| query.split('&').find_map(|param| param.strip_prefix(name)?.strip_prefix('='));
+    let part = param("part")?;
+
+    let (Some(part), Some(part_size)) = (part.parse::<u64>().ok().filter(|&p| p > 0), param("part-size").and_then(parse_part_size)) else {
+        return Some(RangeOutcome::InvalidPart);
+    };
+
+    let start = (part - 1).checked_mul(part_size).filter(|&start| start < full_len);
+    Some(match start {
+        Some(start) => RangeOutcome::Satisfiable(Range { start, end: start.saturating_add(part_size).min(full_len) }),
+        None => RangeOutcome::PartOutOfRange,
+    })
+}
+
+/// Interpret the Range and If-Range headers of a request, or the `part`
+/// query parameters that replace them (see `select_part`)
+pub(crate) fn select_range(config: &Config, req: &Request<impl Body>, etag: &str, last_modified: Option<DateTime<Utc>>, full_len: u64) -> RangeOutcome {
+    if let Some(outcome) = select_part(req, full_len) {
+        return outcome;
+    }
+
+    let Some(range_val) = req.headers().get(header::RANGE) else {
+        return RangeOutcome::Full;
+    };
+
+    if req.headers().get(header::IF_RANGE).is_some_and(|val| !if_range_matches(val, etag, last_modified)) {
+        return RangeOutcome::Full;
+    }
+
+    let Ok(range_val) = range_val.to_str() else {
+        return RangeOutcome::Malformed;
+    };
+
+    if range_val.strip_prefix("bytes=").is_some_and(|ranges| ranges.split(',').count() > config.max_ranges_per_request) {
+        return RangeOutcome::TooManyRanges;
+    }
+
+    if range_val.contains(',') {
+        return match parse_multiple_ranges(range_val, full_len) {
+            Ok(Some(ranges)) => RangeOutcome::Multiple(ranges),
+            Ok(None) => RangeOutcome::Unsatisfiable,
+            Err("invalid range unit") => RangeOutcome::UnsupportedUnit,
+            Err(_) => RangeOutcome::Malformed,
+        };
+    }
+
+    match parse_range(range_val, full_len) {
+        Ok(Some(range)) => RangeOutcome::Satisfiable(range),
+        Ok(None) => RangeOutcome::Unsatisfiable,
+        Err("invalid range unit") => RangeOutcome::UnsupportedUnit,
+        Err(_) => RangeOutcome::Malformed,
+    }
+}
+
+#[test]
+fn test_select_range() {
+    fn outcome(range: &str) -> RangeOutcome {
+        let req = Request::builder()
+            .header(header::RANGE, range)
+            .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+        select_range(&Config::default(), &req, "ETAG", None, 1000)
+    }
+
+    assert_eq!(outcome("bytes=0-9"), RangeOutcome::Satisfiable(Range { start: 0, end: 10 }));
+    assert_eq!(outcome("lines=0-10"), RangeOutcome::UnsupportedUnit);
+    assert_eq!(outcome("bytes=9999-"), RangeOutcome::Unsatisfiable);
+    assert_eq!(outcome("bytes=a-b"), RangeOutcome::Malformed);
+
+    assert_eq!(outcome("lines=0-10").reason(), "unsupported_unit");
+    assert_eq!(outcome("bytes=9999-").reason(), "unsatisfiable");
+
+    assert_eq!(outcome("bytes=0-1,2-3"), RangeOutcome::Multiple(vec![Range { start: 0, end: 2 }, Range { start: 2, end: 4 }]));
+    assert_eq!(outcome("bytes=2-3,0-1"), RangeOutcome::Unsatisfiable);
+    assert_eq!(outcome("bytes=0-1,2-3,4-5,6-7,8-9,10-11,12-13,14-15,16-17,18-19,20-21"), RangeOutcome::TooManyRanges);
+}
+
+#[test]
+fn test_select_part() {
+    fn outcome(query: &str) -> RangeOutcome {
+        let req = Request::builder()
+            .uri(format!("/test.zip?{}", query))
+            .header(header::RANGE, "bytes=0-9")
+            .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+        select_range(&Config::default(), &req, "ETAG", None, 3 * 1024 * 1024 + 5)
+    }
+
+    assert_eq!(parse_part_size("100"), Some(100));
+    assert_eq!(parse_part_size("64MiB"), Some(64 << 20));
+    assert_eq!(parse_part_size("2GiB"), Some(2 << 30));
+    assert_eq!(parse_part_size("0KiB"), None);
+    assert_eq!(parse_part_size("64MB"), None);
+
+    assert_eq!(outcome("part=1&part-size=1MiB"), RangeOutcome::Satisfiable(Range { start: 0, end: 1 << 20 }));
+    assert_eq!(outcome("part-size=1MiB&part=3"), RangeOutcome::Satisfiable(Range { start: 2 << 20, end: 3 << 20 }));
+    assert_eq!(outcome("part=4&part-size=1MiB"), RangeOutcome::Satisfiable(Range { start: 3 << 20, end: (3 << 20) + 5 }));
+    assert_eq!(outcome("part=5&part-size=1MiB"), RangeOutcome::PartOutOfRange);
+    assert_eq!(outcome(&format!("part={}&part-size=1GiB", u64::MAX)), RangeOutcome::PartOutOfRange);
+    assert_eq!(outcome("part=0&part-size=1MiB"), RangeOutcome::InvalidPart);
+    assert_eq!(outcome("part=1"), RangeOutcome::InvalidPart);
+    assert_eq!(outcome("part=1&part-size=big"), RangeOutcome::InvalidPart);
+
+    // Without `part`, the Range header is used
+    assert_eq!(outcome("part-size=1MiB"), RangeOutcome::Satisfiable(Range { start: 0, end: 10 }));
+    assert_eq!(outcome("department=1"), RangeOutcome::Satisfiable(Range { start: 0, end: 10 }));
+}
+
+/// Limit on the total number of bytes streamed over one client connection.
+///
+/// One instance is shared by all requests on a connection by inserting it into
+/// the request extensions. Once the limit is exceeded, response bodies on that
+/// connection fail, which causes hyper to close the connection.
+#[derive(Clone, Debug)]
+pub struct ConnectionBudget {
+    used: Arc<AtomicU64>,
+    limit: u64,
+}
+
+impl ConnectionBudget {
+    pub fn new(limit: u64) -> Self {
+        ConnectionBudget { used: Arc::new(AtomicU64::new(0)), limit }
+    }
+
+    /// Total bytes streamed on the connection so far
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Record `len` bytes streamed, returning false if this exceeds the limit
+    fn consume(&self, len: u64) -> bool {
+        self.used.fetch_add(len, Ordering::Relaxed) + len <= self.limit
+    }
+}
+
+/// Body of responses produced by `hyper_response`
+pub type ResponseBody = StreamBody<stream::Map<BoxBytesStream, fn(Result<Bytes, BoxError>) -> Result<Frame<Bytes>, BoxError>>>;
+
+fn response_body(stream: BoxBytesStream) -> ResponseBody {
+    StreamBody::new(stream.map(|chunk| chunk.map(Frame::data)))
+}
+
+/// An empty body, for responses such as 304 Not Modified that have none
+pub(crate) fn empty_body() -> ResponseBody {
+    response_body(Box::pin(stream::empty()))
+}
+
+/// A response with a body that is already in memory
+pub(crate) fn bytes_response(status: StatusCode, content_type: &str, body: Bytes) -> Response<ResponseBody> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(response_body(Box::pin(stream::once(future::ok(body)))))
+        .unwrap()
+}
+
+/// A response with a short plain text message, for errors that are detected
+/// before starting to stream the data
+fn message_response(status: StatusCode, msg: &'static str) -> Response<ResponseBody> {
+    bytes_response(status, "text/plain", Bytes::from_static(msg.as_bytes()))
+}
+
 /// Serve a `StreamRange` in response to a `hyper` request.
 /// This handles the HTTP Range header and "206 Partial content" and associated headers if required
-pub fn hyper_response(req: &Request<impl Body>, content_type: &str, etag: &str, filename: &str, data: &dyn StreamRange) -> Response<impl Body<Data=Bytes, Error=BoxError>> {
-    let full_len = data.len();
+///
+/// `last_modified`, if known, is sent as the Last-Modified header and allows
+/// If-Range to be given as a date.
+pub fn hyper_response(config: &Config, req: &Request<impl Body>, content_type: &str, etag: &str, last_modified: Option<DateTime<Utc>>, filename: &str, data: &dyn StreamRange) -> Response<ResponseBody> {
+    match prepare_response(config, req, content_type, Some(etag), last_modified, filename, data.len()) {
+        Ok(prepared) => {
+            let stream = prepared.stream(data);
+            prepared.finish(stream)
+        }
+        Err(res) => *res,
+    }
+}
+
+/// Number of bytes at the start of the body that `finish_checked` holds back
+const FIRST_DATA_CHECK_LEN: usize = 64 * 1024;
+
+/// A response to a request for a `StreamRange` that is to be sent, with the
+/// ranges of the data its body will contain
+pub(crate) struct PreparedResponse {
+    builder: hyper::http::response::Builder,
+    status: StatusCode,
+    ranges: BodyRanges,
+    body_len: u64,
+    /// Response to a HEAD request, sent with the headers of a GET but no body
+    head: bool,
+    rate_limit: Option<u64>,
+    budget: Option<ConnectionBudget>,
+    access_log: Option<AccessLogRequest>,
+    request_id: Option<String>,
+}
+
+/// The ranges of the data in the body of a `PreparedResponse`
+enum BodyRanges {
+    /// One range, which is the whole body
+    Single(Range),
+
+    /// Several ranges, each sent as a part of a multipart/byteranges body
+    /// after its part headers, followed by the closing boundary
+    Multipart { ranges: Vec<Range>, part_headers: Vec<Bytes>, closing: Bytes },
+}
+
+impl PreparedResponse {
+    /// Whether the response has a body, which is false for HEAD requests
+    pub(crate) fn has_body(&self) -> bool {
+        !self.head
+    }
+
+    /// The body of the response, made of the ranges of `data`. Nothing is
+    /// read from `data` for a HEAD request.
+    pub(crate) fn stream(&self, data: &dyn StreamRange) -> BoxBytesStream {
+        if self.head {
+            return Box::pin(stream::empty());
+        }
+
+        match &self.ranges {
+            BodyRanges::Single(range) => data.stream_range(*range),
+            BodyRanges::Multipart { ranges, part_headers, closing } => {
+                let bytes = |b: &Bytes| -> BoxBytesStream { Box::pin(stream::once(future::ok(b.clone()))) };
+                let mut parts: Vec<BoxBytesStream> = Vec::with_capacity(ranges.len() * 2 + 1);
+                for (range, header) in ranges.iter().zip(part_headers) {
+                    parts.push(bytes(header));
+                    parts.push(data.stream_range(*range));
+                }
+                parts.push(bytes(closing));
+                Box::pin(stream::iter(parts).flatten())
+            }
+        }
+    }
+
+    /// Complete the response with a body of `stream`, which is the `stream` of the data
+    pub(crate) fn finish(self, mut stream: BoxBytesStream) -> Response<ResponseBody> {
+        if let Some(rate) = self.rate_limit {
+            stream = Box::pin(RateLimited::new(stream, rate));
+        }
+        let status = self.status;
+        let access_log = self.access_log.filter(|entry| entry.claim()).map(|entry| (entry, status));
+
+        if self.head {
+            if let Some((entry, status)) = access_log {
+                entry.log(status, Some(0));
+            }
+            return self.builder.body(empty_body()).unwrap();
+        }
+        let stream = StreamMonitor::new(stream, self.body_len, self.budget, access_log, self.request_id);
+
+        self.builder.body(response_body(Box::pin(stream))).unwrap()
+    }
+
+    /// Like `finish`, but doesn't return until the first 64 KiB of `stream`,
+    /// its end, or an error has arrived. `on_error` may replace the response
+    /// if that error occurs, since the status can still be changed then;
+    /// later errors, or those it returns `None` for, truncate the body as usual.
+    pub(crate) async fn finish_checked(self, stream: BoxBytesStream, on_error: impl FnOnce(&BoxError) -> Option<Response<ResponseBody>>) -> Response<ResponseBody> {
+        let mut stream = stream.fuse();
+        let mut held = Vec::new();
+        let mut held_len = 0;
+
+        while held_len < FIRST_DATA_CHECK_LEN {
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    held_len += chunk.len();
+                    held.push(Ok(chunk));
+                }
+                Some(Err(err)) => {
+                    if let Some(res) = on_error(&err) {
+                        return res;
+                    }
+                    held.push(Err(err));
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        self.finish(Box::pin(stream::iter(held).chain(stream)))
+    }
+}
+
+/// Bytes percent-encoded in an RFC 5987 `filename*` parameter: all but the
+/// `attr-char`s
+const FILENAME_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!').remove(b'#').remove(b'$').remove(b'&').remove(b'+').remove(b'-')
+    .remove(b'.').remove(b'^').remove(b'_').remove(b'`').remove(b'|').remove(b'~');
+
+/// `Content-Disposition` value for an attachment named `filename`, without
+/// its control characters, which could end the header. A name with other
+/// than ASCII characters, or with a quote or backslash, is sent in an
+/// RFC 6266 `filename*` parameter, with those characters replaced by `_` in
+/// `filename` for clients that don't support it.
+fn attachment_disposition(filename: &str) -> String {
+    let filename: String = filename.chars().filter(|c| !c.is_control()).collect();
+    let fallback: String = filename.chars().map(|c| if c.is_ascii() && c != '"' && c != '\\' { c } else { '_' }).collect();
+
+    if fallback == filename {
+        format!("attachment; filename=\"{}\"", filename)
+    } else {
+        format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, utf8_percent_encode(&filename, FILENAME_ENCODE_SET))
+    }
+}
+
+#[test]
+fn test_attachment_disposition() {
+    assert_eq!(attachment_disposition("foo.zip"), "attachment; filename=\"foo.zip\"");
+    assert_eq!(attachment_disposition("réport 2024.zip"), "attachment; filename=\"r_port 2024.zip\"; filename*=UTF-8''r%C3%A9port%202024.zip");
+    assert_eq!(attachment_disposition("日本.zip"), "attachment; filename=\"__.zip\"; filename*=UTF-8''%E6%97%A5%E6%9C%AC.zip");
+    assert_eq!(
+        attachment_disposition("a\"b\\c\r\nSet-Cookie: x.zip"),
+        "attachment; filename=\"a_b_cSet-Cookie: x.zip\"; filename*=UTF-8''a%22b%5CcSet-Cookie%3A%20x.zip"
+    );
+    assert_eq!(attachment_disposition("a\x7fb.zip"), "attachment; filename=\"ab.zip\"");
+}
+
+/// Choose the status, headers and range of the response to a request for
+/// `full_len` bytes, or return the error response to send instead.
+///
+/// Content without an `etag` differs between requests, so it is always sent
+/// in full, with `Accept-Ranges: none`.
+pub(crate) fn prepare_response(config: &Config, req: &Request<impl Body>, content_type: &str, etag: Option<&str>, last_modified: Option<DateTime<Utc>>, filename: &str, full_len: u64) -> Result<PreparedResponse, Box<Response<ResponseBody>>> {
     let full_range = Range { start: 0, end: full_len };
 
-    let range = req.headers().get(hyper::header::RANGE)
-        .filter(|_| req.headers().get(hyper::header::IF_RANGE).map_or(true, |val| val == etag))
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| parse_range(v, full_len).ok())
-        .and_then(|x| x);
+    // Content without an ETag only matches `*`
+    let if_match_fails = |val: &HeaderValue| match etag {
+        Some(etag) => !if_match_matches(val, etag),
+        None => val != "*",
+    };
+    if req.headers().get(header::IF_MATCH).is_some_and(if_match_fails) {
+        info!("If-Match does not match current ETag");
+        return Err(Box::new(message_response(StatusCode::PRECONDITION_FAILED, "Precondition failed")));
+    }
+
+    let outcome = match etag {
+        Some(etag) => select_range(config, req, etag, last_modified, full_len),
+        None if req.headers().contains_key(header::RANGE) || select_part(req, full_len).is_some() => RangeOutcome::Unrepeatable,
+        None => RangeOutcome::Full,
+    };
+
+    let ranges = match outcome {
+        RangeOutcome::Satisfiable(range) => Some(vec![range]),
+        RangeOutcome::Multiple(ranges) => Some(ranges),
+        RangeOutcome::Full => None,
+        RangeOutcome::TooManyRanges => {
+            info!("Rejecting request with more than {} ranges", config.max_ranges_per_request);
+            return Err(Box::new(message_response(StatusCode::BAD_REQUEST, "Too many ranges")));
+        }
+        RangeOutcome::InvalidPart => {
+            info!("Rejecting request with invalid part parameters");
+            return Err(Box::new(message_response(StatusCode::BAD_REQUEST, "Invalid part: expected part=N, numbered from 1, and part-size=BYTES, optionally with a KiB, MiB or GiB suffix")));
+        }
+        RangeOutcome::PartOutOfRange => {
+            info!("Rejecting request for a part after the last");
+            let mut res = message_response(StatusCode::RANGE_NOT_SATISFIABLE, "Part out of range");
+            res.headers_mut().insert(header::CONTENT_RANGE, HeaderValue::from_str(&format!("bytes */{}", full_len)).unwrap());
+            return Err(Box::new(res));
+        }
+        outcome => {
+            info!(zipstream.range_ignored = outcome.reason(), "Ignoring Range header, serving full content");
+            None
+        }
+    };
+
+    // Content without an ETag can't be requested in parts, so it is exempt,
+    // or archives with streamed CRCs or encryption could never be downloaded.
+    // HEAD sends no content, and is how clients learn the length to page by.
+    if config.require_range && etag.is_some() && req.method() != Method::HEAD && ranges.as_ref().is_none_or(|ranges| ranges.iter().map(|range| range.limit_end(full_len).len()).sum::<u64>() == full_len) {
+        info!("Rejecting request for the full content");
+        return Err(Box::new(message_response(StatusCode::BAD_REQUEST, "Range header required. Request the content in parts, such as with Range: bytes=0-1048575; the total length is in the Content-Range of the response.")));
+    }
 
     let mut res = Response::builder()
-        .header(header::CONTENT_TYPE, content_type)
-        .header(header::ACCEPT_RANGES, "bytes")
-        .header(header::ETAG, etag)
-        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename));
+        .header(header::ACCEPT_RANGES, if etag.is_some() { "bytes" } else { "none" });
+
+    if let Some(etag) = etag {
+        res = res.header(header::ETAG, etag);
+    }
+
+    if let Some(last_modified) = last_modified {
+        res = res.header(header::LAST_MODIFIED, last_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+    }
+
+    match config.content_disposition {
+        ContentDisposition::Filename => res = res.header(header::CONTENT_DISPOSITION, attachment_disposition(filename)),
+        ContentDisposition::Attachment => res = res.header(header::CONTENT_DISPOSITION, "attachment"),
+        ContentDisposition::Omit => {}
+    }
+
+    let status = if ranges.is_some() { StatusCode::PARTIAL_CONTENT } else { StatusCode::OK };
+    res = res.status(status);
+
+    let ranges = match ranges.as_deref() {
+        None => {
+            res = res.header(header::CONTENT_TYPE, content_type);
+            BodyRanges::Single(full_range)
+        }
+        Some(&[range]) => {
+            res = res.header(header::CONTENT_TYPE, content_type)
+                     .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, full_len));
+            info!("Serving range {:?}", range);
+            BodyRanges::Single(range.limit_end(full_len))
+        }
+        Some(ranges) => {
+            let boundary = uuid::Uuid::now_v7().simple().to_string();
+            res = res.header(header::CONTENT_TYPE, format!("multipart/byteranges; boundary={}", boundary));
+            info!("Serving {} ranges {:?}", ranges.len(), ranges);
+
+            let part_headers = ranges.iter().map(|range| Bytes::from(format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                boundary, content_type, range.start, range.end - 1, full_len
+            ))).collect();
+            let closing = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+            BodyRanges::Multipart { ranges: ranges.to_vec(), part_headers, closing }
+        }
+    };
+
+    let body_len = match &ranges {
+        BodyRanges::Single(range) => range.len(),
+        BodyRanges::Multipart { ranges, part_headers, closing } => {
+            ranges.iter().map(Range::len).sum::<u64>() + part_headers.iter().map(|h| h.len() as u64).sum::<u64>() + closing.len() as u64
+        }
+    };
+
+    res = res.header(header::CONTENT_LENGTH, body_len);
+
+    Ok(PreparedResponse {
+        builder: res,
+        status,
+        ranges,
+        body_len,
+        head: req.method() == Method::HEAD,
+        rate_limit: config.max_bytes_per_second_per_download,
+        budget: req.extensions().get::<ConnectionBudget>().cloned(),
+        access_log: req.extensions().get::<AccessLogRequest>().cloned(),
+        request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+    })
+}
+
+/// Paces a `BoxBytesStream` to at most `rate` bytes per second with a token
+/// bucket.
+///
+/// Chunks from the inner stream are split into pieces of a tenth of a second's
+/// worth of data, and each piece waits for the tokens it spends, so a capped
+/// download keeps making steady progress instead of stalling long enough for
+/// the client or a proxy to time out.
+struct RateLimited {
+    stream: BoxBytesStream,
+    rate: u64,
+    pending: Bytes,
+    tokens: f64,
+    last_refill: Instant,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl RateLimited {
+    fn new(stream: BoxBytesStream, rate: u64) -> Self {
+        RateLimited {
+            stream,
+            rate: rate.max(1),
+            pending: Bytes::new(),
+            tokens: 0.0,
+            last_refill: Instant::now(),
+            sleep: None,
+        }
+    }
 
-    if let Some(range) = range {
-        res = res.status(StatusCode::PARTIAL_CONTENT)
-                 .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, full_len));
-        info!("Serving range {:?}", range);
+    fn piece_len(&self) -> u64 {
+        (self.rate / 10).max(1)
     }
+}
+
+impl Stream for RateLimited {
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
 
-    let range = range.unwrap_or(full_range).limit_end(full_len);
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                futures::ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
 
-    res = res.header(header::CONTENT_LENGTH, range.len());
+            let now = Instant::now();
+            let refill = now.duration_since(this.last_refill).as_secs_f64() * this.rate as f64;
+            this.tokens = (this.tokens + refill).min(this.piece_len() as f64);
+            this.last_refill = now;
 
-    let stream = StreamMonitor::new(data.stream_range(range), range.len());
+            if this.tokens < 0.0 {
+                let wait = Duration::from_secs_f64(-this.tokens / this.rate as f64);
+                this.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+                continue;
+            }
+
+            if this.pending.is_empty() {
+                match futures::ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                    Some(Ok(bytes)) if bytes.is_empty() => continue,
+                    Some(Ok(bytes)) => this.pending = bytes,
+                    other => return Poll::Ready(other),
+                }
+            }
 
-    res.body(StreamBody::new(stream.map(|chunk| chunk.map(Frame::data)))).unwrap()
+            let len = this.pending.len().min(this.piece_len() as usize);
+            this.tokens -= len as f64;
+            return Poll::Ready(Some(Ok(this.pending.split_to(len))));
+        }
+    }
 }
 
 /// Wraps a `BoxByteStream` with `tracing` instrumentation. The data is passed
 /// through unchanged.
 /// 
 /// * Tracks the progress of the download, and on Drop logs whether the download
-/// was completed or cancelled. Hyper drops the body stream after the specified
-/// content-length is reached, so we do not see the `Stream::poll_next` return
-/// `None` when the stream signals its own end. Ideally, Hyper would offer a
-/// better way to follow the status of a download after the `Body` is passed to
-/// Hyper: https://github.com/hyperium/hyper/issues/2181
+///   was completed or cancelled. Hyper drops the body stream after the specified
+///   content-length is reached, so we do not see the `Stream::poll_next` return
+///   `None` when the stream signals its own end. Ideally, Hyper would offer a
+///   better way to follow the status of a download after the `Body` is passed to
+///   Hyper: https://github.com/hyperium/hyper/issues/2181
 /// 
 /// * Stores a `tracing::Span` and enters it when polling the Stream, like
-/// `Instrument`. The `Instrument` in `tracing` does not impl `Stream`. The one
-/// in `tracing-futures` does, but it hasn't been released recently, and the
-/// released version 0.2.5 does not include the change to enter the `Span` on
-/// drop, which we need here for the logging in the `Drop` impl.
+///   `Instrument`. The `Instrument` in `tracing` does not impl `Stream`. The one
+///   in `tracing-futures` does, but it hasn't been released recently, and the
+///   released version 0.2.5 does not include the change to enter the `Span` on
+///   drop, which we need here for the logging in the `Drop` impl.
 /// 
 /// * Logs any errors returned from the stream, which could be done with
-/// `TryStreamExt::instrument_err`, but this is already intercepting `poll_next`
-/// so it's simple to do there.
+///   `TryStreamExt::instrument_err`, but this is already intercepting `poll_next`
+///   so it's simple to do there.
+/// 
+/// * Records which zip entry failed, if the error is an `EntryError`, so the
+///   final log of a truncated archive identifies the missing entry. The
+///   response has a Content-Length, so it can't carry a trailer reporting the
+///   failure to the client; hyper closes the connection instead.
+/// 
+/// * Charges the bytes to the connection's `ConnectionBudget`, if any, and
+///   fails the stream once it is exhausted.
+///
+/// * Logs the request's access log line on Drop, if enabled, with the number
+///   of bytes sent.
+///
+/// * Reports its progress in the registry read by `download_progress` while
+///   the request has a `RequestId`.
 struct StreamMonitor {
     stream: BoxBytesStream,
+    budget: Option<ConnectionBudget>,
+    access_log: Option<(AccessLogRequest, StatusCode)>,
+    registered: Option<(String, Arc<RegisteredDownload>)>,
     span: Span,
     pos: u64,
     len: u64,
     start_time: Instant,
     errored: bool,
+    failed_entry: Option<String>,
 }
 
 static ACTIVE_DOWNLOADS: AtomicU32 = AtomicU32::new(0);
@@ -144,10 +772,49 @@ pub fn active_downloads() -> u32 {
     ACTIVE_DOWNLOADS.load(Ordering::Relaxed)
 }
 
+static BYTES_SERVED: AtomicU64 = AtomicU64::new(0);
+
+/// Total bytes of downloads sent since startup, including those of downloads
+/// that were canceled or failed partway
+pub fn bytes_served() -> u64 {
+    BYTES_SERVED.load(Ordering::Relaxed)
+}
+
+/// Entry in `DOWNLOADS`, updated by the download's `StreamMonitor`
+struct RegisteredDownload {
+    len: u64,
+    sent: AtomicU64,
+}
+
+/// In-flight downloads by request id
+static DOWNLOADS: Mutex<BTreeMap<String, Arc<RegisteredDownload>>> = Mutex::new(BTreeMap::new());
+
+/// Progress of an in-flight download
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DownloadProgress {
+    pub bytes_sent: u64,
+    pub length: u64,
+}
+
+/// Progress of the download for the request with this `RequestId`, or `None`
+/// if it has finished or never existed
+pub fn download_progress(request_id: &str) -> Option<DownloadProgress> {
+    DOWNLOADS.lock().unwrap().get(request_id).map(|download| DownloadProgress {
+        bytes_sent: download.sent.load(Ordering::Relaxed),
+        length: download.len,
+    })
+}
+
 impl StreamMonitor {
-    fn new(stream: BoxBytesStream, len: u64) -> Self {
+    fn new(stream: BoxBytesStream, len: u64, budget: Option<ConnectionBudget>, access_log: Option<(AccessLogRequest, StatusCode)>, request_id: Option<String>) -> Self {
         let active = ACTIVE_DOWNLOADS.fetch_add(1, Ordering::Relaxed) + 1;
 
+        let registered = request_id.map(|id| {
+            let download = Arc::new(RegisteredDownload { len, sent: AtomicU64::new(0) });
+            DOWNLOADS.lock().unwrap().insert(id.clone(), download.clone());
+            (id, download)
+        });
+
         info!(
             http.response.body.bytes = len,
             zipstream.active_downloads = active,
@@ -156,9 +823,13 @@ impl StreamMonitor {
 
         Self {
             stream,
+            budget,
+            access_log,
+            registered,
             len,
-            span: Span::current(),
+            span: info_span!("stream", first_byte_ms = tracing::field::Empty),
             errored: false,
+            failed_entry: None,
             pos: 0,
             start_time: Instant::now(),
         }
@@ -171,12 +842,26 @@ impl Stream for StreamMonitor {
     fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
         let _entered = this.span.enter();
-        let r = Pin::new(&mut this.stream).poll_next(cx);
+        let mut r = Pin::new(&mut this.stream).poll_next(cx);
+
+        if let (Poll::Ready(Some(Ok(bytes))), Some(budget)) = (&r, &this.budget) {
+            if !budget.consume(bytes.len() as u64) {
+                r = Poll::Ready(Some(Err(format!("Connection exceeded limit of {} bytes", budget.limit).into())));
+            }
+        }
 
         match &r {
             Poll::Pending => {},
             Poll::Ready(Some(Ok(bytes))) => {
+                if this.pos == 0 && !bytes.is_empty() {
+                    this.span.record("first_byte_ms", this.start_time.elapsed().as_secs_f64() * 1000.0);
+                }
                 this.pos += bytes.len() as u64;
+                BYTES_SERVED.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+
+                if let Some((_, download)) = &this.registered {
+                    download.sent.store(this.pos, Ordering::Relaxed);
+                }
             }
             Poll::Ready(Some(Err(err))) => {
                 error!(
@@ -185,6 +870,10 @@ impl Stream for StreamMonitor {
                     "Response stream error: {}", Report(&**err as &(dyn Error + 'static))
                 );
                 this.errored = true;
+
+                if let Some(entry_error) = err.downcast_ref::<EntryError>() {
+                    this.failed_entry = Some(entry_error.archive_path.clone());
+                }
             }
             Poll::Ready(None) => {}
         }
@@ -199,6 +888,13 @@ impl Drop for StreamMonitor {
 
         let active = ACTIVE_DOWNLOADS.fetch_sub(1, Ordering::Relaxed) - 1;
 
+        if let Some((id, download)) = &self.registered {
+            let mut downloads = DOWNLOADS.lock().unwrap();
+            if downloads.get(id).is_some_and(|d| Arc::ptr_eq(d, download)) {
+                downloads.remove(id);
+            }
+        }
+
         let status = if self.pos >= self.len {
             "complete"
         } else if self.errored {
@@ -207,14 +903,21 @@ impl Drop for StreamMonitor {
             "canceled"
         };
 
+        let elapsed = self.start_time.elapsed().as_secs_f64();
         info!(
             http.response.body.bytes = self.len,
             http.response.body.progress = self.pos,
             zipstream.active_downloads = active,
             zipstream.result = status,
-            time = self.start_time.elapsed().as_secs_f64() * 1000.0,
+            zipstream.failed_entry = self.failed_entry.as_deref(),
+            zipstream.bytes_per_second = (elapsed > 0.0).then(|| self.pos as f64 / elapsed),
+            time = elapsed * 1000.0,
             "Download {}", status
         );
+
+        if let Some((entry, status)) = &self.access_log {
+            entry.log(*status, Some(self.pos));
+        }
     }
 }
 
@@ -226,14 +929,35 @@ async fn test_base_hyper_response() {
 
     let data = Bytes::from_static(b"0123456789");
 
-    let res = hyper_response(&req, "application/test", "ETAG", "foo.zip", &data);
+    let res = hyper_response(&Config::default(), &req, "application/test", "ETAG", None, "foo.zip", &data);
 
     assert_eq!(res.status(), StatusCode::OK);
     assert_eq!(res.headers().get(header::CONTENT_TYPE), Some(&header::HeaderValue::from_static("application/test")));
     assert_eq!(res.headers().get(header::CONTENT_DISPOSITION), Some(&header::HeaderValue::from_static("attachment; filename=\"foo.zip\"")));
     assert_eq!(res.headers().get(header::ETAG), Some(&header::HeaderValue::from_static("ETAG")));
     assert_eq!(res.headers().get(header::CONTENT_LENGTH), Some(&header::HeaderValue::from_static("10")));
-    assert_eq!(res.into_body().collect().await.unwrap().to_bytes().as_ref(), b"0123456789");
+    assert_eq!(BodyExt::collect(res.into_body()).await.unwrap().to_bytes().as_ref(), b"0123456789");
+}
+
+#[test]
+fn test_content_disposition_hyper_response() {
+    let req = Request::builder()
+        .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+
+    let data = Bytes::from_static(b"0123456789");
+
+    let disposition = |content_disposition| {
+        let config = Config { content_disposition, ..Config::default() };
+        let res = hyper_response(&config, &req, "application/test", "ETAG", None, "foo.zip", &data);
+        res.headers().get(header::CONTENT_DISPOSITION).cloned()
+    };
+
+    assert_eq!(disposition(ContentDisposition::Filename), Some(header::HeaderValue::from_static("attachment; filename=\"foo.zip\"")));
+    assert_eq!(disposition(ContentDisposition::Attachment), Some(header::HeaderValue::from_static("attachment")));
+    assert_eq!(disposition(ContentDisposition::Omit), None);
+
+    let res = hyper_response(&Config::default(), &req, "application/test", "ETAG", None, "réport 2024.zip", &data);
+    assert_eq!(res.headers()[header::CONTENT_DISPOSITION], "attachment; filename=\"r_port 2024.zip\"; filename*=UTF-8''r%C3%A9port%202024.zip");
 }
 
 #[tokio::test]
@@ -247,14 +971,14 @@ async fn test_range_hyper_response() {
 
     let data = Bytes::from_static(b"0123456789");
 
-    let res = hyper_response(&req, "application/test", "ETAG", "foo.zip", &data);
+    let res = hyper_response(&Config::default(), &req, "application/test", "ETAG", None, "foo.zip", &data);
 
     assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
     assert_eq!(res.headers().get(header::CONTENT_TYPE), Some(&header::HeaderValue::from_static("application/test")));
     assert_eq!(res.headers().get(header::ETAG), Some(&header::HeaderValue::from_static("ETAG")));
     assert_eq!(res.headers().get(header::CONTENT_LENGTH), Some(&header::HeaderValue::from_static("5")));
     assert_eq!(res.headers().get(header::CONTENT_RANGE), Some(&header::HeaderValue::from_static("bytes 4-8/10")));
-    assert_eq!(res.into_body().collect().await.unwrap().to_bytes().as_ref(), b"45678");
+    assert_eq!(BodyExt::collect(res.into_body()).await.unwrap().to_bytes().as_ref(), b"45678");
 }
 
 #[tokio::test]
@@ -268,10 +992,205 @@ async fn test_bad_if_range_hyper_response() {
 
     let data = Bytes::from_static(b"0123456789");
 
-    let res = hyper_response(&req, "application/test", "ETAG", "foo.zip", &data);
+    let res = hyper_response(&Config::default(), &req, "application/test", "ETAG", None, "foo.zip", &data);
 
     assert_eq!(res.status(), StatusCode::OK);
     assert_eq!(res.headers().get(header::CONTENT_LENGTH), Some(&header::HeaderValue::from_static("10")));
     assert_eq!(res.headers().get(header::CONTENT_RANGE), None);
-    assert_eq!(res.into_body().collect().await.unwrap().to_bytes().as_ref(), b"0123456789");
+    assert_eq!(BodyExt::collect(res.into_body()).await.unwrap().to_bytes().as_ref(), b"0123456789");
+}
+
+#[tokio::test]
+async fn test_if_range_date_hyper_response() {
+    use http_body_util::BodyExt;
+
+    let data = Bytes::from_static(b"0123456789");
+    let last_modified = "2024-05-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+    let response = |if_range: &'static str| {
+        let req = Request::builder()
+            .header(header::RANGE, "bytes=4-8")
+            .header(header::IF_RANGE, if_range)
+            .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+        hyper_response(&Config::default(), &req, "application/test", "ETAG", Some(last_modified), "foo.zip", &data)
+    };
+
+    let res = response("Thu, 02 May 2024 08:00:00 GMT");
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers().get(header::LAST_MODIFIED), Some(&header::HeaderValue::from_static("Wed, 01 May 2024 12:00:00 GMT")));
+    assert_eq!(BodyExt::collect(res.into_body()).await.unwrap().to_bytes().as_ref(), b"45678");
+
+    assert_eq!(response("Wed, 01 May 2024 12:00:00 GMT").status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response("Tue, 30 Apr 2024 12:00:00 GMT").status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_access_log_hyper_response() {
+    use http_body_util::BodyExt;
+
+    let (_guard, logs) = crate::test_util::capture_logs();
+
+    let mut req = Request::builder()
+        .uri("/foo.zip")
+        .header(header::RANGE, "bytes=4-8")
+        .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+    let entry = AccessLogRequest::new(&req, "192.0.2.1".parse().unwrap());
+    req.extensions_mut().insert(entry.clone());
+
+    let data = Bytes::from_static(b"0123456789");
+    let res = hyper_response(&Config::default(), &req, "application/test", "ETAG", None, "foo.zip", &data);
+    assert!(entry.is_claimed());
+    BodyExt::collect(res.into_body()).await.unwrap();
+
+    let logs = logs.contents();
+    assert!(logs.contains(r#"\"GET /foo.zip HTTP/1.1\" 206 5 \"-\" \"-\""#), "{}", logs);
+}
+
+#[tokio::test]
+async fn test_connection_budget() {
+    use http_body_util::BodyExt;
+
+    let budget = ConnectionBudget::new(15);
+    let data = Bytes::from_static(b"0123456789");
+
+    let mut req = Request::builder()
+        .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+    req.extensions_mut().insert(budget.clone());
+
+    let res = hyper_response(&Config::default(), &req, "application/test", "ETAG", None, "foo.zip", &data);
+    assert_eq!(BodyExt::collect(res.into_body()).await.unwrap().to_bytes().as_ref(), b"0123456789");
+    assert_eq!(budget.used(), 10);
+
+    let res = hyper_response(&Config::default(), &req, "application/test", "ETAG", None, "foo.zip", &data);
+    assert!(BodyExt::collect(res.into_body()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_rate_limited_hyper_response() {
+    use http_body_util::BodyExt;
+
+    let config = Config { max_bytes_per_second_per_download: Some(10_000), ..Default::default() };
+    let data = Bytes::from(vec![b'x'; 5_000]);
+
+    let req = Request::builder()
+        .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+
+    // 1000 byte pieces, each after the first waiting 0.1s for its tokens
+    let start = Instant::now();
+    let res = hyper_response(&config, &req, "application/test", "ETAG", None, "foo.zip", &data);
+    let mut body = res.into_body();
+    let mut pieces = 0;
+    while let Some(frame) = body.frame().await {
+        assert_eq!(frame.unwrap().into_data().unwrap().len(), 1_000);
+        pieces += 1;
+    }
+    assert_eq!(pieces, 5);
+    assert!(start.elapsed() >= Duration::from_millis(390), "{:?}", start.elapsed());
+}
+
+#[test]
+fn test_if_match_hyper_response() {
+    let data = Bytes::from_static(b"0123456789");
+    let status = |if_match: &str| {
+        let req = Request::builder()
+            .header(header::IF_MATCH, if_match)
+            .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+        hyper_response(&Config::default(), &req, "application/test", "ETAG", None, "foo.zip", &data).status()
+    };
+
+    assert_eq!(status("ETAG"), StatusCode::OK);
+    assert_eq!(status("\"ETAG\""), StatusCode::OK);
+    assert_eq!(status("\"OTHER\", \"ETAG\""), StatusCode::OK);
+    assert_eq!(status("*"), StatusCode::OK);
+    assert_eq!(status("OTHER"), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(status("W/\"ETAG\""), StatusCode::PRECONDITION_FAILED);
+}
+
+#[test]
+fn test_require_range_hyper_response() {
+    let config = Config { require_range: true, ..Default::default() };
+    let data = Bytes::from_static(b"0123456789");
+    let request = |range: Option<&str>| {
+        let mut req = Request::builder();
+        if let Some(range) = range {
+            req = req.header(header::RANGE, range);
+        }
+        req.body(http_body_util::Empty::<Bytes>::new()).unwrap()
+    };
+
+    let res = hyper_response(&config, &request(None), "application/test", "ETAG", None, "foo.zip", &data);
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = hyper_response(&config, &request(Some("bytes=0-")), "application/test", "ETAG", None, "foo.zip", &data);
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = hyper_response(&config, &request(Some("bytes=0-4")), "application/test", "ETAG", None, "foo.zip", &data);
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 0-4/10");
+
+    let res = hyper_response(&Config::default(), &request(None), "application/test", "ETAG", None, "foo.zip", &data);
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let head = Request::head("/foo.zip").body(http_body_util::Empty::<Bytes>::new()).unwrap();
+    let res = hyper_response(&config, &head, "application/test", "ETAG", None, "foo.zip", &data);
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::CONTENT_LENGTH).unwrap(), "10");
+
+    // Content without an ETag can only be sent in full
+    for range in [None, Some("bytes=0-4")] {
+        let Ok(prepared) = prepare_response(&config, &request(range), "application/test", None, None, "foo.zip", 10) else {
+            panic!("expected content without an ETag to be served");
+        };
+        assert_eq!(prepared.status, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn test_too_many_ranges_hyper_response() {
+    let config = Config { max_ranges_per_request: 2, ..Default::default() };
+    let data = Bytes::from_static(b"0123456789");
+
+    let req = Request::builder()
+        .header(header::RANGE, "bytes=0-1,2-3,4-5")
+        .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+    let res = hyper_response(&config, &req, "application/test", "ETAG", None, "foo.zip", &data);
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let req = Request::builder()
+        .header(header::RANGE, "bytes=0-1,2-3")
+        .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+    let res = hyper_response(&config, &req, "application/test", "ETAG", None, "foo.zip", &data);
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+}
+
+#[tokio::test]
+async fn test_multiple_ranges_hyper_response() {
+    use http_body_util::BodyExt;
+    let data = Bytes::from_static(b"0123456789");
+    let request = |range: &str| Request::builder()
+        .header(header::RANGE, range)
+        .body(http_body_util::Empty::<Bytes>::new()).unwrap();
+
+    let res = hyper_response(&Config::default(), &request("bytes=0-1, 5-"), "application/test", "ETAG", None, "foo.zip", &data);
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert!(res.headers().get(header::CONTENT_RANGE).is_none());
+
+    let content_type = res.headers()[header::CONTENT_TYPE].to_str().unwrap().to_owned();
+    let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+    let content_length = res.headers()[header::CONTENT_LENGTH].to_str().unwrap().parse::<usize>().unwrap();
+
+    let body = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+    assert_eq!(body.len(), content_length);
+    assert_eq!(body, format!(
+        "\r\n--{b}\r\nContent-Type: application/test\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+         \r\n--{b}\r\nContent-Type: application/test\r\nContent-Range: bytes 5-9/10\r\n\r\n56789\
+         \r\n--{b}--\r\n", b = boundary));
+
+    // Overlapping and descending ranges fall back to the full content
+    for range in ["bytes=0-4,3-6", "bytes=5-6,0-1"] {
+        let res = hyper_response(&Config::default(), &request(range), "application/test", "ETAG", None, "foo.zip", &data);
+        assert_eq!(res.status(), StatusCode::OK, "{}", range);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/test");
+        assert_eq!(BodyExt::collect(res.into_body()).await.unwrap().to_bytes(), data);
+    }
 }
@@ -1,10 +1,16 @@
 // © 2019 3D Robotics. License: Apache-2.0
 use aws_sdk_s3 as s3;
 use s3::primitives::ByteStream;
-use std::{error::Error, fmt::Display, pin::Pin, task::{Context, Poll}};
-use futures::{ future::{self, lazy}, FutureExt, TryFutureExt, stream, Stream, StreamExt };
+use std::{collections::{BTreeMap, HashMap, VecDeque}, error::Error, fmt::Display, io::SeekFrom, path::PathBuf, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll, Waker}, time::Duration};
+use futures::{ future::{self, lazy}, FutureExt, TryFutureExt, TryStreamExt, stream, Stream, StreamExt };
 use bytes::Bytes;
-use tracing::{info, error};
+use http_body_util::{BodyExt, Empty};
+use hyper::{header, Request, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::{info, error, warn};
 
 pub type BoxBytesStream = Pin<Box<dyn Stream<Item = Result<Bytes, BoxError>> + Send +'static>>;
 pub type BoxError = Box<dyn std::error::Error + 'static + Sync + Send>;
@@ -27,6 +33,8 @@ impl Range {
 
     pub fn len(&self) -> u64 { self.end - self.start }
 
+    pub fn is_empty(&self) -> bool { self.start >= self.end }
+
     pub fn to_http_range_header(self) -> String {
         format!("bytes={}-{}", self.start, self.end-1)
     }
@@ -41,6 +49,9 @@ pub trait StreamRange {
     /// Total number of bytes
     fn len(&self) -> u64;
 
+    /// Whether there are no bytes
+    fn is_empty(&self) -> bool { self.len() == 0 }
+
     /// Create a stream that produces a range of the data
     fn stream_range(&self, range: Range) -> BoxBytesStream;
 }
@@ -52,20 +63,17 @@ impl StreamRange for Bytes {
     }
 }
 
-/// Implements `StreamRange` to serve an object from an S3 bucket
-pub struct S3Object {
-    pub client: s3::Client,
-    pub bucket: String,
-    pub key: String,
-    pub len: u64,
+/// A store that objects can be fetched from by bucket and key
+pub trait ObjectSource: Send + Sync {
+    /// Create a stream that produces a range of the object
+    fn get_range(&self, bucket: &str, key: &str, range: Range) -> BoxBytesStream;
 }
 
-impl StreamRange for S3Object {
-    fn len(&self) -> u64 { self.len }
-    fn stream_range(&self, range: Range) -> BoxBytesStream {
-        let client = self.client.clone();
-        let bucket = self.bucket.clone();
-        let key = self.key.clone();
+impl ObjectSource for s3::Client {
+    fn get_range(&self, bucket: &str, key: &str, range: Range) -> BoxBytesStream {
+        let client = self.clone();
+        let bucket = bucket.to_owned();
+        let key = key.to_owned();
 
         // The inner `Future` that makes the S3 request is large, so
         // lazily allocate it only when we begin streaming the specific file.
@@ -94,6 +102,412 @@ impl StreamRange for S3Object {
     }
 }
 
+/// Delay before the first retry of a failed read in `RetryingSource`, doubled for each further retry
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// An `ObjectSource` that retries transient failures of another source's
+/// reads, such as S3 throttling or 500 errors and connections that drop
+/// mid-body, with exponential backoff. A retry resumes with a request for
+/// the bytes after those already produced.
+///
+/// The S3 client may also retry a GetObject before it returns a response, so
+/// this is mostly for failures partway through the body.
+pub struct RetryingSource {
+    pub inner: Arc<dyn ObjectSource>,
+
+    /// Total reads of a range, including the first, before giving up
+    pub max_attempts: u32,
+}
+
+impl ObjectSource for RetryingSource {
+    fn get_range(&self, bucket: &str, key: &str, range: Range) -> BoxBytesStream {
+        let read = RetryingRead {
+            inner: self.inner.clone(),
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            stream: self.inner.get_range(bucket, key, range),
+            pos: range.start,
+            end: range.end,
+            retries: 0,
+            max_attempts: self.max_attempts,
+        };
+
+        Box::pin(stream::unfold(Some(read), |read| async move {
+            let mut read = read?;
+            loop {
+                match read.stream.next().await {
+                    Some(Ok(chunk)) => {
+                        read.pos += chunk.len() as u64;
+                        return Some((Ok(chunk), Some(read)));
+                    }
+                    Some(Err(e)) => {
+                        let url = format!("s3://{}/{}", read.bucket, read.key);
+                        if !retryable(&*e) || read.retries + 1 >= read.max_attempts {
+                            if read.retries > 0 {
+                                error!(retries = read.retries, "S3 GetObject for {} failed after {} retries", url, read.retries);
+                            }
+                            return Some((Err(e), None));
+                        }
+
+                        // Every byte was read, so there is nothing left to retry
+                        if read.pos >= read.end {
+                            warn!("S3 GetObject for {} failed after its last byte, ignoring: {}", url, e);
+                            return None;
+                        }
+
+                        read.retries += 1;
+                        let delay = RETRY_BASE_DELAY * 2u32.saturating_pow(read.retries - 1);
+                        warn!(retries = read.retries, "S3 GetObject for {} failed at byte {}, retrying in {:?}: {}", url, read.pos, delay, e);
+                        tokio::time::sleep(delay).await;
+
+                        read.stream = read.inner.get_range(&read.bucket, &read.key, Range { start: read.pos, end: read.end });
+                    }
+                    None => {
+                        if read.retries > 0 {
+                            info!(retries = read.retries, "S3 GetObject for s3://{}/{} succeeded after {} retries", read.bucket, read.key, read.retries);
+                        }
+                        return None;
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// State of a read by `RetryingSource`
+struct RetryingRead {
+    inner: Arc<dyn ObjectSource>,
+    bucket: String,
+    key: String,
+    stream: BoxBytesStream,
+    /// Position in the object of the next byte
+    pos: u64,
+    end: u64,
+    retries: u32,
+    max_attempts: u32,
+}
+
+/// Whether a read that failed with `err` may succeed if retried: S3 errors
+/// other than those for the request itself, such as 403 Forbidden and 404
+/// Not Found, and failures while reading the body
+fn retryable(err: &(dyn Error + 'static)) -> bool {
+    use s3::error::{ProvideErrorMetadata, SdkError};
+
+    let mut cause = Some(err);
+    while let Some(e) = cause {
+        if let Some(S3Error { inner, .. }) = e.downcast_ref::<S3Error<GetObjectError>>() {
+            return match inner {
+                SdkError::ConstructionFailure(_) => false,
+                SdkError::ServiceError(_) => {
+                    inner.code() == Some("SlowDown") || inner.raw_response().is_some_and(|res| res.status().is_server_error())
+                }
+                _ => true,
+            };
+        }
+        cause = e.source();
+    }
+    true
+}
+
+/// Implements `StreamRange` to serve `len` bytes starting at `offset` of an
+/// object from an S3 bucket
+pub struct S3Object {
+    pub source: Arc<dyn ObjectSource>,
+    pub bucket: String,
+    pub key: String,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl StreamRange for S3Object {
+    fn len(&self) -> u64 { self.len }
+    fn stream_range(&self, range: Range) -> BoxBytesStream {
+        let range = Range { start: self.offset + range.start, end: self.offset + range.end };
+        self.source.get_range(&self.bucket, &self.key, range)
+    }
+}
+
+/// An in-memory cache of the starts of small S3 objects, such as a license
+/// file included in many archives, shared by all requests. Once it holds more
+/// than `max_bytes`, the least recently used objects are dropped.
+///
+/// Objects are assumed not to change, since a cached copy is served until it
+/// is dropped.
+pub struct ObjectCache {
+    /// Total size of the cached bytes
+    pub max_bytes: u64,
+
+    /// Only reads ending within this many bytes of the start of an object are cached
+    pub max_object_bytes: u64,
+
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Cached bytes from the start of each object, and when they were last used
+    objects: HashMap<(String, String), (Bytes, u64)>,
+
+    /// Objects by when they were last used, least recent first
+    lru: BTreeMap<u64, (String, String)>,
+
+    /// Incremented on each use
+    clock: u64,
+
+    bytes: u64,
+}
+
+impl ObjectCache {
+    pub fn new(max_bytes: u64, max_object_bytes: u64) -> Arc<Self> {
+        Arc::new(ObjectCache { max_bytes, max_object_bytes, state: Mutex::default() })
+    }
+
+    /// The cached bytes of an object, if at least `len` are cached
+    fn get(&self, bucket: &str, key: &str, len: u64) -> Option<Bytes> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.clock += 1;
+
+        let (bytes, used) = state.objects.get_mut(&(bucket.to_owned(), key.to_owned()))?;
+        if (bytes.len() as u64) < len {
+            return None;
+        }
+
+        let id = state.lru.remove(used).unwrap();
+        *used = state.clock;
+        state.lru.insert(state.clock, id);
+        Some(bytes.clone())
+    }
+
+    /// Cache the first `bytes` of an object, replacing any shorter copy
+    fn insert(&self, bucket: &str, key: &str, bytes: Bytes) {
+        if bytes.len() as u64 > self.max_bytes {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let (clock, len) = (state.clock, bytes.len() as u64);
+
+        if let Some((old, used)) = state.objects.insert((bucket.to_owned(), key.to_owned()), (bytes, clock)) {
+            state.lru.remove(&used);
+            state.bytes -= old.len() as u64;
+        }
+        state.lru.insert(clock, (bucket.to_owned(), key.to_owned()));
+        state.bytes += len;
+
+        while state.bytes > self.max_bytes {
+            let (_, id) = state.lru.pop_first().unwrap();
+            let (evicted, _) = state.objects.remove(&id).unwrap();
+            state.bytes -= evicted.len() as u64;
+        }
+    }
+}
+
+/// An `ObjectSource` that serves reads of the start of small objects from an
+/// `ObjectCache`. A read the cache doesn't cover, but that ends within
+/// `ObjectCache::max_object_bytes`, is widened to start at the start of the
+/// object, so the whole of it is cached for later reads of any part. Other
+/// reads go to `inner` unchanged.
+pub struct CachingSource {
+    pub inner: Arc<dyn ObjectSource>,
+    pub cache: Arc<ObjectCache>,
+}
+
+impl ObjectSource for CachingSource {
+    fn get_range(&self, bucket: &str, key: &str, range: Range) -> BoxBytesStream {
+        let (inner, cache) = (self.inner.clone(), self.cache.clone());
+        let (bucket, key) = (bucket.to_owned(), key.to_owned());
+
+        // Deferred until polled, since the streams of all the parts of a
+        // response are created before any of them are streamed, and an
+        // earlier part may read the same object
+        Box::pin(stream::once(future::lazy(move |_| -> BoxBytesStream {
+            if let Some(bytes) = cache.get(&bucket, &key, range.end) {
+                return bytes.stream_range(range);
+            }
+
+            if range.end > cache.max_object_bytes {
+                return inner.get_range(&bucket, &key, range);
+            }
+
+            let read = inner.get_range(&bucket, &key, Range { start: 0, end: range.end });
+            Box::pin(read.try_collect::<Vec<Bytes>>().map_ok(move |chunks| {
+                let bytes = Bytes::from(chunks.concat());
+
+                // A short read is passed on for the entry to fail, but not cached
+                if bytes.len() as u64 == range.end {
+                    cache.insert(&bucket, &key, bytes.clone());
+                }
+                bytes.slice((range.start as usize).min(bytes.len())..)
+            }).into_stream())
+        })).flatten())
+    }
+}
+
+/// Adjacent byte ranges of one S3 object, served by `CoalescedS3Object`s,
+/// that are read with a single GetObject when streamed in order.
+pub struct S3ReadGroup {
+    source: Arc<dyn ObjectSource>,
+    bucket: String,
+    key: String,
+    state: Mutex<GroupState>,
+}
+
+#[derive(Default)]
+struct GroupState {
+    /// The GetObject left by the last part to finish
+    read: Option<GroupRead>,
+
+    /// Ranges of the streams created but not yet finished or dropped
+    streams: Vec<Range>,
+
+    /// Streams waiting for one of `streams` to hand off its read
+    waiting: Vec<Waker>,
+}
+
+impl GroupState {
+    /// End of the run of adjacent streams starting with one ending at `end`,
+    /// so that a GetObject continued by them reads no further
+    fn requested_end(&self, mut end: u64) -> u64 {
+        while let Some(next) = self.streams.iter().find(|r| r.start == end) {
+            end = next.end;
+        }
+        end
+    }
+
+    /// Remove the stream of `range`, waking those that may have been waiting for it
+    fn release(&mut self, range: Range) {
+        if let Some(i) = self.streams.iter().position(|r| *r == range) {
+            self.streams.swap_remove(i);
+        }
+        self.waiting.drain(..).for_each(Waker::wake);
+    }
+}
+
+/// A GetObject in progress, left by one part of an `S3ReadGroup` for the next
+struct GroupRead {
+    stream: BoxBytesStream,
+    /// Position in the object of the start of `buf`
+    pos: u64,
+    buf: Bytes,
+}
+
+impl S3ReadGroup {
+    /// Create a group for ranges of the object
+    pub fn new(source: Arc<dyn ObjectSource>, bucket: String, key: String) -> Arc<Self> {
+        Arc::new(S3ReadGroup { source, bucket, key, state: Mutex::default() })
+    }
+}
+
+/// Implements `StreamRange` to serve `len` bytes starting at `offset` of the
+/// object of an `S3ReadGroup`.
+///
+/// Once a part has streamed to its end, the rest of its GetObject is handed to
+/// the group, and the next part continues it rather than making another
+/// request if it starts where the previous one ended. Chunks spanning the
+/// boundary are split so each part produces exactly its own bytes.
+///
+/// A stream polled while that of the preceding part is still unfinished, as
+/// when it is prefetched, waits for the read to be handed off rather than
+/// starting its own. The GetObject reads up to the end of the last adjacent
+/// part being streamed, rather than that of the group, so a Range request
+/// reads only what it needs.
+pub struct CoalescedS3Object {
+    pub group: Arc<S3ReadGroup>,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl StreamRange for CoalescedS3Object {
+    fn len(&self) -> u64 { self.len }
+    fn stream_range(&self, range: Range) -> BoxBytesStream {
+        let range = Range { start: self.offset + range.start, end: self.offset + range.end };
+        if !range.is_empty() {
+            self.group.state.lock().unwrap().streams.push(range);
+        }
+
+        Box::pin(CoalescedStream {
+            group: self.group.clone(),
+            read: None,
+            range,
+            pos: range.start,
+        })
+    }
+}
+
+struct CoalescedStream {
+    group: Arc<S3ReadGroup>,
+    read: Option<GroupRead>,
+    range: Range,
+    pos: u64,
+}
+
+impl Stream for CoalescedStream {
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.pos >= this.range.end {
+            return Poll::Ready(None);
+        }
+
+        let group = &this.group;
+        let pos = this.pos;
+        let read = match &mut this.read {
+            Some(read) => read,
+            None => {
+                let mut state = group.state.lock().unwrap();
+                if state.streams.iter().any(|r| r.end == pos) {
+                    state.waiting.push(cx.waker().clone());
+                    return Poll::Pending;
+                }
+
+                let previous = state.read.take();
+                let end = state.requested_end(this.range.end);
+                this.read.insert(previous.filter(|read| read.pos == pos).unwrap_or_else(|| GroupRead {
+                    stream: group.source.get_range(&group.bucket, &group.key, Range { start: pos, end }),
+                    pos,
+                    buf: Bytes::new(),
+                }))
+            }
+        };
+
+        while read.buf.is_empty() {
+            match futures::ready!(read.stream.as_mut().poll_next(cx)) {
+                Some(Ok(bytes)) => read.buf = bytes,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    let url = format!("s3://{}/{}", group.bucket, group.key);
+                    return Poll::Ready(Some(Err(format!("S3 GetObject for {} ended before position {}", url, this.range.end).into())));
+                }
+            }
+        }
+
+        let len = read.buf.len().min((this.range.end - this.pos) as usize);
+        let chunk = read.buf.split_to(len);
+        read.pos += len as u64;
+        this.pos += len as u64;
+
+        if this.pos >= this.range.end {
+            let mut state = group.state.lock().unwrap();
+            state.read = this.read.take();
+            state.release(this.range);
+        }
+
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+impl Drop for CoalescedStream {
+    fn drop(&mut self) {
+        if self.pos < self.range.end {
+            self.group.state.lock().unwrap().release(self.range);
+        }
+    }
+}
+
 /// Wraps the error from S3 with context on the S3 URL
 #[derive(Debug, Clone)]
 struct S3Error<T> {
@@ -113,6 +527,124 @@ impl<T> Error for S3Error<T> where T: Error + 'static {
     }
 }
 
+type GetObjectError = s3::error::SdkError<s3::operation::get_object::GetObjectError, s3::config::http::HttpResponse>;
+
+/// S3 rejected a request because of its request rate
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct S3Throttled {
+    /// Delay S3 asked for with a `Retry-After` header, if any
+    pub retry_after: Option<Duration>,
+}
+
+/// Whether `err` is, or was caused by, a GetObject failing with `SlowDown` or
+/// `503 Service Unavailable`
+pub fn s3_throttled(err: &(dyn Error + 'static)) -> Option<S3Throttled> {
+    use s3::error::ProvideErrorMetadata;
+
+    let mut err = Some(err);
+    while let Some(e) = err {
+        if let Some(S3Error { inner, .. }) = e.downcast_ref::<S3Error<GetObjectError>>() {
+            let response = inner.raw_response()?;
+            if inner.code() != Some("SlowDown") && response.status().as_u16() != 503 {
+                return None;
+            }
+
+            let retry_after = response.headers().get("retry-after")
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs);
+            return Some(S3Throttled { retry_after });
+        }
+        err = e.source();
+    }
+    None
+}
+
+/// Client used to fetch `HttpObject`s
+pub type HttpClient = hyper_util::client::legacy::Client<HttpsConnector<HttpConnector>, Empty<Bytes>>;
+
+/// Implements `StreamRange` to serve `len` bytes starting at `offset` of a
+/// file from an HTTP(S) URL using Range requests, such as an S3 presigned GET
+/// URL
+pub struct HttpObject {
+    pub client: HttpClient,
+    pub uri: Uri,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl StreamRange for HttpObject {
+    fn len(&self) -> u64 { self.len }
+    fn stream_range(&self, range: Range) -> BoxBytesStream {
+        let client = self.client.clone();
+        let uri = self.uri.clone();
+        let range = Range { start: self.offset + range.start, end: self.offset + range.end };
+
+        Box::pin(lazy(move |_| {
+            Box::pin(async move {
+                // The query of a presigned URL contains the signature, so leave it out of logs
+                let url = format!("{}://{}{}", uri.scheme_str().unwrap_or(""), uri.authority().map_or("", |a| a.as_str()), uri.path());
+
+                let req = Request::get(uri)
+                    .header(header::RANGE, range.to_http_range_header())
+                    .body(Empty::new())
+                    .unwrap();
+
+                let res = client.request(req).await
+                    .map_err(|inner| HttpError { url: url.clone(), status: None, unsupported: None, inner: Some(inner.into()) })?;
+
+                if res.status() != StatusCode::PARTIAL_CONTENT {
+                    return Err(HttpError { url, status: Some(res.status()), unsupported: None, inner: None });
+                }
+
+                // A 206 for a single range should always be what was asked
+                // for, but an origin that doesn't say it supports byte ranges
+                // can't be trusted to have honored the offset
+                let accepts_bytes = res.headers().get_all(header::ACCEPT_RANGES).iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .flat_map(|v| v.split(','))
+                    .any(|unit| unit.trim().eq_ignore_ascii_case("bytes"));
+                if !accepts_bytes {
+                    return Err(HttpError { url, status: Some(res.status()), unsupported: Some("without Accept-Ranges: bytes"), inner: None });
+                }
+
+                info!("HTTP get complete for {}", url);
+
+                if res.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()) != Some(&range.len().to_string()) {
+                    error!("HTTP file size mismatch for {}, expected {:?}, got {:?}", url, range.len(), res.headers().get(header::CONTENT_LENGTH))
+                }
+
+                Ok(res.into_body().into_data_stream().map_err(BoxError::from))
+            })
+        }).flatten().map_err(BoxError::from).try_flatten_stream())
+    }
+}
+
+/// Error from fetching an `HttpObject`, with context on the URL
+#[derive(Debug)]
+struct HttpError {
+    url: String,
+    status: Option<StatusCode>,
+    /// What made an otherwise successful response unusable
+    unsupported: Option<&'static str>,
+    inner: Option<BoxError>,
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.status, self.unsupported) {
+            (Some(status), Some(unsupported)) => write!(f, "HTTP GET for {} returned {} {}", self.url, status, unsupported),
+            (Some(status), None) => write!(f, "HTTP GET for {} returned {}", self.url, status),
+            (None, _) => write!(f, "HTTP GET for {} failed", self.url),
+        }
+    }
+}
+
+impl Error for HttpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.inner.as_deref().map(|e| e as &(dyn Error + 'static))
+    }
+}
+
 /// Newtype wrapper implementing [`Stream`] for [`ByteStream`].
 ///
 /// https://github.com/smithy-lang/smithy-rs/pull/2983 removed the `Stream` implementation.
@@ -126,20 +658,412 @@ impl Stream for ByteStreamWrap {
     }
 }
 
+/// Implements `StreamRange` to serve `len` bytes starting at `offset` of a
+/// local file, such as a copy of S3 content mirrored to disk
+pub struct FileRange {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl StreamRange for FileRange {
+    fn len(&self) -> u64 { self.len }
+    fn stream_range(&self, range: Range) -> BoxBytesStream {
+        let path = self.path.clone();
+        let range = Range { start: self.offset + range.start, end: self.offset + range.end };
+
+        Box::pin(lazy(move |_| {
+            Box::pin(async move {
+                let file_error = |inner: std::io::Error| FileError { path: path.clone(), inner };
+
+                let mut file = tokio::fs::File::open(&path).await.map_err(file_error)?;
+                file.seek(SeekFrom::Start(range.start)).await.map_err(file_error)?;
+
+                info!("File open complete for {}", path.display());
+
+                let file_len = file.metadata().await.map_err(file_error)?.len();
+                if file_len < range.end {
+                    error!("File size mismatch for {}, expected at least {}, got {}", path.display(), range.end, file_len)
+                }
+
+                let stream = ReaderStream::new(file.take(range.len()))
+                    .map_err(move |inner| BoxError::from(FileError { path: path.clone(), inner }));
+                Ok::<_, FileError>(stream)
+            })
+        }).flatten().map_err(BoxError::from).try_flatten_stream())
+    }
+}
+
+/// Error from reading a `FileRange`, with context on the path
+#[derive(Debug)]
+struct FileError {
+    path: PathBuf,
+    inner: std::io::Error,
+}
+
+impl Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Reading {} failed", self.path.display())
+    }
+}
+
+impl Error for FileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.inner)
+    }
+}
+
 /// A `StreamRange` constructed by concatentating multiple other `StreamRange` trait objects
-pub struct Concatenated(pub Vec<Box<dyn StreamRange>>);
+pub struct Concatenated {
+    parts: Vec<Box<dyn StreamRange>>,
+
+    /// Offset of the end of each part, so a range's first part can be found
+    /// with a binary search rather than a walk over all earlier parts
+    ends: Vec<u64>,
+
+    /// Number of parts after the current one to start streaming early
+    prefetch: usize,
+}
+
+impl Concatenated {
+    pub fn new(parts: Vec<Box<dyn StreamRange>>) -> Concatenated {
+        let ends = parts.iter().scan(0, |end, part| {
+            *end += part.len();
+            Some(*end)
+        }).collect();
+        Concatenated { parts, ends, prefetch: 0 }
+    }
+
+    /// While streaming each part, also poll the streams of up to `parts`
+    /// following parts, keeping up to `PREFETCH_BUFFER_BYTES` of each, so a
+    /// slow start of a part such as an S3 request overlaps the earlier parts.
+    ///
+    /// The parts' streams must not depend on earlier ones having ended, as
+    /// the streams of `PendingCrc` headers do.
+    pub fn with_prefetch(self, parts: usize) -> Concatenated {
+        Concatenated { prefetch: parts, ..self }
+    }
+}
 
 impl StreamRange for Concatenated {
-    fn len(&self) -> u64 { self.0.iter().map(|x| x.len()).sum() }
+    fn len(&self) -> u64 { self.ends.last().copied().unwrap_or(0) }
     fn stream_range(&self, mut range: Range) -> BoxBytesStream {
+        // Skip the parts that end before the range starts
+        let first = self.ends.partition_point(|&end| end <= range.start);
+        if first > 0 {
+            range.take_prefix(self.ends[first - 1]);
+        }
+
         let mut streams = Vec::new();
-        for part in &self.0 {
-            if range.len() == 0 { break; }
+        for part in &self.parts[first..] {
+            if range.is_empty() { break; }
 
             if let Some(inner_range) = range.take_prefix(part.len()) {
                 streams.push(part.stream_range(inner_range));
             }
         }
-        Box::pin(stream::iter(streams.into_iter()).flatten())
+
+        if self.prefetch > 0 && streams.len() > 1 {
+            Box::pin(Prefetch { pending: streams.into(), active: VecDeque::new(), prefetch: self.prefetch })
+        } else {
+            Box::pin(stream::iter(streams).flatten())
+        }
+    }
+}
+
+/// Bytes kept from each part streamed ahead by `Concatenated::with_prefetch`
+pub const PREFETCH_BUFFER_BYTES: u64 = 256 * 1024;
+
+/// Stream of the concatenation of `pending`, polling up to `prefetch`
+/// streams after the current one so that they make progress meanwhile
+struct Prefetch {
+    /// Streams not yet polled
+    pending: VecDeque<BoxBytesStream>,
+
+    /// The current stream followed by those being prefetched
+    active: VecDeque<PrefetchedStream>,
+    prefetch: usize,
+}
+
+struct PrefetchedStream {
+    /// `None` once the stream has ended
+    stream: Option<BoxBytesStream>,
+
+    /// Items produced but not yet passed on, and the number of bytes in them
+    buffer: VecDeque<Result<Bytes, BoxError>>,
+    buffered_bytes: u64,
+}
+
+impl PrefetchedStream {
+    /// Buffer items until the stream is pending or ends, the buffer is full,
+    /// or it fails. Items after an error are left for when it is current.
+    fn fill(&mut self, cx: &mut Context<'_>) {
+        while let Some(stream) = &mut self.stream {
+            if self.buffered_bytes >= PREFETCH_BUFFER_BYTES || self.buffer.back().is_some_and(|item| item.is_err()) {
+                return;
+            }
+
+            match stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    self.buffered_bytes += item.as_ref().map_or(0, |chunk| chunk.len() as u64);
+                    self.buffer.push_back(item);
+                }
+                Poll::Ready(None) => self.stream = None,
+                Poll::Pending => return,
+            }
+        }
+    }
+}
+
+impl Stream for Prefetch {
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            while this.active.len() <= this.prefetch {
+                let Some(stream) = this.pending.pop_front() else { break };
+                this.active.push_back(PrefetchedStream { stream: Some(stream), buffer: VecDeque::new(), buffered_bytes: 0 });
+            }
+
+            for ahead in this.active.iter_mut().skip(1) {
+                ahead.fill(cx);
+            }
+
+            let Some(current) = this.active.front_mut() else {
+                return Poll::Ready(None);
+            };
+
+            if let Some(item) = current.buffer.pop_front() {
+                current.buffered_bytes -= item.as_ref().map_or(0, |chunk| chunk.len() as u64);
+                return Poll::Ready(Some(item));
+            }
+
+            match current.stream.as_mut().map(|stream| stream.as_mut().poll_next(cx)) {
+                Some(Poll::Ready(Some(item))) => return Poll::Ready(Some(item)),
+                Some(Poll::Pending) => return Poll::Pending,
+                Some(Poll::Ready(None)) | None => {
+                    this.active.pop_front();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_range() {
+        let path = std::env::temp_dir().join(format!("zipstream_test_file_range_{}", std::process::id()));
+        let contents: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+
+        let file = FileRange { path: path.clone(), offset: 0, len: contents.len() as u64 };
+        for (start, end) in [(0, 100_000), (0, 0), (1, 2), (12_345, 99_999)] {
+            let chunks: Vec<Bytes> = file.stream_range(Range { start, end }).try_collect().await.unwrap();
+            assert_eq!(chunks.concat(), &contents[start as usize..end as usize], "{} {}", start, end);
+        }
+
+        let part = FileRange { path: path.clone(), offset: 1_000, len: 2_000 };
+        let chunks: Vec<Bytes> = part.stream_range(Range { start: 10, end: 2_000 }).try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), &contents[1_010..3_000]);
+        std::fs::remove_file(&path).unwrap();
+
+        let err = file.stream_range(Range { start: 0, end: 1 }).try_collect::<Vec<_>>().await.unwrap_err();
+        assert_eq!(err.to_string(), format!("Reading {} failed", path.display()));
+        assert_eq!(err.source().unwrap().downcast_ref::<std::io::Error>().unwrap().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_caching_source() {
+        use hyper::Method;
+
+        async fn read(source: &CachingSource, key: &str, start: u64, end: u64) -> Result<Vec<u8>, BoxError> {
+            let chunks: Vec<Bytes> = source.get_range("bucket", key, Range { start, end }).try_collect().await?;
+            Ok(chunks.concat())
+        }
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        for key in ["a", "b", "c"] {
+            s3.put("bucket", key, format!("{}123456789", key), "2021-03-04T05:06:08Z");
+        }
+        s3.put("bucket", "large", vec![7u8; 100], "2021-03-04T05:06:08Z");
+        let source = CachingSource { inner: Arc::new(client), cache: ObjectCache::new(25, 50) };
+
+        // A miss reads from the start of the object, so later reads of any part of it hit
+        assert_eq!(read(&source, "a", 2, 5).await.unwrap(), b"234");
+        assert_eq!(read(&source, "a", 0, 5).await.unwrap(), b"a1234");
+        assert_eq!(read(&source, "a", 4, 5).await.unwrap(), b"4");
+        assert_eq!(s3.count(Method::GET), 1);
+
+        // Reads past the cached bytes replace them with a longer copy
+        assert_eq!(read(&source, "a", 3, 10).await.unwrap(), b"3456789");
+        assert_eq!(read(&source, "a", 0, 10).await.unwrap(), b"a123456789");
+        assert_eq!(s3.count(Method::GET), 2);
+
+        // Reads ending after `max_object_bytes` aren't cached
+        assert_eq!(read(&source, "large", 10, 60).await.unwrap(), vec![7u8; 50]);
+        assert_eq!(read(&source, "large", 10, 60).await.unwrap(), vec![7u8; 50]);
+        assert_eq!(s3.count(Method::GET), 4);
+
+        // Adding c goes over `max_bytes`, dropping b, which was used least recently
+        assert_eq!(read(&source, "b", 0, 10).await.unwrap(), b"b123456789");
+        assert_eq!(read(&source, "a", 0, 1).await.unwrap(), b"a");
+        assert_eq!(read(&source, "c", 0, 10).await.unwrap(), b"c123456789");
+        assert_eq!(s3.count(Method::GET), 6);
+        assert_eq!(read(&source, "a", 5, 10).await.unwrap(), b"56789");
+        assert_eq!(s3.count(Method::GET), 6);
+        assert_eq!(read(&source, "b", 5, 10).await.unwrap(), b"56789");
+        assert_eq!(s3.count(Method::GET), 7);
+
+        assert!(read(&source, "missing", 0, 2).await.is_err());
+        assert!(read(&source, "missing", 0, 2).await.is_err());
+        assert_eq!(s3.count(Method::GET), 9);
+    }
+
+    /// An object that records the ranges requested of it
+    #[derive(Default)]
+    struct RecordingSource {
+        data: Bytes,
+        requests: Mutex<Vec<Range>>,
+    }
+
+    impl ObjectSource for RecordingSource {
+        fn get_range(&self, _bucket: &str, _key: &str, range: Range) -> BoxBytesStream {
+            self.requests.lock().unwrap().push(range);
+            // In small chunks, so that parts of reads are handed off
+            let chunks: Vec<_> = self.data.slice(range.start as usize..range.end as usize).chunks(3).map(|c| Ok(Bytes::copy_from_slice(c))).collect();
+            Box::pin(stream::iter(chunks))
+        }
+    }
+
+    /// An object whose first read fails after its last byte
+    #[derive(Default)]
+    struct FailingAtEnd {
+        data: Bytes,
+        requests: Mutex<Vec<Range>>,
+    }
+
+    impl ObjectSource for FailingAtEnd {
+        fn get_range(&self, _bucket: &str, _key: &str, range: Range) -> BoxBytesStream {
+            let mut requests = self.requests.lock().unwrap();
+            requests.push(range);
+            let data = stream::once(future::ok(self.data.slice(range.start as usize..range.end as usize)));
+            if requests.len() > 1 {
+                return Box::pin(data);
+            }
+            Box::pin(data.chain(stream::once(future::err("connection reset".into()))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_last_byte() {
+        let inner = Arc::new(FailingAtEnd { data: Bytes::from_static(b"0123456789"), ..Default::default() });
+        let source = RetryingSource { inner: inner.clone(), max_attempts: 3 };
+        for range in [Range { start: 2, end: 8 }, Range { start: 0, end: 0 }] {
+            inner.requests.lock().unwrap().clear();
+            let chunks: Vec<Bytes> = source.get_range("bucket", "key", range).try_collect().await.unwrap();
+            assert_eq!(chunks.concat(), &inner.data[range.start as usize..range.end as usize]);
+            assert_eq!(*inner.requests.lock().unwrap(), [range]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_s3_object() {
+        let source = Arc::new(RecordingSource { data: Bytes::from_static(b"aaaabbbbbbcc"), ..Default::default() });
+        let group = S3ReadGroup::new(source.clone(), "bucket".into(), "key".into());
+        let parts = || -> Vec<Box<dyn StreamRange>> {
+            [(0, 4), (4, 6), (10, 2)].iter()
+                .map(|&(offset, len)| Box::new(CoalescedS3Object { group: group.clone(), offset, len }) as Box<dyn StreamRange>)
+                .collect()
+        };
+
+        // One GetObject for each range, ending where the range does
+        for prefetch in [0, 2] {
+            for (start, end) in [(0, 12), (0, 7), (5, 12), (4, 10)] {
+                source.requests.lock().unwrap().clear();
+                let concatenated = Concatenated::new(parts()).with_prefetch(prefetch);
+                let chunks: Vec<Bytes> = concatenated.stream_range(Range { start, end }).try_collect().await.unwrap();
+                assert_eq!(chunks.concat(), &source.data[start as usize..end as usize]);
+                assert_eq!(*source.requests.lock().unwrap(), [Range { start, end }], "prefetch {} range {}..{}", prefetch, start, end);
+            }
+        }
+    }
+
+    /// A part that takes `delay` to produce its first byte, like an S3 object
+    struct Delayed {
+        data: Bytes,
+        delay: Duration,
+    }
+
+    impl StreamRange for Delayed {
+        fn len(&self) -> u64 { self.data.len() as u64 }
+        fn stream_range(&self, range: Range) -> BoxBytesStream {
+            let (data, delay) = (self.data.clone(), self.delay);
+            Box::pin(stream::once(async move {
+                tokio::time::sleep(delay).await;
+                Ok(data.slice(range.start as usize..range.end as usize))
+            }))
+        }
+    }
+
+    /// Prefetching produces the same bytes for any range
+    #[tokio::test]
+    async fn test_prefetch() {
+        let parts = || -> Vec<Box<dyn StreamRange>> {
+            (0..100u8).map(|i| Box::new(Delayed { data: Bytes::from(vec![i; 1000]), delay: Duration::from_millis(1) }) as Box<dyn StreamRange>).collect()
+        };
+        let expected: Vec<u8> = (0..100u8).flat_map(|i| vec![i; 1000]).collect();
+
+        for prefetch in [0, 1, 8, 200] {
+            let concatenated = Concatenated::new(parts()).with_prefetch(prefetch);
+            for (start, end) in [(0, 100_000), (0, 0), (999, 1001), (12_345, 99_999), (50_000, 50_001)] {
+                let chunks: Vec<Bytes> = concatenated.stream_range(Range { start, end }).try_collect().await.unwrap();
+                assert_eq!(chunks.concat(), &expected[start as usize..end as usize], "prefetch {} range {}..{}", prefetch, start, end);
+            }
+        }
+
+        // An error is passed on in order, after the parts before it
+        let failing: Vec<Box<dyn StreamRange>> = vec![
+            Box::new(Delayed { data: Bytes::from_static(b"ab"), delay: Duration::from_millis(10) }),
+            Box::new(FileRange { path: "/nonexistent/zipstream".into(), offset: 0, len: 1 }),
+        ];
+        let mut stream = Concatenated::new(failing).with_prefetch(1).stream_range(Range { start: 0, end: 3 });
+        assert_eq!(stream.next().await.unwrap().unwrap(), Bytes::from_static(b"ab"));
+        assert!(stream.next().await.unwrap().is_err());
+    }
+
+    /// An origin that answers a Range request with 206 but doesn't send
+    /// `Accept-Ranges: bytes` isn't trusted to have sent the requested bytes
+    #[tokio::test]
+    async fn test_http_object_requires_accept_ranges() {
+        use hyper::{server::conn::http1, service::service_fn, Response};
+        use hyper_util::rt::{TokioExecutor, TokioIo};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::task::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|req: Request<hyper::body::Incoming>| async move {
+                    let mut res = Response::builder().status(StatusCode::PARTIAL_CONTENT).header(header::CONTENT_LENGTH, 2);
+                    if req.uri().path() == "/ranges" {
+                        res = res.header(header::ACCEPT_RANGES, "bytes");
+                    }
+                    Ok::<_, std::convert::Infallible>(res.body(http_body_util::Full::new(Bytes::from_static(b"xx"))).unwrap())
+                })));
+            }
+        });
+
+        let client: HttpClient = hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build(HttpsConnector::new());
+        let object = |path: &str| HttpObject { client: client.clone(), uri: format!("http://{}{}", addr, path).parse().unwrap(), offset: 0, len: 2 };
+
+        let chunks: Vec<Bytes> = object("/ranges").stream_range(Range { start: 0, end: 2 }).try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"xx");
+
+        let err = object("/no-ranges").stream_range(Range { start: 0, end: 2 }).try_collect::<Vec<_>>().await.unwrap_err();
+        assert_eq!(err.to_string(), format!("HTTP GET for http://{}/no-ranges returned 206 Partial Content without Accept-Ranges: bytes", addr));
     }
 }
@@ -0,0 +1,151 @@
+//! Export of tracing spans to an OpenTelemetry collector, enabled with
+//! `--otel-endpoint`, and the request event recorded in them.
+use hyper::{header::{self, HeaderName, HeaderValue}, HeaderMap, Request};
+use opentelemetry::{trace::{TraceError, TracerProvider as _}, KeyValue};
+use opentelemetry_http::hyper::HyperClient;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use std::time::Duration;
+use tracing::{info, Subscriber};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Time allowed for each export request to the collector
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Create a provider that batches spans and sends them to the OTLP/HTTP
+/// collector at `endpoint`, such as `http://otel-collector:4318`.
+///
+/// Spans are posted as protobuf to `/v1/traces` under the endpoint. The
+/// provider must be shut down before exiting to send the last batch.
+pub fn tracer_provider(endpoint: &str) -> Result<TracerProvider, TraceError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_http_client(HyperClient::with_default_connector(EXPORT_TIMEOUT, None))
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .with_timeout(EXPORT_TIMEOUT)
+        .build()?;
+
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", "zipstream")]))
+        .build())
+}
+
+/// A tracing layer that records spans with `provider`
+pub fn layer<S>(provider: &TracerProvider) -> impl tracing_subscriber::Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer("zipstream"))
+}
+
+/// Request headers holding credentials, whose values are left out of the logs
+/// and the spans exported from them
+const REDACTED_HEADERS: [HeaderName; 3] = [
+    header::AUTHORIZATION,
+    header::COOKIE,
+    HeaderName::from_static("x-zip-stream-password"),
+];
+
+/// `headers` with the values of `REDACTED_HEADERS` replaced
+fn redacted_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    for (name, value) in headers.iter_mut() {
+        if REDACTED_HEADERS.contains(name) {
+            *value = HeaderValue::from_static("[redacted]");
+        }
+    }
+    headers
+}
+
+/// Log the start of a request, with its headers but not their credentials
+pub fn log_request<B>(req: &Request<B>) {
+    info!(
+        http.request.method = ?req.method(),
+        url.path = req.uri().path(),
+        http.request.raw_headers = ?redacted_headers(req.headers()),
+        "{:?} {}", req.method(), req.uri(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use hyper::{body::Incoming, header, server::conn::http1, service::service_fn, Request, Response};
+    use hyper_util::rt::TokioIo;
+    use std::{convert::Infallible, sync::{Arc, Mutex}};
+    use tokio::net::TcpListener;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    type Received = Arc<Mutex<Vec<(String, Option<String>, Bytes)>>>;
+
+    /// Start a collector that records the path, content type and body of each request
+    async fn mock_collector() -> (String, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let received = Received::default();
+        let state = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let state = state.clone();
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |req: Request<Incoming>| {
+                    let state = state.clone();
+                    async move {
+                        let path = req.uri().path().to_owned();
+                        let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_owned);
+                        let body = req.into_body().collect().await.unwrap().to_bytes();
+                        state.lock().unwrap().push((path, content_type, body));
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::new())))
+                    }
+                })));
+            }
+        });
+        (endpoint, received)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_spans() {
+        let (endpoint, received) = mock_collector().await;
+        let provider = tracer_provider(&format!("{}/", endpoint)).unwrap();
+
+        let subscriber = tracing_subscriber::registry().with(layer(&provider));
+        tracing::subscriber::with_default(subscriber, || {
+            let _request = info_span!("request", http.request.method = "GET").entered();
+            let _fetch = info_span!("upstream_fetch").entered();
+        });
+        provider.shutdown().unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (path, content_type, body) = &received[0];
+        assert_eq!(path, "/v1/traces");
+        assert_eq!(content_type.as_deref(), Some("application/x-protobuf"));
+        for name in [&b"request"[..], b"upstream_fetch", b"zipstream"] {
+            assert!(body.windows(name.len()).any(|w| w == name), "{:?} not exported", String::from_utf8_lossy(name));
+        }
+    }
+
+    #[test]
+    fn test_log_request() {
+        let (_guard, logs) = crate::test_util::capture_logs();
+        let req = Request::builder()
+            .uri("/test.zip")
+            .header("X-Zip-Stream-Password", "hunter2")
+            .header(header::AUTHORIZATION, "Bearer s3cr3t")
+            .header(header::COOKIE, "session=abc123")
+            .header(header::USER_AGENT, "curl/8.0")
+            .body(()).unwrap();
+        log_request(&req);
+
+        let logs = logs.contents();
+        for secret in ["hunter2", "s3cr3t", "abc123"] {
+            assert!(!logs.contains(secret), "{:?} logged: {}", secret, logs);
+        }
+        assert!(logs.contains(r#"\"x-zip-stream-password\": \"[redacted]\""#), "{}", logs);
+        assert!(logs.contains("curl/8.0"), "{}", logs);
+    }
+}
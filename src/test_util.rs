@@ -0,0 +1,323 @@
+//! Helpers for tests that need an S3 endpoint, inspect logs or measure memory use.
+use aws_sdk_s3 as s3;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Incoming, header, server::conn::http1, service::service_fn, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::{alloc::{GlobalAlloc, Layout, System}, cell::Cell, collections::{HashMap, VecDeque}, convert::Infallible, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::Duration};
+use tokio::net::TcpListener;
+
+use crate::serve_range::parse_range;
+use crate::stream_range::{BoxBytesStream, ObjectSource, Range};
+
+/// An `ObjectSource` that serves objects from memory, keyed by `bucket/key`
+#[derive(Default)]
+pub struct MemoryObjects(pub HashMap<String, Bytes>);
+
+impl MemoryObjects {
+    pub fn insert(&mut self, bucket: &str, key: &str, data: impl Into<Bytes>) {
+        self.0.insert(format!("{}/{}", bucket, key), data.into());
+    }
+}
+
+impl ObjectSource for MemoryObjects {
+    fn get_range(&self, bucket: &str, key: &str, range: Range) -> BoxBytesStream {
+        let result = match self.0.get(&format!("{}/{}", bucket, key)) {
+            Some(data) if range.end <= data.len() as u64 => Ok(data.slice(range.start as usize..range.end as usize)),
+            Some(_) => Err(format!("Range {:?} out of bounds for {}/{}", range, bucket, key).into()),
+            None => Err(format!("No such object {}/{}", bucket, key).into()),
+        };
+        Box::pin(futures::stream::once(async move { result }))
+    }
+}
+
+/// An object stored in a `MockS3`
+#[derive(Clone)]
+pub struct MockObject {
+    pub data: Bytes,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// State of a mock S3 server: the objects it serves and the requests it received
+#[derive(Default)]
+pub struct MockS3 {
+    /// Base URL of the server, such as `http://127.0.0.1:1234`
+    pub endpoint: String,
+    pub objects: Mutex<HashMap<String, MockObject>>,
+    pub requests: Mutex<Vec<(Method, String)>>,
+
+    /// Time to wait before responding to each request
+    pub delay: Mutex<Duration>,
+
+    /// Number of requests being handled, and the most handled at once
+    pub in_flight: AtomicUsize,
+    pub max_in_flight: AtomicUsize,
+
+    /// Parts of multipart uploads in progress, by upload id
+    pub uploads: Mutex<HashMap<String, Vec<(u32, Bytes)>>>,
+
+    /// Answer GET requests with a 503 SlowDown error, with this Retry-After
+    /// header if it is `Some`
+    pub slow_down: Mutex<Option<Option<u64>>>,
+
+    /// Answer the next GET requests with these error statuses, one each
+    pub get_failures: Mutex<VecDeque<StatusCode>>,
+
+    /// Send only the first half of the body of this many of the next GET
+    /// requests, then close the connection
+    pub truncated_gets: AtomicUsize,
+}
+
+impl MockS3 {
+    /// Add an object at `s3://bucket/key`
+    pub fn put(&self, bucket: &str, key: &str, data: impl Into<Bytes>, last_modified: &str) {
+        self.objects.lock().unwrap().insert(format!("/{}/{}", bucket, key), MockObject {
+            data: data.into(),
+            last_modified: last_modified.parse().unwrap(),
+        });
+    }
+
+    /// Number of requests received with the given method
+    pub fn count(&self, method: Method) -> usize {
+        self.requests.lock().unwrap().iter().filter(|(m, _)| *m == method).count()
+    }
+
+    async fn serve(&self, req: Request<Incoming>) -> Response<Full<Bytes>> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+
+        let delay = *self.delay.lock().unwrap();
+        tokio::time::sleep(delay).await;
+
+        let (parts, body) = req.into_parts();
+        let body = body.collect().await.map(|b| b.to_bytes()).unwrap_or_default();
+        let res = self.handle(Request::from_parts(parts, body));
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+
+    fn handle(&self, req: Request<Bytes>) -> Response<Full<Bytes>> {
+        // Like S3 with presigned URLs, ignore the query when finding the object
+        let path = req.uri().path().to_owned();
+        self.requests.lock().unwrap().push((req.method().clone(), req.uri().to_string()));
+
+        if let Some(res) = self.handle_multipart(&req, &path) {
+            return res;
+        }
+
+        if let (&Method::GET, Some(retry_after)) = (req.method(), *self.slow_down.lock().unwrap()) {
+            let mut res = Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(header::CONTENT_TYPE, "application/xml");
+            if let Some(retry_after) = retry_after {
+                res = res.header(header::RETRY_AFTER, retry_after);
+            }
+            return res
+                .body(Full::new(Bytes::from_static(b"<Error><Code>SlowDown</Code><Message>Please reduce your request rate.</Message></Error>")))
+                .unwrap();
+        }
+
+        if let (&Method::GET, Some(status)) = (req.method(), self.get_failures.lock().unwrap().pop_front()) {
+            let code = if status.is_server_error() { "InternalError" } else { "AccessDenied" };
+            return Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "application/xml")
+                .body(Full::new(Bytes::from(format!("<Error><Code>{}</Code><Message>Injected failure</Message></Error>", code))))
+                .unwrap();
+        }
+
+        let Some(object) = self.objects.lock().unwrap().get(&path).cloned() else {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header(header::CONTENT_TYPE, "application/xml")
+                .body(Full::new(Bytes::from_static(b"<Error><Code>NoSuchKey</Code><Message>The specified key does not exist.</Message></Error>")))
+                .unwrap();
+        };
+
+        let res = Response::builder()
+            .header(header::LAST_MODIFIED, s3::primitives::DateTime::from_secs(object.last_modified.timestamp())
+                .fmt(s3::primitives::DateTimeFormat::HttpDate).unwrap())
+            .header(header::ETAG, format!("\"{:x}\"", object.data.len()))
+            .header(header::ACCEPT_RANGES, "bytes");
+
+        let range = req.headers().get(header::RANGE)
+            .and_then(|v| parse_range(v.to_str().unwrap(), object.data.len() as u64).unwrap());
+
+        let (res, body) = match range {
+            _ if req.method() == Method::HEAD => (res, Bytes::new()),
+            Some(range) => (
+                res.status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, object.data.len())),
+                object.data.slice(range.start as usize..range.end as usize),
+            ),
+            None => (res, object.data.clone()),
+        };
+
+        let len = if req.method() == Method::HEAD { object.data.len() } else { body.len() };
+
+        // The declared length is longer than the body, so the client sees the connection close early
+        let truncate = req.method() == Method::GET && self.truncated_gets.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok();
+        let body = if truncate { body.slice(..body.len() / 2) } else { body };
+
+        res.header(header::CONTENT_LENGTH, len).body(Full::new(body)).unwrap()
+    }
+}
+
+impl MockS3 {
+    /// Handle the requests of a multipart upload, or return `None` for other requests
+    fn handle_multipart(&self, req: &Request<Bytes>, path: &str) -> Option<Response<Full<Bytes>>> {
+        let query: HashMap<&str, &str> = req.uri().query().unwrap_or_default().split('&')
+            .map(|param| param.split_once('=').unwrap_or((param, "")))
+            .collect();
+        let xml = |body: String| Response::builder()
+            .header(header::CONTENT_TYPE, "application/xml")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap();
+
+        let (bucket, key) = path[1..].split_once('/')?;
+        let mut uploads = self.uploads.lock().unwrap();
+
+        Some(match (req.method(), query.get("uploadId")) {
+            (&Method::POST, None) if query.contains_key("uploads") => {
+                let upload_id = format!("upload-{}", uploads.len() + 1);
+                uploads.insert(upload_id.clone(), Vec::new());
+                xml(format!("<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>", bucket, key, upload_id))
+            }
+            (&Method::PUT, Some(upload_id)) => {
+                let part_number = query.get("partNumber")?.parse().unwrap();
+                uploads.get_mut(*upload_id)?.push((part_number, req.body().clone()));
+                Response::builder()
+                    .header(header::ETAG, format!("\"part-{}\"", part_number))
+                    .body(Full::new(Bytes::new()))
+                    .unwrap()
+            }
+            (&Method::POST, Some(upload_id)) => {
+                let mut parts = uploads.remove(*upload_id)?;
+                parts.sort_by_key(|(part_number, _)| *part_number);
+                let data: Vec<u8> = parts.into_iter().flat_map(|(_, data)| data).collect();
+                self.put(bucket, key, data, "2024-01-01T00:00:00Z");
+                xml(format!("<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><ETag>\"complete\"</ETag></CompleteMultipartUploadResult>", bucket, key))
+            }
+            (&Method::DELETE, Some(upload_id)) => {
+                uploads.remove(*upload_id)?;
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Full::new(Bytes::new()))
+                    .unwrap()
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// Start a mock S3 server on a local port and return a client configured to use it
+pub async fn mock_s3() -> (s3::Client, Arc<MockS3>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let state = Arc::new(MockS3 { endpoint: endpoint.clone(), ..Default::default() });
+
+    let server_state = state.clone();
+    tokio::task::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let state = server_state.clone();
+            tokio::task::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(state.serve(req).await) }
+            })));
+        }
+    });
+
+    let client = s3::Client::from_conf(s3::Config::builder()
+        .behavior_version(s3::config::BehaviorVersion::latest())
+        .region(s3::config::Region::from_static("us-east-1"))
+        .credentials_provider(s3::config::Credentials::new("test", "test", None, None, "test"))
+        .endpoint_url(endpoint)
+        .force_path_style(true)
+        .build());
+
+    (client, state)
+}
+
+/// Log output captured by `capture_logs`
+#[derive(Clone, Default)]
+pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+/// Capture JSON log output on the current thread until the guard is dropped
+pub fn capture_logs() -> (tracing::subscriber::DefaultGuard, CapturedLogs) {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .flatten_event(true)
+        .with_writer(move || writer.clone())
+        .finish();
+
+    (tracing::subscriber::set_default(subscriber), logs)
+}
+
+/// The global allocator of the tests: the system allocator, counting the
+/// bytes each thread has allocated for `peak_allocated`
+pub struct CountingAllocator;
+
+thread_local! {
+    /// Bytes allocated by this thread and not yet freed, and the most there
+    /// have been since `peak_allocated` reset it. Freeing memory allocated by
+    /// another thread can make it negative.
+    static ALLOCATED: Cell<(isize, isize)> = const { Cell::new((0, 0)) };
+}
+
+fn count_allocated(delta: isize) {
+    // Not available while the thread is exiting
+    let _ = ALLOCATED.try_with(|allocated| {
+        let (current, peak) = allocated.get();
+        allocated.set((current + delta, peak.max(current + delta)));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocated(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count_allocated(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocated(new_size as isize - layout.size() as isize);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Run `f` and return its result with the most bytes that were allocated on
+/// this thread at once while it ran, beyond those allocated before it
+pub fn peak_allocated<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let start = ALLOCATED.with(|allocated| {
+        let (current, _) = allocated.get();
+        allocated.set((current, current));
+        current
+    });
+    let result = f();
+    let (_, peak) = ALLOCATED.with(Cell::get);
+    (result, (peak - start) as usize)
+}
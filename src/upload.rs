@@ -0,0 +1,208 @@
+//! Saving archives to S3 while they are streamed to a client
+use aws_sdk_s3 as s3;
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+use std::pin::Pin;
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tracing::{error, info, Instrument, Span};
+
+use crate::error::Report;
+use crate::stream_range::{BoxBytesStream, BoxError, Range, StreamRange};
+
+/// Size of each part of the multipart upload but the last
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// An upload that falls this many bytes behind the client is abandoned
+/// rather than buffering without bound
+const MAX_UPLOAD_LAG: u64 = 64 * 1024 * 1024;
+
+/// Implements `StreamRange` by serving `inner`, and when all of it is
+/// requested at once, also uploading it to `s3://bucket/key` with a
+/// multipart upload.
+///
+/// The upload is completed only if the whole archive was streamed. If the
+/// download fails or is canceled, the multipart upload is aborted. Errors
+/// uploading are logged and never affect the download.
+pub struct TeeToS3<S> {
+    pub inner: S,
+    pub client: s3::Client,
+    pub bucket: String,
+    pub key: String,
+}
+
+impl<S: StreamRange> StreamRange for TeeToS3<S> {
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn stream_range(&self, range: Range) -> BoxBytesStream {
+        let stream = self.inner.stream_range(range);
+        if range != (Range { start: 0, end: self.len() }) {
+            return stream;
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let lag = Arc::new(AtomicU64::new(0));
+        let upload = Upload {
+            client: self.client.clone(),
+            bucket: self.bucket.clone(),
+            key: self.key.clone(),
+            len: self.len(),
+            lag: lag.clone(),
+        };
+        tokio::task::spawn(upload.run(rx).instrument(Span::current()));
+
+        Box::pin(TeeStream { inner: stream, tx: Some(tx), lag })
+    }
+}
+
+/// Passes through a stream, sending a copy of each chunk to an `Upload`
+struct TeeStream {
+    inner: BoxBytesStream,
+
+    /// `None` once the upload is abandoned
+    tx: Option<mpsc::UnboundedSender<Bytes>>,
+
+    /// Bytes sent to the upload and not yet uploaded
+    lag: Arc<AtomicU64>,
+}
+
+impl Stream for TeeStream {
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let res = this.inner.as_mut().poll_next(cx);
+
+        match &res {
+            Poll::Ready(Some(Ok(chunk))) => if let Some(tx) = &this.tx {
+                let lag = this.lag.fetch_add(chunk.len() as u64, Ordering::Relaxed) + chunk.len() as u64;
+                // Dropping the sender before the end makes the upload abort
+                if lag > MAX_UPLOAD_LAG {
+                    error!("Abandoning upload that fell {} bytes behind the download", lag);
+                    this.tx = None;
+                } else if tx.send(chunk.clone()).is_err() {
+                    this.tx = None;
+                }
+            },
+            Poll::Ready(Some(Err(_))) => this.tx = None,
+            _ => {}
+        }
+
+        res
+    }
+}
+
+/// The receiving side of a `TeeStream`, which uploads what it receives
+struct Upload {
+    client: s3::Client,
+    bucket: String,
+    key: String,
+    len: u64,
+    lag: Arc<AtomicU64>,
+}
+
+impl Upload {
+    async fn run(self, mut rx: mpsc::UnboundedReceiver<Bytes>) {
+        // Nothing is created in S3 until data arrives, so a HEAD request or
+        // a download canceled before its first byte costs nothing
+        let Some(first) = rx.recv().await else { return };
+
+        let res = self.client.create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .content_type("application/zip")
+            .send().await;
+
+        let upload_id = match res {
+            Ok(res) => res.upload_id.unwrap_or_default(),
+            Err(e) => {
+                error!("Failed to start upload to s3://{}/{}: {}", self.bucket, self.key, Report(e));
+                return;
+            }
+        };
+
+        let parts = match self.upload_parts(&upload_id, first, rx).await {
+            Ok(parts) => parts,
+            Err(e) => {
+                info!("Aborting upload to s3://{}/{}: {}", self.bucket, self.key, Report(&*e));
+                self.abort(&upload_id).await;
+                return;
+            }
+        };
+
+        let res = self.client.complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(&upload_id)
+            .multipart_upload(s3::types::CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .send().await;
+
+        match res {
+            Ok(_) => info!("Uploaded archive to s3://{}/{}", self.bucket, self.key),
+            Err(e) => {
+                error!("Failed to complete upload to s3://{}/{}: {}", self.bucket, self.key, Report(e));
+                self.abort(&upload_id).await;
+            }
+        }
+    }
+
+    /// Upload the received data in parts, failing if the download ends
+    /// before all of it was received
+    async fn upload_parts(&self, upload_id: &str, first: Bytes, mut rx: mpsc::UnboundedReceiver<Bytes>) -> Result<Vec<s3::types::CompletedPart>, BoxError> {
+        let mut received = first.len() as u64;
+        let mut buf = BytesMut::from(&first[..]);
+        let mut parts = Vec::new();
+
+        loop {
+            let chunk = rx.recv().await;
+            if let Some(chunk) = &chunk {
+                received += chunk.len() as u64;
+                buf.extend_from_slice(chunk);
+            }
+
+            let done = chunk.is_none();
+            if done && received != self.len {
+                return Err(format!("download ended after {} of {} bytes", received, self.len).into());
+            }
+
+            if buf.len() >= PART_SIZE || (done && !buf.is_empty()) {
+                let part = buf.split().freeze();
+                let part_len = part.len() as u64;
+                let part_number = parts.len() as i32 + 1;
+
+                let res = self.client.upload_part()
+                    .bucket(&self.bucket)
+                    .key(&self.key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .body(part.into())
+                    .send().await?;
+
+                parts.push(s3::types::CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(res.e_tag)
+                    .build());
+                self.lag.fetch_sub(part_len, Ordering::Relaxed);
+            }
+
+            if done {
+                return Ok(parts);
+            }
+        }
+    }
+
+    async fn abort(&self, upload_id: &str) {
+        let res = self.client.abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(upload_id)
+            .send().await;
+
+        if let Err(e) = res {
+            error!("Failed to abort upload to s3://{}/{}: {}", self.bucket, self.key, Report(e));
+        }
+    }
+}
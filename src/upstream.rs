@@ -1,57 +1,838 @@
 // © 2019 3D Robotics. License: Apache-2.0
-use crate::Config;
-use crate::stream_range::{ StreamRange, S3Object, BoxError };
-use crate::serve_range::hyper_response;
-use crate::zip::{ ZipEntry, ZipOptions, zip_stream };
+use crate::{Backslashes, Config, ETagAlgorithm, EntryOrder, NameNormalization};
+use crate::stream_range::{ StreamRange, S3Object, S3ReadGroup, CoalescedS3Object, HttpObject, HttpClient, FileRange, ObjectSource, RetryingSource, CachingSource, BoxError, Range, s3_throttled };
+use crate::serve_range::{ bytes_response, empty_body, hyper_response, prepare_response, ResponseBody };
+use crate::zip::{ Compression, EntryLayout, Password, ZipEntry, ZipOptions, estimate_archive_len, zip_stream_with_layout, CENTRAL_DIRECTORY_HINT_PATH };
 use crate::s3url::S3Url;
+use crate::upload::TeeToS3;
+use crate::error::{ErrorResponse, Report};
 
 use aws_sdk_s3 as s3;
-use bytes::Bytes;
-use hyper::{header, body::{self, Body}, Request, Response, Uri, Method, StatusCode};
+use bytes::{Bytes, BytesMut};
+use futures::{future::{self, BoxFuture}, stream, FutureExt, StreamExt, TryStreamExt};
+use hyper::{header, body::Body, Request, Response, Uri, Method, StatusCode};
+use serde::de;
 use serde_derive::Deserialize;
+use std::{borrow::Cow, convert::TryFrom, fmt, io::{self, BufRead, Read}, path::{Component, Path, PathBuf}};
 use std::hash::{ Hash, Hasher };
-use chrono::{DateTime, Utc};
-use tracing::{info, error};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, SecondsFormat, Utc};
+use tracing::{info, info_span, error, warn};
+use unicode_normalization::UnicodeNormalization;
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus, inflate::stream::{inflate, InflateState}};
 
-#[derive(Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Location of an entry's data
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Source {
+    /// An S3 object, read using zipstream's AWS credentials
+    S3(S3Url),
+
+    /// An HTTP(S) URL that supports Range requests, such as an S3 presigned
+    /// GET URL, which needs no credentials
+    Url(String),
+
+    /// A local file, allowed only under `Config::file_sources_under`
+    File(PathBuf),
+
+    /// No data, because the entry is a directory
+    Directory,
+}
+
+impl Hash for Source {
+    // Hash only the URL so that ETags of S3 entries are unchanged
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Source::S3(url) => url.hash(state),
+            Source::Url(url) => unsigned_url(url).hash(state),
+            Source::File(path) => path.as_os_str().hash(state),
+            Source::Directory => "directory".hash(state),
+        }
+    }
+}
+
+/// `url` without the query parameters of a presigned URL's signature, which
+/// differ each time it is signed although the object is the same
+fn unsigned_url(url: &str) -> Cow<'_, str> {
+    let Some((base, query)) = url.split_once('?') else { return Cow::Borrowed(url) };
+    let is_signing = |param: &str| {
+        let name = param.split('=').next().unwrap_or_default().to_ascii_lowercase();
+        name.starts_with("x-amz-") || ["signature", "expires", "awsaccesskeyid"].contains(&&name[..])
+    };
+
+    let kept: Vec<&str> = query.split('&').filter(|param| !is_signing(param)).collect();
+    if kept.is_empty() {
+        Cow::Borrowed(base)
+    } else {
+        Cow::Owned(format!("{}?{}", base, kept.join("&")))
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::S3(url) => url.fmt(f),
+            Source::Url(url) => url.split('?').next().unwrap_or_default().fmt(f),
+            Source::File(path) => write!(f, "file://{}", path.display()),
+            Source::Directory => "directory".fmt(f),
+        }
+    }
+}
+
+impl<'de> de::Deserialize<'de> for Source {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        let s = String::deserialize(deserializer)?;
+        if s.starts_with("https://") || s.starts_with("http://") {
+            s.parse::<Uri>().map_err(de::Error::custom)?;
+            Ok(Source::Url(s))
+        } else if let Some(path) = s.strip_prefix("file://") {
+            // Only the empty host is supported, so the path is absolute
+            if !path.starts_with('/') {
+                return Err(de::Error::custom(format!("file:// URL must have an absolute path: {}", s)));
+            }
+            let path = percent_decode_str(path).decode_utf8().map_err(de::Error::custom)?;
+            Ok(Source::File(PathBuf::from(path.into_owned())))
+        } else {
+            s.parse().map(Source::S3).map_err(de::Error::custom)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 struct ZipFileDescription {
     archive_name: String,
-    source: S3Url,
+    source: Source,
+    /// Position in the source of the entry's first byte
+    offset: u64,
+    /// `UNKNOWN_LENGTH` if omitted, to be replaced using the object's size
+    /// with `Config::resolve_lengths`
+    length: u64,
+    crc: EntryCrc,
+    last_modified: DateTime<Utc>,
+    /// ETag the S3 object is expected to have, checked with `Config::verify_s3_etag`
+    etag: Option<String>,
+    metadata: EntryMetadata,
+    compression: EntryCompression,
+}
+
+impl Hash for ZipFileDescription {
+    // Hash a zero offset as before there were offsets so that ETags of existing manifests are unchanged
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let ZipFileDescription { archive_name, source, offset, length, crc, last_modified, etag, metadata, compression } = self;
+        archive_name.hash(state);
+        source.hash(state);
+        if *offset != 0 {
+            offset.hash(state);
+        }
+        length.hash(state);
+        crc.hash(state);
+        last_modified.hash(state);
+        etag.hash(state);
+        metadata.hash(state);
+        compression.hash(state);
+    }
+}
+
+/// CRC32 of an entry's contents, or `None` to compute it as its data is streamed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct EntryCrc(Option<u32>);
+
+impl Hash for EntryCrc {
+    // Hash a given CRC as before it was optional so that ETags of existing manifests are unchanged
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.0 {
+            Some(crc) => crc.hash(state),
+            None => "streamed crc".hash(state),
+        }
+    }
+}
+
+/// Free-form provenance of an entry, such as the system that produced it,
+/// written to the entry's comment as `key=value` lines
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(transparent)]
+struct EntryMetadata(BTreeMap<String, String>);
+
+impl Hash for EntryMetadata {
+    // Hash nothing without metadata so that ETags of existing manifests are unchanged
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if !self.0.is_empty() {
+            self.0.hash(state);
+        }
+    }
+}
+
+impl EntryMetadata {
+    /// The entry comment holding the metadata, one `key=value` line per key in key order
+    fn comment(&self) -> String {
+        self.0.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join("\n")
+    }
+
+    /// Describe why the metadata can't be read back from its comment, if it can't
+    fn invalid_reason(&self) -> Option<&'static str> {
+        if self.0.keys().any(|key| key.is_empty() || key.contains(['=', '\n'])) {
+            Some("metadata keys must be non-empty and not contain '=' or newlines")
+        } else if self.0.values().any(|value| value.contains('\n')) {
+            Some("metadata values must not contain newlines")
+        } else if self.comment().len() > 0xFFFF {
+            Some("metadata exceeds the maximum entry comment length of 65535 bytes")
+        } else {
+            None
+        }
+    }
+}
+
+/// Compression of an entry's `source` data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct EntryCompression(Compression);
+
+impl Hash for EntryCompression {
+    // Hash nothing for stored entries so that ETags of existing manifests are unchanged
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let Compression::Deflate { uncompressed_len } = self.0 {
+            "deflate".hash(state);
+            uncompressed_len.hash(state);
+        }
+    }
+}
+
+/// Compression method of a manifest entry, given by its optional `compression` field
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ManifestCompression {
+    #[default]
+    #[serde(alias = "store")]
+    Stored,
+
+    /// `source` is a raw DEFLATE stream, used as the entry's compressed data
+    /// as-is. `length` is the compressed length, and `uncompressed_length`
+    /// and `crc` describe the contents once inflated.
+    Deflate,
+}
+
+/// Kind of a manifest entry, given by its optional `type` field
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum EntryType {
+    #[default]
+    File,
+
+    /// An empty directory, which has no `source`, `length`, or `crc`
+    Directory,
+}
+
+/// An entry as written in the manifest, converted to a `ZipFileDescription`
+/// once the fields required by its `type` are checked
+#[derive(Deserialize)]
+struct ManifestEntry {
+    #[serde(rename = "type", default)]
+    entry_type: EntryType,
+    archive_name: String,
+    source: Option<Source>,
+    #[serde(default)]
+    offset: u64,
+    #[serde(default = "unknown_length")]
     length: u64,
-    crc: u32,
+    crc: Option<u32>,
     last_modified: DateTime<Utc>,
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    metadata: EntryMetadata,
+    #[serde(default)]
+    compression: ManifestCompression,
+    #[serde(default)]
+    uncompressed_length: Option<u64>,
+}
+
+impl TryFrom<ManifestEntry> for ZipFileDescription {
+    type Error = String;
+
+    fn try_from(entry: ManifestEntry) -> Result<Self, String> {
+        match entry.entry_type {
+            EntryType::File => {
+                let Some(source) = entry.source else {
+                    return Err(format!("missing source for \"{}\"", entry.archive_name));
+                };
+                // A CRC computed while streaming would be that of the compressed data
+                if entry.compression == ManifestCompression::Deflate && entry.crc.is_none() {
+                    return Err(format!("missing crc for deflate entry \"{}\"", entry.archive_name));
+                }
+                let compression = match (entry.compression, entry.uncompressed_length) {
+                    (ManifestCompression::Stored, None) => Compression::Stored,
+                    (ManifestCompression::Deflate, Some(uncompressed_len)) => Compression::Deflate { uncompressed_len },
+                    (ManifestCompression::Deflate, None) => {
+                        return Err(format!("missing uncompressed_length for deflate entry \"{}\"", entry.archive_name));
+                    }
+                    (ManifestCompression::Stored, Some(_)) => {
+                        return Err(format!("uncompressed_length without \"compression\": \"deflate\" for \"{}\"", entry.archive_name));
+                    }
+                };
+                Ok(ZipFileDescription {
+                    archive_name: entry.archive_name,
+                    source,
+                    offset: entry.offset,
+                    length: entry.length,
+                    crc: EntryCrc(entry.crc),
+                    last_modified: entry.last_modified,
+                    etag: entry.etag,
+                    metadata: entry.metadata,
+                    compression: EntryCompression(compression),
+                })
+            }
+            EntryType::Directory => {
+                if entry.source.is_some() || entry.length != UNKNOWN_LENGTH && entry.length != 0
+                    || entry.compression != ManifestCompression::Stored || entry.uncompressed_length.is_some()
+                {
+                    return Err(format!("directory \"{}\" can't have data", entry.archive_name));
+                }
+
+                let mut archive_name = entry.archive_name;
+                if !archive_name.ends_with('/') {
+                    archive_name.push('/');
+                }
+                Ok(ZipFileDescription {
+                    archive_name,
+                    source: Source::Directory,
+                    offset: 0,
+                    length: 0,
+                    crc: EntryCrc(Some(0)),
+                    last_modified: entry.last_modified,
+                    etag: None,
+                    metadata: entry.metadata,
+                    compression: EntryCompression::default(),
+                })
+            }
+        }
+    }
 }
 
-#[derive(Deserialize, Clone, Debug, Hash)]
-struct UpstreamResponse {
+impl ZipFileDescription {
+    /// Length of the entry's contents once extracted
+    fn uncompressed_length(&self) -> u64 {
+        match self.compression.0 {
+            Compression::Stored => self.length,
+            Compression::Deflate { uncompressed_len } | Compression::Aes { uncompressed_len, .. } => uncompressed_len,
+        }
+    }
+
+    /// Describe why the entry's `crc` doesn't fit its `length`, if it doesn't.
+    /// The CRC of empty data is always 0, and a CRC of 0 for other data is
+    /// much more likely to be a placeholder than the real value.
+    fn crc_inconsistency(&self) -> Option<&'static str> {
+        let length = self.uncompressed_length();
+        let crc = self.crc.0?;
+        if length == 0 && crc != 0 {
+            Some("nonzero crc for an empty entry")
+        } else if crc == 0 && length != 0 && length != UNKNOWN_LENGTH {
+            Some("zero crc for a non-empty entry")
+        } else {
+            None
+        }
+    }
+}
+
+/// Placeholder `length` of entries whose manifest doesn't give one. No real
+/// entry can be this long, since the archive length wouldn't fit in a `u64`.
+const UNKNOWN_LENGTH: u64 = u64::MAX;
+
+fn unknown_length() -> u64 {
+    UNKNOWN_LENGTH
+}
+
+/// A parsed and checked manifest
+#[derive(Clone, Debug)]
+pub struct UpstreamResponse {
     filename: String,
     entries: Vec<ZipFileDescription>,
+    /// Digest of `entries`, updated whenever they are filtered or changed
+    digest: EntriesDigest,
 }
 
-static KEEP_HEADERS: &[header::HeaderName] = &[
-    header::AUTHORIZATION,
-    header::COOKIE,
-    header::USER_AGENT,
-    header::REFERER,
+/// Headers of an upstream 304 Not Modified response that are passed on to the
+/// client: those a 304 must repeat from the 200 response it stands in for
+static NOT_MODIFIED_HEADERS: &[header::HeaderName] = &[
+    header::ETAG,
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::DATE,
+    header::EXPIRES,
+    header::VARY,
 ];
 
+/// Computes the ETag of an archive from the digest of its manifest's entries
+/// and the files zipstream adds to it.
+struct ETagHasher(ETagState);
+
+impl ETagHasher {
+    fn new(algorithm: ETagAlgorithm, filename: &str, num_entries: usize, digest: &EntriesDigest) -> ETagHasher {
+        let mut hasher = ETagState::new(algorithm);
+        filename.hash(&mut hasher);
+        num_entries.hash(&mut hasher);
+        digest.sum.hash(&mut hasher);
+        ETagHasher(hasher)
+    }
+
+    /// Add a file generated by zipstream rather than listed in the manifest
+    fn add_generated(&mut self, archive_path: &str, data: &[u8]) {
+        archive_path.hash(&mut self.0);
+        data.hash(&mut self.0);
+    }
+
+    fn finish(&self) -> String {
+        self.0.hex()
+    }
+}
+
+/// The sum of a digest of each entry of a manifest, which the ETag hashes.
+///
+/// Unlike hashing the entries in sequence, the sum doesn't depend on their
+/// order, so it is computed as the manifest is parsed, before the entries are
+/// sorted, and an entry can be taken out of it again when it is filtered out
+/// or changed.
+#[derive(Clone, Debug)]
+struct EntriesDigest {
+    algorithm: ETagAlgorithm,
+    /// Little-endian 64-bit limbs of a 256-bit sum
+    sum: [u64; 4],
+}
+
+impl EntriesDigest {
+    fn new(algorithm: ETagAlgorithm) -> EntriesDigest {
+        EntriesDigest { algorithm, sum: [0; 4] }
+    }
+
+    fn add(&mut self, entry: &ZipFileDescription) {
+        self.add_sum(self.entry_digest(entry));
+    }
+
+    fn remove(&mut self, entry: &ZipFileDescription) {
+        let entry_digest = self.entry_digest(entry);
+        let mut borrow = false;
+        for (limb, digest) in self.sum.iter_mut().zip(entry_digest) {
+            let (diff, b1) = limb.overflowing_sub(digest);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            *limb = diff;
+            borrow = b1 || b2;
+        }
+    }
+
+    /// Add the entries of another manifest's digest
+    fn merge(&mut self, other: &EntriesDigest) {
+        self.add_sum(other.sum);
+    }
+
+    fn add_sum(&mut self, sum: [u64; 4]) {
+        let mut carry = false;
+        for (limb, digest) in self.sum.iter_mut().zip(sum) {
+            let (total, c1) = limb.overflowing_add(digest);
+            let (total, c2) = total.overflowing_add(carry as u64);
+            *limb = total;
+            carry = c1 || c2;
+        }
+    }
+
+    fn entry_digest(&self, entry: &ZipFileDescription) -> [u64; 4] {
+        let mut hasher = ETagState::new(self.algorithm);
+        entry.hash(&mut hasher);
+        hasher.limbs()
+    }
+}
+
+/// A `Hasher` for ETags that gives the same result across releases and
+/// architectures, unlike `DefaultHasher`, so ETags cached by clients and CDNs
+/// stay valid when zipstream is upgraded or moved.
+#[derive(Clone)]
+enum ETagState {
+    Xxhash(Box<Xxh3>),
+    Sha256(Sha256),
+}
+
+impl ETagState {
+    fn new(algorithm: ETagAlgorithm) -> ETagState {
+        match algorithm {
+            ETagAlgorithm::Xxhash => ETagState::Xxhash(Box::new(Xxh3::new())),
+            ETagAlgorithm::Sha256 => ETagState::Sha256(Sha256::new()),
+        }
+    }
+
+    /// The full digest as little-endian 64-bit limbs, with XXH3 extended to
+    /// 128 bits so that sums of entry digests rarely collide
+    fn limbs(&self) -> [u64; 4] {
+        match self {
+            ETagState::Xxhash(h) => {
+                let digest = h.digest128();
+                [digest as u64, (digest >> 64) as u64, 0, 0]
+            }
+            ETagState::Sha256(h) => {
+                let digest = h.clone().finalize();
+                let limb = |i: usize| u64::from_le_bytes(<[u8; 8]>::try_from(&digest[i * 8..i * 8 + 8]).unwrap());
+                [limb(0), limb(1), limb(2), limb(3)]
+            }
+        }
+    }
+
+    /// The full digest in lowercase hex
+    fn hex(&self) -> String {
+        match self {
+            ETagState::Xxhash(h) => format!("{:016x}", h.digest()),
+            ETagState::Sha256(h) => h.clone().finalize().iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+}
+
+impl Hasher for ETagState {
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            ETagState::Xxhash(h) => h.update(bytes),
+            ETagState::Sha256(h) => h.update(bytes),
+        }
+    }
+
+    // The default methods write integers in native byte order and `usize` at
+    // native width, which would differ between architectures.
+    fn write_u16(&mut self, i: u16) { self.write(&i.to_le_bytes()) }
+    fn write_u32(&mut self, i: u32) { self.write(&i.to_le_bytes()) }
+    fn write_u64(&mut self, i: u64) { self.write(&i.to_le_bytes()) }
+    fn write_u128(&mut self, i: u128) { self.write(&i.to_le_bytes()) }
+    fn write_usize(&mut self, i: usize) { self.write_u64(i as u64) }
+    fn write_i16(&mut self, i: i16) { self.write(&i.to_le_bytes()) }
+    fn write_i32(&mut self, i: i32) { self.write(&i.to_le_bytes()) }
+    fn write_i64(&mut self, i: i64) { self.write(&i.to_le_bytes()) }
+    fn write_i128(&mut self, i: i128) { self.write(&i.to_le_bytes()) }
+    fn write_isize(&mut self, i: isize) { self.write_i64(i as i64) }
+
+    fn finish(&self) -> u64 {
+        match self {
+            ETagState::Xxhash(h) => h.digest(),
+            ETagState::Sha256(h) => {
+                let digest = h.clone().finalize();
+                u64::from_be_bytes(<[u8; 8]>::try_from(&digest[..8]).unwrap())
+            }
+        }
+    }
+}
+
+static DUPLICATE_SOURCES: AtomicU64 = AtomicU64::new(0);
+
+/// Total number of manifest entries that repeated the `source` of an earlier
+/// entry in the same manifest, and were thus fetched from S3 more than once.
+pub fn duplicate_sources() -> u64 {
+    DUPLICATE_SOURCES.load(Ordering::Relaxed)
+}
+
+/// Count the entries whose `source` and `offset` also appear in an earlier entry
+fn count_duplicate_sources(entries: &[ZipFileDescription]) -> usize {
+    let mut seen = HashSet::with_capacity(entries.len());
+    entries.iter()
+        .filter(|e| e.source != Source::Directory)
+        .filter(|e| !seen.insert((&e.source, e.offset)))
+        .count()
+}
+
+/// Find runs of consecutive entries that read adjacent ranges of the same S3
+/// object, returning the shared `S3ReadGroup` of each entry in such a run.
+fn read_groups(entries: &[ZipFileDescription], source: &Arc<dyn ObjectSource>) -> Vec<Option<Arc<S3ReadGroup>>> {
+    let adjacent = |a: &ZipFileDescription, b: &ZipFileDescription| a.source == b.source && a.offset + a.length == b.offset;
+
+    let mut groups = Vec::with_capacity(entries.len());
+    let mut start = 0;
+    while start < entries.len() {
+        let mut end = start + 1;
+        while end < entries.len() && adjacent(&entries[end - 1], &entries[end]) {
+            end += 1;
+        }
+
+        let group = match &entries[start].source {
+            Source::S3(url) if end - start > 1 => Some(S3ReadGroup::new(source.clone(), url.bucket.clone(), url.key.clone())),
+            _ => None,
+        };
+        groups.resize(end, group);
+        start = end;
+    }
+
+    groups
+}
+
+/// Remove `prefix` from the path of `path_and_query`, matching whole path
+/// segments so that `/dl` matches `/dl` and `/dl/foo` but not `/dlfoo`.
+/// A trailing slash on `prefix` is ignored.
+///
+/// If `merge_slashes` is set, empty path segments are removed from the path
+/// first, so `//dl//foo` is treated as `/dl/foo`.
+///
+/// Returns the remaining path and query, always starting with `/`, or `None`
+/// if the path does not match.
+fn strip_path_prefix(path_and_query: &str, prefix: &str, merge_slashes: bool) -> Option<String> {
+    let (path, query) = path_and_query.split_at(path_and_query.find('?').unwrap_or(path_and_query.len()));
+
+    let path = if merge_slashes {
+        let mut merged = String::with_capacity(path.len());
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            merged.push('/');
+            merged.push_str(segment);
+        }
+        if path.ends_with('/') {
+            merged.push('/');
+        }
+        merged
+    } else {
+        path.to_owned()
+    };
+
+    let rest = path.strip_prefix(prefix.trim_end_matches('/'))?;
+
+    if rest.is_empty() {
+        Some(format!("/{}", query))
+    } else if rest.starts_with('/') {
+        Some(format!("{}{}", rest, query))
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_strip_path_prefix() {
+    assert_eq!(strip_path_prefix("/dl/foo", "/dl", false).as_deref(), Some("/foo"));
+    assert_eq!(strip_path_prefix("/dl/foo", "/dl/", false).as_deref(), Some("/foo"));
+    assert_eq!(strip_path_prefix("/dl", "/dl", false).as_deref(), Some("/"));
+    assert_eq!(strip_path_prefix("/dl/", "/dl", false).as_deref(), Some("/"));
+    assert_eq!(strip_path_prefix("/dl?a=b", "/dl", false).as_deref(), Some("/?a=b"));
+    assert_eq!(strip_path_prefix("/dl/foo?a=/b", "/dl", false).as_deref(), Some("/foo?a=/b"));
+    assert_eq!(strip_path_prefix("/dlfoo", "/dl", false), None);
+    assert_eq!(strip_path_prefix("/other", "/dl", false), None);
+    assert_eq!(strip_path_prefix("/foo", "", false).as_deref(), Some("/foo"));
+
+    assert_eq!(strip_path_prefix("//dl//foo/", "/dl", false), None);
+    assert_eq!(strip_path_prefix("//dl//foo/", "/dl", true).as_deref(), Some("/foo/"));
+}
+
+/// Values allowed for the `compression` query parameter, which is passed to
+/// the upstream server in the `X-Zip-Stream-Compression` header so that it can
+/// choose between differently precompressed sources.
+const COMPRESSION_HINTS: &[&str] = &["fast", "best"];
+
+/// Read the `compression` query parameter of a request
+fn compression_hint(req: &Request<impl Body>) -> Result<Option<&'static str>, ErrorResponse> {
+    let query = req.uri().query().unwrap_or_default();
+    let Some(value) = query.split('&').find_map(|param| param.strip_prefix("compression=")) else {
+        return Ok(None);
+    };
+
+    match COMPRESSION_HINTS.iter().find(|&&hint| hint == value) {
+        Some(hint) => Ok(Some(hint)),
+        None => Err((StatusCode::BAD_REQUEST, format!("Invalid compression \"{}\", expected one of: {}", value, COMPRESSION_HINTS.join(", ")).into())),
+    }
+}
+
+/// Whether an archive uses zip64 extensions, chosen by the client with the
+/// `X-Zip-Stream-Zip64` header for compatibility with its zip reader
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum Zip64Mode {
+    /// Never, failing the request if the archive can't be written without them
+    Never,
+
+    /// Only where the archive needs them
+    #[default]
+    Auto,
+
+    /// In every header (see `ZipOptions::force_zip64`)
+    Always,
+}
+
+/// Read the `X-Zip-Stream-Zip64` header of a request
+fn zip64_mode(req: &Request<impl Body>) -> Result<Zip64Mode, ErrorResponse> {
+    let Some(value) = req.headers().get("X-Zip-Stream-Zip64") else {
+        return Ok(Zip64Mode::default());
+    };
+
+    match value.to_str().unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "never" => Ok(Zip64Mode::Never),
+        "auto" => Ok(Zip64Mode::Auto),
+        "always" => Ok(Zip64Mode::Always),
+        _ => Err((StatusCode::BAD_REQUEST, "Invalid X-Zip-Stream-Zip64, expected one of: never, auto, always".into())),
+    }
+}
+
+/// The password to encrypt the archive for a request with: that of its
+/// `X-Zip-Stream-Password` header if `Config::password_header` allows it,
+/// or else `Config::encryption_password`
+fn archive_password(config: &Config, req: &Request<impl Body>) -> Result<Option<Password>, ErrorResponse> {
+    let Some(value) = req.headers().get("X-Zip-Stream-Password") else {
+        return Ok(config.encryption_password.clone());
+    };
+
+    // Rejected rather than ignored, so that the archive isn't sent unencrypted
+    if !config.password_header {
+        return Err((StatusCode::BAD_REQUEST, "X-Zip-Stream-Password is not enabled".into()));
+    }
+    if value.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Empty X-Zip-Stream-Password".into()));
+    }
+    Ok(Some(Password::new(value.as_bytes())))
+}
+
+/// The `prefix` query parameter of a request, selecting the entries under it
+/// (see `strip_entry_prefix`), and whether `strip_prefix=true` asks for it to
+/// be removed from their names
+fn entry_prefix(req: &Request<impl Body>) -> Result<Option<(String, bool)>, ErrorResponse> {
+    let query = req.uri().query().unwrap_or_default();
+    let Some(value) = query.split('&').find_map(|param| param.strip_prefix("prefix=")) else {
+        return Ok(None);
+    };
+
+    let Ok(prefix) = percent_encoding::percent_decode_str(value).decode_utf8() else {
+        return Err((StatusCode::BAD_REQUEST, "Invalid prefix, expected percent-encoded UTF-8".into()));
+    };
+    let strip = query.split('&').any(|param| param == "strip_prefix=true");
+
+    Ok(Some((prefix.into_owned(), strip)))
+}
+
+/// Remove `prefix` from `archive_name`, matching whole path components so
+/// that `docs` and `docs/` match `docs` and `docs/a.txt` but not `docs2/c.txt`.
+/// Returns the rest of the name without its leading slash, or `None` if it
+/// isn't under the prefix.
+fn strip_entry_prefix<'a>(archive_name: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = archive_name.strip_prefix(prefix)?;
+    if prefix.is_empty() || rest.is_empty() || rest.starts_with('/') {
+        Some(rest.trim_start_matches('/'))
+    } else {
+        None
+    }
+}
+
+/// Keep only the entries under the request's `prefix`, if it has one, and
+/// remove the prefix from their names if asked. With the prefix removed, an
+/// entry named exactly the prefix, such as its directory, is dropped.
+fn filter_prefix(req: &Request<impl Body>, res: &mut UpstreamResponse) -> Result<(), ErrorResponse> {
+    let Some((prefix, strip)) = entry_prefix(req)? else {
+        return Ok(());
+    };
+
+    let UpstreamResponse { entries, digest, .. } = res;
+    let total = entries.len();
+    entries.retain(|e| strip_entry_prefix(&e.archive_name, &prefix).is_some() || { digest.remove(e); false });
+
+    if strip {
+        for entry in entries.iter_mut() {
+            digest.remove(entry);
+            entry.archive_name = strip_entry_prefix(&entry.archive_name, &prefix).unwrap_or_default().to_owned();
+            digest.add(entry);
+
+            // A later component can start with a drive letter, like `C:` in `docs/C:/x`
+            if let Some(reason) = path_traversal_reason(&entry.archive_name) {
+                return Err((StatusCode::BAD_REQUEST, format!("{} after removing the prefix: \"{}\"", reason, entry.archive_name).into()));
+            }
+        }
+        entries.retain(|e| !e.archive_name.is_empty() || { digest.remove(e); false });
+    }
+
+    info!("Selected {} of {} entries with prefix {:?}", entries.len(), total, prefix);
+    Ok(())
+}
+
+/// Keep only the entries last modified at or after the request's `since`
+/// query parameter, an RFC 3339 timestamp, if it has one
+fn filter_since(req: &Request<impl Body>, res: &mut UpstreamResponse) -> Result<(), ErrorResponse> {
+    let query = req.uri().query().unwrap_or_default();
+    let Some(value) = query.split('&').find_map(|param| param.strip_prefix("since=")) else {
+        return Ok(());
+    };
+
+    let since = percent_encoding::percent_decode_str(value).decode_utf8().ok()
+        .and_then(|value| DateTime::parse_from_rfc3339(&value).ok())
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("Invalid since \"{}\", expected an RFC 3339 timestamp", value).into()))?;
+
+    let UpstreamResponse { entries, digest, .. } = res;
+    let total = entries.len();
+    entries.retain(|e| e.last_modified >= since || { digest.remove(e); false });
+
+    info!("Selected {} of {} entries modified since {}", entries.len(), total, since);
+    Ok(())
+}
+
 /// Modify a client request into an upstream request
-pub fn request(config: &Config, req: &Request<body::Incoming>) -> Result<Request<http_body_util::Empty<Bytes>>, (StatusCode, &'static str)> {
-    if req.method() != Method::GET {
-        return Err((StatusCode::METHOD_NOT_ALLOWED, "Only GET requests allowed"))
+pub fn request(config: &Config, req: &Request<impl Body>) -> Result<Request<http_body_util::Empty<Bytes>>, ErrorResponse> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Err((StatusCode::METHOD_NOT_ALLOWED, "Only GET and HEAD requests allowed".into()))
+    }
+
+    let req_path = req.uri().path_and_query().expect("request URL should have path").as_str();
+
+    let Some(path) = strip_path_prefix(req_path, &config.strip_prefix, config.merge_slashes) else {
+        return Err((StatusCode::NOT_FOUND, "Not found".into()))
+    };
+
+    upstream_request(config, req, &path)
+}
+
+/// Paths of the manifests to merge into one archive, from the `m` query
+/// parameters of a request. Empty unless `Config::merge_manifests` is set.
+pub fn merge_manifest_paths(config: &Config, req: &Request<impl Body>) -> Result<Vec<String>, ErrorResponse> {
+    if !config.merge_manifests {
+        return Ok(Vec::new());
     }
 
-    let mut new_req = Request::builder().uri({
-        let req_path = req.uri().path_and_query().expect("request URL should have path").as_str();
+    let query = req.uri().query().unwrap_or_default();
+    query.split('&').filter_map(|param| param.strip_prefix("m=")).map(|param| {
+        let path = percent_decode_str(param).decode_utf8()
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Manifest path is not valid UTF-8".into()))?;
 
-        if !req_path.starts_with(&config.strip_prefix) {
-            return Err((StatusCode::NOT_FOUND, "Not found"))
+        if !path.starts_with('/') {
+            return Err((StatusCode::BAD_REQUEST, format!("Manifest path \"{}\" must start with /", path).into()));
+        }
+        // Decoding may have revealed them, where the primary path can't have any
+        if path.split(['/', '?']).any(|segment| segment == "." || segment == "..") {
+            return Err((StatusCode::BAD_REQUEST, format!("Manifest path \"{}\" has a . or .. segment", path).into()));
         }
+        Ok(path.into_owned())
+    }).collect()
+}
+
+/// Bytes of a decoded manifest path that are percent-encoded again in its
+/// upstream request, since they aren't allowed in a URI
+const MANIFEST_PATH_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'<').add(b'>').add(b'`').add(b'{').add(b'}');
+
+/// Create an upstream request for one of the manifests to merge, at `path`,
+/// which is handled like the path of a request: `config` must be that of the
+/// route selected for it, and its `strip_prefix` is removed
+pub fn manifest_request(config: &Config, req: &Request<impl Body>, path: &str) -> Result<Request<http_body_util::Empty<Bytes>>, ErrorResponse> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Err((StatusCode::METHOD_NOT_ALLOWED, "Only GET and HEAD requests allowed".into()))
+    }
 
-        format!("{}{}", config.upstream, &req_path[config.strip_prefix.len()..]).parse::<Uri>().unwrap()
-    }).header("X-Via-Zip-Stream", config.via_zip_stream_header_value.clone());
+    let Some(path) = strip_path_prefix(path, &config.strip_prefix, config.merge_slashes) else {
+        return Err((StatusCode::NOT_FOUND, "Not found".into()))
+    };
+
+    upstream_request(config, req, &utf8_percent_encode(&path, MANIFEST_PATH_ENCODE_SET).to_string())
+}
+
+/// Create a request for `path` on the upstream server, forwarding the
+/// relevant headers of the client request
+fn upstream_request(config: &Config, req: &Request<impl Body>, path: &str) -> Result<Request<http_body_util::Empty<Bytes>>, ErrorResponse> {
+    let uri = format!("{}{}", config.upstream, path).parse::<Uri>()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid path".into()))?;
 
-    for header in KEEP_HEADERS {
+    // Always a GET, even for a HEAD request, because the headers of an
+    // archive are computed from the manifest in the body
+    let mut new_req = Request::builder().uri(uri)
+        .header("X-Via-Zip-Stream", config.via_zip_stream_header_value.clone());
+
+    if let Some(hint) = compression_hint(req)? {
+        new_req = new_req.header("X-Zip-Stream-Compression", hint);
+    }
+
+    for header in &config.forward_headers {
         if let Some(value) = req.headers().get(header) {
             new_req = new_req.header(header, value);
         }
@@ -60,47 +841,2996 @@ pub fn request(config: &Config, req: &Request<body::Incoming>) -> Result<Request
     Ok(new_req.body(http_body_util::Empty::<Bytes>::new()).unwrap())
 }
 
-/// Parse an upstream JSON response and produce a streaming zip file response
-pub fn response(client: s3::Client, req: &Request<body::Incoming>, response_body: Bytes) -> Result<Response<impl Body<Data=Bytes, Error=BoxError>>, (StatusCode, &'static str)> {
-    let mut res: UpstreamResponse = serde_json::from_slice(&response_body[..]).map_err(|e| {
-        error!("Invalid upstream response JSON: {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to parse upstream request")
-    })?;
-    
-    drop(response_body);
+/// Answer with 304 Not Modified for an upstream 304, such as to a conditional
+/// request, which has no manifest to build an archive from
+pub fn not_modified_response(upstream: &Response<impl Body>) -> Response<ResponseBody> {
+    let mut res = Response::builder().status(StatusCode::NOT_MODIFIED);
 
-    res.entries.sort();
+    for header in NOT_MODIFIED_HEADERS {
+        if let Some(value) = upstream.headers().get(header) {
+            res = res.header(header, value);
+        }
+    }
 
-    let etag = {
-        //TODO: use a hash function that is stable across releases and architectures
-        let mut hasher = std::collections::hash_map::DefaultHasher::new();
-        res.hash(&mut hasher);
-        format!("{:x}", hasher.finish())
-    };
-    
-    let entries: Vec<ZipEntry> = res.entries.into_iter().map(|file| {
-        ZipEntry {
-            archive_path: file.archive_name,
-            crc: file.crc,
-            data: Box::new(S3Object { 
-                client: client.clone(),
-                bucket: file.source.bucket,
-                key: file.source.key,
-                len: file.length
-            }),
-            last_modified: file.last_modified,
+    res.body(empty_body()).unwrap()
+}
+
+/// Whether a proxied upstream response should be buffered so that Range
+/// requests can be served from it: it must be a complete `200 OK` response
+/// with a Content-Length no larger than `Config::passthrough_range_limit`.
+pub fn should_buffer_passthrough(config: &Config, res: &Response<impl Body>) -> bool {
+    let Some(limit) = config.passthrough_range_limit else { return false };
+
+    let len = res.headers().get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    res.status() == StatusCode::OK && len.is_some_and(|len| len <= limit)
+}
+
+/// Serve a buffered upstream response, handling Range requests for it.
+///
+/// The upstream headers are preserved, including repeated ones such as
+/// `Set-Cookie`, except for those describing the body framing and range,
+/// which are computed by `hyper_response`.
+pub fn buffered_passthrough(config: &Config, req: &Request<impl Body>, upstream: hyper::http::response::Parts, body: Bytes) -> Response<impl Body<Data=Bytes, Error=BoxError>> {
+    let content_type = upstream.headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("application/octet-stream");
+    let etag = upstream.headers.get(header::ETAG).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let last_modified = upstream.headers.get(header::LAST_MODIFIED)
+        .and_then(|v| DateTime::parse_from_rfc2822(v.to_str().ok()?).ok())
+        .map(|t| t.with_timezone(&Utc));
+
+    let mut res = hyper_response(config, req, content_type, etag, last_modified, "", &body);
+
+    let headers = res.headers_mut();
+    headers.remove(header::ETAG);
+    headers.remove(header::CONTENT_DISPOSITION);
+
+    let computed = [header::CONTENT_LENGTH, header::TRANSFER_ENCODING, header::CONTENT_RANGE, header::ACCEPT_RANGES];
+    for name in upstream.headers.keys().filter(|name| !computed.contains(name)) {
+        headers.remove(name);
+    }
+    for (name, value) in upstream.headers.iter().filter(|(name, _)| !computed.contains(name)) {
+        headers.append(name, value.clone());
+    }
+
+    res
+}
+
+/// Archive path of the generated listing added by `Config::contents_listing`
+const CONTENTS_LISTING_PATH: &str = "_contents.txt";
+
+/// Generate a plain text listing of the archive entries, one per line with
+/// tab-separated path, length, and modification time.
+fn contents_listing(entries: &[ZipEntry]) -> Bytes {
+    let mut listing = String::new();
+    for entry in entries {
+        listing += &format!(
+            "{}\t{}\t{}\n",
+            entry.archive_path,
+            entry.uncompressed_len(),
+            entry.last_modified.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+    }
+    listing.into()
+}
+
+/// Archive path of the generated index added by `Config::index_json`
+const INDEX_JSON_PATH: &str = "index.json";
+
+/// Generate a JSON description of the archive, with its filename and the
+/// path, length, CRC and modification time of each entry.
+fn index_json(filename: &str, entries: &[ZipEntry]) -> Bytes {
+    let entries: Vec<_> = entries.iter().map(|entry| serde_json::json!({
+        "path": entry.archive_path,
+        "length": entry.uncompressed_len(),
+        "crc": entry.crc,
+        "last_modified": entry.last_modified.to_rfc3339_opts(SecondsFormat::Secs, true),
+    })).collect();
+
+    serde_json::to_vec(&serde_json::json!({
+        "filename": filename,
+        "entry_count": entries.len(),
+        "entries": entries,
+    })).unwrap().into()
+}
+
+/// A zip entry for a file generated by zipstream. Its modification time is
+/// that of the newest entry so the archive remains reproducible.
+fn generated_entry(archive_path: &str, data: Bytes, entries: &[ZipEntry]) -> ZipEntry {
+    ZipEntry {
+        archive_path: archive_path.into(),
+        crc: Some(crc32fast::hash(&data)),
+        data: Box::new(data),
+        last_modified: entries.iter().map(|e| e.last_modified).max().unwrap_or(DateTime::UNIX_EPOCH),
+        is_directory: false,
+        comment: String::new(),
+        compression: Compression::Stored,
+    }
+}
+
+/// Identifier of the request being served, added to the request extensions
+/// so it can be embedded in the archive.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Expand `Config::comment_template`, replacing `{request_id}` and `{time}`,
+/// and truncate the result to the maximum zip comment length.
+fn archive_comment(template: &str, request_id: &str, now: DateTime<Utc>) -> String {
+    let mut comment = template
+        .replace("{request_id}", request_id)
+        .replace("{time}", &now.to_rfc3339_opts(SecondsFormat::Secs, true));
+
+    if comment.len() > 0xFFFF {
+        let mut end = 0xFFFF;
+        while !comment.is_char_boundary(end) {
+            end -= 1;
         }
-    }).collect();
+        comment.truncate(end);
+    }
 
-    let num_entries = entries.len();
+    comment
+}
 
-    let stream = zip_stream(entries, ZipOptions::default());
+/// Expand `Config::filename_template` into the filename of the archive in the
+/// `Content-Disposition` header. `{filename}` is replaced last, so that a
+/// manifest filename containing a placeholder is kept as it is. Path
+/// separators become `_`, and a template that expands to nothing falls back
+/// to the manifest filename.
+fn archive_filename(template: &str, filename: &str, date: DateTime<Utc>, count: usize, size: u64, etag: &str) -> String {
+    let name: String = template
+        .replace("{date}", &date.format("%Y-%m-%d").to_string())
+        .replace("{count}", &count.to_string())
+        .replace("{size}", &size.to_string())
+        .replace("{etag}", etag)
+        .replace("{filename}", filename)
+        .chars()
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect();
 
-    info!(
-        zipstream.entries = num_entries,
-        "Streaming zip file {}: {} entries, {} bytes", res.filename, num_entries, stream.len()
-    );
+    if name.trim().is_empty() { filename.to_owned() } else { name }
+}
+
+/// Metadata of an S3 object returned by HeadObject
+#[derive(Clone, Debug, PartialEq)]
+struct HeadInfo {
+    content_length: Option<u64>,
+    last_modified: Option<DateTime<Utc>>,
+    etag: Option<String>,
+}
+
+type HeadError = Box<s3::error::SdkError<s3::operation::head_object::HeadObjectError>>;
+
+async fn head(client: &s3::Client, source: &S3Url) -> Result<HeadInfo, HeadError> {
+    let head = client.head_object()
+        .bucket(&source.bucket)
+        .key(&source.key)
+        .send().await
+        .map_err(Box::new)?;
 
-    Ok(hyper_response(req, "application/zip", &etag, &res.filename, &stream))
+    Ok(HeadInfo {
+        content_length: head.content_length.and_then(|len| u64::try_from(len).ok()),
+        last_modified: head.last_modified.and_then(|t| DateTime::from_timestamp(t.secs(), t.subsec_nanos())),
+        etag: head.e_tag,
+    })
 }
 
+/// Make a HeadObject request for each of `sources`, with at most
+/// `concurrency` in flight at once. The results are in the order of `sources`.
+async fn head_all(client: &s3::Client, sources: &[&S3Url], concurrency: usize) -> Vec<Result<HeadInfo, HeadError>> {
+    // Collect the futures first rather than mapping the stream, because the
+    // closure's higher-ranked lifetime otherwise defeats the `Send` inference
+    // for the request future (rust-lang/rust#102211).
+    let heads: Vec<_> = sources.iter().enumerate()
+        .map(|(i, source)| async move { (i, head(client, source).await) })
+        .collect();
+
+    let mut results: Vec<_> = stream::iter(heads)
+        .buffer_unordered(concurrency.max(1))
+        .collect().await;
+
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Check the entries with S3 sources against the metadata of their objects:
+///
+/// * With `Config::s3_last_modified`, replace the `last_modified` of each entry
+///   with the LastModified time of its object. URL sources keep the manifest's
+///   time, since a presigned GET URL can't be used for HEAD.
+///
+/// * With `Config::verify_s3_etag`, reject the manifest if an entry's `etag`
+///   doesn't match the ETag of its object, which means the object was replaced
+///   after the manifest was generated.
+///
+/// * With `Config::resolve_lengths`, set the length of entries without one to
+///   the size of their object after `offset`.
+async fn fetch_s3_metadata(config: &Config, client: &s3::Client, res: &mut UpstreamResponse) -> Result<(), ErrorResponse> {
+    let UpstreamResponse { entries, digest, .. } = res;
+    let needs_head = |entry: &ZipFileDescription| {
+        matches!(entry.source, Source::S3(_)) && (
+            config.s3_last_modified ||
+            (config.verify_s3_etag && entry.etag.is_some()) ||
+            entry.length == UNKNOWN_LENGTH
+        )
+    };
+
+    let sources: Vec<&S3Url> = entries.iter()
+        .filter(|entry| needs_head(entry))
+        .filter_map(|entry| match &entry.source { Source::S3(url) => Some(url), Source::Url(_) | Source::File(_) | Source::Directory => None })
+        .collect();
+
+    let heads = head_all(client, &sources, config.head_concurrency).await;
+
+    let head_entries = entries.iter_mut().filter(|entry| needs_head(entry));
+    for (entry, head) in head_entries.zip(heads) {
+        let archive_name = &entry.archive_name;
+        let failed = || (StatusCode::BAD_GATEWAY, format!("Failed to read S3 metadata for {}", archive_name).into());
+
+        let head = head.map_err(|e| {
+            error!("S3 HeadObject for {} failed: {}", entry.source, Report(e));
+            failed()
+        })?;
+
+        if let (true, Some(expected)) = (config.verify_s3_etag, &entry.etag) {
+            // S3 returns the ETag quoted, but manifests may list it either way
+            if head.etag.as_deref().map(|etag| etag.trim_matches('"')) != Some(expected.trim_matches('"')) {
+                error!("S3 object {} has ETag {:?}, but the manifest expects {}", entry.source, head.etag, expected);
+                return Err((StatusCode::BAD_GATEWAY, format!("S3 object for {} has changed", entry.archive_name).into()));
+            }
+        }
+
+        // The entry is hashed with the metadata it has from S3
+        digest.remove(entry);
+
+        if entry.length == UNKNOWN_LENGTH {
+            entry.length = head.content_length
+                .and_then(|len| len.checked_sub(entry.offset))
+                .ok_or_else(|| {
+                    error!("S3 object {} has length {:?}, which can't contain offset {}", entry.source, head.content_length, entry.offset);
+                    failed()
+                })?;
+        }
+
+        if config.s3_last_modified {
+            entry.last_modified = head.last_modified.ok_or_else(|| {
+                error!("S3 HeadObject for {} returned no LastModified", entry.source);
+                failed()
+            })?;
+        }
+
+        digest.add(entry);
+    }
+
+    Ok(())
+}
+
+/// Whether the S3 credentials provider can currently resolve credentials that
+/// haven't expired, so that failures to refresh them are noticed before
+/// downloads fail
+pub async fn s3_credentials_valid(provider: Option<&s3::config::SharedCredentialsProvider>) -> bool {
+    use s3::config::ProvideCredentials;
+
+    let Some(provider) = provider else {
+        warn!("No S3 credentials provider configured");
+        return false;
+    };
+
+    match provider.provide_credentials().await {
+        Ok(credentials) if credentials.expiry().is_some_and(|expiry| expiry <= SystemTime::now()) => {
+            warn!("S3 credentials expired");
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            error!("Failed to resolve S3 credentials: {}", Report(e));
+            false
+        }
+    }
+}
+
+/// Parse and validate a manifest from the upstream server
+fn parse_manifest(config: &Config, body: &[u8]) -> Result<UpstreamResponse, ErrorResponse> {
+    let _span = info_span!("manifest_parse", http.request.body.size = body.len()).entered();
+
+    parse_manifest_from(config, &mut serde_json::Deserializer::from_slice(body))
+}
+
+/// Parse and validate a manifest as `de` reads it. Each entry is checked,
+/// normalized and added to the digest as soon as it is parsed (see
+/// `EntriesParser`), so the JSON is never held whole.
+fn parse_manifest_from<'de, R: serde_json::de::Read<'de>>(config: &Config, de: &mut serde_json::Deserializer<R>) -> Result<UpstreamResponse, ErrorResponse> {
+    let mut parser = EntriesParser {
+        config,
+        entries: Vec::new(),
+        digest: EntriesDigest::new(config.etag_algorithm),
+        original_names: Vec::new(),
+        rejected: None,
+    };
+
+    let filename = de::Deserializer::deserialize_map(&mut *de, ManifestVisitor(&mut parser)).and_then(|filename| {
+        de.end()?;
+        Ok(filename)
+    });
+    let filename = match (filename, parser.rejected) {
+        (_, Some(rejected)) => return Err(rejected),
+        (Ok(filename), None) => filename,
+        // Only the gzip decoder fails to read
+        (Err(e), None) if e.is_io() => return Err(invalid_gzip(e)),
+        (Err(e), None) => return Err(invalid_manifest_json(e)),
+    };
+
+    let mut entries = parser.entries;
+    entries.shrink_to_fit();
+
+    if config.name_normalization != NameNormalization::Keep {
+        check_normalized_collisions(&parser.original_names, &entries)?;
+    }
+
+    Ok(UpstreamResponse { filename, entries, digest: parser.digest })
+}
+
+fn invalid_manifest_json(e: serde_json::Error) -> ErrorResponse {
+    error!("Invalid upstream response JSON: {}", e);
+    (StatusCode::BAD_GATEWAY, "Upstream manifest is not valid JSON".into())
+}
+
+/// Fields of a manifest
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum ManifestField {
+    Filename,
+    Entries,
+    #[serde(other)]
+    Other,
+}
+
+/// Deserializes a manifest object, giving its `filename` and passing its
+/// `entries` to an `EntriesParser`
+struct ManifestVisitor<'a, 'b>(&'b mut EntriesParser<'a>);
+
+impl<'de> de::Visitor<'de> for ManifestVisitor<'_, '_> {
+    type Value = String;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a manifest")
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<String, A::Error> {
+        let mut filename = None;
+        let mut has_entries = false;
+
+        while let Some(field) = map.next_key()? {
+            match field {
+                ManifestField::Filename if filename.is_some() => return Err(de::Error::duplicate_field("filename")),
+                ManifestField::Filename => filename = Some(map.next_value()?),
+                ManifestField::Entries if has_entries => return Err(de::Error::duplicate_field("entries")),
+                ManifestField::Entries => {
+                    map.next_value_seed(&mut *self.0)?;
+                    has_entries = true;
+                }
+                ManifestField::Other => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+
+        if !has_entries {
+            return Err(de::Error::missing_field("entries"));
+        }
+        filename.ok_or_else(|| de::Error::missing_field("filename"))
+    }
+}
+
+/// Parses the `entries` of a manifest one at a time, checking and normalizing
+/// each (see `check_entry`) and adding it to the digest before parsing the
+/// next, so that only the checked entries are kept.
+struct EntriesParser<'a> {
+    config: &'a Config,
+    entries: Vec<ZipFileDescription>,
+    digest: EntriesDigest,
+    /// Names as the manifest gives them, with `Config::name_normalization`,
+    /// to find those that became equal
+    original_names: Vec<String>,
+    /// Why an entry was rejected. Parsing stops with a JSON error, and this is
+    /// the response instead.
+    rejected: Option<ErrorResponse>,
+}
+
+impl<'de> de::DeserializeSeed<'de> for &mut EntriesParser<'_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> de::Visitor<'de> for &mut EntriesParser<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of manifest entries")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(entry) = seq.next_element::<ManifestEntry>()? {
+            let mut entry = match ZipFileDescription::try_from(entry) {
+                Ok(entry) => entry,
+                Err(message) => {
+                    error!("Upstream response contains invalid entry: {}", message);
+                    let e = de::Error::custom(&message);
+                    self.rejected = Some(invalid_manifest(message));
+                    return Err(e);
+                }
+            };
+
+            if self.config.name_normalization != NameNormalization::Keep {
+                self.original_names.push(entry.archive_name.clone());
+            }
+
+            if let Err(rejected) = check_entry(self.config, &mut entry) {
+                let e = de::Error::custom(&rejected.1);
+                self.rejected = Some(rejected);
+                return Err(e);
+            }
+
+            self.digest.add(&entry);
+            self.entries.push(entry);
+        }
+        Ok(())
+    }
+}
+
+/// Number of body chunks read ahead of the parser by `read_manifest`
+const MANIFEST_CHUNKS_BUFFERED: usize = 4;
+
+/// Like `parse_manifest`, but parse the body as it arrives, undoing its
+/// `Content-Encoding`, so that the JSON of a huge manifest is never held in
+/// memory whole alongside the parsed entries. The parser runs on a blocking
+/// thread, reading chunks of the body from a channel.
+pub async fn read_manifest<B>(config: &Config, content_encoding: Option<&header::HeaderValue>, mut body: B) -> Result<UpstreamResponse, ErrorResponse>
+where
+    B: Body<Data = Bytes> + Send + Unpin,
+    B::Error: Into<BoxError>,
+{
+    use http_body_util::BodyExt;
+
+    let gzip = match content_encoding.map(|v| v.to_str().unwrap_or_default().trim()) {
+        None | Some("") => false,
+        Some(encoding) if encoding.eq_ignore_ascii_case("identity") => false,
+        Some(encoding) if encoding.eq_ignore_ascii_case("gzip") || encoding.eq_ignore_ascii_case("x-gzip") => true,
+        Some(encoding) => return Err(unsupported_encoding(encoding)),
+    };
+
+    let span = info_span!("manifest_parse", http.request.body.size = tracing::field::Empty);
+    let (tx, rx) = tokio::sync::mpsc::channel(MANIFEST_CHUNKS_BUFFERED);
+    let parser_span = span.clone();
+    let parser_config = config.clone();
+    let parser = tokio::task::spawn_blocking(move || {
+        let _span = parser_span.entered();
+        let reader = io::BufReader::new(ChannelReader { rx, chunk: Bytes::new() });
+        if gzip {
+            let reader = io::BufReader::new(GzipReader::new(reader));
+            parse_manifest_from(&parser_config, &mut serde_json::Deserializer::from_reader(reader))
+        } else {
+            parse_manifest_from(&parser_config, &mut serde_json::Deserializer::from_reader(reader))
+        }
+    });
+
+    let mut size = 0;
+    let read: Result<(), BoxError> = async {
+        loop {
+            // The parser stops at the first error, without waiting for the rest
+            let frame = tokio::select! {
+                frame = body.frame() => frame,
+                _ = tx.closed() => break,
+            };
+            let Some(frame) = frame else { break };
+
+            if let Ok(data) = frame.map_err(Into::into)?.into_data() {
+                size += data.len();
+                if tx.send(data).await.is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }.await;
+    drop(tx);
+    span.record("http.request.body.size", size);
+
+    let parsed = parser.await.expect("manifest parser panicked");
+    if let Err(e) = read {
+        error!("Failed to read upstream body: {}", Report(&*e));
+        return Err((StatusCode::BAD_GATEWAY, "Upstream request failed".into()));
+    }
+
+    parsed
+}
+
+/// Validate and normalize an entry of a manifest as it is parsed
+fn check_entry(config: &Config, entry: &mut ZipFileDescription) -> Result<(), ErrorResponse> {
+    if entry.archive_name.contains('\\') {
+        match config.backslashes {
+            Backslashes::Normalize => entry.archive_name = entry.archive_name.replace('\\', "/"),
+            Backslashes::Reject => {
+                error!("Upstream response contains archive_name with a backslash");
+                return Err(invalid_manifest(format!(
+                    "archive_name contains a backslash: \"{}\"", entry.archive_name
+                )));
+            }
+        }
+    }
+
+    match config.name_normalization {
+        NameNormalization::Keep => {}
+        NameNormalization::Nfc => entry.archive_name = entry.archive_name.nfc().collect(),
+        NameNormalization::Lowercase => entry.archive_name = entry.archive_name.nfc().collect::<String>().to_lowercase(),
+    }
+
+    if entry.length == UNKNOWN_LENGTH {
+        let supported = config.resolve_lengths && matches!(entry.source, Source::S3(_));
+        if !supported {
+            error!("Upstream response contains entry without length");
+            return Err(invalid_manifest(format!(
+                "length is required for \"{}\"", entry.archive_name
+            )));
+        }
+    }
+
+    if let Source::File(path) = &entry.source {
+        if !file_source_allowed(config, path) {
+            error!("Upstream response contains file:// source outside --file-sources-under");
+            return Err(invalid_manifest(format!(
+                "file source not allowed for \"{}\"", entry.archive_name
+            )));
+        }
+    }
+
+    if entry.archive_name.is_empty() {
+        error!("Upstream response contains empty archive_name");
+        return Err(invalid_manifest("archive_name must not be empty".into()));
+    }
+
+    // After backslashes are normalized, so `..\` is caught as well
+    if let Some(reason) = path_traversal_reason(&entry.archive_name) {
+        error!("Upstream response contains archive_name outside the extraction directory");
+        return Err(invalid_manifest(format!(
+            "{}: \"{}\"", reason, entry.archive_name
+        )));
+    }
+
+    if let Some(reason) = entry.metadata.invalid_reason() {
+        error!("Upstream response contains invalid metadata");
+        return Err(invalid_manifest(format!(
+            "{} in \"{}\"", reason, entry.archive_name
+        )));
+    }
+
+    // The hint precedes the data, so the CRC can't be computed first
+    if entry.crc.0.is_none() && config.central_directory_hint {
+        error!("Upstream response contains entry without crc, required by --central-directory-hint");
+        return Err(invalid_manifest(format!(
+            "crc is required with the central directory hint for \"{}\"", entry.archive_name
+        )));
+    }
+
+    if let Some(reason) = entry.crc_inconsistency() {
+        if config.strict_manifest {
+            error!("Upstream response contains entry with {}", reason);
+            return Err(invalid_manifest(format!(
+                "{} \"{}\"", reason, entry.archive_name
+            )));
+        }
+        warn!("Upstream response contains entry with {}", reason);
+    }
+
+    // Longer names don't fit the 16-bit length field of the zip headers
+    let max_path_length = config.max_path_length.min(u16::MAX as usize);
+    if entry.archive_name.len() > max_path_length {
+        error!("Upstream response contains archive_name longer than {} bytes", max_path_length);
+        let name: String = entry.archive_name.chars().take(64).collect();
+        return Err(invalid_manifest(format!(
+            "archive_name exceeds maximum length of {} bytes: \"{}...\"", max_path_length, name
+        )));
+    }
+
+    Ok(())
+}
+
+/// A manifest the upstream server sent that can't be served, which is the
+/// upstream's fault rather than the client's
+fn invalid_manifest(message: String) -> ErrorResponse {
+    (StatusCode::BAD_GATEWAY, message.into())
+}
+
+/// Whether a `file://` source may be read: only if it is under
+/// `Config::file_sources_under`, without `..` components that could leave it
+fn file_source_allowed(config: &Config, path: &Path) -> bool {
+    config.file_sources_under.as_ref().is_some_and(|root| {
+        path.starts_with(root) && !path.components().any(|c| c == Component::ParentDir)
+    })
+}
+
+/// Describe why an `archive_name` would be extracted outside the directory
+/// the archive is extracted into (a "zip slip"), if it would
+fn path_traversal_reason(archive_name: &str) -> Option<&'static str> {
+    let bytes = archive_name.as_bytes();
+    if archive_name.starts_with('/') {
+        Some("archive_name must be a relative path")
+    } else if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        Some("archive_name must not start with a drive letter")
+    } else if archive_name.split('/').any(|component| component == "..") {
+        Some("archive_name must not contain \"..\" components")
+    } else {
+        None
+    }
+}
+
+/// Reject entries whose different `archive_name`s became the same when
+/// normalized, which would overwrite each other when extracted
+fn check_normalized_collisions(original_names: &[String], entries: &[ZipFileDescription]) -> Result<(), ErrorResponse> {
+    let mut seen = HashMap::with_capacity(entries.len());
+    for (original, entry) in original_names.iter().zip(entries) {
+        match seen.insert(&entry.archive_name, original) {
+            Some(other) if other != original => {
+                error!("Upstream response contains archive_names that collide after normalization");
+                return Err(invalid_manifest(format!(
+                    "archive_names \"{}\" and \"{}\" are the same after normalization", other, original
+                )));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Reject entries with the same `archive_name`, which extraction tools handle
+/// inconsistently: some overwrite one with the other, some fail
+fn check_duplicate_names(entries: &mut [ZipFileDescription]) -> Result<(), ErrorResponse> {
+    // Sorting by `archive_name` makes duplicates adjacent. `stream_archive`
+    // sorts again in the configured order.
+    entries.sort();
+
+    if let Some(pair) = entries.windows(2).find(|pair| pair[0].archive_name == pair[1].archive_name) {
+        error!("Upstream response contains duplicate archive_name {}", pair[0].archive_name);
+        return Err((StatusCode::CONFLICT, format!("Manifest has more than one entry for archive_name \"{}\"", pair[0].archive_name).into()));
+    }
+    Ok(())
+}
+
+/// Reject a file entry whose `archive_name` is also a directory in the path of
+/// another entry, such as `foo` and `foo/bar.txt`. A filesystem can't have
+/// both, so extractors either fail or overwrite one with the other.
+fn check_file_directory_conflicts(entries: &[ZipFileDescription]) -> Result<(), ErrorResponse> {
+    let files: HashSet<&str> = entries.iter()
+        .filter(|e| e.source != Source::Directory)
+        .map(|e| e.archive_name.as_str())
+        .collect();
+
+    for entry in entries {
+        let name = &entry.archive_name;
+        let conflict = name.match_indices('/').map(|(i, _)| &name[..i]).find(|dir| files.contains(dir));
+        if let Some(file) = conflict {
+            error!("Upstream response contains file {} that is also a directory of {}", file, name);
+            return Err((StatusCode::CONFLICT, format!(
+                "archive_name \"{}\" is both a file and a directory of \"{}\"", file, name
+            ).into()));
+        }
+    }
+    Ok(())
+}
+
+fn invalid_gzip(e: impl fmt::Display) -> ErrorResponse {
+    error!("Failed to decompress upstream manifest: {}", e);
+    (StatusCode::BAD_GATEWAY, "Upstream manifest is not valid gzip".into())
+}
+
+fn unsupported_encoding(encoding: &str) -> ErrorResponse {
+    error!("Upstream manifest has unsupported Content-Encoding {:?}", encoding);
+    (StatusCode::BAD_GATEWAY, "Upstream manifest has an unsupported Content-Encoding".into())
+}
+
+/// Reads the chunks sent to a channel, so that a body can be parsed on a
+/// blocking thread as it arrives
+struct ChannelReader {
+    rx: tokio::sync::mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Decompresses a single gzip member (RFC 1952) as it is read, checking its
+/// CRC and length at the end
+struct GzipReader<R> {
+    inner: R,
+    state: Box<InflateState>,
+    header_read: bool,
+    done: bool,
+    crc: crc32fast::Hasher,
+    len: u32,
+}
+
+impl<R: BufRead> GzipReader<R> {
+    fn new(inner: R) -> GzipReader<R> {
+        GzipReader {
+            inner,
+            state: InflateState::new_boxed(DataFormat::Raw),
+            header_read: false,
+            done: false,
+            crc: crc32fast::Hasher::new(),
+            len: 0,
+        }
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        const FHCRC: u8 = 0x02;
+        const FEXTRA: u8 = 0x04;
+        const FNAME: u8 = 0x08;
+        const FCOMMENT: u8 = 0x10;
+
+        let mut header = [0; 10];
+        self.inner.read_exact(&mut header)?;
+        if header[..3] != [0x1f, 0x8b, 8] || header[3] & 0xe0 != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid gzip header"));
+        }
+        let flags = header[3];
+
+        if flags & FEXTRA != 0 {
+            let mut len = [0; 2];
+            self.inner.read_exact(&mut len)?;
+            io::copy(&mut (&mut self.inner).take(u16::from_le_bytes(len).into()), &mut io::sink())?;
+        }
+        for flag in [FNAME, FCOMMENT] {
+            if flags & flag != 0 {
+                let mut field = Vec::new();
+                if self.inner.read_until(0, &mut field)? == 0 || field.last() != Some(&0) {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+        if flags & FHCRC != 0 {
+            self.inner.read_exact(&mut [0; 2])?;
+        }
+        Ok(())
+    }
+
+    fn check_trailer(&mut self) -> io::Result<()> {
+        let mut trailer = [0; 8];
+        self.inner.read_exact(&mut trailer)?;
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let len = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+        if crc != self.crc.clone().finalize() || len != self.len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "gzip CRC or length mismatch"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for GzipReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.header_read {
+            self.read_header()?;
+            self.header_read = true;
+        }
+
+        while !self.done && !buf.is_empty() {
+            let input = self.inner.fill_buf()?;
+            let eof = input.is_empty();
+            let res = inflate(&mut self.state, input, buf, MZFlush::None);
+            self.inner.consume(res.bytes_consumed);
+
+            let written = &buf[..res.bytes_written];
+            self.crc.update(written);
+            self.len = self.len.wrapping_add(written.len() as u32);
+
+            match res.status {
+                Ok(MZStatus::StreamEnd) => {
+                    self.check_trailer()?;
+                    self.done = true;
+                }
+                Ok(_) | Err(MZError::Buf) if res.bytes_written > 0 => {}
+                Ok(_) | Err(MZError::Buf) if eof => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) if res.bytes_consumed > 0 => continue,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid gzip data")),
+            }
+            return Ok(res.bytes_written);
+        }
+        Ok(0)
+    }
+}
+
+/// Parse an upstream JSON response and produce a streaming zip file response
+pub async fn response(config: &Config, client: s3::Client, http_client: HttpClient, req: &Request<impl Body>, response_body: Bytes) -> Result<Response<ResponseBody>, ErrorResponse> {
+    let res = parse_manifest(config, &response_body)?;
+    drop(response_body);
+
+    archive_response(config, client, http_client, req, res).await
+}
+
+/// Like `response`, but parse the upstream response body as it arrives (see
+/// `read_manifest`) instead of after it has been read into memory.
+pub async fn streamed_response<B>(config: &Config, client: s3::Client, http_client: HttpClient, req: &Request<impl Body>, content_encoding: Option<&header::HeaderValue>, response_body: B) -> Result<Response<ResponseBody>, ErrorResponse>
+where
+    B: Body<Data = Bytes> + Send + Unpin,
+    B::Error: Into<BoxError>,
+{
+    let res = read_manifest(config, content_encoding, response_body).await?;
+    archive_response(config, client, http_client, req, res).await
+}
+
+/// Produce a streaming zip file response with the entries of several
+/// manifests, each parsed with `read_manifest`. See `merge_manifests`.
+pub async fn merged_response(config: &Config, client: s3::Client, http_client: HttpClient, req: &Request<impl Body>, manifests: Vec<UpstreamResponse>) -> Result<Response<ResponseBody>, ErrorResponse> {
+    let num_manifests = manifests.len();
+    let res = merge_manifests(manifests)?;
+    info!("Merged {} manifests into {} entries", num_manifests, res.entries.len());
+
+    archive_response(config, client, http_client, req, res).await
+}
+
+/// Combine manifests into one with the filename of the first and the entries
+/// of all of them. Entries that are identical in several manifests are
+/// included once, but different entries with the same `archive_name` are
+/// rejected.
+fn merge_manifests(manifests: Vec<UpstreamResponse>) -> Result<UpstreamResponse, ErrorResponse> {
+    let mut manifests = manifests.into_iter();
+    let Some(mut merged) = manifests.next() else {
+        return Err((StatusCode::BAD_REQUEST, "No manifests to merge".into()));
+    };
+
+    for manifest in manifests {
+        merged.entries.extend(manifest.entries);
+        merged.digest.merge(&manifest.digest);
+    }
+
+    // Sorting by `archive_name` first makes both duplicates and collisions adjacent
+    let UpstreamResponse { entries, digest, .. } = &mut merged;
+    entries.sort();
+    entries.dedup_by(|entry, kept| entry == kept && { digest.remove(entry); true });
+
+    if let Some(pair) = merged.entries.windows(2).find(|pair| pair[0].archive_name == pair[1].archive_name) {
+        error!("Merged manifests have different entries for {}", pair[0].archive_name);
+        return Err((StatusCode::CONFLICT, format!("Manifests have different entries for archive_name \"{}\"", pair[0].archive_name).into()));
+    }
+
+    Ok(merged)
+}
+
+/// Reject archives with more entries than `Config::max_entries` with 413
+/// Payload Too Large
+fn check_entry_count(config: &Config, entries: &[ZipFileDescription]) -> Result<(), ErrorResponse> {
+    match config.max_entries {
+        Some(max) if entries.len() > max => {
+            error!("Manifest has {} entries, more than --max-entries {}", entries.len(), max);
+            Err((StatusCode::PAYLOAD_TOO_LARGE, format!("Archive has {} entries, more than the maximum of {}", entries.len(), max).into()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reject archives whose entries would extract to more than
+/// `Config::max_uncompressed_bytes` in total with 413 Payload Too Large, such
+/// as deflate entries declaring a small compressed and huge uncompressed
+/// length
+fn check_uncompressed_bytes(config: &Config, entries: &[ZipFileDescription]) -> Result<(), ErrorResponse> {
+    let Some(max) = config.max_uncompressed_bytes else {
+        return Ok(());
+    };
+
+    let total = entries.iter().fold(0u64, |total, e| total.saturating_add(e.uncompressed_length()));
+    if total > max {
+        error!("Manifest extracts to {} bytes, more than --max-uncompressed-bytes {}", total, max);
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, format!("Archive would extract to {} bytes, more than the maximum of {}", total, max).into()));
+    }
+    Ok(())
+}
+
+/// Produce a streaming zip file response for a manifest
+async fn archive_response(config: &Config, client: s3::Client, http_client: HttpClient, req: &Request<impl Body>, mut res: UpstreamResponse) -> Result<Response<ResponseBody>, ErrorResponse> {
+    let zip64 = zip64_mode(req)?;
+    let password = archive_password(config, req)?;
+    filter_prefix(req, &mut res)?;
+    // Before `fetch_s3_metadata`, which makes a request per entry
+    check_entry_count(config, &res.entries)?;
+    // After `filter_prefix`, since stripping the prefix can make names equal
+    check_duplicate_names(&mut res.entries)?;
+    if config.reject_file_directory_conflicts {
+        check_file_directory_conflicts(&res.entries)?;
+    }
+
+    let duplicate_sources = count_duplicate_sources(&res.entries);
+    if duplicate_sources > 0 {
+        DUPLICATE_SOURCES.fetch_add(duplicate_sources as u64, Ordering::Relaxed);
+
+        if config.warn_duplicate_sources.is_some_and(|max| duplicate_sources > max) {
+            warn!(zipstream.duplicate_sources = duplicate_sources, "Manifest has {} entries with duplicate sources", duplicate_sources);
+        } else {
+            info!(zipstream.duplicate_sources = duplicate_sources, "Manifest has {} entries with duplicate sources", duplicate_sources);
+        }
+    }
+
+    if config.s3_last_modified || config.verify_s3_etag || config.resolve_lengths {
+        fetch_s3_metadata(config, &client, &mut res).await?;
+    }
+
+    // After `fetch_s3_metadata`, which may replace `last_modified`
+    filter_since(req, &mut res)?;
+    // After `fetch_s3_metadata`, which may resolve lengths
+    check_uncompressed_bytes(config, &res.entries)?;
+
+    let mut response = stream_archive(config, client, http_client, req, res, zip64, password).await;
+    let vary = if config.password_header { "Accept, X-Zip-Stream-Zip64, X-Zip-Stream-Password" } else { "Accept, X-Zip-Stream-Zip64" };
+    response.headers_mut().insert(header::VARY, header::HeaderValue::from_static(vary));
+    Ok(response)
+}
+
+/// Build the archive for a manifest whose entries have their final metadata,
+/// and produce the response serving it.
+///
+/// This is separate from `archive_response` because the archive's
+/// `StreamRange`s aren't `Send`, so they can't be held across its awaits.
+fn stream_archive(config: &Config, client: s3::Client, http_client: HttpClient, req: &Request<impl Body>, mut res: UpstreamResponse, zip64: Zip64Mode, password: Option<Password>) -> BoxFuture<'static, Response<ResponseBody>> {
+    match config.entry_order {
+        EntryOrder::Name => res.entries.sort(),
+        EntryOrder::Source => res.entries.sort_by(|a, b| a.source.cmp(&b.source).then_with(|| a.cmp(b))),
+    }
+
+    let declared_bytes: u64 = res.entries.iter().map(|e| e.length).sum();
+    let mut etag = ETagHasher::new(config.etag_algorithm, &res.filename, res.entries.len(), &res.digest);
+    if config.entry_order == EntryOrder::Source {
+        // The digest of the entries doesn't depend on their order
+        etag.add_generated(".entry-order-source", &[]);
+    }
+    let mut source: Arc<dyn ObjectSource> = Arc::new(RetryingSource { inner: Arc::new(client.clone()), max_attempts: config.s3_max_attempts });
+    if let Some(cache) = &config.s3_cache {
+        source = Arc::new(CachingSource { inner: source, cache: cache.clone() });
+    }
+
+    // Adjacent ranges of the same object are read with one GetObject
+    let groups = read_groups(&res.entries, &source);
+
+    let mut entries: Vec<ZipEntry> = res.entries.into_iter().zip(groups).map(|(file, group)| {
+        ZipEntry {
+            archive_path: file.archive_name,
+            crc: file.crc.0,
+            is_directory: file.source == Source::Directory,
+            comment: file.metadata.comment(),
+            data: match (file.source, group) {
+                (_, Some(group)) => Box::new(CoalescedS3Object {
+                    group,
+                    offset: file.offset,
+                    len: file.length,
+                }),
+                (Source::S3(url), None) => Box::new(S3Object {
+                    source: source.clone(),
+                    bucket: url.bucket,
+                    key: url.key,
+                    offset: file.offset,
+                    len: file.length
+                }),
+                // Validated when parsing the manifest
+                (Source::Url(url), None) => Box::new(HttpObject {
+                    client: http_client.clone(),
+                    uri: url.parse().unwrap(),
+                    offset: file.offset,
+                    len: file.length,
+                }),
+                (Source::File(path), None) => Box::new(FileRange {
+                    path,
+                    offset: file.offset,
+                    len: file.length,
+                }),
+                (Source::Directory, None) => Box::new(Bytes::new()),
+            },
+            last_modified: file.last_modified,
+            compression: file.compression.0,
+        }
+    }).collect();
+
+    // For `Config::filename_template`, which also counts the manifest's entries only
+    let manifest_entries = entries.len();
+    let newest = entries.iter().map(|e| e.last_modified).max();
+
+    // Describes the manifest's entries only, not those generated below
+    if config.index_json {
+        let index = index_json(&res.filename, &entries);
+        etag.add_generated(INDEX_JSON_PATH, &index);
+        let index = generated_entry(INDEX_JSON_PATH, index, &entries);
+        entries.insert(0, index);
+    }
+
+    if config.contents_listing {
+        let listing = contents_listing(&entries);
+        etag.add_generated(CONTENTS_LISTING_PATH, &listing);
+        entries.push(generated_entry(CONTENTS_LISTING_PATH, listing, &entries));
+    }
+
+    let comment = match &config.comment_template {
+        Some(template) => {
+            let request_id = req.extensions().get::<RequestId>().map_or("", |id| &id.0);
+            let comment = archive_comment(template, request_id, SystemTime::now().into());
+            // The comment differs between requests, so ranges can't be combined across them
+            etag.add_generated("", comment.as_bytes());
+            comment
+        }
+        None => String::new(),
+    };
+
+    if config.central_directory_hint {
+        // The hint is derived from the other entries, but shifts every offset
+        etag.add_generated(CENTRAL_DIRECTORY_HINT_PATH, &[]);
+    }
+
+    if config.spanning_marker {
+        // Shifts every offset, like the hint
+        etag.add_generated(".zip-spanning-marker", &[]);
+    }
+
+    if config.ntfs_timestamps {
+        // Lengthens every header
+        etag.add_generated(".zip-ntfs-timestamps", &[]);
+    }
+
+    if zip64 == Zip64Mode::Always {
+        // Lengthens every header, like the NTFS timestamps
+        etag.add_generated(".zip64", &[]);
+    }
+
+    // Sizes of an entry that need zip64, whatever its offset
+    let large_entry = entries.iter().any(|e| e.data.len() >= 0xFFFFFFFF || e.uncompressed_len() >= 0xFFFFFFFF);
+
+    let etag = etag.finish();
+    let num_entries = entries.len();
+    let encrypted = password.is_some();
+    let streamed_crc = entries.iter().any(|e| e.crc.is_none());
+
+    // A per-request comment changes the content without changing the dates,
+    // so only the ETag can validate an If-Range in that case.
+    let last_modified = match config.comment_template {
+        Some(_) => None,
+        None => entries.iter().map(|e| e.last_modified).max(),
+    };
+
+    let options = ZipOptions {
+        central_directory_hint: config.central_directory_hint,
+        comment,
+        spanning_marker: config.spanning_marker,
+        ntfs_timestamps: config.ntfs_timestamps,
+        prefetch_entries: config.prefetch_entries,
+        force_zip64: zip64 == Zip64Mode::Always,
+        password,
+        ..Default::default()
+    };
+    let archive_entries = num_entries + usize::from(options.central_directory_hint);
+
+    // A HEAD request only needs the length, which is computed without
+    // building the headers of what may be a huge manifest
+    let (archive_bytes, archive) = if req.method() == Method::HEAD && !accepts_json(req) {
+        (estimate_archive_len(&entries, &options), None)
+    } else {
+        match zip_stream_with_layout(entries, options) {
+            Ok((stream, layout)) => (stream.len(), Some((stream, layout))),
+            Err(e) => {
+                // The manifest checks should have rejected the entries
+                error!("Failed to build archive {}: {}", res.filename, e);
+                return future::ready(bytes_response(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", Bytes::from(e.to_string()))).boxed();
+            }
+        }
+    };
+
+    // Size of the archive relative to the entry data the manifest declared,
+    // which is large for manifests of many tiny files or wrong lengths
+    let overhead_ratio = (declared_bytes > 0).then(|| archive_bytes as f64 / declared_bytes as f64);
+
+    // Offsets and counts within these limits always fit the 32-bit fields
+    if zip64 == Zip64Mode::Never && (large_entry || archive_bytes >= 0xFFFFFFFF || archive_entries >= 0xFFFF) {
+        warn!("Archive {} of {} bytes and {} entries requires zip64, which the client refused", res.filename, archive_bytes, archive_entries);
+        return future::ready(bytes_response(
+            StatusCode::NOT_ACCEPTABLE,
+            "text/plain",
+            Bytes::from_static(b"Archive requires zip64: it, or an entry in it, is 4 GiB or larger, or it has 65535 or more entries"),
+        )).boxed();
+    }
+
+    if let (true, Some((_, layout))) = (accepts_json(req), &archive) {
+        info!(
+            zipstream.declared_bytes = declared_bytes,
+            zipstream.archive_bytes = archive_bytes,
+            zipstream.overhead_ratio = overhead_ratio,
+            "Returning summary of zip file {}: {} entries, {} bytes", res.filename, num_entries, archive_bytes
+        );
+        return future::ready(summary_response(&res.filename, &etag, archive_bytes, layout)).boxed();
+    }
+
+    info!(
+        zipstream.entries = num_entries,
+        zipstream.declared_bytes = declared_bytes,
+        zipstream.archive_bytes = archive_bytes,
+        zipstream.overhead_ratio = overhead_ratio,
+        "Streaming zip file {}: {} entries, {} bytes", res.filename, num_entries, archive_bytes
+    );
+
+    // Not part of the ETag, since it doesn't change the archive
+    let filename = match &config.filename_template {
+        Some(template) => {
+            let date = newest.unwrap_or_else(|| SystemTime::now().into());
+            archive_filename(template, &res.filename, date, manifest_entries, archive_bytes, &etag)
+        }
+        None => res.filename.clone(),
+    };
+
+    // Encryption salts each entry randomly, so the archive differs between
+    // requests and the ETag can't validate ranges of it. CRCs computed while
+    // streaming are only known to responses that stream all of the data, so
+    // those archives aren't served in ranges either.
+    let response_etag = (!encrypted && !streamed_crc).then_some(&etag[..]);
+    let prepared = match prepare_response(config, req, "application/zip", response_etag, last_modified, &filename, archive_bytes) {
+        Ok(prepared) => prepared,
+        Err(res) => return future::ready(*res).boxed(),
+    };
+
+    let Some((stream, _)) = archive else {
+        // A HEAD request, whose response has no body
+        return future::ready(prepared.finish(Box::pin(stream::empty()))).boxed();
+    };
+
+    let throttled_retry_after = config.throttled_retry_after;
+    let on_error = move |err: &BoxError| throttled_retry_after.and_then(|default| throttled_response(err, default));
+    // The ETag names the unencrypted archive, so encrypted ones aren't uploaded
+    let upload = config.upload_archives_to.as_ref().filter(|_| !encrypted).map(|dest| (client, S3Url {
+        bucket: dest.bucket.clone(),
+        key: format!("{}{}.zip", dest.key, etag),
+    }));
+
+    if prepared.has_body() && config.buffer_small_responses_under.is_some_and(|limit| archive_bytes < limit) {
+        // Read the whole archive first, and send it with a single write
+        let full = stream.stream_range(Range { start: 0, end: archive_bytes });
+        return async move {
+            let data = full.try_fold(BytesMut::new(), |mut buf, chunk| {
+                buf.extend_from_slice(&chunk);
+                future::ready(Ok(buf))
+            });
+            let data: Bytes = match data.await {
+                Ok(data) => data.freeze(),
+                Err(err) => {
+                    error!("Failed to buffer archive: {}", Report(&*err));
+                    return on_error(&err).unwrap_or_else(|| {
+                        bytes_response(StatusCode::BAD_GATEWAY, "text/plain", Bytes::from_static(b"Failed to read archive data"))
+                    });
+                }
+            };
+
+            let stream = prepared.stream(&*with_upload(data, upload));
+            prepared.finish(stream)
+        }.boxed();
+    }
+
+    let stream = prepared.stream(&*with_upload(stream, upload));
+
+    if throttled_retry_after.is_some() {
+        // Wait for the first S3 request before sending the headers, so that
+        // throttling can still be reported with a 503
+        prepared.finish_checked(stream, on_error).boxed()
+    } else {
+        future::ready(prepared.finish(stream)).boxed()
+    }
+}
+
+/// Wrap `data` to also upload it to `upload` if set (see `TeeToS3`)
+fn with_upload(data: impl StreamRange + 'static, upload: Option<(s3::Client, S3Url)>) -> Box<dyn StreamRange> {
+    match upload {
+        Some((client, dest)) => Box::new(TeeToS3 { inner: data, client, bucket: dest.bucket, key: dest.key }),
+        None => Box::new(data),
+    }
+}
+
+/// A 503 response asking the client to retry, if `err` is S3 throttling
+/// requests. The Retry-After is S3's if it sent one, otherwise `default_retry_after`.
+fn throttled_response(err: &BoxError, default_retry_after: Duration) -> Option<Response<ResponseBody>> {
+    let throttled = s3_throttled(&**err)?;
+    let retry_after = throttled.retry_after.unwrap_or(default_retry_after);
+    warn!("S3 is throttling requests, asking the client to retry after {}s", retry_after.as_secs());
+
+    let mut response = bytes_response(StatusCode::SERVICE_UNAVAILABLE, "text/plain", Bytes::from_static(b"S3 is busy, retry later"));
+    response.headers_mut().insert(header::RETRY_AFTER, retry_after.as_secs().into());
+    Some(response)
+}
+
+/// Whether the request's Accept header lists `application/json`, asking for
+/// the archive summary rather than the archive, with a q-value that is
+/// neither 0 nor below that of the media range matching `application/zip`
+fn accepts_json(req: &Request<impl Body>) -> bool {
+    // Media ranges, lowercased, with their q-values
+    let media_ranges: Vec<(String, f32)> = req.headers().get_all(header::ACCEPT).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|media_range| {
+            let mut params = media_range.split(';');
+            let range = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let q = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(1.0, |q| q.trim().parse::<f32>().unwrap_or(0.0));
+            (range, q)
+        })
+        .collect();
+
+    // The q-value of the most specific media range matching `media_type`
+    let quality = |media_type: &str| {
+        let subtypes = format!("{}/*", media_type.split('/').next().unwrap_or_default());
+        media_ranges.iter()
+            .filter_map(|(range, q)| match &range[..] {
+                r if r == media_type => Some((2, *q)),
+                r if r == subtypes => Some((1, *q)),
+                "*/*" => Some((0, *q)),
+                _ => None,
+            })
+            .max_by_key(|&(specificity, _)| specificity)
+            .map(|(_, q)| q)
+    };
+
+    let listed = media_ranges.iter().any(|(range, _)| range == "application/json");
+    let json = quality("application/json").unwrap_or(0.0);
+    listed && json > 0.0 && json >= quality("application/zip").unwrap_or(0.0)
+}
+
+/// A JSON description of the archive that would otherwise be served
+fn summary_response(filename: &str, etag: &str, size: u64, layout: &[EntryLayout]) -> Response<ResponseBody> {
+    let entries: Vec<_> = layout.iter().map(|entry| serde_json::json!({
+        "archive_name": entry.archive_path,
+        "header_offset": entry.header_offset,
+        "data_offset": entry.data_offset,
+        "length": entry.length,
+    })).collect();
+
+    let summary = serde_json::json!({
+        "filename": filename,
+        "size": size,
+        "entry_count": layout.len(),
+        "etag": etag,
+        "entries": entries,
+    });
+
+    bytes_response(StatusCode::OK, "application/json", summary.to_string().into())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http_body_util::Empty;
+
+    fn test_client() -> s3::Client {
+        s3::Client::from_conf(s3::Config::builder()
+            .behavior_version(s3::config::BehaviorVersion::latest())
+            .region(s3::config::Region::from_static("us-east-1"))
+            .build())
+    }
+
+    fn test_http_client() -> HttpClient {
+        hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
+            .build(hyper_tls::HttpsConnector::new())
+    }
+
+    fn test_request() -> Request<Empty<Bytes>> {
+        Request::builder().uri("/test.zip").body(Empty::new()).unwrap()
+    }
+
+    fn manifest(entries: &[(&str, &str)]) -> Bytes {
+        let entries: Vec<_> = entries.iter().map(|(name, source)| serde_json::json!({
+            "archive_name": name,
+            "source": source,
+            "length": 2,
+            "crc": 0xf8e1180fu32,
+            "last_modified": "2006-11-10T15:40:56Z",
+        })).collect();
+
+        serde_json::to_vec(&serde_json::json!({ "filename": "test.zip", "entries": entries })).unwrap().into()
+    }
+
+    #[test]
+    fn test_request_forward_headers() {
+        let req = Request::builder().uri("/test.zip")
+            .header(header::COOKIE, "a=b")
+            .header(header::USER_AGENT, "test")
+            .header("X-Tenant-Id", "42")
+            .header(header::ACCEPT, "*/*")
+            .body(Empty::<Bytes>::new()).unwrap();
+
+        let config = Config { upstream: "http://upstream".into(), ..Default::default() };
+        let upstream_req = request(&config, &req).unwrap();
+        assert_eq!(upstream_req.headers()[header::COOKIE], "a=b");
+        assert_eq!(upstream_req.headers()[header::USER_AGENT], "test");
+        assert!(upstream_req.headers().get("X-Tenant-Id").is_none());
+        assert!(upstream_req.headers().get(header::ACCEPT).is_none());
+
+        let forward_headers = vec![header::USER_AGENT, header::HeaderName::from_static("x-tenant-id")];
+        let config = Config { upstream: "http://upstream".into(), forward_headers, ..Default::default() };
+        let upstream_req = request(&config, &req).unwrap();
+        assert!(upstream_req.headers().get(header::COOKIE).is_none());
+        assert_eq!(upstream_req.headers()[header::USER_AGENT], "test");
+        assert_eq!(upstream_req.headers()["X-Tenant-Id"], "42");
+    }
+
+    #[test]
+    fn test_request_strip_prefix() {
+        let config = Config { upstream: "http://upstream".into(), strip_prefix: "/dl".into(), ..Default::default() };
+        let req = |path: &str| Request::builder().uri(path).body(Empty::<Bytes>::new()).unwrap();
+
+        assert_eq!(request(&config, &req("/dl/foo/test.zip")).unwrap().uri(), "http://upstream/foo/test.zip");
+        assert_eq!(request(&config, &req("/dlfoo/test.zip")).unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_request_compression_hint() {
+        let config = Config { upstream: "http://upstream".into(), ..Default::default() };
+        let req = |path: &str| Request::builder().uri(path).body(Empty::<Bytes>::new()).unwrap();
+
+        let upstream_req = request(&config, &req("/test.zip?a=b&compression=best")).unwrap();
+        assert_eq!(upstream_req.headers().get("X-Zip-Stream-Compression").unwrap(), "best");
+        assert!(request(&config, &req("/test.zip")).unwrap().headers().get("X-Zip-Stream-Compression").is_none());
+
+        let (status, msg) = request(&config, &req("/test.zip?compression=turbo")).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(msg.contains("turbo"));
+    }
+
+    #[tokio::test]
+    async fn test_max_path_length() {
+        let config = Config { max_path_length: 10, ..Default::default() };
+
+        let body = manifest(&[("short.txt", "s3://bucket/a")]);
+        assert!(response(&config, test_client(), test_http_client(), &test_request(), body).await.is_ok());
+
+        let body = manifest(&[("much/too/long.txt", "s3://bucket/a")]);
+        let Err((status, msg)) = response(&config, test_client(), test_http_client(), &test_request(), body).await else {
+            panic!("expected long archive_name to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(msg.contains("much/too/long.txt"));
+
+        // A higher limit can't allow names that overflow the header's length field
+        let config = Config { max_path_length: 100_000, ..Default::default() };
+        assert!(parse_manifest(&config, &manifest(&[(&"a".repeat(65_535), "s3://bucket/a")])).is_ok());
+        let Err((status, msg)) = parse_manifest(&config, &manifest(&[(&"a".repeat(65_536), "s3://bucket/a")])) else {
+            panic!("expected over-long archive_name to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(msg.contains("maximum length of 65535 bytes"));
+
+        let Err((status, msg)) = parse_manifest(&Config::default(), &manifest(&[("", "s3://bucket/a")])) else {
+            panic!("expected empty archive_name to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(msg, "archive_name must not be empty");
+    }
+
+    #[tokio::test]
+    async fn test_expansion_limits() {
+        let entry = |name: &str, length: u64, uncompressed_length: u64| serde_json::json!({
+            "archive_name": name,
+            "source": "s3://bucket/bomb.deflate",
+            "length": length,
+            "compression": "deflate",
+            "uncompressed_length": uncompressed_length,
+            "crc": 1,
+            "last_modified": "2006-11-10T15:40:56Z",
+        });
+        let body = |entries: Vec<serde_json::Value>| Bytes::from(serde_json::to_vec(&serde_json::json!({ "filename": "test.zip", "entries": entries })).unwrap());
+        let bomb = body((0..4).map(|i| entry(&format!("{}.bin", i), 1_000, 1 << 40)).collect());
+
+        // Neither is limited by default
+        assert!(response(&Config::default(), test_client(), test_http_client(), &test_request(), bomb.clone()).await.is_ok());
+
+        let config = Config { max_uncompressed_bytes: Some(1 << 42), ..Default::default() };
+        assert!(response(&config, test_client(), test_http_client(), &test_request(), bomb.clone()).await.is_ok());
+        let config = Config { max_uncompressed_bytes: Some((1 << 42) - 1), ..Default::default() };
+        let Err((status, msg)) = response(&config, test_client(), test_http_client(), &test_request(), bomb.clone()).await else {
+            panic!("expected expansion bomb to be rejected");
+        };
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(msg, format!("Archive would extract to {} bytes, more than the maximum of {}", 1u64 << 42, (1u64 << 42) - 1));
+
+        // Lengths that overflow when summed still exceed the limit
+        let overflow = body(vec![entry("a.bin", 1, u64::MAX - 1), entry("b.bin", 1, u64::MAX - 1)]);
+        let config = Config { max_uncompressed_bytes: Some(u64::MAX - 1), ..Default::default() };
+        assert_eq!(response(&config, test_client(), test_http_client(), &test_request(), overflow).await.err().unwrap().0, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let config = Config { max_entries: Some(3), ..Default::default() };
+        let Err((status, msg)) = response(&config, test_client(), test_http_client(), &test_request(), bomb.clone()).await else {
+            panic!("expected too many entries to be rejected");
+        };
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(msg, "Archive has 4 entries, more than the maximum of 3");
+
+        // Entries not selected by `prefix` don't count
+        let req = Request::builder().uri("/test.zip?prefix=0").body(Empty::<Bytes>::new()).unwrap();
+        assert!(response(&config, test_client(), test_http_client(), &req, bomb).await.is_ok());
+    }
+
+    #[test]
+    fn test_backslashes() {
+        let body = manifest(&[("dir\\file.txt", "s3://bucket/a")]);
+
+        let res = parse_manifest(&Config::default(), &body).unwrap();
+        assert_eq!(res.entries[0].archive_name, "dir/file.txt");
+
+        let config = Config { backslashes: Backslashes::Reject, ..Default::default() };
+        let Err((status, msg)) = parse_manifest(&config, &body) else {
+            panic!("expected backslash to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(msg.contains("dir\\file.txt"));
+    }
+
+    #[test]
+    fn test_strict_manifest() {
+        let entry = |length: u64, crc: u32| -> Bytes {
+            serde_json::to_vec(&serde_json::json!({"filename": "test.zip", "entries": [{
+                "archive_name": "a.txt",
+                "source": "s3://bucket/a",
+                "length": length,
+                "crc": crc,
+                "last_modified": "2006-11-10T15:40:56Z",
+            }]})).unwrap().into()
+        };
+        let strict = Config { strict_manifest: true, ..Default::default() };
+
+        // Only logged without the flag
+        assert!(parse_manifest(&Config::default(), &entry(0, 0xf8e1180f)).is_ok());
+
+        for body in [entry(0, 0xf8e1180f), entry(2, 0)] {
+            let Err((status, msg)) = parse_manifest(&strict, &body) else {
+                panic!("expected inconsistent crc to be rejected");
+            };
+            assert_eq!(status, StatusCode::BAD_GATEWAY);
+            assert!(msg.contains("a.txt"));
+        }
+
+        assert!(parse_manifest(&strict, &entry(2, 0xf8e1180f)).is_ok());
+        assert!(parse_manifest(&strict, &entry(0, 0)).is_ok());
+        assert!(parse_manifest(&strict, &serde_json::to_vec(&serde_json::json!({"filename": "test.zip", "entries": [
+            {"archive_name": "empty", "type": "directory", "last_modified": "2006-11-10T15:40:56Z"},
+        ]})).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_path_traversal() {
+        for name in ["../../etc/passwd", "a/../../b", "..", "a/..", "/etc/cron.d/x", "C:/Windows/x", "c:x", "..\\..\\evil"] {
+            let body = manifest(&[(name, "s3://bucket/a")]);
+            let Err((status, msg)) = parse_manifest(&Config::default(), &body) else {
+                panic!("expected {:?} to be rejected", name);
+            };
+            assert_eq!(status, StatusCode::BAD_GATEWAY);
+            assert!(msg.contains("archive_name must"), "{}", msg);
+        }
+
+        for name in ["a/b.txt", "..a/b", "a../b", "a/.../b", "ab:c", "./a"] {
+            assert!(parse_manifest(&Config::default(), &manifest(&[(name, "s3://bucket/a")])).is_ok(), "{}", name);
+        }
+
+        // Names with the prefix removed are checked too
+        let body = manifest(&[("docs/C:x", "s3://bucket/a")]);
+        let mut res = parse_manifest(&Config::default(), &body).unwrap();
+        let req = Request::builder().uri("/test.zip?prefix=docs/&strip_prefix=true").body(Empty::<Bytes>::new()).unwrap();
+        assert_eq!(filter_prefix(&req, &mut res).unwrap_err().0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_name_normalization() {
+        let decomposed = manifest(&[("Cafe\u{301}.txt", "s3://bucket/a")]);
+        let composed = manifest(&[("Caf\u{e9}.txt", "s3://bucket/a")]);
+
+        let config = Config { name_normalization: NameNormalization::Nfc, ..Default::default() };
+        assert_eq!(parse_manifest(&config, &decomposed).unwrap().entries[0].archive_name, "Caf\u{e9}.txt");
+
+        let config = Config { name_normalization: NameNormalization::Lowercase, ..Default::default() };
+        assert_eq!(parse_manifest(&config, &decomposed).unwrap().entries[0].archive_name, "caf\u{e9}.txt");
+
+        // The ETag is that of the normalized names
+        let etag = |config: Config, body: Bytes| async move {
+            let Ok(res) = response(&config, test_client(), test_http_client(), &test_request(), body).await else { panic!("response failed") };
+            res.headers()[header::ETAG].clone()
+        };
+        let nfc = Config { name_normalization: NameNormalization::Nfc, ..Default::default() };
+        assert_eq!(etag(nfc.clone(), decomposed.clone()).await, etag(Config::default(), composed.clone()).await);
+        assert_ne!(etag(Config::default(), decomposed.clone()).await, etag(Config::default(), composed.clone()).await);
+
+        // Names that differ only before normalization are rejected
+        let both = manifest(&[("Cafe\u{301}.txt", "s3://bucket/a"), ("Caf\u{e9}.txt", "s3://bucket/b")]);
+        assert!(parse_manifest(&Config::default(), &both).is_ok());
+        let Err((status, msg)) = parse_manifest(&nfc, &both) else {
+            panic!("expected collision to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(msg.contains("same after normalization"));
+
+        let cases = manifest(&[("README.txt", "s3://bucket/a"), ("readme.txt", "s3://bucket/b")]);
+        assert!(parse_manifest(&nfc, &cases).is_ok());
+        let config = Config { name_normalization: NameNormalization::Lowercase, ..Default::default() };
+        assert!(parse_manifest(&config, &cases).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_buffered_passthrough() {
+        use http_body_util::BodyExt;
+
+        let config = Config { passthrough_range_limit: Some(100), ..Default::default() };
+
+        let upstream = Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(header::CONTENT_LENGTH, "10")
+            .header(header::ETAG, "\"abc\"")
+            .header(header::SET_COOKIE, "a=1")
+            .header(header::SET_COOKIE, "b=2")
+            .body(Empty::<Bytes>::new()).unwrap();
+        assert!(should_buffer_passthrough(&config, &upstream));
+        assert!(!should_buffer_passthrough(&Config::default(), &upstream));
+
+        let req = Request::builder().header(header::RANGE, "bytes=2-4").body(Empty::<Bytes>::new()).unwrap();
+        let res = buffered_passthrough(&config, &req, upstream.into_parts().0, Bytes::from_static(b"0123456789"));
+
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "text/plain");
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"abc\"");
+        assert_eq!(res.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 2-4/10");
+        assert_eq!(res.headers().get(header::CONTENT_LENGTH).unwrap(), "3");
+        assert!(res.headers().get(header::CONTENT_DISPOSITION).is_none());
+        assert_eq!(res.headers().get_all(header::CONTENT_TYPE).iter().count(), 1);
+        assert_eq!(res.headers().get_all(header::SET_COOKIE).iter().collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(BodyExt::collect(res.into_body()).await.unwrap().to_bytes().as_ref(), b"234");
+    }
+
+    #[test]
+    fn test_entries_digest() {
+        let abc = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/b"), ("c.txt", "s3://bucket/c")]);
+        let cba = manifest(&[("c.txt", "s3://bucket/c"), ("b.txt", "s3://bucket/b"), ("a.txt", "s3://bucket/a")]);
+        let ac = manifest(&[("a.txt", "s3://bucket/a"), ("c.txt", "s3://bucket/c")]);
+
+        for algorithm in [ETagAlgorithm::Xxhash, ETagAlgorithm::Sha256] {
+            let config = Config { etag_algorithm: algorithm, ..Default::default() };
+            let digest = |body: &Bytes| parse_manifest(&config, body).unwrap().digest.sum;
+
+            // The order of the entries doesn't matter, and removing one gives
+            // the digest of a manifest without it
+            assert_eq!(digest(&abc), digest(&cba));
+            let mut res = parse_manifest(&config, &abc).unwrap();
+            res.digest.remove(&res.entries[1]);
+            assert_eq!(res.digest.sum, digest(&ac));
+            assert_ne!(digest(&abc), digest(&ac));
+
+            // Removing what a merge added gives the original digest back
+            let mut merged = parse_manifest(&config, &ac).unwrap().digest;
+            merged.merge(&parse_manifest(&config, &abc).unwrap().digest);
+            for entry in &parse_manifest(&config, &abc).unwrap().entries {
+                merged.remove(entry);
+            }
+            assert_eq!(merged.sum, digest(&ac));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_etag_algorithm() {
+        let body = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/b")]);
+
+        async fn etag(algorithm: ETagAlgorithm, body: &Bytes) -> String {
+            let config = Config { etag_algorithm: algorithm, ..Default::default() };
+            let Ok(res) = response(&config, test_client(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+            res.headers()[header::ETAG].to_str().unwrap().to_owned()
+        }
+
+        // Fixed values, so a change that would invalidate cached ETags fails here
+        let expected = [
+            (ETagAlgorithm::Xxhash, "22be43b9940ef28f"),
+            (ETagAlgorithm::Sha256, "5c6490addb451c924445eec3c00b803602901930ae0e02e3b1d18e160f388f43"),
+        ];
+
+        for (algorithm, expected) in expected {
+            let tag = etag(algorithm, &body).await;
+            let hex = tag.trim_matches('"');
+            assert!(hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)), "{}", tag);
+            assert_eq!(hex, expected);
+            assert_eq!(etag(algorithm, &body).await, tag);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_sources() {
+        let body = manifest(&[("a.txt", "s3://bucket/shared"), ("b.txt", "s3://bucket/shared"), ("c.txt", "s3://bucket/other")]);
+        let res = parse_manifest(&Config::default(), &body).unwrap();
+        assert_eq!(count_duplicate_sources(&res.entries), 1);
+
+        let before = duplicate_sources();
+        assert!(response(&Config::default(), test_client(), test_http_client(), &test_request(), body).await.is_ok());
+        assert!(duplicate_sources() > before);
+    }
+
+    #[tokio::test]
+    async fn test_contents_listing() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2006-11-10T15:40:56Z");
+        s3.put("bucket", "b", &b"xx"[..], "2006-11-10T15:40:56Z");
+
+        let config = Config { contents_listing: true, ..Default::default() };
+        let body = manifest(&[("dir/b.txt", "s3://bucket/b"), ("a.txt", "s3://bucket/a")]);
+        let Ok(res) = response(&config, client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        std::fs::write("test_contents.zip", &zip).unwrap();
+
+        let output = Command::new("python3").arg("-c")
+            .arg("import sys, zipfile; sys.stdout.write(zipfile.ZipFile('test_contents.zip').read('_contents.txt').decode())")
+            .output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "a.txt\t2\t2006-11-10T15:40:56Z\ndir/b.txt\t2\t2006-11-10T15:40:56Z\n");
+
+        let Ok(res) = response(&Config::default(), client, test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        assert_ne!(res.headers().get(header::ETAG).unwrap(), etag);
+    }
+
+    #[tokio::test]
+    async fn test_s3_cache() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "license", &b"xx"[..], "2006-11-10T15:40:56Z");
+        let config = Config { s3_cache: Some(crate::stream_range::ObjectCache::new(1024, 1024)), ..Default::default() };
+        let body = manifest(&[("a/LICENSE", "s3://bucket/license"), ("b/LICENSE", "s3://bucket/license")]);
+
+        let mut archives = Vec::new();
+        for _ in 0..2 {
+            let Ok(res) = response(&config, client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+            archives.push(BodyExt::collect(res.into_body()).await.unwrap().to_bytes());
+        }
+        assert_eq!(archives[0], archives[1]);
+        assert_eq!(s3.count(Method::GET), 1);
+    }
+
+    /// Prefetching keeps the S3 requests of a manifest of 100 small files
+    /// overlapping, without changing the archive
+    #[tokio::test]
+    async fn test_prefetch_entries() {
+        use http_body_util::BodyExt;
+        use std::sync::atomic::Ordering;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        *s3.delay.lock().unwrap() = std::time::Duration::from_millis(10);
+        let files: Vec<(String, String)> = (0..100).map(|i| (format!("{}.txt", i), format!("s3://bucket/{}", i))).collect();
+        for i in 0..100 {
+            s3.put("bucket", &i.to_string(), &b"xx"[..], "2006-11-10T15:40:56Z");
+        }
+        let body = manifest(&files.iter().map(|(name, source)| (&name[..], &source[..])).collect::<Vec<_>>());
+
+        let mut archives = Vec::new();
+        let mut max_in_flight = Vec::new();
+        for prefetch_entries in [0, 8] {
+            s3.max_in_flight.store(0, Ordering::SeqCst);
+            let config = Config { prefetch_entries, ..Default::default() };
+            let Ok(res) = response(&config, client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+            archives.push(BodyExt::collect(res.into_body()).await.unwrap().to_bytes());
+            max_in_flight.push(s3.max_in_flight.load(Ordering::SeqCst));
+        }
+
+        assert_eq!(archives[0], archives[1]);
+        assert_eq!(s3.count(Method::GET), 200);
+        assert_eq!(max_in_flight[0], 1);
+        assert!(max_in_flight[1] > 1 && max_in_flight[1] <= 9, "{:?}", max_in_flight);
+    }
+
+    #[tokio::test]
+    async fn test_index_json() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2006-11-10T15:40:56Z");
+
+        let config = Config { index_json: true, ..Default::default() };
+        let body = manifest(&[("dir/b.txt", "s3://bucket/a"), ("a.txt", "s3://bucket/a")]);
+        let Ok(res) = response(&config, client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        std::fs::write("test_index_json.zip", &zip).unwrap();
+
+        let output = Command::new("python3").arg("-c")
+            .arg("import sys, zipfile; z = zipfile.ZipFile('test_index_json.zip'); assert z.testzip() is None; print(z.namelist()); sys.stdout.write(z.read('index.json').decode())")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let output = String::from_utf8(output.stdout).unwrap();
+        let (names, index) = output.split_once('\n').unwrap();
+        assert_eq!(names, "['index.json', 'a.txt', 'dir/b.txt']");
+
+        let index: serde_json::Value = serde_json::from_str(index).unwrap();
+        assert_eq!(index, serde_json::json!({
+            "filename": "test.zip",
+            "entry_count": 2,
+            "entries": [
+                { "path": "a.txt", "length": 2, "crc": 0xf8e1180fu32, "last_modified": "2006-11-10T15:40:56Z" },
+                { "path": "dir/b.txt", "length": 2, "crc": 0xf8e1180fu32, "last_modified": "2006-11-10T15:40:56Z" },
+            ],
+        }));
+
+        let Ok(res) = response(&Config::default(), client, test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        assert_ne!(res.headers().get(header::ETAG).unwrap(), etag);
+    }
+
+    #[tokio::test]
+    async fn test_comment_template() {
+        use http_body_util::BodyExt;
+
+        let now = "2024-05-01T12:00:00Z".parse().unwrap();
+        assert_eq!(archive_comment("{request_id} at {time}", "abc", now), "abc at 2024-05-01T12:00:00Z");
+        assert_eq!(archive_comment(&"é".repeat(40000), "", now).len(), 65534);
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2006-11-10T15:40:56Z");
+
+        let config = Config { comment_template: Some("Request {request_id}".into()), ..Default::default() };
+        let mut req = test_request();
+        req.extensions_mut().insert(RequestId("0123abcd".into()));
+        let Ok(res) = response(&config, client, test_http_client(), &req, manifest(&[("a.txt", "s3://bucket/a")])).await else { panic!("response failed") };
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+
+        assert!(zip.ends_with(b"\x00\x00\x10\x00Request 0123abcd"), "{:?}", &zip[zip.len() - 30..]);
+    }
+
+    /// Compress `data` with Python's gzip module, which writes the optional
+    /// filename field as well
+    fn gzip(data: &[u8]) -> Bytes {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let mut child = Command::new("python3").arg("-c")
+            .arg("import gzip, io, sys; buf = io.BytesIO(); f = gzip.GzipFile('manifest.json', 'wb', fileobj=buf); f.write(sys.stdin.buffer.read()); f.close(); sys.stdout.buffer.write(buf.getvalue())")
+            .stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(data).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        output.stdout.into()
+    }
+
+    #[tokio::test]
+    async fn test_manifest_encoding() {
+        let body = manifest(&[("a.txt", "s3://bucket/a")]);
+        let gzipped = gzip(&body);
+        assert_eq!(gzipped[3] & 0x08, 0x08);
+
+        let expected = format!("{:?}", parse_manifest(&Config::default(), &body).unwrap());
+        let read = |value: Option<&'static str>, data: Bytes| async move {
+            let encoding = value.map(header::HeaderValue::from_static);
+            read_manifest(&Config::default(), encoding.as_ref(), chunked_body(vec![data], stream::empty())).await
+        };
+        for (value, data) in [(None, body.clone()), (Some("identity"), body.clone()), (Some("gzip"), gzipped.clone()), (Some("X-GZIP"), gzipped.clone())] {
+            assert_eq!(format!("{:?}", read(value, data).await.unwrap()), expected);
+        }
+
+        let mut corrupt = gzipped.to_vec();
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 1;
+        for (value, data) in [("gzip", body.clone()), ("gzip", Bytes::from(corrupt)), ("gzip", gzipped.slice(..20)), ("br", gzipped)] {
+            let (status, _) = read(Some(value), data).await.unwrap_err();
+            assert_eq!(status, StatusCode::BAD_GATEWAY);
+        }
+    }
+
+    /// A body that sends `chunks`, then `end`
+    fn chunked_body(chunks: Vec<Bytes>, end: impl futures::Stream<Item = Result<Bytes, BoxError>> + Send + Unpin) -> impl Body<Data = Bytes, Error = BoxError> + Send + Unpin {
+        let frames = stream::iter(chunks.into_iter().map(Ok)).chain(end).map_ok(hyper::body::Frame::data);
+        http_body_util::StreamBody::new(frames)
+    }
+
+    fn split(data: &[u8], size: usize) -> Vec<Bytes> {
+        data.chunks(size).map(Bytes::copy_from_slice).collect()
+    }
+
+    #[tokio::test]
+    async fn test_read_manifest() {
+        let files: Vec<(String, String)> = (0..2_000).map(|i| (format!("dir/{}.txt", i), format!("s3://bucket/{}", i))).collect();
+        let body = manifest(&files.iter().map(|(name, source)| (&name[..], &source[..])).collect::<Vec<_>>());
+        let expected = format!("{:?}", parse_manifest(&Config::default(), &body).unwrap());
+        let gzipped = gzip(&body);
+        let gzip_encoding = header::HeaderValue::from_static("gzip");
+
+        for size in [7, 1_000, 65_536] {
+            let res = read_manifest(&Config::default(), None, chunked_body(split(&body, size), stream::empty())).await.unwrap();
+            assert_eq!(format!("{:?}", res), expected);
+            let res = read_manifest(&Config::default(), Some(&gzip_encoding), chunked_body(split(&gzipped, size), stream::empty())).await.unwrap();
+            assert_eq!(format!("{:?}", res), expected);
+        }
+
+        // The manifest is parsed as it arrives, so invalid JSON fails without
+        // waiting for the rest of the body
+        let invalid = chunked_body(vec![Bytes::from_static(br#"{"filename": ["#)], stream::pending());
+        let result = tokio::time::timeout(Duration::from_secs(5), read_manifest(&Config::default(), None, invalid)).await.unwrap();
+        assert_eq!(result.unwrap_err().0, StatusCode::BAD_GATEWAY);
+
+        let failing = chunked_body(split(&body, 100).into_iter().take(3).collect(), stream::once(future::ready(Err("connection reset".into()))));
+        assert_eq!(read_manifest(&Config::default(), None, failing).await.unwrap_err().0, StatusCode::BAD_GATEWAY);
+
+        let truncated = chunked_body(split(&gzipped[..gzipped.len() - 4], 100), stream::empty());
+        assert_eq!(read_manifest(&Config::default(), Some(&gzip_encoding), truncated).await.unwrap_err().0, StatusCode::BAD_GATEWAY);
+
+        let invalid_entry = manifest(&[("../a.txt", "s3://bucket/a")]);
+        assert_eq!(read_manifest(&Config::default(), None, chunked_body(vec![invalid_entry], stream::empty())).await.unwrap_err().0, StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn test_manifest_memory() {
+        // Each entry has a long field that isn't used, so that the JSON is
+        // much larger than the entries parsed from it
+        let num_entries = 10_000;
+        let mut body = br#"{"filename": "test.zip", "entries": ["#.to_vec();
+        for i in 0..num_entries {
+            if i > 0 {
+                body.push(b',');
+            }
+            body.extend_from_slice(format!(
+                r#"{{"archive_name": "dir/{}.txt", "source": "s3://bucket/{}", "length": 2, "crc": 4175501327, "last_modified": "2006-11-10T15:40:56Z", "note": "{}"}}"#,
+                i, i, "x".repeat(2048),
+            ).as_bytes());
+        }
+        body.extend_from_slice(b"]}");
+
+        let (res, peak) = crate::test_util::peak_allocated(|| {
+            parse_manifest_from(&Config::default(), &mut serde_json::Deserializer::from_reader(&body[..])).unwrap()
+        });
+        assert_eq!(res.entries.len(), num_entries);
+
+        // Bounded by the entries kept, not by the JSON
+        let per_entry = peak / num_entries;
+        assert!(per_entry < 512, "{} bytes per entry", per_entry);
+        assert!(peak < body.len() / 4, "{} bytes for {} bytes of JSON", peak, body.len());
+    }
+
+    #[tokio::test]
+    async fn test_filename_template() {
+        let date = "2024-06-01T23:59:59Z".parse().unwrap();
+        assert_eq!(archive_filename("export-{date}-{count}files.zip", "a.zip", date, 1234, 10, "abc"), "export-2024-06-01-1234files.zip");
+        assert_eq!(archive_filename("{etag}/{size}\\{filename}", "{count}.zip", date, 1, 10, "abc"), "abc_10_{count}.zip");
+        assert_eq!(archive_filename(" ", "a.zip", date, 1, 10, "abc"), "a.zip");
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2006-11-10T15:40:56Z");
+        let body = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/a")]);
+
+        let Ok(plain) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+        let etag = plain.headers()[header::ETAG].to_str().unwrap().trim_matches('"').to_owned();
+        let size = plain.headers()[header::CONTENT_LENGTH].to_str().unwrap().to_owned();
+
+        let config = Config { filename_template: Some("export-{date}-{count}files-{size}-{etag}.zip".into()), ..Default::default() };
+        let Ok(res) = response(&config, client, test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        assert_eq!(res.headers()[header::ETAG], plain.headers()[header::ETAG]);
+        assert_eq!(
+            res.headers()[header::CONTENT_DISPOSITION].to_str().unwrap(),
+            format!("attachment; filename=\"export-2006-11-10-2files-{}-{}.zip\"", size, etag)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encryption() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2006-11-10T15:40:56Z");
+        let body = manifest(&[("a.txt", "s3://bucket/a")]);
+        let request = |headers: &[(&str, &str)]| {
+            let mut req = Request::builder().uri("/test.zip");
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            req.body(Empty::<Bytes>::new()).unwrap()
+        };
+        let extract = |zip: Bytes, password: &[u8]| {
+            let mut archive = ::zip::ZipArchive::new(std::io::Cursor::new(zip)).unwrap();
+            let mut file = archive.by_name_decrypt("a.txt", password).unwrap();
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut file, &mut data).unwrap();
+            data
+        };
+
+        // Ranges are ignored, since the archive differs between requests
+        let config = Config { encryption_password: Some(Password::new(b"secret")), ..Default::default() };
+        let Ok(res) = response(&config, client.clone(), test_http_client(), &request(&[("Range", "bytes=0-9")]), body.clone()).await else { panic!("response failed") };
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ACCEPT_RANGES], "none");
+        assert!(res.headers().get(header::ETAG).is_none());
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        assert_eq!(extract(zip, b"secret"), b"xx");
+
+        // The header is rejected unless allowed, so that the archive isn't sent unencrypted
+        let res = response(&Config::default(), client.clone(), test_http_client(), &request(&[("X-Zip-Stream-Password", "hunter2")]), body.clone()).await;
+        assert_eq!(res.err().unwrap().0, StatusCode::BAD_REQUEST);
+
+        let config = Config { password_header: true, ..config };
+        let Ok(res) = response(&config, client, test_http_client(), &request(&[("X-Zip-Stream-Password", "hunter2")]), body).await else { panic!("response failed") };
+        assert_eq!(res.headers()[header::VARY], "Accept, X-Zip-Stream-Zip64, X-Zip-Stream-Password");
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        assert_eq!(extract(zip, b"hunter2"), b"xx");
+    }
+
+    #[tokio::test]
+    async fn test_streamed_crc() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2006-11-10T15:40:56Z");
+        let body: Bytes = serde_json::to_vec(&serde_json::json!({"filename": "test.zip", "entries": [
+            {"archive_name": "a.txt", "source": "s3://bucket/a", "length": 2, "last_modified": "2006-11-10T15:40:56Z"},
+            {"archive_name": "b.txt", "source": "s3://bucket/a", "length": 2, "crc": 0xf8e1180fu32, "last_modified": "2006-11-10T15:40:56Z"},
+        ]})).unwrap().into();
+
+        // Ranges are ignored, since the CRC is only known once all of the data is streamed
+        let req = Request::builder().uri("/test.zip").header(header::RANGE, "bytes=0-9").body(Empty::<Bytes>::new()).unwrap();
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &req, body.clone()).await else { panic!("response failed") };
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ACCEPT_RANGES], "none");
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+
+        let mut archive = ::zip::ZipArchive::new(std::io::Cursor::new(zip)).unwrap();
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut archive.by_name("a.txt").unwrap(), &mut data).unwrap();
+        assert_eq!(data, b"xx");
+        assert_eq!(archive.by_name("a.txt").unwrap().crc32(), 0xf8e1180f);
+
+        // The hint is written before the data
+        let config = Config { central_directory_hint: true, ..Default::default() };
+        let Err((status, msg)) = response(&config, client, test_http_client(), &test_request(), body).await else { panic!("expected error") };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(msg.contains("a.txt"), "{}", msg);
+
+        let deflate = serde_json::to_vec(&serde_json::json!({"filename": "test.zip", "entries": [
+            {"archive_name": "a.txt", "source": "s3://bucket/a", "length": 2, "compression": "deflate", "uncompressed_length": 2, "last_modified": "2006-11-10T15:40:56Z"},
+        ]})).unwrap();
+        assert!(parse_manifest(&Config::default(), &deflate).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_presigned_url_source() {
+        use http_body_util::BodyExt;
+
+        let (_, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2006-11-10T15:40:56Z");
+        s3.put("bucket", "b", &b"xx"[..], "2006-11-10T15:40:56Z");
+
+        let a = format!("{}/bucket/a?X-Amz-Signature=aaa", s3.endpoint);
+        let b = format!("{}/bucket/b?X-Amz-Signature=bbb", s3.endpoint);
+        let body = manifest(&[("a.txt", &a), ("b.txt", &b)]);
+
+        // The S3 client has no credentials or endpoint, so isn't used
+        let Ok(res) = response(&Config::default(), test_client(), test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+
+        let requests = s3.requests.lock().unwrap().clone();
+        assert_eq!(requests, [(Method::GET, "/bucket/a?X-Amz-Signature=aaa".to_string()), (Method::GET, "/bucket/b?X-Amz-Signature=bbb".to_string())]);
+
+        std::fs::write("test_presigned.zip", &zip).unwrap();
+        let status = std::process::Command::new("unzip").arg("-t").arg("test_presigned.zip").status().unwrap();
+        assert!(status.success());
+
+        // Signing the URLs again doesn't change the ETag, but other parameters do
+        let etag = |a: &str| {
+            let res = parse_manifest(&Config::default(), &manifest(&[("a.txt", a)])).unwrap();
+            ETagHasher::new(ETagAlgorithm::Xxhash, &res.filename, res.entries.len(), &res.digest).finish()
+        };
+        let signed = |signature: &str| format!("{}/bucket/a?versionId=1&X-Amz-Date=20240101T000000Z&X-Amz-Signature={}", s3.endpoint, signature);
+        assert_eq!(etag(&signed("aaa")), etag(&signed("bbb")));
+        assert_eq!(etag(&signed("aaa")), etag(&format!("{}/bucket/a?versionId=1", s3.endpoint)));
+        assert_ne!(etag(&signed("aaa")), etag(&format!("{}/bucket/a?versionId=2", s3.endpoint)));
+
+        let invalid = manifest(&[("a.txt", "ftp://example.com/a")]);
+        assert!(response(&Config::default(), test_client(), test_http_client(), &test_request(), invalid).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_source() {
+        use http_body_util::BodyExt;
+
+        let dir = std::env::temp_dir().join(format!("zipstream_test_file_source_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a b.bin"), b"..xx..").unwrap();
+        let source = |name: &str| format!("file://{}/{}", dir.display(), name);
+
+        let mut body: serde_json::Value = serde_json::from_slice(&manifest(&[("a.txt", &source("a%20b.bin"))])).unwrap();
+        body["entries"][0]["offset"] = 2.into();
+        let body = Bytes::from(serde_json::to_vec(&body).unwrap());
+
+        // Rejected unless allowed, and only under the allowed directory
+        assert_eq!(parse_manifest(&Config::default(), &body).unwrap_err().0, StatusCode::BAD_GATEWAY);
+        let config = Config { file_sources_under: Some(dir.clone()), ..Default::default() };
+        for name in ["../a%20b.bin", "x/../../a%20b.bin"] {
+            assert_eq!(parse_manifest(&config, &manifest(&[("a.txt", &source(name))])).unwrap_err().0, StatusCode::BAD_GATEWAY);
+        }
+        assert!(parse_manifest(&config, &manifest(&[("a.txt", "file://a.bin")])).is_err());
+
+        let Ok(res) = response(&config, test_client(), test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        std::fs::write("test_file_source.zip", &zip).unwrap();
+        let output = std::process::Command::new("unzip").args(["-p", "test_file_source.zip", "a.txt"]).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(output.stdout, b"xx");
+    }
+
+    #[tokio::test]
+    async fn test_accept_json_summary() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2006-11-10T15:40:56Z");
+        s3.put("bucket", "b", &b"xx"[..], "2006-11-10T15:40:56Z");
+        let body = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/b")]);
+
+        let request = |accept| Request::builder().uri("/test.zip").header(header::ACCEPT, accept).body(Empty::<Bytes>::new()).unwrap();
+
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &request("application/zip"), body.clone()).await else { panic!("response failed") };
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/zip");
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept, X-Zip-Stream-Zip64");
+        let etag = res.headers().get(header::ETAG).unwrap().to_str().unwrap().to_owned();
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+
+        // Listed with a q-value of 0, or below that of the archive, it isn't chosen
+        for accept in ["application/json;q=0", "application/zip, application/json;q=0.5", "application/json;q=0.5, application/*", "*/*"] {
+            let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &request(accept), body.clone()).await else { panic!("response failed") };
+            assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/zip", "{}", accept);
+        }
+
+        let Ok(res) = response(&Config::default(), client, test_http_client(), &request("application/json;q=0.9, */*;q=0.1"), body).await else { panic!("response failed") };
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept, X-Zip-Stream-Zip64");
+        let summary: serde_json::Value = serde_json::from_slice(&BodyExt::collect(res.into_body()).await.unwrap().to_bytes()).unwrap();
+
+        assert_eq!(summary["filename"], "test.zip");
+        assert_eq!(summary["size"], zip.len());
+        assert_eq!(summary["entry_count"], 2);
+        assert_eq!(summary["etag"], etag);
+        assert_eq!(summary["entries"][1]["archive_name"], "b.txt");
+        let data_offset = summary["entries"][1]["data_offset"].as_u64().unwrap() as usize;
+        assert_eq!(&zip[data_offset..data_offset + 2], b"xx");
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_reads() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "sharded", &b"aaaabbbbbbcc"[..], "2006-11-10T15:40:56Z");
+
+        let parts = [("part1", 0, &b"aaaa"[..]), ("part2", 4, &b"bbbbbb"[..]), ("part3", 10, &b"cc"[..])];
+        let entries: Vec<_> = parts.iter().map(|(name, offset, data)| serde_json::json!({
+            "archive_name": name,
+            "source": "s3://bucket/sharded",
+            "offset": offset,
+            "length": data.len(),
+            "crc": crc32fast::hash(data),
+            "last_modified": "2006-11-10T15:40:56Z",
+        })).collect();
+        let body = serde_json::to_vec(&serde_json::json!({ "filename": "test.zip", "entries": entries })).unwrap();
+
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), body.clone().into()).await else { panic!("response failed") };
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        assert_eq!(s3.count(Method::GET), 1);
+
+        // Prefetched parts wait for the read of the part before them
+        let config = Config { prefetch_entries: 2, ..Default::default() };
+        let Ok(res) = response(&config, client, test_http_client(), &test_request(), body.into()).await else { panic!("response failed") };
+        assert_eq!(BodyExt::collect(res.into_body()).await.unwrap().to_bytes(), zip);
+        assert_eq!(s3.count(Method::GET), 2);
+
+        std::fs::write("test_coalesced.zip", &zip).unwrap();
+        let output = Command::new("python3").arg("-c")
+            .arg("import sys, zipfile; z = zipfile.ZipFile('test_coalesced.zip'); sys.stdout.write(' '.join(z.read(n).decode() for n in z.namelist()))")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "aaaa bbbbbb cc");
+    }
+
+    #[tokio::test]
+    async fn test_upload_archives_to() {
+        use http_body_util::BodyExt;
+        use std::time::Duration;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "x", &b"xx"[..], "2006-11-10T15:40:56Z");
+        let body = manifest(&[("a.txt", "s3://bucket/x"), ("b.txt", "s3://bucket/x")]);
+        let config = Config { upload_archives_to: Some("s3://dest/archives/".parse().unwrap()), ..Default::default() };
+
+        let uploaded = |key: String| {
+            let s3 = s3.clone();
+            async move {
+                for _ in 0..100 {
+                    if let Some(object) = s3.objects.lock().unwrap().get(&key) {
+                        return Some(object.data.clone());
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                None
+            }
+        };
+
+        let Ok(res) = response(&config, client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+        let key = format!("/dest/archives/{}.zip", res.headers()[header::ETAG].to_str().unwrap().trim_matches('"'));
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        assert_eq!(uploaded(key.clone()).await, Some(zip));
+
+        // A canceled download is not saved
+        s3.objects.lock().unwrap().remove(&key);
+        let Ok(res) = response(&config, client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+        let mut res_body = res.into_body();
+        res_body.frame().await.unwrap().unwrap();
+        drop(res_body);
+
+        for _ in 0..100 {
+            if s3.count(Method::DELETE) > 0 { break }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(s3.count(Method::DELETE), 1);
+        assert!(s3.uploads.lock().unwrap().is_empty());
+        assert!(!s3.objects.lock().unwrap().contains_key(&key));
+
+        // Range requests are served without uploading
+        let request = Request::builder().uri("/test.zip").header(header::RANGE, "bytes=0-9").body(Empty::<Bytes>::new()).unwrap();
+        let Ok(res) = response(&config, client, test_http_client(), &request, body).await else { panic!("response failed") };
+        BodyExt::collect(res.into_body()).await.unwrap();
+        assert_eq!(uploaded(key).await, None);
+        assert_eq!(s3.count(Method::POST), 3);
+    }
+
+    #[tokio::test]
+    async fn test_entry_order_source() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "x", &b"xx"[..], "2006-11-10T15:40:56Z");
+        s3.put("bucket", "y", &b"xx"[..], "2006-11-10T15:40:56Z");
+        let body = manifest(&[("a.txt", "s3://bucket/y"), ("b.txt", "s3://bucket/x"), ("c.txt", "s3://bucket/y")]);
+
+        let request = || Request::builder().uri("/test.zip").header(header::ACCEPT, "application/json").body(Empty::<Bytes>::new()).unwrap();
+        let summary = |res: Response<ResponseBody>| async move {
+            let json: serde_json::Value = serde_json::from_slice(&BodyExt::collect(res.into_body()).await.unwrap().to_bytes()).unwrap();
+            let names: Vec<String> = json["entries"].as_array().unwrap().iter().map(|e| e["archive_name"].as_str().unwrap().to_owned()).collect();
+            (names, json["etag"].clone())
+        };
+
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &request(), body.clone()).await else { panic!("response failed") };
+        let (names, name_etag) = summary(res).await;
+        assert_eq!(names, ["a.txt", "b.txt", "c.txt"]);
+
+        let config = Config { entry_order: EntryOrder::Source, ..Default::default() };
+        let Ok(res) = response(&config, client, test_http_client(), &request(), body).await else { panic!("response failed") };
+        let (names, source_etag) = summary(res).await;
+        assert_eq!(names, ["b.txt", "a.txt", "c.txt"]);
+        assert_ne!(source_etag, name_etag);
+    }
+
+    #[tokio::test]
+    async fn test_merged_manifests() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        for key in ["a", "b", "c"] {
+            s3.put("bucket", key, &b"xx"[..], "2006-11-10T15:40:56Z");
+        }
+
+        let first = manifest(&[("a.txt", "s3://bucket/a"), ("shared.txt", "s3://bucket/b")]);
+        let second = manifest(&[("shared.txt", "s3://bucket/b"), ("c.txt", "s3://bucket/c")]);
+
+        let parse = |bodies: &[&Bytes]| bodies.iter().map(|body| parse_manifest(&Config::default(), body).unwrap()).collect::<Vec<_>>();
+        let Ok(res) = merged_response(&Config::default(), client.clone(), test_http_client(), &test_request(), parse(&[&first, &second])).await else { panic!("response failed") };
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        std::fs::write("test_merged.zip", &zip).unwrap();
+
+        let output = Command::new("python3").arg("-c")
+            .arg("import sys, zipfile; sys.stdout.write(' '.join(zipfile.ZipFile('test_merged.zip').namelist()))")
+            .output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "a.txt c.txt shared.txt");
+
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), first.clone()).await else { panic!("response failed") };
+        assert_ne!(res.headers().get(header::ETAG).unwrap(), etag);
+
+        let conflicting = manifest(&[("a.txt", "s3://bucket/c")]);
+        let Err((status, msg)) = merged_response(&Config::default(), client, test_http_client(), &test_request(), parse(&[&first, &conflicting])).await else { panic!("expected conflict") };
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(msg.contains("a.txt"));
+    }
+
+    #[test]
+    fn test_merge_manifest_paths() {
+        let req = |path: &str| Request::builder().uri(path).body(Empty::<Bytes>::new()).unwrap();
+        let config = Config { upstream: "http://upstream".into(), merge_manifests: true, ..Default::default() };
+
+        let paths = merge_manifest_paths(&config, &req("/merged.zip?m=/one&x=y&m=/dl/two%20b%3Fv%3D1")).unwrap();
+        assert_eq!(paths, ["/one", "/dl/two b?v=1"]);
+        assert_eq!(manifest_request(&config, &req("/merged.zip"), "/one").unwrap().uri(), "http://upstream/one");
+
+        // The route's prefix is removed, and paths outside it aren't fetched
+        let config = Config { strip_prefix: "/dl".into(), ..config };
+        assert_eq!(manifest_request(&config, &req("/dl/merged.zip"), &paths[1]).unwrap().uri(), "http://upstream/two%20b?v=1");
+        assert_eq!(manifest_request(&config, &req("/dl/merged.zip"), "/other/two").unwrap_err().0, StatusCode::NOT_FOUND);
+
+        assert_eq!(merge_manifest_paths(&config, &req("/merged.zip?m=other")).unwrap_err().0, StatusCode::BAD_REQUEST);
+        assert_eq!(merge_manifest_paths(&config, &req("/merged.zip?m=/dl/%2E%2E/secret")).unwrap_err().0, StatusCode::BAD_REQUEST);
+        assert_eq!(merge_manifest_paths(&config, &req("/merged.zip?m=/%FF")).unwrap_err().0, StatusCode::BAD_REQUEST);
+        assert!(merge_manifest_paths(&Config::default(), &req("/merged.zip?m=/one")).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_archive_size_logged() {
+        let (_guard, logs) = crate::test_util::capture_logs();
+
+        let body = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/b")]);
+        let Ok(res) = response(&Config::default(), test_client(), test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        let archive_bytes: u64 = res.headers()[header::CONTENT_LENGTH].to_str().unwrap().parse().unwrap();
+
+        let logs = logs.contents();
+        let line = logs.lines().find(|line| line.contains("Streaming zip file")).unwrap();
+        let event: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(event["zipstream.declared_bytes"], 4);
+        assert_eq!(event["zipstream.archive_bytes"], archive_bytes);
+        assert_eq!(event["zipstream.overhead_ratio"], archive_bytes as f64 / 4.0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_entry_logged() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2006-11-10T15:40:56Z");
+
+        let (_guard, logs) = crate::test_util::capture_logs();
+
+        let body = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/deleted")]);
+        let Ok(res) = response(&Config::default(), client, test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        assert!(BodyExt::collect(res.into_body()).await.is_err());
+
+        let logs = logs.contents();
+        assert!(logs.contains(r#""zipstream.result":"failed""#), "{}", logs);
+        assert!(logs.contains(r#""zipstream.failed_entry":"b.txt""#), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn test_s3_last_modified() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+
+        let config = Config { s3_last_modified: true, ..Default::default() };
+        let body = manifest(&[("a.txt", "s3://bucket/a")]);
+        let Ok(res) = response(&config, client, test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+
+        // Extended timestamp extra field with the S3 LastModified time
+        let timestamp = "2021-03-04T05:06:08Z".parse::<DateTime<Utc>>().unwrap().timestamp() as u32;
+        let mut expected = vec![0x55, 0x54, 5, 0, 1];
+        expected.extend(timestamp.to_le_bytes());
+        assert!(zip.windows(expected.len()).any(|w| w == expected));
+        assert_eq!(s3.count(Method::HEAD), 1);
+    }
+
+    #[tokio::test]
+    async fn test_head_all_concurrency() {
+        use std::sync::atomic::Ordering;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        *s3.delay.lock().unwrap() = std::time::Duration::from_millis(20);
+
+        let sources: Vec<S3Url> = (0..10).map(|i| S3Url { bucket: "bucket".into(), key: i.to_string() }).collect();
+        for source in &sources[..9] {
+            s3.put("bucket", &source.key, &b"xx"[..], "2021-03-04T05:06:08Z");
+        }
+
+        let heads = head_all(&client, &sources.iter().collect::<Vec<_>>(), 3).await;
+
+        assert_eq!(heads.len(), 10);
+        assert!(heads[..9].iter().all(|head| head.as_ref().unwrap().content_length == Some(2)));
+        assert!(heads[9].is_err());
+        assert_eq!(s3.count(Method::HEAD), 10);
+
+        let max_in_flight = s3.max_in_flight.load(Ordering::SeqCst);
+        assert!(max_in_flight > 1 && max_in_flight <= 3, "{}", max_in_flight);
+    }
+
+    #[tokio::test]
+    async fn test_verify_s3_etag() {
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+
+        let manifest = |etag: &str| -> Bytes {
+            serde_json::to_vec(&serde_json::json!({ "filename": "test.zip", "entries": [{
+                "archive_name": "a.txt",
+                "source": "s3://bucket/a",
+                "length": 2,
+                "crc": 0xf8e1180fu32,
+                "last_modified": "2006-11-10T15:40:56Z",
+                "etag": etag,
+            }]})).unwrap().into()
+        };
+
+        // The mock S3 ETag is the object length in hex
+        let config = Config { verify_s3_etag: true, ..Default::default() };
+        assert!(response(&config, client.clone(), test_http_client(), &test_request(), manifest("\"2\"")).await.is_ok());
+        assert!(response(&config, client.clone(), test_http_client(), &test_request(), manifest("2")).await.is_ok());
+
+        let Err((status, msg)) = response(&config, client.clone(), test_http_client(), &test_request(), manifest("\"3\"")).await else {
+            panic!("expected ETag mismatch to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(msg.contains("a.txt"));
+        assert_eq!(s3.count(Method::HEAD), 3);
+
+        // Not checked unless enabled
+        assert!(response(&Config::default(), client, test_http_client(), &test_request(), manifest("\"3\"")).await.is_ok());
+        assert_eq!(s3.count(Method::HEAD), 3);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_lengths() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "generated", &b"hello world"[..], "2021-03-04T05:06:08Z");
+        s3.put("bucket", "static", &b"xx"[..], "2021-03-04T05:06:08Z");
+
+        let manifest = |offset: u64| -> Bytes {
+            serde_json::to_vec(&serde_json::json!({ "filename": "test.zip", "entries": [{
+                "archive_name": "a.txt",
+                "source": "s3://bucket/generated",
+                "offset": offset,
+                "crc": crc32fast::hash(b"world"),
+                "last_modified": "2006-11-10T15:40:56Z",
+            }, {
+                "archive_name": "b.txt",
+                "source": "s3://bucket/static",
+                "length": 2,
+                "crc": 0xf8e1180fu32,
+                "last_modified": "2006-11-10T15:40:56Z",
+            }]})).unwrap().into()
+        };
+
+        let Err((status, _)) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), manifest(6)).await else {
+            panic!("expected missing length to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(s3.count(Method::HEAD), 0);
+
+        // Only the entry without a length is looked up
+        let config = Config { resolve_lengths: true, ..Default::default() };
+        let Ok(res) = response(&config, client.clone(), test_http_client(), &test_request(), manifest(6)).await else { panic!("response failed") };
+        assert_eq!(s3.count(Method::HEAD), 1);
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+
+        std::fs::write("test_resolve_lengths.zip", &zip).unwrap();
+        let output = Command::new("python3").arg("-c")
+            .arg("import sys, zipfile; z = zipfile.ZipFile('test_resolve_lengths.zip'); sys.stdout.write(' '.join(z.read(n).decode() for n in z.namelist()))")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "world xx");
+
+        let Err((status, _)) = response(&config, client, test_http_client(), &test_request(), manifest(12)).await else {
+            panic!("expected offset past the end to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_directory_entries() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+        let manifest = |directory: serde_json::Value| -> Bytes {
+            serde_json::to_vec(&serde_json::json!({"filename": "test.zip", "entries": [{
+                "archive_name": "logs/a.txt",
+                "source": "s3://bucket/a",
+                "length": 2,
+                "crc": 0xf8e1180fu32,
+                "last_modified": "2021-03-04T05:06:08Z",
+            }, directory]})).unwrap().into()
+        };
+
+        let body = manifest(serde_json::json!({"type": "directory", "archive_name": "empty", "last_modified": "2021-03-04T05:06:08Z"}));
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+
+        std::fs::write("test_directory_entries.zip", &zip).unwrap();
+        let output = Command::new("python3").arg("-c")
+            .arg("import sys, zipfile; i = zipfile.ZipFile('test_directory_entries.zip').getinfo('empty/'); sys.stdout.write(f'{i.is_dir()} {i.file_size} {i.external_attr:08x}')")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "True 0 41ed0000");
+
+        let dir = std::env::temp_dir().join(format!("zipstream_test_directory_entries_{}", std::process::id()));
+        let status = Command::new("unzip").arg("-q").arg("-d").arg(&dir).arg("test_directory_entries.zip").status().unwrap();
+        assert!(status.success());
+        assert!(dir.join("empty").is_dir());
+        assert_eq!(std::fs::read(dir.join("logs/a.txt")).unwrap(), b"xx");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let body = manifest(serde_json::json!({"type": "directory", "archive_name": "empty/", "source": "s3://bucket/a", "last_modified": "2021-03-04T05:06:08Z"}));
+        let Err((status, message)) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), body).await else {
+            panic!("expected directory with a source to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(message, "directory \"empty/\" can't have data");
+
+        let body = manifest(serde_json::json!({"archive_name": "b.txt", "last_modified": "2021-03-04T05:06:08Z"}));
+        let Err((status, _)) = response(&Config::default(), client, test_http_client(), &test_request(), body).await else {
+            panic!("expected file without a source to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_entry_metadata() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+        let manifest = |metadata: serde_json::Value| -> Bytes {
+            serde_json::to_vec(&serde_json::json!({"filename": "test.zip", "entries": [{
+                "archive_name": "a.txt",
+                "source": "s3://bucket/a",
+                "length": 2,
+                "crc": 0xf8e1180fu32,
+                "last_modified": "2021-03-04T05:06:08Z",
+                "metadata": metadata,
+            }, {
+                "archive_name": "b.txt",
+                "source": "s3://bucket/a",
+                "length": 2,
+                "crc": 0xf8e1180fu32,
+                "last_modified": "2021-03-04T05:06:08Z",
+            }]})).unwrap().into()
+        };
+
+        let body = manifest(serde_json::json!({"system": "ingest=v2", "modified_by": "alice"}));
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+
+        std::fs::write("test_entry_metadata.zip", &zip).unwrap();
+        let output = Command::new("python3").arg("-c")
+            .arg("import sys, zipfile; z = zipfile.ZipFile('test_entry_metadata.zip'); sys.stdout.write('|'.join(i.comment.decode() for i in z.infolist()))")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "modified_by=alice\nsystem=ingest=v2|");
+
+        let body = manifest(serde_json::json!({"a=b": "c"}));
+        let Err((status, _)) = response(&Config::default(), client, test_http_client(), &test_request(), body).await else {
+            panic!("expected key with '=' to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_head_request() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+        let body = manifest(&[("a.txt", "s3://bucket/a")]);
+
+        let head = Request::builder().method(Method::HEAD).uri("/test.zip").body(Empty::<Bytes>::new()).unwrap();
+        assert_eq!(request(&Config::default(), &head).unwrap().method(), Method::GET);
+
+        // The length of a HEAD response is computed without building the archive
+        let configs = [Config::default(), Config { central_directory_hint: true, ntfs_timestamps: true, index_json: true, ..Default::default() }];
+        for config in configs {
+            let Ok(head_res) = response(&config, client.clone(), test_http_client(), &head, body.clone()).await else { panic!("response failed") };
+            assert_eq!(head_res.status(), StatusCode::OK);
+            let head_headers = head_res.headers().clone();
+            assert!(BodyExt::collect(head_res.into_body()).await.unwrap().to_bytes().is_empty());
+            assert_eq!(s3.count(Method::GET), 0);
+
+            let Ok(get_res) = response(&config, client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+            assert_eq!(get_res.headers()[header::ETAG], head_headers[header::ETAG]);
+            assert_eq!(get_res.headers()[header::CONTENT_LENGTH], head_headers[header::CONTENT_LENGTH]);
+            let zip = BodyExt::collect(get_res.into_body()).await.unwrap().to_bytes();
+            assert_eq!(zip.len().to_string(), head_headers[header::CONTENT_LENGTH]);
+            s3.requests.lock().unwrap().clear();
+        }
+
+        let post = Request::builder().method(Method::POST).uri("/test.zip").body(Empty::<Bytes>::new()).unwrap();
+        assert_eq!(request(&Config::default(), &post).unwrap_err().0, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_retry_after() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+        let body = manifest(&[("a.txt", "s3://bucket/a")]);
+        let config = Config { throttled_retry_after: Some(Duration::from_secs(5)), ..Default::default() };
+
+        *s3.slow_down.lock().unwrap() = Some(None);
+        let Ok(res) = response(&config, client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "5");
+
+        // S3's Retry-After is passed on
+        *s3.slow_down.lock().unwrap() = Some(Some(2));
+        let Ok(res) = response(&config, client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "2");
+
+        // Without the option, the headers are sent before S3 is asked
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(BodyExt::collect(res.into_body()).await.is_err());
+
+        *s3.slow_down.lock().unwrap() = None;
+        let Ok(res) = response(&config, client, test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        assert_eq!(res.status(), StatusCode::OK);
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        assert!(zip.windows(2).any(|w| w == b"xx"));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_names() {
+        let client = test_client();
+        let body = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/b"), ("a.txt", "s3://bucket/c")]);
+        let Err((status, msg)) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), body).await else {
+            panic!("expected duplicate archive_name to be rejected");
+        };
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(msg.contains("\"a.txt\""), "{}", msg);
+
+        // Names that only become equal once the prefix is stripped
+        let body = manifest(&[("docs/a.txt", "s3://bucket/a"), ("docs//a.txt", "s3://bucket/b")]);
+        let req = Request::builder().uri("/test.zip?prefix=docs/&strip_prefix=true").body(Empty::<Bytes>::new()).unwrap();
+        let Err((status, _)) = response(&Config::default(), client, test_http_client(), &req, body).await else {
+            panic!("expected duplicate stripped archive_name to be rejected");
+        };
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_file_directory_conflicts() {
+        let client = test_client();
+        let config = Config { reject_file_directory_conflicts: true, ..Default::default() };
+
+        let body = manifest(&[("foo", "s3://bucket/a"), ("foo/bar.txt", "s3://bucket/b")]);
+        let Err((status, msg)) = response(&config, client.clone(), test_http_client(), &test_request(), body).await else {
+            panic!("expected file used as a directory to be rejected");
+        };
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(msg.contains("\"foo\"") && msg.contains("\"foo/bar.txt\""), "{}", msg);
+
+        // Also a directory further up the path, and regardless of order
+        let body = manifest(&[("a/b/c/d.txt", "s3://bucket/a"), ("a/b", "s3://bucket/b")]);
+        let Err((status, _)) = response(&config, client.clone(), test_http_client(), &test_request(), body).await else {
+            panic!("expected file used as a directory to be rejected");
+        };
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        // Names that only share a prefix, and directory entries, don't conflict
+        let entries = parse_manifest(&config, &manifest(&[("foo", "s3://bucket/a"), ("foo.d/bar.txt", "s3://bucket/b"), ("foobar/baz.txt", "s3://bucket/c")])).unwrap().entries;
+        assert!(check_file_directory_conflicts(&entries).is_ok());
+        let body = Bytes::from(serde_json::to_vec(&serde_json::json!({ "filename": "test.zip", "entries": [
+            {"type": "directory", "archive_name": "foo", "last_modified": "2021-03-04T05:06:08Z"},
+            {"archive_name": "foo/bar.txt", "source": "s3://bucket/a", "length": 2, "crc": 0xf8e1180fu32, "last_modified": "2021-03-04T05:06:08Z"},
+        ]})).unwrap());
+        assert!(check_file_directory_conflicts(&parse_manifest(&config, &body).unwrap().entries).is_ok());
+
+        // Allowed without the option
+        let body = manifest(&[("foo", "s3://bucket/a"), ("foo/bar.txt", "s3://bucket/b")]);
+        assert!(response(&Config::default(), client, test_http_client(), &test_request(), body).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_deflate_entry() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let contents = "hello hello hello hello hello hello hello\n";
+        // `contents` as a raw DEFLATE stream
+        let deflated: &[u8] = &[203, 72, 205, 201, 201, 87, 200, 32, 76, 114, 1, 0];
+        let entry = |extra: serde_json::Value| {
+            let mut entry = serde_json::json!({
+                "archive_name": "hello.txt",
+                "source": "s3://bucket/hello.txt.deflate",
+                "length": deflated.len(),
+                "crc": 0x1351f569u32,
+                "last_modified": "2006-11-10T15:40:56Z",
+            });
+            entry.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            Bytes::from(serde_json::to_vec(&serde_json::json!({ "filename": "test.zip", "entries": [entry] })).unwrap())
+        };
+
+        let body = entry(serde_json::json!({ "compression": "deflate", "uncompressed_length": contents.len() }));
+        for compression in ["stored", "store"] {
+            let entries = parse_manifest(&Config::default(), &entry(serde_json::json!({ "compression": compression }))).unwrap().entries;
+            assert_eq!(entries[0].compression.0, Compression::Stored);
+        }
+        assert_eq!(
+            parse_manifest(&Config::default(), &entry(serde_json::json!({ "compression": "deflate" }))).unwrap_err().0,
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            parse_manifest(&Config::default(), &entry(serde_json::json!({ "uncompressed_length": contents.len() }))).unwrap_err().0,
+            StatusCode::BAD_GATEWAY
+        );
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "hello.txt.deflate", deflated, "2021-03-04T05:06:08Z");
+        let req = Request::builder().uri("/test.zip").body(Empty::<Bytes>::new()).unwrap();
+        let Ok(res) = response(&Config::default(), client, test_http_client(), &req, body).await else { panic!("response failed") };
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        std::fs::write("test_deflate.zip", &zip).unwrap();
+
+        let output = Command::new("unzip").args(["-p", "test_deflate.zip", "hello.txt"]).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), contents);
+
+        let output = Command::new("python3").arg("-c")
+            .arg("import zipfile; z = zipfile.ZipFile('test_deflate.zip'); assert z.testzip() is None; i = z.getinfo('hello.txt'); print(i.compress_type, i.compress_size, i.file_size)")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), format!("8 {} {}", deflated.len(), contents.len()));
+    }
+
+    /// A client accepting transport compression still gets the archive
+    /// unencoded, with stored entries, so that a compressing proxy in front
+    /// doesn't compress twice
+    #[tokio::test]
+    async fn test_accept_encoding() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+        s3.put("bucket", "b", &b"xx"[..], "2021-03-04T05:06:08Z");
+        let body = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/b")]);
+
+        let req = Request::builder().uri("/test.zip").header(header::ACCEPT_ENCODING, "gzip, deflate, br").body(Empty::<Bytes>::new()).unwrap();
+        assert!(request(&Config::default(), &req).unwrap().headers().get(header::ACCEPT_ENCODING).is_none());
+
+        let Ok(res) = response(&Config::default(), client, test_http_client(), &req, body).await else { panic!("response failed") };
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+        let content_length = res.headers()[header::CONTENT_LENGTH].clone();
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        assert_eq!(zip.len().to_string(), content_length);
+        std::fs::write("test_accept_encoding.zip", &zip).unwrap();
+
+        let output = Command::new("python3").arg("-c")
+            .arg("import zipfile; z = zipfile.ZipFile('test_accept_encoding.zip'); assert z.testzip() is None; print(sorted({i.compress_type for i in z.infolist()}))")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "[0]");
+    }
+
+    #[tokio::test]
+    async fn test_parts() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+        let body = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/a"), ("c.txt", "s3://bucket/a")]);
+        let get = |uri: String| {
+            let (client, body) = (client.clone(), body.clone());
+            async move {
+                let req = Request::builder().uri(uri).body(Empty::<Bytes>::new()).unwrap();
+                let Ok(res) = response(&Config::default(), client, test_http_client(), &req, body).await else { panic!("response failed") };
+                let (parts, body) = res.into_parts();
+                (parts, BodyExt::collect(body).await.unwrap().to_bytes())
+            }
+        };
+
+        let (_, full) = get("/test.zip".into()).await;
+        let mut joined = Vec::new();
+        for part in 1.. {
+            let (res, data) = get(format!("/test.zip?part={}&part-size=100", part)).await;
+            if res.status == StatusCode::RANGE_NOT_SATISFIABLE {
+                assert_eq!(res.headers[header::CONTENT_RANGE], format!("bytes */{}", full.len()));
+                break;
+            }
+            assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+            let start = (part - 1) * 100;
+            assert_eq!(res.headers[header::CONTENT_RANGE], format!("bytes {}-{}/{}", start, start + data.len() - 1, full.len()));
+            joined.extend_from_slice(&data);
+        }
+        assert_eq!(joined, full);
+        assert_eq!(full.len().div_ceil(100), 4);
+
+        let (res, _) = get("/test.zip?part=1&part-size=0".into()).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_zip64_header() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+        let body = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/a")]);
+        let req = |zip64: Option<&str>| {
+            let req = Request::builder().uri("/test.zip");
+            let req = if let Some(zip64) = zip64 { req.header("X-Zip-Stream-Zip64", zip64) } else { req };
+            req.body(Empty::<Bytes>::new()).unwrap()
+        };
+        let archive = |zip64: Option<&'static str>| {
+            let (client, body) = (client.clone(), body.clone());
+            async move {
+                let Ok(res) = response(&Config::default(), client, test_http_client(), &req(zip64), body).await else { panic!("response failed") };
+                assert_eq!(res.status(), StatusCode::OK);
+                let etag = res.headers()[header::ETAG].clone();
+                (etag, BodyExt::collect(res.into_body()).await.unwrap().to_bytes())
+            }
+        };
+
+        let (auto_etag, auto) = archive(None).await;
+        assert_eq!(archive(Some("auto")).await, (auto_etag.clone(), auto.clone()));
+        assert_eq!(archive(Some(" Never ")).await, (auto_etag.clone(), auto.clone()));
+        assert!(!auto.windows(4).any(|w| w == b"PK\x06\x06"));
+
+        let (always_etag, always) = archive(Some("always")).await;
+        assert_ne!(always_etag, auto_etag);
+        assert!(always.windows(4).any(|w| w == b"PK\x06\x06"));
+        std::fs::write("test_zip64_header.zip", &always).unwrap();
+        let output = Command::new("unzip").arg("-t").arg("test_zip64_header.zip").output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+
+        let Err((status, msg)) = response(&Config::default(), test_client(), test_http_client(), &req(Some("sometimes")), body.clone()).await else { panic!("expected error") };
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(msg.contains("never, auto, always"));
+
+        // Refused before reading any source, which this client couldn't
+        let large = serde_json::to_vec(&serde_json::json!({ "filename": "test.zip", "entries": [{
+            "archive_name": "large.bin",
+            "source": "s3://bucket/large",
+            "length": 5_000_000_000u64,
+            "crc": 0,
+            "last_modified": "2006-11-10T15:40:56Z",
+        }] })).unwrap().into();
+        let Ok(res) = response(&Config::default(), test_client(), test_http_client(), &req(Some("never")), large).await else { panic!("response failed") };
+        assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(res.headers()[header::VARY], "Accept, X-Zip-Stream-Zip64");
+        let msg = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&msg).contains("requires zip64"));
+    }
+
+    #[tokio::test]
+    async fn test_prefix() {
+        use http_body_util::BodyExt;
+        use std::process::Command;
+
+        let body = manifest(&[("docs/a.txt", "s3://bucket/a"), ("docs/sub/b.txt", "s3://bucket/a"), ("docs2/c.txt", "s3://bucket/a"), ("d.txt", "s3://bucket/a")]);
+        let req = |uri: &str| Request::builder().uri(uri).body(Empty::<Bytes>::new()).unwrap();
+        let names = |uri: &str| {
+            let mut res = parse_manifest(&Config::default(), &body).unwrap();
+            filter_prefix(&req(uri), &mut res).unwrap();
+            res.entries.into_iter().map(|e| e.archive_name).collect::<Vec<_>>()
+        };
+
+        assert_eq!(names("/test.zip?prefix=docs/"), ["docs/a.txt", "docs/sub/b.txt"]);
+        assert_eq!(names("/test.zip?prefix=docs"), ["docs/a.txt", "docs/sub/b.txt"]);
+        assert_eq!(names("/test.zip?prefix=doc"), Vec::<String>::new());
+        assert_eq!(names("/test.zip?prefix=docs2&strip_prefix=true"), ["c.txt"]);
+        assert_eq!(names("/test.zip?prefix=docs&strip_prefix=true"), ["a.txt", "sub/b.txt"]);
+        assert_eq!(names("/test.zip?prefix=docs/sub/b.txt"), ["docs/sub/b.txt"]);
+        assert_eq!(names("/test.zip?prefix=docs%2Fsub"), ["docs/sub/b.txt"]);
+        assert_eq!(names("/test.zip?prefix=docs/&strip_prefix=true"), ["a.txt", "sub/b.txt"]);
+        assert_eq!(names("/test.zip?strip_prefix=true&prefix=docs/sub"), ["b.txt"]);
+        assert_eq!(names("/test.zip"), ["docs/a.txt", "docs/sub/b.txt", "docs2/c.txt", "d.txt"]);
+
+        let mut res = parse_manifest(&Config::default(), &body).unwrap();
+        assert_eq!(filter_prefix(&req("/test.zip?prefix=%FF"), &mut res).unwrap_err().0, StatusCode::BAD_REQUEST);
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+        let archive = |uri: &'static str| {
+            let (client, body) = (client.clone(), body.clone());
+            async move {
+                let Ok(res) = response(&Config::default(), client, test_http_client(), &req(uri), body).await else { panic!("response failed") };
+                let etag = res.headers()[header::ETAG].clone();
+                (etag, BodyExt::collect(res.into_body()).await.unwrap().to_bytes())
+            }
+        };
+
+        // The ETag is that of the selected entries with their new names
+        let (full_etag, _) = archive("/test.zip").await;
+        let (filtered_etag, _) = archive("/test.zip?prefix=docs/").await;
+        let (stripped_etag, zip) = archive("/test.zip?prefix=docs/&strip_prefix=true").await;
+        assert_ne!(full_etag, filtered_etag);
+        assert_ne!(filtered_etag, stripped_etag);
+
+        let selected = manifest(&[("a.txt", "s3://bucket/a"), ("sub/b.txt", "s3://bucket/a")]);
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &req("/test.zip"), selected).await else { panic!("response failed") };
+        assert_eq!(res.headers()[header::ETAG], stripped_etag);
+
+        std::fs::write("test_prefix.zip", &zip).unwrap();
+        let output = Command::new("python3").arg("-c")
+            .arg("import zipfile; z = zipfile.ZipFile('test_prefix.zip'); assert z.testzip() is None; print(' '.join(z.namelist()))")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "a.txt sub/b.txt");
+
+        // A prefix matching nothing gives a valid empty archive
+        let (_, zip) = archive("/test.zip?prefix=missing/").await;
+        std::fs::write("test_prefix_empty.zip", &zip).unwrap();
+        let output = Command::new("python3").arg("-c")
+            .arg("import zipfile; assert zipfile.ZipFile('test_prefix_empty.zip').namelist() == []")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    #[tokio::test]
+    async fn test_since() {
+        use http_body_util::BodyExt;
+
+        let body: Bytes = serde_json::to_vec(&serde_json::json!({"filename": "test.zip", "entries": [
+            {"archive_name": "old.txt", "source": "s3://bucket/a", "length": 2, "crc": 0xf8e1180fu32, "last_modified": "2024-01-01T00:00:00Z"},
+            {"archive_name": "exact.txt", "source": "s3://bucket/a", "length": 2, "crc": 0xf8e1180fu32, "last_modified": "2024-05-01T00:00:00Z"},
+            {"archive_name": "new.txt", "source": "s3://bucket/a", "length": 2, "crc": 0xf8e1180fu32, "last_modified": "2024-06-01T12:00:00Z"},
+        ]})).unwrap().into();
+        let req = |uri: &str| Request::builder().uri(uri).body(Empty::<Bytes>::new()).unwrap();
+        let names = |uri: &str| {
+            let mut res = parse_manifest(&Config::default(), &body).unwrap();
+            filter_since(&req(uri), &mut res).map(|()| res.entries.into_iter().map(|e| e.archive_name).collect::<Vec<_>>())
+        };
+
+        assert_eq!(names("/test.zip?since=2024-05-01T00:00:00Z").unwrap(), ["exact.txt", "new.txt"]);
+        assert_eq!(names("/test.zip?since=2024-05-01T02:00:00%2B02:00").unwrap(), ["exact.txt", "new.txt"]);
+        assert_eq!(names("/test.zip?since=2024-05-01T00:00:01Z").unwrap(), ["new.txt"]);
+        assert_eq!(names("/test.zip").unwrap().len(), 3);
+
+        for invalid in ["yesterday", "2024-05-01", "2024-13-01T00:00:00Z", ""] {
+            let (status, msg) = names(&format!("/test.zip?since={}", invalid)).unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(msg.contains("RFC 3339"), "{}", msg);
+        }
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"xx"[..], "2021-03-04T05:06:08Z");
+        let etag = |uri: &'static str| {
+            let (client, body) = (client.clone(), body.clone());
+            async move {
+                let Ok(res) = response(&Config::default(), client, test_http_client(), &req(uri), body).await else { panic!("response failed") };
+                res.headers()[header::ETAG].clone()
+            }
+        };
+        assert_ne!(etag("/test.zip").await, etag("/test.zip?since=2024-05-01T00:00:00Z").await);
+
+        // Nothing modified since gives a valid empty archive
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &req("/test.zip?since=2025-01-01T00:00:00Z"), body.clone()).await else { panic!("response failed") };
+        let zip = BodyExt::collect(res.into_body()).await.unwrap().to_bytes();
+        std::fs::write("test_since_empty.zip", &zip).unwrap();
+        let output = std::process::Command::new("python3").arg("-c")
+            .arg("import zipfile; assert zipfile.ZipFile('test_since_empty.zip').namelist() == []")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+        let Err((status, _)) = response(&Config::default(), client, test_http_client(), &req("/test.zip?since=soon"), body).await else { panic!("expected invalid since to be rejected") };
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_s3_retries() {
+        use http_body_util::BodyExt;
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        let data: Bytes = (0..100u8).collect::<Vec<u8>>().into();
+        s3.put("bucket", "a", data.clone(), "2021-03-04T05:06:08Z");
+        let body: Bytes = serde_json::to_vec(&serde_json::json!({"filename": "test.zip", "entries": [{
+            "archive_name": "a.bin",
+            "source": "s3://bucket/a",
+            "length": 100,
+            "crc": crc32fast::hash(&data),
+            "last_modified": "2021-03-04T05:06:08Z",
+        }]})).unwrap().into();
+
+        let zip = |config: Config| {
+            let (client, body) = (client.clone(), body.clone());
+            async move {
+                let Ok(res) = response(&config, client, test_http_client(), &test_request(), body).await else { panic!("response failed") };
+                BodyExt::collect(res.into_body()).await.map(|b| b.to_bytes())
+            }
+        };
+        let expected = zip(Config::default()).await.unwrap();
+
+        // Interrupted bodies resume from where they stopped
+        let (_guard, logs) = crate::test_util::capture_logs();
+        s3.truncated_gets.store(2, Ordering::SeqCst);
+        let gets = s3.count(Method::GET);
+        assert_eq!(zip(Config::default()).await.unwrap(), expected);
+        assert_eq!(s3.count(Method::GET) - gets, 3);
+        assert!(s3.requests.lock().unwrap().last().unwrap().1.starts_with("/bucket/a"));
+        assert!(logs.contents().contains("succeeded after 2 retries"), "{}", logs.contents());
+
+        // ...unless retries are disabled, or run out
+        s3.truncated_gets.store(1, Ordering::SeqCst);
+        assert!(zip(Config { s3_max_attempts: 1, ..Default::default() }).await.is_err());
+        s3.truncated_gets.store(3, Ordering::SeqCst);
+        assert!(zip(Config::default()).await.is_err());
+        assert!(logs.contents().contains("failed after 2 retries"));
+
+        s3.get_failures.lock().unwrap().push_back(StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(zip(Config::default()).await.unwrap(), expected);
+
+        // 404 and 403 fail without a retry
+        let gets = s3.count(Method::GET);
+        s3.get_failures.lock().unwrap().push_back(StatusCode::FORBIDDEN);
+        assert!(zip(Config::default()).await.is_err());
+        assert_eq!(s3.count(Method::GET) - gets, 1);
+
+        let missing = manifest(&[("b.txt", "s3://bucket/missing")]);
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), missing).await else { panic!("response failed") };
+        let gets = s3.count(Method::GET);
+        assert!(BodyExt::collect(res.into_body()).await.is_err());
+        assert_eq!(s3.count(Method::GET) - gets, 1);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_small_responses() {
+        use http_body_util::BodyExt;
+
+        async fn frames(res: Response<ResponseBody>) -> Vec<Bytes> {
+            let mut body = res.into_body();
+            let mut frames = vec![];
+            while let Some(frame) = body.frame().await {
+                frames.extend(frame.unwrap().into_data().ok());
+            }
+            frames
+        }
+
+        let (client, s3) = crate::test_util::mock_s3().await;
+        s3.put("bucket", "a", &b"aa"[..], "2021-03-04T05:06:08Z");
+        s3.put("bucket", "b", &b"bb"[..], "2021-03-04T05:06:08Z");
+        let body = manifest(&[("a.txt", "s3://bucket/a"), ("b.txt", "s3://bucket/b")]);
+
+        let Ok(res) = response(&Config::default(), client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+        let streamed = frames(res).await;
+        assert!(streamed.len() > 1);
+        let zip: Bytes = streamed.concat().into();
+
+        let config = Config { buffer_small_responses_under: Some(zip.len() as u64 + 1), ..Default::default() };
+        let Ok(res) = response(&config, client.clone(), test_http_client(), &test_request(), body.clone()).await else { panic!("response failed") };
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], zip.len().to_string());
+        assert_eq!(frames(res).await, vec![zip.clone()]);
+
+        let req = Request::builder().uri("/test.zip").header(header::RANGE, "bytes=0-9").body(Empty::<Bytes>::new()).unwrap();
+        let Ok(res) = response(&config, client.clone(), test_http_client(), &req, body.clone()).await else { panic!("response failed") };
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(frames(res).await, vec![zip.slice(0..10)]);
+
+        // At the limit, the archive is streamed as usual
+        let config = Config { buffer_small_responses_under: Some(zip.len() as u64), ..Default::default() };
+        let Ok(res) = response(&config, client, test_http_client(), &test_request(), body).await else { panic!("response failed") };
+        assert!(frames(res).await.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn test_s3_last_modified_missing_object() {
+        let (client, _s3) = crate::test_util::mock_s3().await;
+
+        let config = Config { s3_last_modified: true, ..Default::default() };
+        let body = manifest(&[("a.txt", "s3://bucket/missing")]);
+        let Err((status, _)) = response(&config, client, test_http_client(), &test_request(), body).await else { panic!("expected failure") };
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+}
@@ -1,24 +1,200 @@
 // © 2019 3D Robotics. License: Apache-2.0
+use aes::{Aes256, cipher::{KeyIvInit, StreamCipher}};
 use bytes::{Bytes, BytesMut, BufMut};
-use crate::stream_range::{ self, StreamRange };
-use chrono::{DateTime, Utc, Datelike, Timelike};
+use crate::stream_range::{ self, BoxBytesStream, BoxError, Range, StreamRange };
+use chrono::{DateTime, Utc, Datelike, TimeZone, Timelike};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::{error::Error, fmt};
+use std::sync::{Arc, OnceLock};
 
 /// A file to be included in a zip archive.
 pub struct ZipEntry {
-    /// Filename within the archive.
+    /// Filename within the archive, written as UTF-8.
     pub archive_path: String,
 
     /// Contents of file.
     pub data: Box<dyn StreamRange>,
 
-    /// CRC32 checksum of the file contents.
-    /// This must be precomputed because it's included in the file header.
-    pub crc: u32,
+    /// CRC32 checksum of the file contents, included in the file header.
+    ///
+    /// If `None`, it is computed while the data is streamed, and written in a
+    /// data descriptor following the data and in the central directory header.
+    /// This only works for responses that include all of the entry's data:
+    /// streaming a range of the archive that includes the data descriptor or
+    /// central directory header of such an entry, but not all of its data,
+    /// fails with an `EntryError`.
+    pub crc: Option<u32>,
 
     /// Last modified date.
     /// If you want the zip file to be reproducible for Range requests, do
     /// not default to the current time.
     pub last_modified: DateTime<Utc>,
+
+    /// The entry is a directory. Its `archive_path` must end with `/`, and
+    /// its `data` must be empty.
+    pub is_directory: bool,
+
+    /// Comment written in the entry's central directory header, truncated to
+    /// 65535 bytes.
+    pub comment: String,
+
+    /// How `data` is compressed. zipstream doesn't compress data itself, but
+    /// can include data that was compressed in advance.
+    pub compression: Compression,
+}
+
+impl ZipEntry {
+    /// Length of the entry's contents once extracted
+    pub fn uncompressed_len(&self) -> u64 {
+        match self.compression {
+            Compression::Stored => self.data.len(),
+            Compression::Deflate { uncompressed_len } | Compression::Aes { uncompressed_len, .. } => uncompressed_len,
+        }
+    }
+
+    /// The larger of the compressed and uncompressed lengths, which decides
+    /// whether the entry's headers need zip64 sizes
+    fn max_len(&self) -> u64 {
+        self.data.len().max(self.uncompressed_len())
+    }
+}
+
+/// The properties of an entry that decide the lengths of its headers, so
+/// that they can be computed without building them
+#[derive(Clone, Copy, Debug)]
+struct EntryShape {
+    path_len: u64,
+    /// Length of the comment as written, truncated to 65535 bytes
+    comment_len: u64,
+    data_len: u64,
+    /// See `ZipEntry::max_len`
+    max_len: u64,
+    compression: Compression,
+    has_crc: bool,
+}
+
+impl EntryShape {
+    fn of(file: &ZipEntry) -> EntryShape {
+        EntryShape {
+            path_len: file.archive_path.len() as u64,
+            comment_len: file.comment.len().min(0xFFFF) as u64,
+            data_len: file.data.len(),
+            max_len: file.max_len(),
+            compression: file.compression,
+            has_crc: file.crc.is_some(),
+        }
+    }
+
+    /// Whether the local header and data descriptor need zip64 sizes
+    fn local_needs_zip64(&self, options: &ZipOptions) -> bool {
+        self.max_len >= 0xFFFFFFFF || options.force_zip64
+    }
+
+    /// Whether the central directory header, with the local header at
+    /// `offset`, needs zip64 sizes and offset
+    fn central_needs_zip64(&self, offset: u64, options: &ZipOptions) -> bool {
+        self.local_needs_zip64(options) || offset >= 0xFFFFFFFF
+    }
+
+    /// Length of the local file header, including the timestamp fields
+    fn local_header_len(&self, options: &ZipOptions) -> u64 {
+        let zip64_values = if self.local_needs_zip64(options) { 2 } else { 0 };
+        30 + self.path_len + extra_fields_len(zip64_values, self.compression, timestamp_fields_len(options))
+    }
+
+    /// Length of the central directory file header, with the local header at `offset`
+    fn central_header_len(&self, offset: u64, options: &ZipOptions) -> u64 {
+        let zip64_values = if self.central_needs_zip64(offset, options) { 3 } else { 0 };
+        46 + self.path_len + extra_fields_len(zip64_values, self.compression, timestamp_fields_len(options)) + self.comment_len
+    }
+
+    /// Length of the data descriptor following the data of an entry without a
+    /// `crc`, or 0 for an entry with one
+    fn data_descriptor_len(&self, options: &ZipOptions) -> u64 {
+        match (self.has_crc, self.local_needs_zip64(options)) {
+            (true, _) => 0,
+            (false, false) => 16,
+            (false, true) => 24,
+        }
+    }
+}
+
+/// Compression method of a zip entry's data
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum Compression {
+    /// The data is the file contents
+    #[default]
+    Stored,
+
+    /// The data is a raw DEFLATE stream (RFC 1951, without a zlib or gzip
+    /// wrapper) of `uncompressed_len` bytes of contents. The entry's `crc` is
+    /// that of the uncompressed contents, and must be given, or `zip_stream`
+    /// fails with `ZipError::CompressedRequiresCrc`.
+    Deflate { uncompressed_len: u64 },
+
+    /// The data is encrypted with WinZip AES, as `zip_stream` does for
+    /// `ZipOptions::password`, from contents compressed with compression
+    /// `method` (0 for stored, 8 for deflate) to `uncompressed_len` bytes.
+    Aes { method: u16, uncompressed_len: u64 },
+}
+
+impl Compression {
+    fn method(self) -> u16 {
+        match self {
+            Compression::Stored => 0,
+            Compression::Deflate { .. } => 8,
+            Compression::Aes { .. } => 99,
+        }
+    }
+}
+
+/// Password for `ZipOptions::password`, which `Debug` doesn't show
+#[derive(Clone, PartialEq, Eq)]
+pub struct Password(Arc<[u8]>);
+
+impl Password {
+    pub fn new(password: &[u8]) -> Password {
+        Password(password.into())
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(..)")
+    }
+}
+
+/// Host system recorded in the upper byte of the "version made by" field.
+///
+/// This determines how extractors interpret the external file attributes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum HostSystem {
+    /// MS-DOS / FAT. External attributes are DOS attribute bits.
+    Fat,
+
+    /// Unix. External attributes hold the file mode in the upper 16 bits.
+    #[default]
+    Unix,
+}
+
+impl HostSystem {
+    fn id(self) -> u8 {
+        match self {
+            HostSystem::Fat => 0,
+            HostSystem::Unix => 3,
+        }
+    }
+
+    fn external_attributes(self, is_directory: bool) -> u32 {
+        match (self, is_directory) {
+            (HostSystem::Fat, false) => 0x20, // FILE_ATTRIBUTE_ARCHIVE
+            (HostSystem::Fat, true) => 0x10, // FILE_ATTRIBUTE_DIRECTORY
+            (HostSystem::Unix, false) => 0x81A40000, // -rw-r--r--
+            (HostSystem::Unix, true) => 0x41ED0000, // drwxr-xr-x
+        }
+    }
 }
 
 /// Options passed to `zip_stream`
@@ -27,22 +203,141 @@ pub struct ZipOptions {
     /// Create a zip file using zip64 extensions even if the file will be under 2^32 bytes.
     /// Otherwise, zip64 will be used only if necessary.
     pub force_zip64: bool,
+
+    /// Host system written in the "version made by" field of central directory headers.
+    pub host_system: HostSystem,
+
+    /// Zip spec version written in the "version made by" field, as major * 10 + minor.
+    /// Defaults to 2.0.
+    pub version_made_by: Option<u8>,
+
+    /// Minimum "version needed to extract" written in every header, as
+    /// major * 10 + minor, for extractors that require a particular value.
+    /// Only the field changes; the archive uses the same features either way.
+    pub min_version: Option<u8>,
+
+    /// Add a first entry named `.zip-central-directory` containing the central
+    /// directory records of all the other entries, byte-for-byte identical to
+    /// those at the end of the archive. This lets clients reading over HTTP
+    /// index the archive from its start instead of fetching the tail first.
+    /// The entry is an ordinary stored file, so the archive remains valid.
+    /// Every entry must have a `crc`, since the records are written first.
+    pub central_directory_hint: bool,
+
+    /// Archive comment written in the end of central directory record.
+    /// Truncated to the 65535 bytes the format allows.
+    pub comment: String,
+
+    /// Start the archive with the `PK00` temporary spanning marker the spec
+    /// describes for split archives that turned out to need a single segment,
+    /// for legacy tools that expect it. The archive isn't actually split.
+    pub spanning_marker: bool,
+
+    /// Also write each entry's modification time in an NTFS extra field,
+    /// which has 100 ns resolution and no 2038 limit, unlike the DOS time
+    /// (2 seconds) and the extended timestamp (32-bit Unix seconds).
+    pub ntfs_timestamps: bool,
+
+    /// Start reading the data of up to this many following entries while
+    /// streaming each one, so that archives of many small files from S3 don't
+    /// stall at each entry while its request starts. The output is the same.
+    pub prefetch_entries: usize,
+
+    /// Encrypt the data of each file with WinZip AES-256 (AE-2) and this
+    /// password, which 7-Zip and WinZip can extract. Names, sizes and dates
+    /// aren't encrypted, nor is the central directory hint. Each entry gets
+    /// a random salt, so the archive differs each time it is streamed, and
+    /// only the full archive can be streamed: a range that includes part of
+    /// an entry's data fails with an `EntryError`.
+    pub password: Option<Password>,
 }
 
+/// Archive path of the entry added by `ZipOptions::central_directory_hint`
+pub const CENTRAL_DIRECTORY_HINT_PATH: &str = ".zip-central-directory";
+
 // Zip format spec:
 // https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
 
 const ZIP64_VERSION: u8 = 45;
 const BASE_VERSION: u8 = 20;
 
+/// Temporary spanning marker, see APPNOTE section 8.5.4
+const SPANNING_MARKER: &[u8] = b"PK00";
+
+/// Length of the data before the first local file header
+fn archive_prefix_len(options: &ZipOptions) -> u64 {
+    if options.spanning_marker { SPANNING_MARKER.len() as u64 } else { 0 }
+}
+
+/// The "version needed to extract" field for an entry or record
+fn version_needed(needs_zip64: bool, options: &ZipOptions) -> u16 {
+    let version = if needs_zip64 { ZIP64_VERSION } else { BASE_VERSION };
+    version.max(options.min_version.unwrap_or(0)) as u16
+}
+
+/// The "version made by" field of the central directory headers and the
+/// zip64 end of central directory record: the zip spec version, then the host
+/// system
+fn version_made_by(options: &ZipOptions) -> u16 {
+    u16::from_le_bytes([options.version_made_by.unwrap_or(BASE_VERSION), options.host_system.id()])
+}
+
+/// Version that 7-Zip and WinZip write for AES encrypted entries
+const AES_VERSION: u16 = 51;
+
+/// The "version needed to extract" field for the headers of `file`
+fn entry_version_needed(file: &ZipEntry, needs_zip64: bool, options: &ZipOptions) -> u16 {
+    let version = version_needed(needs_zip64, options);
+    match file.compression {
+        Compression::Aes { .. } => version.max(AES_VERSION),
+        _ => version,
+    }
+}
+
+/// General purpose bit 0: the data is encrypted
+const FLAG_ENCRYPTED: u16 = 0x0001;
+
+/// General purpose bit 3: the CRC and sizes are in a data descriptor following the data
+const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+
+/// General purpose bit 11: the file name is UTF-8. Without it, extractors
+/// such as Windows Explorer decode it as code page 437.
+const FLAG_UTF8: u16 = 0x0800;
+
+/// The general purpose bit flag for an entry
+fn general_purpose_flags(file: &ZipEntry) -> u16 {
+    let mut flags = 0;
+    if let Compression::Aes { .. } = file.compression {
+        flags |= FLAG_ENCRYPTED;
+    }
+    if file.crc.is_none() {
+        flags |= FLAG_DATA_DESCRIPTOR;
+    }
+    // ASCII names are the same in both encodings, so only others are flagged
+    if !file.archive_path.is_ascii() {
+        flags |= FLAG_UTF8;
+    }
+    flags
+}
+
+/// Clamp `t` to the range of MS-DOS dates and times, 1980-01-01 00:00:00 to
+/// 2107-12-31 23:59:58, whose 7-bit year would otherwise wrap
+fn dos_clamp(t: DateTime<Utc>) -> DateTime<Utc> {
+    let min = Utc.with_ymd_and_hms(1980, 1, 1, 0, 0, 0).unwrap();
+    let max = Utc.with_ymd_and_hms(2107, 12, 31, 23, 59, 58).unwrap();
+    t.clamp(min, max)
+}
+
 fn zip_date(t: DateTime<Utc>) -> u16 {
-    let year = t.year().saturating_sub(1980) as u16;
+    let t = dos_clamp(t);
+    let year = (t.year() - 1980) as u16;
     let month = t.month() as u16;
     let day = t.day() as u16;
     day | month << 5 | year << 9
 }
 
 fn zip_time(t: DateTime<Utc>) -> u16 {
+    let t = dos_clamp(t);
     let second = (t.second() / 2) as u16;
     let minute = t.minute() as u16;
     let hour = t.hour() as u16;
@@ -54,78 +349,209 @@ fn test_zip_date_time() {
     let t = "2006-10-11T15:40:56Z".parse::<DateTime<Utc>>().unwrap();
     assert_eq!(zip_time(t), 0x7d1c);
     assert_eq!(zip_date(t), 0x354b);
+
+    let t = "1970-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    assert_eq!((zip_date(t), zip_time(t)), (0x0021, 0x0000));
+    let t = "2200-06-15T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    assert_eq!((zip_date(t), zip_time(t)), (0xff9f, 0xbf7d));
+}
+
+/// `t` as the unsigned 32-bit Unix time of the extended timestamp field,
+/// saturating before 1970 and after 2106
+fn unix_time(t: DateTime<Utc>) -> u32 {
+    t.timestamp().clamp(0, u32::MAX as i64) as u32
+}
+
+#[test]
+fn test_unix_time() {
+    assert_eq!(unix_time("2006-10-11T15:40:56Z".parse().unwrap()), 1_160_581_256);
+    assert_eq!(unix_time("1960-01-01T00:00:00Z".parse().unwrap()), 0);
+    assert_eq!(unix_time("2200-01-01T00:00:00Z".parse().unwrap()), u32::MAX);
+}
+
+/// Length of the NTFS extra field written with `ZipOptions::ntfs_timestamps`
+const NTFS_FIELD_LEN: usize = 36;
+
+/// Seconds from the FILETIME epoch, 1601-01-01, to the Unix epoch
+const FILETIME_UNIX_EPOCH: i128 = 11_644_473_600;
+
+/// `t` as a Windows FILETIME, in 100 ns intervals since 1601-01-01,
+/// saturating outside the range it can represent
+fn filetime(t: DateTime<Utc>) -> u64 {
+    let intervals = (t.timestamp() as i128 + FILETIME_UNIX_EPOCH) * 10_000_000 + (t.timestamp_subsec_nanos() / 100) as i128;
+    intervals.clamp(0, u64::MAX as i128) as u64
+}
+
+#[test]
+fn test_filetime() {
+    assert_eq!(filetime(DateTime::UNIX_EPOCH), 116_444_736_000_000_000);
+    assert_eq!(filetime("2006-10-11T15:40:56.1234567Z".parse().unwrap()), 128_050_548_561_234_567);
+    assert_eq!(filetime("1500-01-01T00:00:00Z".parse().unwrap()), 0);
 }
 
-fn local_file_header(file: &ZipEntry, force_zip64: bool) -> Bytes {
-    let needs_zip64 = file.data.len() >= 0xFFFFFFFF || force_zip64;
-    let mut buf = BytesMut::with_capacity(30 + file.archive_path.len() + if needs_zip64 { 20 } else { 0 } + 9);
+/// Timestamp extra fields, which end both the local and central directory
+/// headers: the extended timestamp, followed by the NTFS timestamps with
+/// `ZipOptions::ntfs_timestamps`. They're the same for all entries with the
+/// same modification time, so `zip_stream` shares them between them.
+fn timestamp_fields(last_modified: DateTime<Utc>, options: &ZipOptions) -> Bytes {
+    let mut buf = BytesMut::with_capacity(timestamp_fields_len(options));
+    buf.put_u16_le(0x5455); // UT
+    buf.put_u16_le(5); // Length
+    buf.put_u8(1); // last modified date present
+    buf.put_u32_le(unix_time(last_modified)); // last modified timestamp
+
+    if options.ntfs_timestamps {
+        // Extractors expect all three times, so the access and creation times
+        // repeat the modification time
+        let time = filetime(last_modified);
+        buf.put_u16_le(0x000A); // NTFS
+        buf.put_u16_le(NTFS_FIELD_LEN as u16 - 4); // Length
+        buf.put_u32_le(0); // Reserved
+        buf.put_u16_le(0x0001); // Attribute tag 1: timestamps
+        buf.put_u16_le(24); // Size of attribute
+        buf.put_u64_le(time); // Last modification time
+        buf.put_u64_le(time); // Last access time
+        buf.put_u64_le(time); // Creation time
+    }
+
+    buf.freeze()
+}
+
+/// Length of the fields returned by `timestamp_fields`
+fn timestamp_fields_len(options: &ZipOptions) -> usize {
+    9 + if options.ntfs_timestamps { NTFS_FIELD_LEN } else { 0 }
+}
+
+/// Length of the AES extra field of encrypted entries
+const AES_FIELD_LEN: u64 = 11;
+
+/// Length of the extra fields `extra_fields` builds for `zip64_values`
+/// values and `compression`, followed by a timestamp field of `timestamp_len`
+fn extra_fields_len(zip64_values: u64, compression: Compression, timestamp_len: usize) -> u64 {
+    let zip64_len = if zip64_values > 0 { 4 + zip64_values * 8 } else { 0 };
+    let aes_len = if let Compression::Aes { .. } = compression { AES_FIELD_LEN } else { 0 };
+    zip64_len + aes_len + timestamp_len as u64
+}
+
+/// Build the zip64 extended information extra field holding `values`, if
+/// any, and the AES extra field of an encrypted entry, and return them with
+/// the total length of them and `timestamp_field`, which follows them, for
+/// the header's extra field length.
+fn extra_fields(zip64_values: Option<&[u64]>, compression: Compression, timestamp_field: &[u8]) -> (Bytes, u16) {
+    let mut buf = BytesMut::new();
+
+    if let Some(values) = zip64_values {
+        buf.put_u16_le(0x0001); // Zip64 extended information
+        buf.put_u16_le(values.len() as u16 * 8); // Size of this "extra" block
+        for &value in values {
+            buf.put_u64_le(value);
+        }
+    }
+
+    if let Compression::Aes { method, .. } = compression {
+        buf.put_u16_le(0x9901); // AE-x encryption structure
+        buf.put_u16_le(AES_FIELD_LEN as u16 - 4); // Data size
+        buf.put_u16_le(2); // Version number: AE-2, without a CRC
+        buf.put_slice(b"AE"); // Vendor ID
+        buf.put_u8(3); // Encryption strength: AES-256
+        buf.put_u16_le(method); // Actual compression method
+    }
+
+    let len = buf.len() + timestamp_field.len();
+    (buf.freeze(), len as u16)
+}
+
+fn local_file_header(file: &ZipEntry, options: &ZipOptions) -> Bytes {
+    let timestamp_field = timestamp_fields(file.last_modified, options);
+    let mut buf = BytesMut::from(&local_file_header_prefix(file, options, &timestamp_field)[..]);
+    buf.put_slice(&timestamp_field);
+    buf.freeze()
+}
+
+/// The local file header up to `timestamp_field`, which must follow it
+///
+/// Without a `crc`, the CRC and sizes are zero, and are instead given by the
+/// data descriptor.
+fn local_file_header_prefix(file: &ZipEntry, options: &ZipOptions, timestamp_field: &[u8]) -> Bytes {
+    let needs_zip64 = EntryShape::of(file).local_needs_zip64(options);
+    let (compressed_size, uncompressed_size) = if file.crc.is_some() { (file.data.len(), file.uncompressed_len()) } else { (0, 0) };
+    let zip64_values = [
+        uncompressed_size, // Original uncompressed file size
+        compressed_size, // Size of compressed data
+    ];
+    let (extra_field, extra_len) = extra_fields(needs_zip64.then_some(&zip64_values[..]), file.compression, timestamp_field);
+    let mut buf = BytesMut::with_capacity(30 + file.archive_path.len() + extra_field.len());
 
     buf.put_u32_le(0x04034b50); // local file header signature
-    buf.put_u16_le(if needs_zip64 { ZIP64_VERSION } else { BASE_VERSION } as u16); //  version needed to extract
-    buf.put_u16_le(0); // general purpose bit flag
-    buf.put_u16_le(0); // compression method
+    buf.put_u16_le(entry_version_needed(file, needs_zip64, options)); //  version needed to extract
+    buf.put_u16_le(general_purpose_flags(file)); // general purpose bit flag
+    buf.put_u16_le(file.compression.method()); // compression method
     buf.put_u16_le(zip_time(file.last_modified)); // last mod file time
     buf.put_u16_le(zip_date(file.last_modified)); // last mod file date
-    buf.put_u32_le(file.crc); // crc-32
+    buf.put_u32_le(file.crc.unwrap_or(0)); // crc-32
 
     if needs_zip64 {
         buf.put_u32_le(0xFFFFFFFF); // compressed size
         buf.put_u32_le(0xFFFFFFFF); // uncompressed size
     } else {
-        buf.put_u32_le(file.data.len() as u32); // compressed size
-        buf.put_u32_le(file.data.len() as u32); // uncompressed size
+        buf.put_u32_le(compressed_size as u32); // compressed size
+        buf.put_u32_le(uncompressed_size as u32); // uncompressed size
     }
 
     buf.put_u16_le(file.archive_path.len() as u16); // file name length
-    buf.put_u16_le(if needs_zip64 { 20 } else { 0 } + 9); // extra field length
+    buf.put_u16_le(extra_len); // extra field length
 
     // file name
     buf.put_slice(file.archive_path.as_bytes());
 
-    if needs_zip64 {
-        buf.put_u16_le(0x0001); // Zip64 extended information
-        buf.put_u16_le(16); // Size of this "extra" block
-        buf.put_u64_le(file.data.len()); // Original uncompressed file size
-        buf.put_u64_le(file.data.len()); // Size of compressed data
-    }
-
-    // Extended timestamp header
-    buf.put_u16_le(0x5455); // UT
-    buf.put_u16_le(5); // Length
-    buf.put_u8(1); // last modified date present
-    buf.put_u32_le(file.last_modified.timestamp() as u32); // last modified timestamp
+    buf.put_slice(&extra_field);
 
     buf.freeze()
 }
 
-fn central_directory_file_header(file: &ZipEntry, offset: u64, force_zip64: bool) -> Bytes {
-    let needs_zip64 = file.data.len() >= 0xFFFFFFFF || offset >= 0xFFFFFFFF || force_zip64;
-    let mut buf = BytesMut::with_capacity(46 + file.archive_path.len() + if needs_zip64 { 28 } else { 0 } + 9);
+fn central_directory_file_header(file: &ZipEntry, offset: u64, options: &ZipOptions) -> Bytes {
+    central_directory_file_header_with(file, offset, options, &timestamp_fields(file.last_modified, options))
+}
+
+/// The central directory file header ending with `timestamp_field`.
+///
+/// Without a `crc`, the CRC field at `CENTRAL_HEADER_CRC_OFFSET` is zero, to
+/// be filled in once it is computed.
+fn central_directory_file_header_with(file: &ZipEntry, offset: u64, options: &ZipOptions, timestamp_field: &[u8]) -> Bytes {
+    let needs_zip64 = EntryShape::of(file).central_needs_zip64(offset, options);
+    let zip64_values = [
+        file.uncompressed_len(), // Original uncompressed file size
+        file.data.len(), // Size of compressed data
+        offset, // Offset of local header record
+    ];
+    let (extra_field, extra_len) = extra_fields(needs_zip64.then_some(&zip64_values[..]), file.compression, timestamp_field);
+    let comment = file.comment.as_bytes();
+    let comment = &comment[..comment.len().min(0xFFFF)];
+    let mut buf = BytesMut::with_capacity(46 + file.archive_path.len() + extra_len as usize + comment.len());
 
     buf.put_u32_le(0x02014b50); // central file header signature
-    buf.put_u8(BASE_VERSION); // version made by = zip spec 4.5
-    buf.put_u8(3); // version made by = unix
-    buf.put_u16_le(if needs_zip64 { ZIP64_VERSION } else { BASE_VERSION } as u16); //  version needed to extract
-    buf.put_u16_le(0); // general purpose bit flag
-    buf.put_u16_le(0); // compression method
+    buf.put_u16_le(version_made_by(options)); // version made by = zip spec version, host system
+    buf.put_u16_le(entry_version_needed(file, needs_zip64, options)); //  version needed to extract
+    buf.put_u16_le(general_purpose_flags(file)); // general purpose bit flag
+    buf.put_u16_le(file.compression.method()); // compression method
     buf.put_u16_le(zip_time(file.last_modified)); // last mod file time
     buf.put_u16_le(zip_date(file.last_modified)); // last mod file date
-    buf.put_u32_le(file.crc); // crc-32
+    buf.put_u32_le(file.crc.unwrap_or(0)); // crc-32
 
     if needs_zip64 {
         buf.put_u32_le(0xFFFFFFFF); // compressed size
         buf.put_u32_le(0xFFFFFFFF); // uncompressed size
     } else {
         buf.put_u32_le(file.data.len() as u32); // compressed size
-        buf.put_u32_le(file.data.len() as u32); // uncompressed size
+        buf.put_u32_le(file.uncompressed_len() as u32); // uncompressed size
     }
     
     buf.put_u16_le(file.archive_path.len() as u16); // file name length
-    buf.put_u16_le(if needs_zip64 { 28 } else { 0 } + 9); // extra field length
-    buf.put_u16_le(0); // file comment length
+    buf.put_u16_le(extra_len); // extra field length
+    buf.put_u16_le(comment.len() as u16); // file comment length
     buf.put_u16_le(0); // disk number start
     buf.put_u16_le(0); // internal file attributes
-    buf.put_u32_le(0x81A40000); // external file attributes (-rw-r--r--)
+    buf.put_u32_le(options.host_system.external_attributes(file.is_directory)); // external file attributes
 
     if needs_zip64 {
         buf.put_u32_le(0xFFFFFFFF);
@@ -135,32 +561,66 @@ fn central_directory_file_header(file: &ZipEntry, offset: u64, force_zip64: bool
 
     buf.extend(file.archive_path.as_bytes());
 
-    if needs_zip64 {
-        buf.put_u16_le(0x0001); // Zip64 extended information
-        buf.put_u16_le(24); // Size of this "extra" block
-        buf.put_u64_le(file.data.len()); // Original uncompressed file size
-        buf.put_u64_le(file.data.len()); // Size of compressed data
-        buf.put_u64_le(offset); // Offset of local header record
-    }
+    buf.put_slice(&extra_field);
+    buf.put_slice(timestamp_field);
+    buf.put_slice(comment); // file comment
 
-    // Extended timestamp header
-    buf.put_u16_le(0x5455); // UT
-    buf.put_u16_le(5); // Length
-    buf.put_u8(1); // last modified date present
-    buf.put_u32_le(file.last_modified.timestamp() as u32); // last modified timestamp
+    buf.freeze()
+}
+
+/// Position of the CRC in a central directory file header
+const CENTRAL_HEADER_CRC_OFFSET: usize = 16;
+
+/// Position of the CRC in a data descriptor
+const DATA_DESCRIPTOR_CRC_OFFSET: usize = 4;
+
+/// See `EntryShape::data_descriptor_len`
+fn data_descriptor_len(file: &ZipEntry, options: &ZipOptions) -> u64 {
+    EntryShape::of(file).data_descriptor_len(options)
+}
+
+/// The data descriptor of an entry without a `crc`, with the CRC field at
+/// `DATA_DESCRIPTOR_CRC_OFFSET` zero, to be filled in once it is computed.
+/// It has 8-byte sizes if the local header has a zip64 extra field.
+fn data_descriptor(file: &ZipEntry, options: &ZipOptions) -> Bytes {
+    let mut buf = BytesMut::with_capacity(24);
+    buf.put_u32_le(0x08074b50); // data descriptor signature
+    buf.put_u32_le(0); // crc-32
+
+    if data_descriptor_len(file, options) == 24 {
+        buf.put_u64_le(file.data.len()); // compressed size
+        buf.put_u64_le(file.uncompressed_len()); // uncompressed size
+    } else {
+        buf.put_u32_le(file.data.len() as u32); // compressed size
+        buf.put_u32_le(file.uncompressed_len() as u32); // uncompressed size
+    }
 
     buf.freeze()
 }
 
-fn end_of_central_directory(central_directory_offset: u64, size_of_central_directory: u64, num_entries: u64, force_zip64: bool) -> Bytes {
-    let mut buf = BytesMut::with_capacity(56 + 20 + 22);
+/// Whether the end of the archive needs the zip64 end of central directory
+/// record and locator
+fn end_needs_zip64(central_directory_offset: u64, size_of_central_directory: u64, num_entries: u64, options: &ZipOptions) -> bool {
+    num_entries >= 0xFFFF || size_of_central_directory >= 0xFFFFFFFF || central_directory_offset >= 0xFFFFFFFF || options.force_zip64
+}
 
-    if num_entries >= 0xFFFF || size_of_central_directory >= 0xFFFFFFFF || central_directory_offset >= 0xFFFFFFFF || force_zip64 {
+/// Length of the records `end_of_central_directory` builds
+fn end_of_central_directory_len(central_directory_offset: u64, size_of_central_directory: u64, num_entries: u64, options: &ZipOptions) -> u64 {
+    let zip64_len = if end_needs_zip64(central_directory_offset, size_of_central_directory, num_entries, options) { 56 + 20 } else { 0 };
+    zip64_len + 22 + options.comment.len().min(0xFFFF) as u64
+}
+
+fn end_of_central_directory(central_directory_offset: u64, size_of_central_directory: u64, num_entries: u64, options: &ZipOptions) -> Bytes {
+    let comment = options.comment.as_bytes();
+    let comment = &comment[..comment.len().min(0xFFFF)];
+    let mut buf = BytesMut::with_capacity(56 + 20 + 22 + comment.len());
+
+    if end_needs_zip64(central_directory_offset, size_of_central_directory, num_entries, options) {
         // Zip64 end of central directory record
         buf.put_u32_le(0x06064b50); //  signature
         buf.put_u64_le(56-12); // size of zip64 end of central directory record
-        buf.put_u16_le(ZIP64_VERSION as u16); // version made by
-        buf.put_u16_le(ZIP64_VERSION as u16); // version needed to extract
+        buf.put_u16_le(version_made_by(options)); // version made by
+        buf.put_u16_le(version_needed(true, options)); // version needed to extract
         buf.put_u32_le(0); //   number of this disk
         buf.put_u32_le(0); //   number of the disk with the start of the central directory
         buf.put_u64_le(num_entries); //   total number of entries in the central directory on this disk
@@ -187,36 +647,523 @@ fn end_of_central_directory(central_directory_offset: u64, size_of_central_direc
     buf.put_u16_le(num_entries_16); // total number of entries in the central directory
     buf.put_u32_le(size_of_central_directory_32); // size of the central directory
     buf.put_u32_le(central_directory_offset_32); // offset of start of central directory with respect to the starting disk number
-    buf.put_u16_le(0); //  .ZIP file comment length
+    buf.put_u16_le(comment.len() as u16); //  .ZIP file comment length
+    buf.put_slice(comment); // .ZIP file comment
 
     buf.freeze()
 }
 
+/// Error from the data of a zip entry, identifying the entry
+#[derive(Debug)]
+pub struct EntryError {
+    pub archive_path: String,
+    pub inner: BoxError,
+}
+
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to read data for {}", self.archive_path)
+    }
+}
+
+impl Error for EntryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.inner)
+    }
+}
+
+/// Why `zip_stream` can't build an archive from its entries and options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZipError {
+    /// `ZipOptions::central_directory_hint` is set, but this entry has no
+    /// `crc`. The hint precedes the data, so the CRC can't be computed first.
+    HintRequiresCrc { archive_path: String },
+
+    /// This entry is compressed but has no `crc`. One computed while
+    /// streaming would be that of the compressed data.
+    CompressedRequiresCrc { archive_path: String },
+}
+
+impl fmt::Display for ZipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZipError::HintRequiresCrc { archive_path } => write!(f, "The central directory hint requires the crc of {}", archive_path),
+            ZipError::CompressedRequiresCrc { archive_path } => write!(f, "Compressed entry {} requires a crc", archive_path),
+        }
+    }
+}
+
+impl Error for ZipError {}
+
+/// Header bytes containing the CRC of an entry without a `crc`, which is
+/// filled in at `crc_offset` when the bytes are streamed, after the entry's
+/// data has been streamed and the CRC computed.
+struct PendingCrc {
+    bytes: Bytes,
+    crc_offset: usize,
+    crc: Arc<OnceLock<u32>>,
+    archive_path: String,
+}
+
+impl StreamRange for PendingCrc {
+    fn len(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    fn stream_range(&self, range: Range) -> BoxBytesStream {
+        let (bytes, crc_offset, crc, archive_path) = (self.bytes.clone(), self.crc_offset, self.crc.clone(), self.archive_path.clone());
+
+        // Deferred until polled, since the streams of all the parts of a
+        // response are created before any of them are streamed
+        Box::pin(stream::once(future::lazy(move |_| {
+            let Some(crc) = crc.get() else {
+                return Err(EntryError {
+                    archive_path,
+                    inner: "CRC is unknown because the response doesn't include all of the entry's data".into(),
+                }.into());
+            };
+
+            let mut buf = BytesMut::from(&bytes[..]);
+            buf[crc_offset..crc_offset + 4].copy_from_slice(&crc.to_le_bytes());
+            Ok(buf.freeze().slice(range.start as usize..range.end as usize))
+        })))
+    }
+}
+
+/// Pass through `data`, storing the CRC of all of it in `crc` when it ends
+fn compute_crc(data: BoxBytesStream, crc: Arc<OnceLock<u32>>) -> BoxBytesStream {
+    Box::pin(stream::unfold((data, Some(crc32fast::Hasher::new())), move |(mut data, mut hasher)| {
+        let crc = crc.clone();
+        async move {
+            match data.next().await {
+                Some(Ok(chunk)) => {
+                    if let Some(hasher) = &mut hasher {
+                        hasher.update(&chunk);
+                    }
+                    Some((Ok(chunk), (data, hasher)))
+                }
+                // The data is incomplete, so its CRC remains unknown
+                Some(Err(e)) => Some((Err(e), (data, None))),
+                None => {
+                    if let Some(hasher) = hasher {
+                        let _ = crc.set(hasher.finalize());
+                    }
+                    None
+                }
+            }
+        }
+    }))
+}
+
+/// Pass through `data`, failing if it produces more or fewer than `len` bytes,
+/// which would leave the following headers at other offsets than the archive
+/// declares. An overrunning chunk is not passed through.
+fn check_len(data: BoxBytesStream, len: u64) -> BoxBytesStream {
+    Box::pin(stream::unfold((data, 0u64, false), move |(mut data, mut count, done)| async move {
+        if done {
+            return None;
+        }
+
+        match data.next().await {
+            Some(Ok(chunk)) => {
+                count += chunk.len() as u64;
+                if count > len {
+                    let e = format!("Data is longer than its length of {} bytes", len);
+                    return Some((Err(e.into()), (data, count, true)));
+                }
+                Some((Ok(chunk), (data, count, false)))
+            }
+            Some(Err(e)) => Some((Err(e), (data, count, false))),
+            None if count < len => {
+                let e = format!("Data ended after {} of its {} bytes", count, len);
+                Some((Err(e.into()), (data, count, true)))
+            }
+            None => None,
+        }
+    }))
+}
+
+/// A zip entry's local file header and data, and its data descriptor if it
+/// has no `crc`. Errors from the data are wrapped to add the entry's path.
+///
+/// The header's extended timestamp field is kept separately so that it can
+/// be shared with other entries.
+struct LocalEntry {
+    header_prefix: Bytes,
+    timestamp_field: Bytes,
+    archive_path: String,
+    data: Box<dyn StreamRange>,
+    data_descriptor: Option<PendingCrc>,
+}
+
+impl StreamRange for LocalEntry {
+    fn len(&self) -> u64 {
+        self.header_prefix.len() as u64 + self.timestamp_field.len() as u64 + self.data.len()
+            + self.data_descriptor.as_ref().map_or(0, |d| d.len())
+    }
+
+    fn stream_range(&self, mut range: Range) -> BoxBytesStream {
+        let mut streams = Vec::with_capacity(3);
+
+        for part in [&self.header_prefix, &self.timestamp_field] {
+            if let Some(part_range) = range.take_prefix(part.len() as u64).filter(|r| !r.is_empty()) {
+                streams.push(part.stream_range(part_range));
+            }
+        }
+
+        if let Some(data_range) = range.take_prefix(self.data.len()).filter(|r| !r.is_empty()) {
+            let archive_path = self.archive_path.clone();
+            let mut data = check_len(self.data.stream_range(data_range), data_range.len());
+            if let Some(descriptor) = self.data_descriptor.as_ref().filter(|_| data_range.len() == self.data.len()) {
+                data = compute_crc(data, descriptor.crc.clone());
+            }
+            streams.push(Box::pin(data.map_err(move |inner| {
+                EntryError { archive_path: archive_path.clone(), inner }.into()
+            })));
+        }
+
+        if let Some(descriptor) = &self.data_descriptor {
+            if let Some(descriptor_range) = range.take_prefix(descriptor.len()).filter(|r| !r.is_empty()) {
+                streams.push(descriptor.stream_range(descriptor_range));
+            }
+        }
+
+        Box::pin(stream::iter(streams).flatten())
+    }
+}
+
+// WinZip AES encryption spec:
+// https://www.winzip.com/en/support/aes-encryption/
+
+/// Length of the salt before the encrypted data, for AES-256
+const AES_SALT_LEN: usize = 16;
+
+/// Length of the password verification value following the salt
+const AES_VERIFIER_LEN: usize = 2;
+
+/// Length of the authentication code following the encrypted data
+const AES_AUTH_CODE_LEN: usize = 10;
+
+/// Bytes that encryption adds to the data of an entry
+const AES_OVERHEAD: u64 = (AES_SALT_LEN + AES_VERIFIER_LEN + AES_AUTH_CODE_LEN) as u64;
+
+/// PBKDF2 iterations deriving the keys from the password and salt
+const AES_KEY_ITERATIONS: u32 = 1000;
+
+/// AES-256 in CTR mode with the little-endian counter WinZip uses
+type AesCtr = ctr::Ctr128LE<Aes256>;
+
+/// `file` with its data encrypted with `password`, unless it is a directory
+fn encrypt(file: ZipEntry, password: &Password) -> ZipEntry {
+    if file.is_directory {
+        return file;
+    }
+
+    let compression = Compression::Aes { method: file.compression.method(), uncompressed_len: file.uncompressed_len() };
+    ZipEntry {
+        // AE-2 doesn't store the CRC, since the authentication code checks the data
+        crc: Some(0),
+        data: Box::new(AesData { data: file.data, password: password.clone() }),
+        compression,
+        ..file
+    }
+}
+
+/// The shape of an entry once `encrypt` has encrypted it
+fn encrypted_shape(file: &ZipEntry) -> EntryShape {
+    let shape = EntryShape::of(file);
+    if file.is_directory {
+        return shape;
+    }
+
+    let data_len = shape.data_len + AES_OVERHEAD;
+    EntryShape {
+        data_len,
+        max_len: data_len.max(file.uncompressed_len()),
+        compression: Compression::Aes { method: file.compression.method(), uncompressed_len: file.uncompressed_len() },
+        has_crc: true,
+        ..shape
+    }
+}
+
+/// The data of an entry encrypted with WinZip AES-256: a random salt and the
+/// password verification value, the data encrypted with AES-CTR, and the
+/// authentication code of the encrypted data.
+struct AesData {
+    data: Box<dyn StreamRange>,
+    password: Password,
+}
+
+impl StreamRange for AesData {
+    fn len(&self) -> u64 {
+        self.data.len() + AES_OVERHEAD
+    }
+
+    fn stream_range(&self, range: Range) -> BoxBytesStream {
+        // The authentication code covers all of the data, and the salt is
+        // random, so no part of another stream of the entry can be combined
+        if range != (Range { start: 0, end: self.len() }) {
+            return Box::pin(stream::once(future::err("Encrypted entries can only be streamed in full".into())));
+        }
+
+        enum State {
+            Start(BoxBytesStream, Password),
+            Data(BoxBytesStream, Box<(AesCtr, Hmac<Sha1>)>),
+            Done,
+        }
+
+        let data = self.data.stream_range(Range { start: 0, end: self.data.len() });
+
+        // The keys are derived once polled, since the streams of all the parts
+        // of a response are created before any of them are streamed
+        Box::pin(stream::unfold(State::Start(data, self.password.clone()), |state| async move {
+            match state {
+                State::Start(data, password) => {
+                    // PBKDF2 is deliberately slow, so it runs on the blocking
+                    // pool rather than holding up the other streams of this thread
+                    let (salt, keys) = match tokio::task::spawn_blocking(move || derive_keys(&password)).await {
+                        Ok(Ok(derived)) => derived,
+                        Ok(Err(e)) => return Some((Err(e), State::Done)),
+                        Err(e) => return Some((Err(format!("Failed to derive keys: {}", e).into()), State::Done)),
+                    };
+                    let cipher = AesCtr::new(keys[..32].into(), &1u128.to_le_bytes().into());
+                    let mac = Hmac::<Sha1>::new_from_slice(&keys[32..64]).unwrap();
+
+                    let mut header = BytesMut::with_capacity(AES_SALT_LEN + AES_VERIFIER_LEN);
+                    header.put_slice(&salt);
+                    header.put_slice(&keys[64..]);
+                    Some((Ok(header.freeze()), State::Data(data, Box::new((cipher, mac)))))
+                }
+                State::Data(mut data, mut keys) => match data.next().await {
+                    Some(Ok(chunk)) => {
+                        let (cipher, mac) = &mut *keys;
+                        let mut buf = BytesMut::from(&chunk[..]);
+                        cipher.apply_keystream(&mut buf);
+                        mac.update(&buf);
+                        Some((Ok(buf.freeze()), State::Data(data, keys)))
+                    }
+                    Some(Err(e)) => Some((Err(e), State::Done)),
+                    None => {
+                        let code = keys.1.finalize().into_bytes();
+                        Some((Ok(Bytes::copy_from_slice(&code[..AES_AUTH_CODE_LEN])), State::Done))
+                    }
+                },
+                State::Done => None,
+            }
+        }))
+    }
+}
+
+/// A random salt, and the encryption key, authentication key, and password
+/// verification value derived from it and `password`
+fn derive_keys(password: &Password) -> Result<([u8; AES_SALT_LEN], [u8; 32 + 32 + AES_VERIFIER_LEN]), BoxError> {
+    let mut salt = [0; AES_SALT_LEN];
+    getrandom::getrandom(&mut salt).map_err(|e| format!("Failed to generate salt: {}", e))?;
+
+    let mut keys = [0; 32 + 32 + AES_VERIFIER_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha1>(&password.0, &salt, AES_KEY_ITERATIONS, &mut keys);
+    Ok((salt, keys))
+}
+
+/// Concatenated central directory file headers for `files`, with the first
+/// local header at `offset`
+fn central_directory_records(files: &[ZipEntry], mut offset: u64, options: &ZipOptions) -> Result<Bytes, ZipError> {
+    let mut buf = BytesMut::new();
+    for file in files {
+        if file.crc.is_none() {
+            return Err(ZipError::HintRequiresCrc { archive_path: file.archive_path.clone() });
+        }
+        buf.extend_from_slice(&central_directory_file_header(file, offset, options));
+        offset += EntryShape::of(file).local_header_len(options) + file.data.len();
+    }
+    Ok(buf.freeze())
+}
+
+/// Build the entry added by `ZipOptions::central_directory_hint`, to be placed
+/// before `files`.
+fn central_directory_hint(files: &[ZipEntry], options: &ZipOptions) -> Result<ZipEntry, ZipError> {
+    let last_modified = files.iter().map(|f| f.last_modified).max().unwrap_or(DateTime::UNIX_EPOCH);
+    let entry = |data: Bytes| ZipEntry {
+        archive_path: CENTRAL_DIRECTORY_HINT_PATH.into(),
+        crc: Some(crc32fast::hash(&data)),
+        data: Box::new(data),
+        last_modified,
+        is_directory: false,
+        comment: String::new(),
+        compression: Compression::Stored,
+    };
+
+    // The records contain the offsets of the other entries, which follow the
+    // hint, so their content depends on the hint's own length. Lengths only
+    // grow as offsets cross the zip64 threshold, so this converges.
+    let mut hint = entry(Bytes::new());
+    loop {
+        let offset = archive_prefix_len(options) + local_file_header(&hint, options).len() as u64 + hint.data.len();
+        let records = central_directory_records(files, offset, options)?;
+
+        if records.len() as u64 == hint.data.len() {
+            return Ok(entry(records));
+        }
+
+        hint = entry(records);
+    }
+}
+
+/// Position of an entry within an archive, returned by `zip_stream_with_layout`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryLayout {
+    pub archive_path: String,
+
+    /// Offset of the entry's local file header
+    pub header_offset: u64,
+
+    /// Offset of the entry's data, following the local file header
+    pub data_offset: u64,
+
+    /// Length of the entry's data
+    pub length: u64,
+}
+
 /// Create a `StreamRange` that produces a ZIP file with the passed entries.
-pub fn zip_stream(files: impl IntoIterator<Item = ZipEntry>, options: ZipOptions) -> impl StreamRange {
+/// Fails if the entries can't be written with `options`, as described by
+/// `ZipError`.
+pub fn zip_stream(files: impl IntoIterator<Item = ZipEntry>, options: ZipOptions) -> Result<impl StreamRange, ZipError> {
+    Ok(zip_stream_with_layout(files, options)?.0)
+}
+
+/// Like `zip_stream`, but also return the position of each entry in the
+/// archive, including any added by `options`.
+pub fn zip_stream_with_layout(files: impl IntoIterator<Item = ZipEntry>, options: ZipOptions) -> Result<(impl StreamRange, Vec<EntryLayout>), ZipError> {
+    let mut files: Vec<ZipEntry> = files.into_iter().map(|file| match &options.password {
+        Some(password) => encrypt(file, password),
+        None => file,
+    }).collect();
+
+    if options.central_directory_hint {
+        let hint = central_directory_hint(&files, &options)?;
+        files.insert(0, hint);
+    }
+
     let mut data_parts: Vec<Box<dyn StreamRange>> = Vec::new();
     let mut central_directory_parts: Vec<Box<dyn StreamRange>> = Vec::new();
-    let mut offset = 0;
+    let mut layout = Vec::with_capacity(files.len());
+    let mut offset = archive_prefix_len(&options);
+
+    if options.spanning_marker {
+        data_parts.push(Box::new(Bytes::from_static(SPANNING_MARKER)));
+    }
+    let mut timestamp_field: Option<(DateTime<Utc>, Bytes)> = None;
 
     for file in files {
-        let local_header = local_file_header(&file, options.force_zip64);
-        let central_header = central_directory_file_header(&file, offset, options.force_zip64);
+        if file.crc.is_none() && file.compression != Compression::Stored {
+            return Err(ZipError::CompressedRequiresCrc { archive_path: file.archive_path });
+        }
 
-        offset += local_header.len() as u64 + file.data.len() as u64;
+        let timestamp_field = match &timestamp_field {
+            Some((last_modified, field)) if *last_modified == file.last_modified => field.clone(),
+            _ => timestamp_field.insert((file.last_modified, timestamp_fields(file.last_modified, &options))).1.clone(),
+        };
 
-        data_parts.push(Box::new(local_header));
-        data_parts.push(file.data);
+        let header_prefix = local_file_header_prefix(&file, &options, &timestamp_field);
+        let central_header = central_directory_file_header_with(&file, offset, &options, &timestamp_field);
 
-        central_directory_parts.push(Box::new(central_header));
+        let header_len = header_prefix.len() as u64 + timestamp_field.len() as u64;
+
+        layout.push(EntryLayout {
+            archive_path: file.archive_path.clone(),
+            header_offset: offset,
+            data_offset: offset + header_len,
+            length: file.data.len(),
+        });
+
+        offset += header_len + file.data.len() + data_descriptor_len(&file, &options);
+
+        let data_descriptor = if file.crc.is_none() {
+            // The CRC of empty data is known without streaming it
+            let crc = Arc::new(if file.data.is_empty() { OnceLock::from(0) } else { OnceLock::new() });
+            central_directory_parts.push(Box::new(PendingCrc {
+                bytes: central_header,
+                crc_offset: CENTRAL_HEADER_CRC_OFFSET,
+                crc: crc.clone(),
+                archive_path: file.archive_path.clone(),
+            }));
+            Some(PendingCrc {
+                bytes: data_descriptor(&file, &options),
+                crc_offset: DATA_DESCRIPTOR_CRC_OFFSET,
+                crc,
+                archive_path: file.archive_path.clone(),
+            })
+        } else {
+            central_directory_parts.push(Box::new(central_header));
+            None
+        };
+
+        data_parts.push(Box::new(LocalEntry { header_prefix, timestamp_field, archive_path: file.archive_path, data: file.data, data_descriptor }));
     }
 
     let num_entries = central_directory_parts.len() as u64;
-    let size_of_central_directory = central_directory_parts.iter().map(|x| x.len() as u64).sum();
+    let size_of_central_directory = central_directory_parts.iter().map(|x| x.len()).sum();
+
+    // The central directory headers of entries without a `crc` can only be
+    // streamed once the entries have been, so they aren't prefetched
+    let parts: Vec<Box<dyn StreamRange>> = vec![
+        Box::new(stream_range::Concatenated::new(data_parts).with_prefetch(options.prefetch_entries)),
+        Box::new(stream_range::Concatenated::new(central_directory_parts)),
+        Box::new(end_of_central_directory(offset, size_of_central_directory, num_entries, &options)),
+    ];
+
+    Ok((stream_range::Concatenated::new(parts), layout))
+}
+
+/// Compute the length of the archive `zip_stream` would produce for `files`
+/// and `options`, from the lengths of its headers, without building them.
+pub fn estimate_archive_len(files: &[ZipEntry], options: &ZipOptions) -> u64 {
+    let shape = |file| if options.password.is_some() { encrypted_shape(file) } else { EntryShape::of(file) };
+
+    // Length of the local headers and data, and of the central directory, with the first entry at `offset`
+    let entries_len = |mut offset: u64| {
+        let start = offset;
+        let mut central_directory_len = 0;
+        for shape in files.iter().map(shape) {
+            central_directory_len += shape.central_header_len(offset, options);
+            offset += shape.local_header_len(options) + shape.data_len + shape.data_descriptor_len(options);
+        }
+        (offset - start, central_directory_len)
+    };
 
-    data_parts.extend(central_directory_parts.into_iter());
-    data_parts.push(Box::new(end_of_central_directory(offset, size_of_central_directory, num_entries, options.force_zip64)));
+    let mut num_entries = files.len() as u64;
+    let mut offset = archive_prefix_len(options);
+    let mut central_directory_len = 0;
+
+    if options.central_directory_hint {
+        // Same iteration as `central_directory_hint`, on lengths alone
+        let hint_shape = |len| EntryShape {
+            path_len: CENTRAL_DIRECTORY_HINT_PATH.len() as u64,
+            comment_len: 0,
+            data_len: len,
+            max_len: len,
+            compression: Compression::Stored,
+            has_crc: true,
+        };
+        let mut hint = hint_shape(0);
+        loop {
+            let (_, records_len) = entries_len(offset + hint.local_header_len(options) + hint.data_len);
+            if records_len == hint.data_len {
+                break;
+            }
+            hint = hint_shape(records_len);
+        }
 
-    stream_range::Concatenated(data_parts)
+        num_entries += 1;
+        central_directory_len = hint.central_header_len(offset, options);
+        offset += hint.local_header_len(options) + hint.data_len;
+    }
+
+    let (files_len, files_central_directory_len) = entries_len(offset);
+    offset += files_len;
+    central_directory_len += files_central_directory_len;
+
+    offset + central_directory_len + end_of_central_directory_len(offset, central_directory_len, num_entries, options)
 }
 
 #[cfg(test)]
@@ -240,22 +1187,69 @@ mod test {
             ZipEntry {
                 archive_path: "foo.txt".into(),
                 data: Box::new(Bytes::from_static(&b"xx"[..])),
-                crc: 0xf8e1180f,
+                crc: Some(0xf8e1180f),
                 last_modified: "2006-11-10T15:40:56Z".parse::<DateTime<Utc>>().unwrap(),
+                is_directory: false,
+                comment: String::new(),
+                compression: Compression::Stored,
             },
             ZipEntry {
                 archive_path: "bar.txt".into(),
                 data: Box::new(Bytes::from_static(&b"ABC"[..])),
-                crc: 0xa3830348,
+                crc: Some(0xa3830348),
                 last_modified: "2018-12-06T20:15:59Z".parse::<DateTime<Utc>>().unwrap(),
+                is_directory: false,
+                comment: String::new(),
+                compression: Compression::Stored,
             }
         ]
     }
 
+    /// Check that a header's declared extra field length matches the extra
+    /// fields written after the file name, returning their header IDs.
+    fn check_extra_fields(header: &[u8], name_len_offset: usize, fixed_len: usize) -> Vec<u16> {
+        let field = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]) as usize;
+        let name_len = field(name_len_offset);
+        let extra_len = field(name_len_offset + 2);
+        assert_eq!(header.len(), fixed_len + name_len + extra_len);
+
+        let mut ids = Vec::new();
+        let mut pos = fixed_len + name_len;
+        while pos < header.len() {
+            ids.push(field(pos) as u16);
+            pos += 4 + field(pos + 2);
+        }
+        assert_eq!(pos, header.len());
+        ids
+    }
+
+    #[test]
+    fn test_extra_field_lengths() {
+        let file = test_entries().remove(0);
+
+        for force_zip64 in [false, true] {
+            for (timestamp, ntfs_timestamps) in [(false, false), (true, false), (true, true)] {
+                let options = ZipOptions { force_zip64, ntfs_timestamps, ..Default::default() };
+                let timestamp_field = if timestamp { timestamp_fields(file.last_modified, &options) } else { Bytes::new() };
+                let expected_ids: Vec<u16> = [(force_zip64, 0x0001), (timestamp, 0x5455), (ntfs_timestamps, 0x000A)].iter()
+                    .filter(|(present, _)| *present)
+                    .map(|(_, id)| *id)
+                    .collect();
+
+                let mut local = local_file_header_prefix(&file, &options, &timestamp_field).to_vec();
+                local.extend_from_slice(&timestamp_field);
+                assert_eq!(check_extra_fields(&local, 26, 30), expected_ids, "local, zip64={} timestamp={} ntfs={}", force_zip64, timestamp, ntfs_timestamps);
+
+                let central = central_directory_file_header_with(&file, 1234, &options, &timestamp_field);
+                assert_eq!(check_extra_fields(&central, 28, 46), expected_ids, "central, zip64={} timestamp={} ntfs={}", force_zip64, timestamp, ntfs_timestamps);
+            }
+        }
+    }
+
     /// Exhaustively test that all subranges return the same data as a slice of the whole.
     #[tokio::test]
     async fn test_concat() {
-        let zip = zip_stream(test_entries(), ZipOptions::default());
+        let zip = zip_stream(test_entries(), ZipOptions::default()).unwrap();
         let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
 
         assert_eq!(zip.len(), buf.len() as u64);
@@ -269,30 +1263,475 @@ mod test {
         }
     }
 
+    /// Write a zip file to disk and check it with zipinfo, unzip, and python.
+    fn check_zip(path: &str, buf: &[u8]) {
+        std::fs::write(path, buf).unwrap();
+
+        assert!(Command::new("zipinfo").arg("-v").arg(path).status().unwrap().success());
+        assert!(Command::new("unzip").arg("-t").arg(path).status().unwrap().success());
+        assert!(Command::new("python3").arg("-m").arg("zipfile").arg("-t").arg(path).status().unwrap().success());
+    }
+
+    /// Find the central directory file headers in a generated zip file.
+    fn central_headers(buf: &[u8]) -> Vec<&[u8]> {
+        (0..buf.len().saturating_sub(46))
+            .filter(|&i| buf[i..i+4] == [0x50, 0x4b, 0x01, 0x02])
+            .map(|i| &buf[i..])
+            .collect()
+    }
+
     /// Generate a 32-bit zip file and check it with zipinfo, unzip, and python.
     #[tokio::test]
     async fn test_zip32() {
-        let zip = zip_stream(test_entries(), ZipOptions { force_zip64: false });
+        let zip = zip_stream(test_entries(), ZipOptions { force_zip64: false, ..Default::default() }).unwrap();
 
         let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
-        std::fs::write("test.zip", &buf).unwrap();
-
-        assert!(Command::new("zipinfo").arg("-v").arg("test.zip").status().unwrap().success());
-        assert!(Command::new("unzip").arg("-t").arg("test.zip").status().unwrap().success());
-        assert!(Command::new("python3").arg("-m").arg("zipfile").arg("-t").arg("test.zip").status().unwrap().success());
+        check_zip("test.zip", &buf);
     }
 
     /// Generate a 64-bit zip file and check it with zipinfo, unzip, and python.
     #[tokio::test]
     async fn test_zip64() {
-        let zip = zip_stream(test_entries(), ZipOptions { force_zip64: true });
+        let zip = zip_stream(test_entries(), ZipOptions { force_zip64: true, ..Default::default() }).unwrap();
 
         let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
-        std::fs::write("test64.zip", &buf).unwrap();
+        check_zip("test64.zip", &buf);
+    }
+
+    /// Entries without a `crc` get a data descriptor with the CRC computed
+    /// while streaming, and only a response including their data succeeds.
+    #[tokio::test]
+    async fn test_streamed_crc() {
+        let entries = || {
+            let mut entries: Vec<ZipEntry> = test_entries().into_iter().map(|e| ZipEntry { crc: None, ..e }).collect();
+            entries.push(ZipEntry { archive_path: "empty.txt".into(), data: Box::new(Bytes::new()), ..test_entries().remove(0) });
+            entries[2].crc = None;
+            entries
+        };
+
+        for force_zip64 in [false, true] {
+            let options = ZipOptions { force_zip64, ..Default::default() };
+            let estimate = estimate_archive_len(&entries(), &options);
+            let zip = zip_stream(entries(), options).unwrap();
+            assert_eq!(zip.len(), estimate);
+
+            let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+            assert_eq!(buf.len() as u64, zip.len());
+            check_zip(if force_zip64 { "test_streamed_crc64.zip" } else { "test_streamed_crc.zip" }, &buf);
 
-        assert!(Command::new("zipinfo").arg("-v").arg("test64.zip").status().unwrap().success());
-        assert!(Command::new("unzip").arg("-t").arg("test64.zip").status().unwrap().success());
-        assert!(Command::new("python3").arg("-m").arg("zipfile").arg("-t").arg("test64.zip").status().unwrap().success());
+            // Prefetching the data doesn't stream the data descriptors early
+            let prefetched = zip_stream(entries(), ZipOptions { force_zip64, prefetch_entries: 2, ..Default::default() }).unwrap();
+            assert_eq!(concat(prefetched.stream_range(Range { start: 0, end: zip.len() })).await.unwrap(), buf);
+
+            // Bit 3 is set, and the CRC is zero in the local header and filled in in the central directory
+            assert_eq!(u16::from_le_bytes([buf[6], buf[7]]), FLAG_DATA_DESCRIPTOR);
+            assert_eq!(buf[14..18], [0; 4]);
+            let crcs: Vec<&[u8]> = central_headers(&buf).iter().map(|h| &h[16..20]).collect();
+            assert_eq!(crcs, [&0xf8e1180fu32.to_le_bytes()[..], &0xa3830348u32.to_le_bytes()[..], &[0; 4][..]]);
+
+            // The central directory alone can't be streamed before the data
+            let zip = zip_stream(entries(), ZipOptions { force_zip64, ..Default::default() }).unwrap();
+            let central_start = buf.len() - central_headers(&buf)[0].len();
+            let err = concat(zip.stream_range(Range { start: central_start as u64, end: zip.len() })).await.unwrap_err();
+            assert_eq!(err.to_string(), "Failed to read data for foo.txt");
+        }
+
+        // The CRC of compressed data can't be computed while streaming it
+        let mut compressed = entries();
+        compressed[0].compression = Compression::Deflate { uncompressed_len: 2 };
+        let result = zip_stream(compressed, ZipOptions::default());
+        assert_eq!(result.err(), Some(ZipError::CompressedRequiresCrc { archive_path: "foo.txt".into() }));
+    }
+
+    /// A source that produces more or fewer bytes than its declared length
+    /// fails the entry, rather than shifting the rest of the archive.
+    #[tokio::test]
+    async fn test_data_length() {
+        /// Claims `len` bytes, but streams `data` for any range
+        struct WrongLen { len: u64, data: Bytes }
+
+        impl StreamRange for WrongLen {
+            fn len(&self) -> u64 { self.len }
+            fn stream_range(&self, _range: Range) -> BoxBytesStream {
+                Box::pin(futures::stream::iter([Ok(self.data.slice(..1)), Ok(self.data.slice(1..))]))
+            }
+        }
+
+        for (len, message) in [(3, "Data ended after 2 of its 3 bytes"), (1, "Data is longer than its length of 1 bytes")] {
+            let mut entries = test_entries();
+            entries[0].data = Box::new(WrongLen { len, data: Bytes::from_static(b"xx") });
+            let (zip, layout) = zip_stream_with_layout(entries, ZipOptions::default()).unwrap();
+
+            let mut stream = zip.stream_range(Range { start: 0, end: zip.len() });
+            let mut streamed = 0;
+            let err = loop {
+                match stream.next().await.unwrap() {
+                    Ok(chunk) => streamed += chunk.len() as u64,
+                    Err(e) => break e,
+                }
+            };
+            assert_eq!(err.to_string(), "Failed to read data for foo.txt");
+            assert_eq!(err.source().unwrap().to_string(), message);
+            assert_eq!(streamed, layout[0].data_offset + len.min(2));
+        }
+    }
+
+    /// Non-ASCII names have the UTF-8 flag, and extract with the right name.
+    #[tokio::test]
+    async fn test_utf8_names() {
+        let name = "café/日本語.txt";
+        let mut entries = test_entries();
+        entries[1].archive_path = name.into();
+        let zip = zip_stream(entries, ZipOptions::default()).unwrap();
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        let flags = |header: &[u8], offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let central = central_headers(&buf);
+        assert_eq!(flags(&buf, 6), 0);
+        assert_eq!(flags(central[0], 8), 0);
+        assert_eq!(flags(central[1], 8), FLAG_UTF8);
+        let local = &buf[buf.windows(name.len()).position(|w| w == name.as_bytes()).unwrap() - 30..];
+        assert_eq!(flags(local, 6), FLAG_UTF8);
+
+        check_zip("test_utf8.zip", &buf);
+
+        let output = Command::new("python3").arg("-c")
+            .arg("import sys, zipfile; z = zipfile.ZipFile('test_utf8.zip'); sys.stdout.buffer.write('\\n'.join(z.namelist()).encode())")
+            .output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("foo.txt\n{}", name));
+
+        let dir = std::env::temp_dir().join(format!("zipstream_test_utf8_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        // unzip writes non-ASCII names as escapes like #U00e9 unless the locale is UTF-8
+        let status = Command::new("unzip").env("LC_ALL", "C.UTF-8").arg("-q").arg("test_utf8.zip").arg("-d").arg(&dir).status().unwrap();
+        assert!(status.success());
+        assert_eq!(std::fs::read(dir.join(name)).unwrap(), b"ABC");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Stream an archive of `S3Object` entries backed by an in-memory object source.
+    #[tokio::test]
+    async fn test_object_source() {
+        use crate::stream_range::S3Object;
+        use crate::test_util::MemoryObjects;
+        use std::sync::Arc;
+
+        let mut objects = MemoryObjects::default();
+        objects.insert("bucket", "foo", &b"xx"[..]);
+        objects.insert("bucket", "bar", &b"ABC"[..]);
+        let source: Arc<dyn crate::stream_range::ObjectSource> = Arc::new(objects);
+
+        let entries = test_entries().into_iter().zip(["foo", "bar"]).map(|(entry, key)| ZipEntry {
+            data: Box::new(S3Object { source: source.clone(), bucket: "bucket".into(), key: key.into(), offset: 0, len: entry.data.len() }),
+            ..entry
+        });
+        let zip = zip_stream(entries, ZipOptions::default()).unwrap();
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        let expected = zip_stream(test_entries(), ZipOptions::default()).unwrap();
+        assert_eq!(buf, concat(expected.stream_range(Range { start: 0, end: expected.len() })).await.unwrap());
+
+        let range = Range { start: 60, end: 130 };
+        assert_eq!(concat(zip.stream_range(range)).await.unwrap(), buf[60..130]);
+
+        check_zip("test_object_source.zip", &buf);
+    }
+
+    /// The central directory hint entry matches the actual central directory.
+    #[tokio::test]
+    async fn test_central_directory_hint() {
+        let zip = zip_stream(test_entries(), ZipOptions { central_directory_hint: true, ..Default::default() }).unwrap();
+
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        // Two records in the hint entry, then three in the central directory
+        let headers = central_headers(&buf);
+        assert_eq!(headers.len(), 5);
+
+        // The first entry's data is the central directory, minus its own record
+        let name_len = u16::from_le_bytes([buf[26], buf[27]]) as usize;
+        let extra_len = u16::from_le_bytes([buf[28], buf[29]]) as usize;
+        let data_len = u32::from_le_bytes([buf[18], buf[19], buf[20], buf[21]]) as usize;
+        assert_eq!(&buf[30..30 + name_len], CENTRAL_DIRECTORY_HINT_PATH.as_bytes());
+        let hint = &buf[30 + name_len + extra_len..][..data_len];
+
+        let cd_start = buf.len() - headers[3].len();
+        assert_eq!(hint, &buf[cd_start..cd_start + data_len]);
+        assert_eq!(&headers[2][46..46 + name_len], CENTRAL_DIRECTORY_HINT_PATH.as_bytes());
+
+        check_zip("test_hint.zip", &buf);
+
+        let mut entries = test_entries();
+        entries[1].crc = None;
+        let path = entries[1].archive_path.clone();
+        let result = zip_stream(entries, ZipOptions { central_directory_hint: true, ..Default::default() });
+        assert_eq!(result.err(), Some(ZipError::HintRequiresCrc { archive_path: path }));
+    }
+
+    /// Sharing the extended timestamp field between entries with the same
+    /// modification time doesn't change the output.
+    #[tokio::test]
+    async fn test_shared_timestamp_field() {
+        let mut entries = test_entries();
+        entries[1].last_modified = entries[0].last_modified;
+        let zip = zip_stream(entries, ZipOptions::default()).unwrap();
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        assert_eq!(crc32fast::hash(&buf), 0xbbb56943);
+        check_zip("test_shared.zip", &buf);
+
+        for (force_zip64, crc) in [(false, 0xa64cddbd), (true, 0x2215355e)] {
+            let zip = zip_stream(test_entries(), ZipOptions { force_zip64, ..Default::default() }).unwrap();
+            let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+            assert_eq!(crc32fast::hash(&buf), crc);
+        }
+    }
+
+    /// `estimate_archive_len` matches the length of the archive.
+    #[test]
+    fn test_estimate_archive_len() {
+        /// Data that is never read, to test large lengths
+        struct Unread(u64);
+
+        impl StreamRange for Unread {
+            fn len(&self) -> u64 { self.0 }
+            fn stream_range(&self, _range: Range) -> BoxBytesStream { unreachable!() }
+        }
+
+        let large_entries = || {
+            let mut entries = test_entries();
+            entries[0].data = Box::new(Unread(0x1_0000_0000));
+            entries.push(ZipEntry { archive_path: "baz.txt".into(), data: Box::new(Unread(5)), comment: "key=value".into(), ..test_entries().remove(0) });
+            entries
+        };
+
+        let options = [
+            ZipOptions::default(),
+            ZipOptions { force_zip64: true, ..Default::default() },
+            ZipOptions { central_directory_hint: true, ..Default::default() },
+            ZipOptions { central_directory_hint: true, force_zip64: true, ..Default::default() },
+            ZipOptions { comment: "comment".into(), ..Default::default() },
+            ZipOptions { spanning_marker: true, central_directory_hint: true, ..Default::default() },
+            ZipOptions { ntfs_timestamps: true, central_directory_hint: true, ..Default::default() },
+            ZipOptions { password: Some(Password::new(b"secret")), central_directory_hint: true, ..Default::default() },
+            ZipOptions { password: Some(Password::new(b"secret")), force_zip64: true, ..Default::default() },
+        ];
+
+        for options in options {
+            for entries in [test_entries(), large_entries(), vec![]] {
+                let estimate = estimate_archive_len(&entries, &options);
+                assert_eq!(estimate, zip_stream(entries, options.clone()).unwrap().len(), "{:?}", options);
+            }
+        }
+    }
+
+    /// The spanning marker precedes the first entry, and the offsets account for it.
+    #[tokio::test]
+    async fn test_spanning_marker() {
+        for central_directory_hint in [false, true] {
+            let options = ZipOptions { spanning_marker: true, central_directory_hint, ..Default::default() };
+            let (zip, layout) = zip_stream_with_layout(test_entries(), options).unwrap();
+            let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+            assert_eq!(&buf[..8], &[0x50, 0x4b, 0x30, 0x30, 0x50, 0x4b, 0x03, 0x04]);
+            assert_eq!(layout[0].header_offset, 4);
+
+            let path = if central_directory_hint { "test_spanning_marker_hint.zip" } else { "test_spanning_marker.zip" };
+            check_zip(path, &buf);
+        }
+    }
+
+    /// The layout gives the position of each entry's data.
+    #[tokio::test]
+    async fn test_layout() {
+        let (zip, layout) = zip_stream_with_layout(test_entries(), ZipOptions::default()).unwrap();
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        assert_eq!(layout.len(), 2);
+        assert_eq!(layout[0].header_offset, 0);
+        assert_eq!(&buf[layout[0].data_offset as usize..][..2], b"xx");
+        assert_eq!(&buf[layout[1].header_offset as usize..][..4], &[0x50, 0x4b, 0x03, 0x04]);
+        assert_eq!(&buf[layout[1].data_offset as usize..][..3], b"ABC");
+        assert_eq!(layout[1].length, 3);
+    }
+
+    /// Selecting the FAT host system changes "version made by" and the attribute encoding.
+    #[tokio::test]
+    async fn test_host_system_fat() {
+        let zip = zip_stream(test_entries(), ZipOptions { host_system: HostSystem::Fat, version_made_by: Some(63), ..Default::default() }).unwrap();
+
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        let headers = central_headers(&buf);
+        assert_eq!(headers.len(), 2);
+        for header in headers {
+            assert_eq!(header[4], 63); // version made by
+            assert_eq!(header[5], 0); // host system = FAT
+            assert_eq!(header[38..42], 0x20u32.to_le_bytes()); // external attributes
+        }
+
+        check_zip("test_fat.zip", &buf);
+
+        // The zip64 end of central directory record has the same version made by
+        let zip = zip_stream(test_entries(), ZipOptions { host_system: HostSystem::Fat, version_made_by: Some(63), force_zip64: true, ..Default::default() }).unwrap();
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        let end = (0..buf.len() - 4).find(|&i| buf[i..i+4] == [0x50, 0x4b, 0x06, 0x06]).unwrap();
+        assert_eq!(buf[end + 12..end + 14], [63, 0]);
+        check_zip("test_fat_zip64.zip", &buf);
+    }
+
+    #[tokio::test]
+    async fn test_min_version() {
+        let zip = zip_stream(test_entries(), ZipOptions { force_zip64: true, min_version: Some(63), ..Default::default() }).unwrap();
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        let version_needed = |signature: [u8; 4], field: usize| -> Vec<u16> {
+            (0..buf.len() - 4)
+                .filter(|&i| buf[i..i+4] == signature)
+                .map(|i| u16::from_le_bytes([buf[i + field], buf[i + field + 1]]))
+                .collect()
+        };
+        assert_eq!(version_needed([0x50, 0x4b, 0x03, 0x04], 4), [63, 63]); // local file headers
+        assert_eq!(version_needed([0x50, 0x4b, 0x01, 0x02], 6), [63, 63]); // central directory
+        assert_eq!(version_needed([0x50, 0x4b, 0x06, 0x06], 14), [63]); // zip64 end of central directory
+
+        // unzip only supports up to version 4.6, so check with python
+        std::fs::write("test_min_version.zip", &buf).unwrap();
+        assert!(Command::new("python3").arg("-m").arg("zipfile").arg("-t").arg("test_min_version.zip").status().unwrap().success());
+
+        // A floor below what's needed has no effect
+        let zip = zip_stream(test_entries(), ZipOptions { force_zip64: true, min_version: Some(20), ..Default::default() }).unwrap();
+        let default = zip_stream(test_entries(), ZipOptions { force_zip64: true, ..Default::default() }).unwrap();
+        assert_eq!(
+            concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap(),
+            concat(default.stream_range(Range { start: 0, end: default.len() })).await.unwrap(),
+        );
+    }
+
+    /// The NTFS field keeps the sub-second part of times past 2038 in both
+    /// headers, where the DOS time and extended timestamp can't
+    #[tokio::test]
+    async fn test_ntfs_timestamps() {
+        let mut entries = test_entries();
+        entries[0].last_modified = "2040-02-29T12:34:56.789Z".parse().unwrap();
+        let zip = zip_stream(entries, ZipOptions { ntfs_timestamps: true, ..Default::default() }).unwrap();
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        check_zip("test_ntfs_timestamps.zip", &buf);
+
+        // Python's zipfile ignores the field, so read it from the raw extra
+        // fields of the central and local headers
+        let script = r#"
+import struct, zipfile
+from datetime import datetime, timedelta
+def ntfs_mtime(extra):
+    while extra:
+        tag, size = struct.unpack('<HH', extra[:4])
+        if tag == 0x000A:
+            return datetime(1601, 1, 1) + timedelta(microseconds=struct.unpack('<Q', extra[12:20])[0] // 10)
+        extra = extra[4 + size:]
+z = zipfile.ZipFile('test_ntfs_timestamps.zip')
+for info in z.infolist():
+    z.fp.seek(info.header_offset)
+    header = z.fp.read(30)
+    name_len, extra_len = struct.unpack('<HH', header[26:30])
+    local_extra = z.fp.read(name_len + extra_len)[name_len:]
+    assert ntfs_mtime(local_extra) == ntfs_mtime(info.extra)
+    print(info.filename, ntfs_mtime(info.extra).isoformat())
+"#;
+        let output = Command::new("python3").arg("-c").arg(script).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "foo.txt 2040-02-29T12:34:56.789000\nbar.txt 2018-12-06T20:15:59\n");
+    }
+
+    /// Times outside the range of the DOS and extended timestamp fields are
+    /// clamped to their limits rather than wrapping to unrelated dates
+    #[tokio::test]
+    async fn test_out_of_range_timestamps() {
+        let mut entries = test_entries();
+        entries[0].last_modified = "1970-01-01T00:00:00Z".parse().unwrap();
+        entries[1].last_modified = "2200-06-15T12:00:00Z".parse().unwrap();
+        let zip = zip_stream(entries, ZipOptions::default()).unwrap();
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        check_zip("test_out_of_range_timestamps.zip", &buf);
+
+        let script = r#"
+import struct, zipfile
+for info in zipfile.ZipFile('test_out_of_range_timestamps.zip').infolist():
+    tag, size, flags, mtime = struct.unpack('<HHBI', info.extra[:9])
+    assert tag == 0x5455
+    print(info.filename, info.date_time, mtime)
+"#;
+        let output = Command::new("python3").arg("-c").arg(script).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "foo.txt (1980, 1, 1, 0, 0, 0) 0\nbar.txt (2107, 12, 31, 23, 59, 58) 4294967295\n");
+    }
+
+    /// Entries encrypted with a password are read back by another
+    /// implementation of WinZip AES, as 7-Zip and WinZip would read them
+    #[tokio::test]
+    async fn test_encryption() {
+        let contents = b"compressible ".repeat(100);
+        let mut entries = test_entries();
+        entries[1].crc = None;
+        entries.push(ZipEntry {
+            archive_path: "deflated.txt".into(),
+            crc: Some(crc32fast::hash(&contents)),
+            data: Box::new(Bytes::from(miniz_oxide::deflate::compress_to_vec(&contents, 6))),
+            compression: Compression::Deflate { uncompressed_len: contents.len() as u64 },
+            ..test_entries().remove(0)
+        });
+        entries.push(ZipEntry { archive_path: "empty.txt".into(), data: Box::new(Bytes::new()), crc: Some(0), ..test_entries().remove(0) });
+        entries.push(ZipEntry { archive_path: "dir/".into(), data: Box::new(Bytes::new()), crc: Some(0), is_directory: true, ..test_entries().remove(0) });
+
+        let options = ZipOptions { password: Some(Password::new(b"secret")), central_directory_hint: true, ..Default::default() };
+        assert!(!format!("{:?}", options).contains("secret"));
+        let (zip, layout) = zip_stream_with_layout(entries, options).unwrap();
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        assert_eq!(buf.len() as u64, zip.len());
+
+        // unzip doesn't support AES, but zipinfo lists the entries
+        std::fs::write("test_encryption.zip", &buf).unwrap();
+        assert!(Command::new("zipinfo").arg("-v").arg("test_encryption.zip").status().unwrap().success());
+
+        // 7-Zip checks the authentication codes as well, when it is installed
+        if let Some(seven_zip) = ["7zz", "7z", "7za"].iter().find(|name| Command::new(name).arg("i").output().is_ok()) {
+            assert!(Command::new(seven_zip).arg("t").arg("-psecret").arg("test_encryption.zip").status().unwrap().success());
+            let output = Command::new(seven_zip).arg("e").arg("-so").arg("-psecret").arg("test_encryption.zip").arg("deflated.txt").output().unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout, contents);
+        }
+
+        // Each stream has new salts
+        let again = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        assert_ne!(buf, again);
+
+        let mut archive = ::zip::ZipArchive::new(std::io::Cursor::new(buf)).unwrap();
+        let expected: [(&str, &[u8]); 6] = [
+            (CENTRAL_DIRECTORY_HINT_PATH, b""),
+            ("foo.txt", b"xx"),
+            ("bar.txt", b"ABC"),
+            ("deflated.txt", &contents),
+            ("empty.txt", b""),
+            ("dir/", b""),
+        ];
+        for (name, data) in expected {
+            let encrypted = !matches!(name, CENTRAL_DIRECTORY_HINT_PATH | "dir/");
+            assert_eq!(archive.by_name(name).is_err(), encrypted, "{}", name);
+            if encrypted {
+                assert!(archive.by_name_decrypt(name, b"wrong").is_err(), "{}", name);
+            }
+
+            let mut file = archive.by_name_decrypt(name, b"secret").unwrap();
+            let mut extracted = Vec::new();
+            std::io::Read::read_to_end(&mut file, &mut extracted).unwrap();
+            if name != CENTRAL_DIRECTORY_HINT_PATH {
+                assert_eq!(extracted, data, "{}", name);
+            }
+        }
+
+        // Ranges of encrypted data can't be streamed
+        let foo = &layout[1];
+        assert_eq!(foo.length, 2 + AES_OVERHEAD);
+        assert!(concat(zip.stream_range(Range { start: foo.data_offset, end: foo.data_offset + 1 })).await.is_err());
     }
-    
 }